@@ -13,6 +13,11 @@ use directories::BaseDirs;
 use emulator_connect::connections::federated::FederatedEmulatorConnection;
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
 use emulator_connect::CTVEmulator;
+use sapio_psbt::external_signer::ExternalSigner;
+use sapio_wasm_plugin::host::limits::PluginLimits;
+use sapio_wasm_plugin::host::log::LogConfig;
+use sapio_wasm_plugin::host::queries::CommandOracle;
+use sapio_wasm_plugin::host::trust::TrustStore;
 use schemars::JsonSchema;
 use serde::*;
 use std::collections::BTreeMap;
@@ -31,7 +36,9 @@ pub struct EmulatorConfig {
     /// if the emulator should be used or not. We tag explicitly for convenience
     /// in the config file format.
     pub enabled: bool,
-    /// list of emulators to use & how to contact them
+    /// list of emulators to use & how to contact them. The EPK is pinned as
+    /// the identity of the server at that address: connecting fails if the
+    /// server cannot authenticate as the holder of the EPK's private key.
     #[schemars(with = "Vec<(String, String)>")]
     pub emulators: Vec<(ExtendedPubKey, String)>,
     /// threshold could be larger than u8, but that seems very unlikely/an error.
@@ -134,7 +141,7 @@ pub enum Auth {
 }
 
 /// Which Bitcoin Node should Sapio use
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct Node {
     /// the url to connect to
     pub url: String,
@@ -153,9 +160,28 @@ pub struct NetworkConfig {
     pub api_node: Node,
     /// the emulator to use, if any
     pub emulator_nodes: Option<EmulatorConfig>,
-    /// mapping of name:module hash for translation during compilation
+    /// mapping of name:module hash for translation during compilation,
+    /// superseded by plugin_lockfile if that is set
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub plugin_map: Option<BTreeMap<String, WasmerCacheHash>>,
+    /// a lockfile (made with `contract lock`) pinning plugin names to modules
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_lockfile: Option<PathBuf>,
+    /// the external signer to sign PSBTs with, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub signer: Option<ExternalSigner>,
+    /// fuel and memory limits for running plugins, defaults if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_limits: Option<PluginLimits>,
+    /// the publishers whose plugins are trusted, defaults to warning about all
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_trust: Option<TrustStore>,
+    /// oracles plugins may ask for prices, by name
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub oracles: Option<BTreeMap<String, CommandOracle>>,
+    /// where log records from plugins go, stderr if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_log: Option<LogConfig>,
 }
 
 impl From<WasmerCacheHash> for [u8; 32] {
@@ -441,6 +467,12 @@ impl ConfigVerifier {
                     "example.please.change.this.before.using:8367".into())],
             }),
             plugin_map: None,
+            plugin_lockfile: None,
+            signer: None,
+            plugin_limits: None,
+            plugin_trust: None,
+            oracles: None,
+            plugin_log: None,
         };
         let cv: ConfigVerifier = Config { network, active }.into();
         println!(
@@ -488,6 +520,12 @@ impl std::default::Default for ConfigVerifier {
                     "ctv.d31373.org:8367".into())],
             }),
             plugin_map: None,
+            plugin_lockfile: None,
+            signer: None,
+            plugin_limits: None,
+            plugin_trust: None,
+            oracles: None,
+            plugin_log: None,
         };
         ConfigVerifier {
             main: None,
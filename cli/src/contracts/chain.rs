@@ -0,0 +1,167 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! chain data for plugins, from the configured node
+use crate::config::Node;
+use bitcoin::{OutPoint, TxOut};
+use bitcoincore_rpc_async as rpc;
+use bitcoincore_rpc_async::RpcApi;
+use sapio_base::txindex::{AsyncTxIndex, CachedTxIndex};
+use sapio_tools::{block_on, AsyncBitcoinNodeIndex, DiskTxIndex};
+use sapio_wasm_plugin::host::queries::ChainData;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::runtime::Runtime;
+use tokio::sync::OnceCell;
+
+/// the transaction index binding uses: the disk cache, backed by the node
+type NodeIndex = CachedTxIndex<DiskTxIndex, AsyncBitcoinNodeIndex>;
+
+/// Answers plugins' chain queries from a node, which is only connected to
+/// if a plugin makes a query. Outputs are looked up in the same transaction
+/// index as binding, so transactions already cached aren't fetched again.
+///
+/// Plugins run synchronously, so queries are run on a runtime of their own.
+pub struct NodeChainData {
+    node: Node,
+    cache: PathBuf,
+    index: OnceCell<NodeIndex>,
+    runtime: Option<Runtime>,
+}
+
+impl NodeChainData {
+    /// Answer queries from `node`, caching transactions in the directory
+    /// `cache`
+    pub fn new(node: Node, cache: PathBuf) -> std::io::Result<Self> {
+        Ok(NodeChainData {
+            node,
+            cache,
+            index: OnceCell::new(),
+            runtime: Some(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?,
+            ),
+        })
+    }
+
+    /// the index, connecting to the node on the first query
+    async fn index(&self) -> Result<&NodeIndex, String> {
+        self.index
+            .get_or_try_init(|| async {
+                let Node { url, auth } = self.node.clone();
+                let client = rpc::Client::new(url, auth)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let cache = DiskTxIndex::open(&self.cache, Default::default())
+                    .map_err(|e| e.to_string())?;
+                Ok(CachedTxIndex {
+                    cache,
+                    primary: AsyncBitcoinNodeIndex {
+                        client,
+                        can_add: false,
+                    },
+                })
+            })
+            .await
+    }
+
+    fn block_on<T: Send>(
+        &self,
+        f: impl Future<Output = Result<T, String>> + Send,
+    ) -> Result<T, String> {
+        block_on(self.runtime.as_ref().expect("only taken when dropped"), f)
+    }
+}
+
+impl Drop for NodeChainData {
+    fn drop(&mut self) {
+        // requests are handled in async code, where a runtime can't be dropped
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl ChainData for NodeChainData {
+    fn tip_height(&self) -> Result<u32, String> {
+        self.block_on(async {
+            let index = self.index().await?;
+            index
+                .primary
+                .client
+                .get_block_count()
+                .await
+                .map(|h| h as u32)
+                .map_err(|e| e.to_string())
+        })
+    }
+    fn lookup_output(&self, out: &OutPoint) -> Result<TxOut, String> {
+        self.block_on(async {
+            let index = self.index().await?;
+            index.lookup_output(out).await.map_err(|e| e.to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sapio_base::txindex::TxIndex;
+    use std::sync::Arc;
+
+    /// chain data for a node which isn't there, with `tx` in its cache
+    fn unreachable_node(name: &str, tx: &bitcoin::Transaction) -> NodeChainData {
+        let dir =
+            std::env::temp_dir().join(format!("sapio-cli-chain-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        DiskTxIndex::open(&dir, Default::default())
+            .unwrap()
+            .cache_tx(Arc::new(tx.clone()))
+            .unwrap();
+        let node = Node {
+            url: "http://127.0.0.1:1".into(),
+            auth: rpc::Auth::None,
+        };
+        NodeChainData::new(node, dir).unwrap()
+    }
+
+    fn example_tx() -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn outputs_come_from_the_index() {
+        let tx = example_tx();
+        let chain = unreachable_node("sync", &tx);
+        assert_eq!(
+            chain.lookup_output(&OutPoint::new(tx.txid(), 0)),
+            Ok(tx.output[0].clone())
+        );
+        assert!(chain.lookup_output(&OutPoint::new(tx.txid(), 1)).is_err());
+        assert!(chain.tip_height().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queries_from_async_code() {
+        let tx = example_tx();
+        let chain = unreachable_node("async", &tx);
+        assert_eq!(
+            chain.lookup_output(&OutPoint::new(tx.txid(), 0)),
+            Ok(tx.output[0].clone())
+        );
+        // and dropping it here doesn't panic
+        drop(chain);
+    }
+}
@@ -4,6 +4,8 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod chain;
+pub mod native;
 pub mod request;
 pub mod server;
 pub use request::*;
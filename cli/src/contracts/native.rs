@@ -0,0 +1,21 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the sapio-contrib contracts, linked in as native plugins, so they can be
+//! listed and called like loaded plugins, and called by name from them.
+use sapio_contrib::contracts::readme_contracts::{
+    BasicEscrow, BasicEscrow2, PayToPublicKey, TrustlessEscrow,
+};
+use sapio_contrib::contracts::treepay::TreePay;
+use sapio_contrib::contracts::undo_send::UndoSendInternal;
+use sapio_wasm_plugin::NATIVE_PLUGIN;
+
+NATIVE_PLUGIN![TreePay, "treepay"];
+NATIVE_PLUGIN![PayToPublicKey, "pay_to_public_key"];
+NATIVE_PLUGIN![BasicEscrow, "basic_escrow"];
+NATIVE_PLUGIN![BasicEscrow2, "basic_escrow_2"];
+NATIVE_PLUGIN![TrustlessEscrow, "trustless_escrow"];
+NATIVE_PLUGIN![UndoSendInternal, "undo_send"];
@@ -18,11 +18,25 @@ use sapio::{
 };
 use sapio_base::{
     effects::{MapEffectDB, PathFragment},
+    psbt_v2::{PsbtV2, PsbtVersion},
     serialization_helpers::SArc,
-    txindex::{TxIndex, TxIndexLogger},
+    txindex::{CachedTxIndex, TxIndex, TxIndexLogger},
 };
+use sapio_psbt::external_signer::ExternalSigner;
+use sapio_tools::{AsyncBitcoinNodeIndex, DiskTxIndex};
 use sapio_wasm_plugin::{
-    host::{plugin_handle::ModuleLocator, PluginHandle, WasmPluginHandle},
+    host::{
+        limits::PluginLimits,
+        lockfile::PluginLockfile,
+        log::{LogCollector, LogConfig, Tee},
+        plugin_handle::{ModuleLocator, PluginConfig, WASMCacheID},
+        queries::{ChainData, CommandOracle, HostQueries, PriceOracle},
+        trust::{TrustStore, VerifiedPublisher},
+        NativePluginHandle, PluginHandle, WasmPluginHandle,
+    },
+    host_query::QueryRecord,
+    manifest::PluginManifest,
+    plugin_log::LogRecord,
     CreateArgs, API, OrdinalsInfo,
 };
 use schemars::JsonSchema;
@@ -33,12 +47,33 @@ use std::{
     collections::{BTreeMap, HashMap},
     convert::TryInto,
     error::Error,
-    path::PathBuf,
-    rc::Rc,
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
 };
 
-use crate::{config::EmulatorConfig, util::create_mock_output};
+use crate::{
+    config::{EmulatorConfig, Node},
+    contracts::chain::NodeChainData,
+    util::create_mock_output,
+};
+
+/// Settings for handling a request which pick programs the host runs, files
+/// it writes, or what it trusts. They are set by the host from its own
+/// configuration, and are never read from a request.
+#[derive(Default, Clone)]
+pub struct HostConfig {
+    /// the external signer used by `Sign`
+    pub signer: Option<ExternalSigner>,
+    /// the publishers whose plugins are trusted, defaults to warning about all
+    pub plugin_trust: Option<TrustStore>,
+    /// the node plugins may query chain data from
+    pub chain_node: Option<Node>,
+    /// the oracles plugins may ask for prices, by name
+    pub oracles: Option<BTreeMap<String, CommandOracle>>,
+    /// where log records from plugins go, stderr if not set
+    pub plugin_log: Option<LogConfig>,
+}
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Common {
@@ -48,6 +83,23 @@ pub struct Common {
     #[schemars(with = "String")]
     pub net: bitcoin::Network,
     pub plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+    /// the limits plugins run under, defaults if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_limits: Option<PluginLimits>,
+    /// the lockfile plugins are resolved with and checked against, replacing
+    /// plugin_map if set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_lock: Option<PluginLockfile>,
+    /// answers to plugins' queries to give instead of asking the node or
+    /// oracles, e.g. the `host_queries` recorded in a contract's metadata
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub host_queries: Option<QueryRecord>,
+    /// return the log records from plugins with the response
+    #[serde(default)]
+    pub debug: bool,
+    /// set by the host, see `HostConfig`
+    #[serde(skip)]
+    pub host: HostConfig,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct List;
@@ -74,7 +126,10 @@ pub struct Bind {
     pub use_txn: Option<String>,
     pub compiled: Compiled,
     #[serde(skip_serializing_if = "Option::is_none", default)]
-    pub ordinals_info: Option<OrdinalsInfo>
+    pub ordinals_info: Option<OrdinalsInfo>,
+    /// the version of the PSBTs returned
+    #[serde(default)]
+    pub psbt_version: PsbtVersion,
 }
 pub type BindReturn = Program;
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -95,12 +150,46 @@ pub struct Info;
 pub struct InfoReturn {
     name: String,
     description: String,
+    /// the plugin's manifest, if it was built with one
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    manifest: Option<PluginManifest>,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Load;
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct LoadReturn {
     key: String,
+    /// the trusted publisher who signed the plugin, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    publisher: Option<VerifiedPublisher>,
+}
+
+/// Lock the plugins in the cache by name
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Lock {
+    /// where to write the lockfile, if anywhere
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub output: Option<PathBuf>,
+    /// relock every plugin at its highest version, instead of keeping
+    /// plugins locked by the current lockfile where possible
+    #[serde(default)]
+    pub update: bool,
+}
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct LockReturn {
+    lockfile: PluginLockfile,
+}
+
+/// Sign a PSBT with the configured external signer
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Sign {
+    /// base64 PSBT, version 0 or 2
+    pub psbt: String,
+}
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SignReturn {
+    /// the signed base64 PSBT, in the version given
+    psbt: String,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -112,6 +201,8 @@ pub enum Command {
     Logo(Logo),
     Info(Info),
     Load(Load),
+    Sign(Sign),
+    Lock(Lock),
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub enum CommandReturn {
@@ -122,6 +213,8 @@ pub enum CommandReturn {
     Logo(LogoReturn),
     Info(InfoReturn),
     Load(LoadReturn),
+    Sign(SignReturn),
+    Lock(LockReturn),
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -133,6 +226,9 @@ pub struct Request {
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Response {
     pub result: Result<CommandReturn, RequestError>,
+    /// the log records from plugins, if the request asked for them
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub logs: Option<Vec<LogRecord>>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
@@ -147,6 +243,8 @@ impl Display for RequestError {
 impl Error for RequestError {}
 
 type ResultT<T> = Result<T, Box<dyn Error>>;
+/// either a native or a WASM plugin
+type AnyPluginHandle = Box<dyn PluginHandle<Input = CreateArgs<Value>, Output = Value>>;
 impl Request {
     async fn get_emulator(&self) -> ResultT<Arc<dyn CTVEmulator>> {
         let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &self.context.emulator {
@@ -161,14 +259,25 @@ impl Request {
         Ok(emulator)
     }
     pub async fn handle(self) -> Response {
-        let v = self.handle_inner().await.map_err(|e| -> RequestError {
-            e.downcast::<RequestError>()
-                .map(|d| *d)
-                .unwrap_or_else(|e| RequestError(e.to_string().into()))
-        });
-        Response { result: v }
+        let logs = self
+            .context
+            .debug
+            .then(|| Arc::new(LogCollector::default()));
+        let v = self
+            .handle_inner(logs.clone())
+            .await
+            .map_err(|e| -> RequestError {
+                e.downcast::<RequestError>()
+                    .map(|d| *d)
+                    .unwrap_or_else(|e| RequestError(e.to_string().into()))
+            });
+        Response {
+            result: v,
+            logs: logs.map(|l| l.take()),
+        }
     }
-    pub async fn handle_inner(self) -> ResultT<CommandReturn> {
+    /// handle the request, also sending plugins' log records to `logs` if set
+    pub async fn handle_inner(self, logs: Option<Arc<LogCollector>>) -> ResultT<CommandReturn> {
         let emulator = self.get_emulator().await?;
         // create the future to get the sph,
         // but do not await it since not all calls will use it.
@@ -178,34 +287,95 @@ impl Request {
             module_locator,
             net,
             plugin_map,
+            plugin_limits,
+            plugin_lock,
+            host_queries,
+            host:
+                HostConfig {
+                    signer,
+                    plugin_trust,
+                    chain_node,
+                    oracles,
+                    plugin_log,
+                },
             ..
         } = context;
+        let plugin_map = match &plugin_lock {
+            Some(lock) => Some(lock.plugin_map()?),
+            None => plugin_map,
+        };
+        let chain = match chain_node {
+            Some(node) => {
+                let chain: Arc<dyn ChainData> =
+                    Arc::new(NodeChainData::new(node, tx_cache_dir(&path, net))?);
+                Some(chain)
+            }
+            None => None,
+        };
+        let oracles = oracles
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, o)| -> (String, Arc<dyn PriceOracle>) { (name, Arc::new(o)) })
+            .collect();
+        let mut queries = HostQueries::new(chain, oracles);
+        // a replay answers only what was recorded, anything else is an error
+        if let Some(record) = host_queries {
+            queries = queries.replaying(record);
+        }
+        let mut log_sink = plugin_log.unwrap_or_default().sink()?;
+        if let Some(logs) = logs {
+            log_sink = Arc::new(Tee(vec![log_sink, logs]));
+        }
+        let config = PluginConfig {
+            path: path.clone(),
+            emulator: emulator.clone(),
+            net,
+            plugin_map: plugin_map.unwrap_or_default(),
+            limits: plugin_limits.unwrap_or_default(),
+            trust: Arc::new(plugin_trust.unwrap_or_default()),
+            lock: plugin_lock.clone().map(Arc::new),
+            queries: Arc::new(queries),
+            log: log_sink,
+        };
+        // listing and locking look at every cached plugin, whether or not the
+        // lockfile agrees with it
+        let unlocked = || PluginConfig {
+            lock: None,
+            ..config.clone()
+        };
+        // native plugins are called in process, by key
+        let native_sph = match &module_locator {
+            Some(ModuleLocator::Key(key)) => {
+                NativePluginHandle::get(&WASMCacheID::from_str(key)?, &config)?
+            }
+            _ => None,
+        };
         let default_sph = || -> Result<_, &'static str> {
+            let module_locator =
+                module_locator.ok_or("Expected to have exactly one of key or file")?;
             Ok(WasmPluginHandle::<Value>::new_async(
-                &path,
-                &emulator,
-                module_locator.ok_or("Expected to have exactly one of key or file")?,
-                net,
-                plugin_map.clone(),
+                module_locator,
+                config.clone(),
             ))
         };
         match command {
             Command::List(_list) => {
-                let mut plugins = WasmPluginHandle::<Value>::load_all_keys(
-                    &path,
-                    emulator.clone(),
-                    context.net,
-                    plugin_map,
-                )?;
-                let m = plugins
+                let mut plugins = WasmPluginHandle::<Value>::load_all_keys(unlocked())?;
+                let mut m = plugins
                     .iter_mut()
                     .map(|p| p.get_name().map(|name| (p.id().to_string(), name)))
                     .collect::<Result<BTreeMap<_, _>, _>>()?;
+                for mut p in NativePluginHandle::all(&config) {
+                    m.insert(p.id().to_string(), p.get_name()?);
+                }
                 Ok(CommandReturn::List(ListReturn { items: m }))
             }
             Command::Call(call) => {
                 let params = call.params;
-                let mut sph = default_sph()?.await?;
+                let mut sph: AnyPluginHandle = match native_sph {
+                    Some(native) => Box::new(native),
+                    None => Box::new(default_sph()?.await?),
+                };
 
                 let api = sph.get_api()?;
                 let schema = serde_json::to_value(api.input())?;
@@ -221,21 +391,32 @@ impl Request {
                 let v = sph.call(&PathFragment::Root.into(), &create_args)?;
                 Ok(CommandReturn::Call(CallReturn { result: v }))
             }
-            Command::Bind(bind) => Ok(CommandReturn::Bind(bind.call(net, emulator).await?)),
+            Command::Bind(bind) => Ok(CommandReturn::Bind(
+                bind.call(net, emulator, &path).await?,
+            )),
             Command::Api(_api) => {
-                let mut sph = default_sph()?.await?;
+                let mut sph: AnyPluginHandle = match native_sph {
+                    Some(native) => Box::new(native),
+                    None => Box::new(default_sph()?.await?),
+                };
                 Ok(CommandReturn::Api(ApiReturn {
                     api: sph.get_api()?,
                 }))
             }
             Command::Logo(_logo) => {
-                let mut sph = default_sph()?.await?;
+                let mut sph: AnyPluginHandle = match native_sph {
+                    Some(native) => Box::new(native),
+                    None => Box::new(default_sph()?.await?),
+                };
                 Ok(CommandReturn::Logo(LogoReturn {
                     logo: sph.get_logo()?,
                 }))
             }
             Command::Info(_info) => {
-                let mut sph = default_sph()?.await?;
+                let mut sph: AnyPluginHandle = match native_sph {
+                    Some(native) => Box::new(native),
+                    None => Box::new(default_sph()?.await?),
+                };
                 let api = sph.get_api()?;
                 Ok(CommandReturn::Info(InfoReturn {
                     name: sph.get_name()?,
@@ -247,14 +428,44 @@ impl Request {
                         .and_then(|m| m.description.as_ref())
                         .unwrap()
                         .clone(),
+                    manifest: sph.get_manifest()?,
+                }))
+            }
+            Command::Sign(sign) => {
+                let signer = signer.ok_or("No external signer configured")?;
+                let (psbt, version) = PsbtV2::decode_any(&base64::decode(sign.psbt.trim())?)?;
+                let signed = signer.sign_psbt(&psbt, version).await?;
+                Ok(CommandReturn::Sign(SignReturn {
+                    psbt: base64::encode(signed.encode(version)),
                 }))
             }
             Command::Load(_load) => {
                 let sph = default_sph()?.await?;
                 Ok(CommandReturn::Load(LoadReturn {
                     key: sph.id().to_string(),
+                    publisher: sph.publisher().cloned(),
                 }))
             }
+            Command::Lock(lock) => {
+                let mut plugins = WasmPluginHandle::<Value>::load_all_keys(unlocked())?;
+                // plugins built without a manifest can't be locked by name
+                let mut manifests = vec![];
+                for p in plugins.iter_mut() {
+                    if let Some(manifest) = p.get_manifest()? {
+                        manifests.push((p.id(), manifest));
+                    }
+                }
+                let previous = if lock.update {
+                    None
+                } else {
+                    plugin_lock.as_ref()
+                };
+                let lockfile = PluginLockfile::resolve(manifests, previous)?;
+                if let Some(output) = &lock.output {
+                    lockfile.save(output)?;
+                }
+                Ok(CommandReturn::Lock(LockReturn { lockfile }))
+            }
         }
     }
 }
@@ -264,6 +475,7 @@ impl Bind {
         self,
         net: bitcoin::Network,
         emulator: Arc<dyn CTVEmulator>,
+        module_path: &Path,
     ) -> Result<BindReturn, Box<dyn Error>> {
         let Bind {
             client_url,
@@ -273,7 +485,8 @@ impl Bind {
             use_txn,
             compiled,
             outpoint,
-            ordinals_info
+            ordinals_info,
+            psbt_version,
         } = self;
         let use_txn = use_txn
             .map(|buf| base64::decode(buf.as_bytes()))
@@ -336,14 +549,32 @@ impl Bind {
                 return Err(Err(RequestError("Must have a valid address".into()))?);
             }
         };
-        let logger = Rc::new(TxIndexLogger::new());
-        (*logger).add_tx(Arc::new(tx.clone()))?;
-        let mut bound = compiled.bind_psbt(
-            OutPoint::new(tx.txid(), vout as u32),
-            BTreeMap::new(),
-            logger,
-            emulator.as_ref(),
-        )?;
+        let cache = DiskTxIndex::open(tx_cache_dir(module_path, net), Default::default())?;
+        if !use_mock {
+            cache.sync_with_node(&client).await?;
+        }
+        // the funding transaction may be a mock, or not yet broadcast, so it is
+        // only kept in memory for this bind rather than in the disk cache
+        let funding = TxIndexLogger::new();
+        funding.add_tx(Arc::new(tx.clone()))?;
+        let index = CachedTxIndex {
+            cache: funding,
+            primary: CachedTxIndex {
+                cache,
+                primary: AsyncBitcoinNodeIndex {
+                    client,
+                    can_add: false,
+                },
+            },
+        };
+        let mut bound = compiled
+            .bind_psbt_prefetched(
+                OutPoint::new(tx.txid(), vout as u32),
+                BTreeMap::new(),
+                &index,
+                emulator.as_ref(),
+            )
+            .await?;
         if outpoint.is_none() {
             let added_output_metadata = vec![OutputMeta::default(); tx.output.len()];
             let output_metadata = vec![ObjectMetadata::default(); tx.output.len()];
@@ -370,6 +601,12 @@ impl Bind {
                 },
             );
         }
+        bound.set_psbt_version(psbt_version)?;
         Ok(bound)
     }
 }
+
+/// transactions are cached next to the modules directory, per network
+fn tx_cache_dir(module_path: &Path, net: bitcoin::Network) -> PathBuf {
+    module_path.with_file_name("txcache").join(net.to_string())
+}
@@ -14,28 +14,47 @@ use crate::contracts::Bind;
 use crate::contracts::Call;
 use crate::contracts::Command;
 use crate::contracts::Common;
+use crate::contracts::HostConfig;
 use crate::contracts::Info;
 use crate::contracts::List;
 use crate::contracts::Load;
+use crate::contracts::Lock;
 use crate::contracts::Logo;
 use crate::contracts::Request;
 use crate::contracts::Response;
 use bitcoin::consensus::serialize;
 use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::DerivationPath;
 use bitcoin::util::bip32::ExtendedPrivKey;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::Network;
+use bitcoincore_rpc_async as rpc;
 use clap::clap_app;
 use clap::ArgMatches;
 use config::*;
+use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::servers::audit::AuditLog;
+use emulator_connect::servers::frost::FrostOracle;
 use emulator_connect::servers::hd::HDOracleEmulator;
+use emulator_connect::servers::limits::ServerLimits;
+use emulator_connect::servers::policy::{AdminCommand, ContractRegistry};
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
+use rpc::RpcApi;
 use sapio::contract::Compiled;
+use sapio::miniscript::psbt::PsbtExt;
+use sapio_base::psbt_v2::{PsbtV2, PsbtVersion};
+use sapio_base::txindex::AsyncTxIndex;
+use sapio_psbt::external_signer::ExternalSigner;
+use sapio_psbt::keystore::{relative_path, DEFAULT_ITERATIONS};
 use sapio_base::util::CTVHash;
-use sapio_wasm_plugin::host::plugin_handle::ModuleLocator;
+use sapio_tools::AsyncBitcoinNodeIndex;
+use sapio_wasm_plugin::host::lockfile::PluginLockfile;
+use sapio_wasm_plugin::host::plugin_handle::{ModuleLocator, WASMCacheID};
+use sapio_wasm_plugin::host::trust::PluginSignature;
 use schemars::schema_for;
 use serde_json::Deserializer;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -50,6 +69,30 @@ async fn config(custom_config: Option<&str>) -> Result<Config, Box<dyn Error>> {
     Config::setup(custom_config, "org", "judica", "sapio-cli").await
 }
 
+/// The emulator from the active config, or none if emulators are disabled
+fn configured_emulator(config: &Config) -> Result<Arc<dyn CTVEmulator>, Box<dyn Error>> {
+    let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &config.active.emulator_nodes {
+        if emcfg.enabled {
+            emcfg.get_emulator()?
+        } else {
+            Arc::new(CTVAvailable)
+        }
+    } else {
+        Arc::new(CTVAvailable)
+    };
+    // TODO: is this still required to drop the emulator from a unique thread?
+    {
+        let mut emulator = emulator.clone();
+        // Drop Emulator from own thread...
+        std::thread::spawn(move || loop {
+            if Arc::get_mut(&mut emulator).is_some() {
+                break;
+            }
+        });
+    }
+    Ok(emulator)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let app = clap_app!("sapio-cli" =>
@@ -79,19 +122,67 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
      (about: "Make Requests to Emulator Servers")
      (@subcommand sign =>
       (about: "Sign a PSBT")
-      (@arg input: -k --key +takes_value +required #{1,2} {check_file} "The file to read the key from")
+      (@arg input: -k --key +takes_value #{1,2} {check_file} "The file to read the key from")
+      (@arg keystore: --keystore +takes_value conflicts_with[input] {check_file} "Sign with keys from this encrypted keystore")
+      (@arg select: -s --select +takes_value requires[keystore] "The key or key/account in the keystore to sign with, defaults to every key")
+      (@arg external: --external +takes_value conflicts_with[input keystore] "Sign with this external signer command (arguments split on whitespace) instead of a key. Without --key, --keystore or --external, the configured signer is used.")
       (@arg psbt: -p --psbt +takes_value  #{1,2} {check_file} "The file containing the PSBT to Sign")
       (@arg out: -o --output +takes_value  #{1,2} {check_file_not} "The file to save the resulting PSBT")
+      (@arg psbt_version: --psbt_version +takes_value {check_psbt_version} "Encode the signed PSBT as version 0 or 2, defaults to the version given")
+      (@arg report: --report conflicts_with[external] "Print JSON with a report of what was signed to stdout, including the PSBT unless --output is given. Only available when signing with --key or --keystore.")
      )
      (@subcommand new =>
       (about: "Get a new xpriv")
       (@arg network: -n --network +takes_value +required #{1,2}  "One of: signet, testnet, regtest, bitcoin")
-      (@arg out: -o --output +takes_value +required #{1,2} {check_file_not} "The file to save the resulting key")
+      (@group dest +required =>
+        (@arg out: -o --output +takes_value #{1,2} {check_file_not} "The file to save the resulting key, unencrypted")
+        (@arg keystore: --keystore +takes_value "The encrypted keystore to add the key to, created if missing")
+      )
+      (@arg label: -l --label +takes_value requires[keystore] "The label of the key in the keystore, defaults to default")
+     )
+     (@subcommand import =>
+      (about: "Add a key file to an encrypted keystore")
+      (@arg input: -i --input +takes_value +required #{1,2} {check_file} "The file to read the key from")
+      (@arg keystore: --keystore +takes_value +required "The encrypted keystore to add the key to, created if missing")
+      (@arg label: -l --label +takes_value +required "The label of the key in the keystore")
+     )
+     (@subcommand sign_plugin =>
+      (about: "Sign a WASM plugin as its publisher, writing the signature to <file>.sig")
+      (@arg file: -f --file +takes_value +required {check_file} "The WASM plugin file")
+      (@group from +required =>
+        (@arg input: -k --key +takes_value {check_file} "The file to read the key from")
+        (@arg keystore: --keystore +takes_value {check_file} "Sign with a key from this encrypted keystore")
+      )
+      (@arg select: -s --select +takes_value requires[keystore] "The key or key/account in the keystore to sign with")
+      (@arg meta: -m --meta +takes_value ... "Metadata to sign, as name=value, e.g. -m name=treepay version=0.1.0")
      )
      (@subcommand show =>
       (about: "Show xpub for file")
       (@arg input: -i --input +takes_value +required #{1,2} {check_file} "The file to read the key from")
      )
+     (@subcommand list =>
+      (about: "List the keys and accounts in an encrypted keystore")
+      (@arg keystore: --keystore +takes_value +required {check_file} "The encrypted keystore")
+     )
+     (@subcommand account =>
+      (about: "Add an account (a labeled derivation path) to a key in an encrypted keystore")
+      (@arg keystore: --keystore +takes_value +required {check_file} "The encrypted keystore")
+      (@arg select: -s --select +takes_value +required "The key to add the account to")
+      (@arg label: -l --label +takes_value +required "The label of the account")
+      (@arg path: --path +takes_value +required "The derivation path from the master key, e.g. m/86'/1'/0'")
+     )
+     (@subcommand xpub =>
+      (about: "Derive an xpub with its key origin")
+      (@arg keystore: --keystore +takes_value +required {check_file} "The encrypted keystore")
+      (@arg select: -s --select +takes_value +required "The key or key/account to derive from")
+      (@arg path: --path +takes_value "The derivation path from the key or account, defaults to m")
+     )
+     (@subcommand descriptor =>
+      (about: "Export receive and change descriptors for a key or account")
+      (@arg keystore: --keystore +takes_value +required {check_file} "The encrypted keystore")
+      (@arg select: -s --select +takes_value +required "The key or key/account to export")
+      (@arg kind: -t --type +takes_value "One of: tr, wpkh, pkh. Defaults to tr")
+     )
     )
     (@subcommand studio =>
      (@setting SubcommandRequiredElseHelp)
@@ -126,9 +217,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
      (@subcommand server =>
       (about: "run an emulation server")
       (@arg sync: --sync  "Run in Synchronous mode")
+      (@arg audit_log: --audit_log +takes_value "Append a record of every signature to this file")
+      (@arg max_connections: --max_connections +takes_value "Maximum number of open connections")
+      (@arg max_peer_connections: --max_peer_connections +takes_value "Maximum number of open connections from one IP")
+      (@arg idle_timeout: --idle_timeout +takes_value "Seconds before an idle connection is closed")
+      (@arg rate_limit: --rate_limit +takes_value "Maximum requests per minute on a connection")
+      (@arg registry: --registry +takes_value requires[admin_token] "Only sign templates of contracts registered in this file")
+      (@arg admin_token: --admin_token +takes_value requires[registry] "Token clients must present to administer the registry")
       (@arg seed: +takes_value +required {check_file} "The file containing the Seed")
       (@arg interface: +required +takes_value "The Interface to Bind")
      )
+     (@subcommand frost_server =>
+      (about: "run one oracle of a FROST threshold emulator federation")
+      (@arg sync: --sync  "Run in Synchronous mode")
+      (@arg key_file: --key_file +takes_value +required "Save the key share to (or load it from) this file")
+      (@arg admin_token: --admin_token +takes_value "Token a coordinator must present to run key generation, which is refused if unset")
+      (@arg seed: +takes_value +required {check_file} "The file containing the Seed for the oracle's identity key")
+      (@arg interface: +required +takes_value "The Interface to Bind")
+     )
+     (@subcommand admin =>
+      (about: "Administer the contract registry of a policy restricted emulator")
+      (@arg interface: --interface +takes_value +required "The address of the emulator")
+      (@arg pk: --pk +takes_value +required "The root xpub of the emulator")
+      (@arg token: --token +takes_value +required "The admin token of the emulator")
+      (@group action +required =>
+       (@arg register: --register +takes_value {check_file} "Register the compiled contract json in this file")
+       (@arg list: --list "List registered contracts")
+       (@arg revoke: --revoke +takes_value "Revoke a registered contract by id")
+      )
+     )
+     (@subcommand verify_audit =>
+      (about: "Check an emulator audit log has not been tampered with")
+      (@arg pk: --pk +takes_value +required "The root xpub of the emulator which signed the log")
+      (@arg log: +takes_value +required {check_file} "The audit log file")
+     )
      )
      (@subcommand psbt =>
       (@setting SubcommandRequiredElseHelp)
@@ -137,6 +259,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
        (about: "finalize and extract this psbt to transaction hex")
        (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
       )
+      (@subcommand combine =>
+       (about: "combine the signatures of several psbts for the same transaction")
+       (@arg psbts: +takes_value +required ... {check_file} "Files containing base64 psbts")
+       (@arg psbt_version: --psbt_version +takes_value {check_psbt_version} "Encode the result as version 0 or 2, defaults to the first psbt's version")
+      )
+      (@subcommand emulate =>
+       (about: "add the signatures of the configured emulators")
+       (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
+      )
+      (@subcommand broadcast =>
+       (about: "finalize a psbt and broadcast it through the configured node, after checking the node's mempool would accept it")
+       (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
+       (@arg dry_run: --dry_run "Only check mempool acceptance, do not broadcast")
+      )
+      (@subcommand analyze =>
+       (about: "explain a psbt: inputs, outputs, fee, taproot leaves and what is still unsigned")
+       (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
+       (@arg json: -j --json "Print JSON instead of a table")
+      )
+      (@subcommand convert =>
+       (about: "convert a psbt between versions 0 and 2 (BIP-370)")
+       (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
+       (@arg psbt_version: --psbt_version +takes_value +required {check_psbt_version} "The version to convert to, 0 or 2")
+      )
      )
      (@subcommand contract =>
       (@setting SubcommandRequiredElseHelp)
@@ -144,6 +290,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@subcommand bind =>
        (about: "Bind Contract to a specific UTXO")
        (@arg base64_psbt: --base64_psbt "Output as a base64 PSBT")
+       (@arg psbt_version: --psbt_version +takes_value {check_psbt_version} "Produce version 0 (default) or 2 PSBTs")
        (@group from  =>
             (@arg outpoint: --outpoint +takes_value "Use this specific outpoint")
             (@arg txn: --txn +takes_value "Use this specific transaction ")
@@ -159,6 +306,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg key:  -k --key +takes_value "Which Contract to Create, given a WASM Hash")
        )
        (@arg json: "JSON of args")
+       (@arg replay: --replay +takes_value {check_file} "Answer the plugin's chain and oracle queries from this file, e.g. the host_queries recorded in a contract's metadata")
+       (@arg debug: --debug "Return the plugin's log records with the result")
       )
       (@subcommand load =>
        (about: "Load a wasm contract module, returns the hex sha3 hash key")
@@ -193,6 +342,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
        (about: "list available contracts")
        (@arg workspace: -w --workspace +takes_value "Where to search for.")
       )
+      (@subcommand lock =>
+       (about: "Lock the loaded contract modules by name, writing the configured plugin_lockfile")
+       (@arg workspace: -w --workspace +takes_value "Where to search for.")
+       (@arg output: -o --output +takes_value "Where to write the lockfile, instead of the configured plugin_lockfile")
+       (@arg update: --update "Relock all modules at their highest version")
+      )
       )
       );
     let matches = app.get_matches();
@@ -253,31 +408,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
         Some(("signer", sign_matches)) => match sign_matches.subcommand() {
             Some(("sign", args)) => {
-                let input = args.value_of_os("input").unwrap();
                 let psbt_str = args.value_of("psbt");
                 let output = args.value_of_os("out");
 
-                let buf = tokio::fs::read(input).await?;
-                let xpriv = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
-                let psbt = get_psbt_from(psbt_str).await?;
-                let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
-                let bytes = xpriv.sign(psbt, hash_ty)?;
+                let (mut psbt, version) = get_psbt_from(psbt_str).await?;
+                let version = match args.value_of("psbt_version") {
+                    Some(v) => PsbtVersion::from_str(v)?,
+                    None => version,
+                };
+                let mut report = None;
+                if let Some(input) = args.value_of_os("input") {
+                    let buf = tokio::fs::read(input).await?;
+                    let xpriv = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
+                    let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
+                    let r = xpriv.sign_psbt_v2_mut(&mut psbt, &Secp256k1::new(), hash_ty)?;
+                    report = Some(serde_json::to_value(r)?);
+                } else if let Some(keystore) = args.value_of_os("keystore") {
+                    let (keystore, _) = open_keystore(keystore, false)?;
+                    let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
+                    let secp = Secp256k1::new();
+                    let r = match args.value_of("select") {
+                        Some(select) => keystore
+                            .select(select)?
+                            .sign_psbt_v2_mut(&mut psbt, &secp, hash_ty)?,
+                        None => keystore
+                            .signing_key()
+                            .sign_psbt_v2_mut(&mut psbt, &secp, hash_ty)?,
+                    };
+                    report = Some(serde_json::to_value(r)?);
+                } else {
+                    // external signers only return the signed PSBT
+                    if args.is_present("report") {
+                        return Err("--report needs --key or --keystore".into());
+                    }
+                    let signer = if let Some(cmd) = args.value_of("external") {
+                        let mut words = cmd.split_whitespace().map(String::from);
+                        let command = words.next().ok_or("Empty signer command")?;
+                        ExternalSigner::new(command, words.collect())
+                    } else {
+                        config(custom_config)
+                            .await?
+                            .active
+                            .signer
+                            .ok_or("No --key, --external or configured signer")?
+                    };
+                    psbt = signer.sign_psbt(&psbt, version).await?;
+                }
+                let bytes = base64::encode(psbt.encode(version));
 
                 if let Some(file_out) = output {
-                    std::fs::write(file_out, &base64::encode(bytes))?;
-                } else {
-                    println!("{}", base64::encode(bytes));
+                    std::fs::write(file_out, &bytes)?;
+                }
+                if args.is_present("report") {
+                    let mut out = serde_json::json! {{
+                        "report": report,
+                    }};
+                    if output.is_none() {
+                        out["psbt"] = bytes.into();
+                    }
+                    println!("{}", serde_json::to_string_pretty(&out)?);
+                } else if output.is_none() {
+                    println!("{}", bytes);
                 }
             }
             Some(("new", args)) => {
                 let network = args.value_of("network").unwrap();
                 let network = Network::from_str(network)?;
-                let out = args.value_of_os("out").unwrap();
                 let xpriv = sapio_psbt::SigningKey::new_key(network)?;
                 let pubkey = xpriv.pubkey(&Secp256k1::new());
-                tokio::fs::write(out, &xpriv.0[0].encode()).await?;
+                if let Some(path) = args.value_of_os("keystore") {
+                    let (mut keystore, passphrase) = open_keystore(path, true)?;
+                    keystore.add_key(args.value_of("label").unwrap_or("default"), xpriv.0[0])?;
+                    keystore.save(path.as_ref(), &passphrase, DEFAULT_ITERATIONS)?;
+                } else {
+                    let out = args.value_of_os("out").unwrap();
+                    tokio::fs::write(out, &xpriv.0[0].encode()).await?;
+                }
                 println!("{}", pubkey[0]);
             }
+            Some(("import", args)) => {
+                let buf = tokio::fs::read(args.value_of_os("input").unwrap()).await?;
+                let xpriv = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
+                let path = args.value_of_os("keystore").unwrap();
+                let (mut keystore, passphrase) = open_keystore(path, true)?;
+                keystore.add_key(args.value_of("label").unwrap(), xpriv.0[0])?;
+                keystore.save(path.as_ref(), &passphrase, DEFAULT_ITERATIONS)?;
+                println!("{}", xpriv.pubkey(&Secp256k1::new())[0]);
+            }
+            Some(("sign_plugin", args)) => {
+                let secp = Secp256k1::new();
+                let xpriv = if let Some(input) = args.value_of_os("input") {
+                    let buf = tokio::fs::read(input).await?;
+                    sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?.0[0]
+                } else {
+                    let (keystore, _) =
+                        open_keystore(args.value_of_os("keystore").unwrap(), false)?;
+                    let select = args.value_of("select").ok_or("--select is required")?;
+                    let selection = keystore.select(select)?;
+                    selection.key.xpriv.derive_priv(&secp, &selection.path())?
+                };
+                let metadata = args
+                    .values_of("meta")
+                    .into_iter()
+                    .flatten()
+                    .map(|m| {
+                        m.split_once('=')
+                            .map(|(k, v)| (k.to_string(), v.to_string()))
+                            .ok_or_else(|| format!("Metadata {} is not name=value", m))
+                    })
+                    .collect::<Result<BTreeMap<_, _>, _>>()?;
+                let file = args.value_of("file").unwrap();
+                let key = WASMCacheID::generate(&tokio::fs::read(file).await?);
+                let signature =
+                    PluginSignature::sign(&secp, &xpriv.to_keypair(&secp), &key, metadata)?;
+                tokio::fs::write(
+                    format!("{}.sig", file),
+                    serde_json::to_string_pretty(&signature)?,
+                )
+                .await?;
+                println!("{}", serde_json::to_string_pretty(&signature)?);
+            }
+            Some(("list", args)) => {
+                let (keystore, _) = open_keystore(args.value_of_os("keystore").unwrap(), false)?;
+                let secp = Secp256k1::new();
+                let keys: Vec<_> = keystore
+                    .keys
+                    .iter()
+                    .map(|k| {
+                        serde_json::json!({
+                            "label": k.label,
+                            "fingerprint": k.xpriv.fingerprint(&secp),
+                            "xpub": ExtendedPubKey::from_priv(&secp, &k.xpriv),
+                            "accounts": k.accounts,
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&keys)?);
+            }
+            Some(("account", args)) => {
+                let path = args.value_of_os("keystore").unwrap();
+                let (mut keystore, passphrase) = open_keystore(path, false)?;
+                let derivation = DerivationPath::from_str(args.value_of("path").unwrap())?;
+                keystore.add_account(
+                    args.value_of("select").unwrap(),
+                    args.value_of("label").unwrap(),
+                    derivation,
+                )?;
+                keystore.save(path.as_ref(), &passphrase, DEFAULT_ITERATIONS)?;
+            }
+            Some(("xpub", args)) => {
+                let (keystore, _) = open_keystore(args.value_of_os("keystore").unwrap(), false)?;
+                let path = relative_path(args.value_of("path").unwrap_or("m"))?;
+                let ((fingerprint, path), xpub) = keystore
+                    .select(args.value_of("select").unwrap())?
+                    .xpub_at(&Secp256k1::new(), &path)?;
+                let origin: String = path.into_iter().map(|c| format!("/{}", c)).collect();
+                println!("[{}{}]{}", fingerprint, origin, xpub);
+            }
+            Some(("descriptor", args)) => {
+                let (keystore, _) = open_keystore(args.value_of_os("keystore").unwrap(), false)?;
+                let descriptors = keystore
+                    .select(args.value_of("select").unwrap())?
+                    .descriptors(&Secp256k1::new(), args.value_of("kind").unwrap_or("tr"))?;
+                for d in descriptors {
+                    println!("{}", d);
+                }
+            }
             Some(("show", args)) => {
                 let input = args.value_of_os("input").unwrap();
                 let buf = tokio::fs::read(input).await?;
@@ -289,26 +585,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
         Some(("emulator", sign_matches)) => {
             let config = config(custom_config).await?;
-            let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &config.active.emulator_nodes
-            {
-                if emcfg.enabled {
-                    emcfg.get_emulator()?
-                } else {
-                    Arc::new(CTVAvailable)
-                }
-            } else {
-                Arc::new(CTVAvailable)
-            };
-            // TODO: is this still required to drop the emulator from a unique thread?
-            {
-                let mut emulator = emulator.clone();
-                // Drop Emulator from own thread...
-                std::thread::spawn(move || loop {
-                    if Arc::get_mut(&mut emulator).is_some() {
-                        break;
-                    }
-                });
-            }
+            let emulator = configured_emulator(&config)?;
             match sign_matches.subcommand() {
                 Some(("sign", args)) => {
                     let psbt = decode_psbt_file(args, "psbt")?;
@@ -323,7 +600,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 Some(("show", args)) => {
                     let psbt = decode_psbt_file(args, "psbt")?;
-                    println!("{:?}", psbt);
+                    print!("{}", sapio_psbt::analyze::analyze(&psbt, config.network));
                 }
                 Some(("server", args)) => {
                     let filename = args.value_of("seed").unwrap();
@@ -332,7 +609,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let root = ExtendedPrivKey::new_master(config.network, &contents[..]).unwrap();
                     let pk_root = ExtendedPubKey::from_priv(&Secp256k1::new(), &root);
                     let sync_mode = args.is_present("sync");
-                    let oracle = HDOracleEmulator::new(root, sync_mode);
+                    let mut limits = ServerLimits::default();
+                    if let Some(n) = args.value_of("max_connections") {
+                        limits.max_connections = n.parse()?;
+                    }
+                    if let Some(n) = args.value_of("max_peer_connections") {
+                        limits.max_connections_per_peer = n.parse()?;
+                    }
+                    if let Some(n) = args.value_of("idle_timeout") {
+                        limits.idle_timeout = std::time::Duration::from_secs(n.parse()?);
+                    }
+                    if let Some(n) = args.value_of("rate_limit") {
+                        limits.max_requests_per_minute = n.parse()?;
+                    }
+                    let mut oracle = HDOracleEmulator::new(root, sync_mode).with_limits(limits);
+                    if let Some(log) = args.value_of("audit_log") {
+                        oracle = oracle.with_audit_log(AuditLog::open(log, &root.private_key).await?);
+                    }
+                    if let (Some(registry), Some(token)) =
+                        (args.value_of("registry"), args.value_of("admin_token"))
+                    {
+                        let registry = ContractRegistry::open(registry.into())?;
+                        oracle = oracle.with_policy(
+                            Arc::new(std::sync::RwLock::new(registry)),
+                            token.into(),
+                        );
+                    }
                     let interface = args.value_of("interface").unwrap();
                     let server = oracle.bind(interface);
                     let status = serde_json::json! {{
@@ -343,6 +645,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("{}", serde_json::to_string_pretty(&status).unwrap());
                     server.await?;
                 }
+                Some(("frost_server", args)) => {
+                    let contents = tokio::fs::read(args.value_of("seed").unwrap()).await?;
+                    let identity = ExtendedPrivKey::new_master(config.network, &contents[..])?;
+                    let mut oracle =
+                        FrostOracle::new(identity.private_key, args.is_present("sync"));
+                    if let Some(token) = args.value_of("admin_token") {
+                        oracle = oracle.with_admin_token(token.into());
+                    }
+                    let oracle = oracle
+                        .with_key_file(args.value_of("key_file").unwrap().into())
+                        .await?;
+                    let interface = args.value_of("interface").unwrap();
+                    let status = serde_json::json! {{
+                        "interface": interface,
+                        "identity": identity.private_key.public_key(&Secp256k1::new()),
+                        "key": oracle.key().map(|k| k.public),
+                    }};
+                    let server = oracle.bind(interface);
+                    println!("{}", serde_json::to_string_pretty(&status).unwrap());
+                    server.await?;
+                }
+                Some(("admin", args)) => {
+                    let connection = HDOracleEmulatorConnection::new(
+                        args.value_of("interface").unwrap().to_string(),
+                        ExtendedPubKey::from_str(args.value_of("pk").unwrap())?,
+                        None,
+                        Arc::new(Secp256k1::new()),
+                    )
+                    .await?;
+                    let command = if let Some(file) = args.value_of("register") {
+                        AdminCommand::Register(Box::new(serde_json::from_slice(
+                            &tokio::fs::read(file).await?[..],
+                        )?))
+                    } else if let Some(id) = args.value_of("revoke") {
+                        AdminCommand::Revoke(id.into())
+                    } else {
+                        AdminCommand::List
+                    };
+                    let response = connection
+                        .admin(args.value_of("token").unwrap().into(), command)
+                        .await?;
+                    println!("{}", serde_json::to_string_pretty(&response)?);
+                }
+                Some(("verify_audit", args)) => {
+                    let identity = ExtendedPubKey::from_str(args.value_of("pk").unwrap())?
+                        .public_key
+                        .x_only_public_key()
+                        .0;
+                    let (entries, head) =
+                        AuditLog::verify(args.value_of("log").unwrap(), &identity)?;
+                    let status = serde_json::json! {{
+                        "entries": entries,
+                        "head": head,
+                    }};
+                    println!("{}", serde_json::to_string_pretty(&status).unwrap());
+                }
                 _ => unreachable!(),
             }
         }
@@ -350,17 +708,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Some(("finalize", args)) => {
                 let psbt_str = args.value_of("psbt");
 
-                let psbt = get_psbt_from(psbt_str).await?;
-                let js = sapio_psbt::external_api::finalize_psbt_format_api(psbt);
+                let (psbt, version) = get_psbt_from(psbt_str).await?;
+                let js = sapio_psbt::external_api::finalize_psbt_v2_format_api(psbt, version);
                 println!("{}", serde_json::to_string_pretty(&js)?);
             }
+            Some(("combine", args)) => {
+                let mut combined: Option<(PsbtV2, PsbtVersion)> = None;
+                for file in args.values_of_os("psbts").unwrap() {
+                    let s = tokio::fs::read_to_string(file).await?;
+                    let (psbt, version) = PsbtV2::decode_any(&base64::decode(s.trim())?)?;
+                    match combined.as_mut() {
+                        Some((c, _)) => c.combine(psbt)?,
+                        None => combined = Some((psbt, version)),
+                    }
+                }
+                let (psbt, version) = combined.expect("at least one psbt is required");
+                let version = match args.value_of("psbt_version") {
+                    Some(v) => PsbtVersion::from_str(v)?,
+                    None => version,
+                };
+                println!("{}", base64::encode(psbt.encode(version)));
+            }
+            Some(("emulate", args)) => {
+                let config = config(custom_config).await?;
+                let emulator = configured_emulator(&config)?;
+                let (mut psbt, version) = get_psbt_from(args.value_of("psbt")).await?;
+                psbt.psbt = emulator.sign(psbt.psbt)?;
+                println!("{}", base64::encode(psbt.encode(version)));
+            }
+            Some(("broadcast", args)) => {
+                let config = config(custom_config).await?;
+                let (mut psbt, _) = get_psbt_from(args.value_of("psbt")).await?;
+                let secp = Secp256k1::new();
+                let mut errors = vec![];
+                for (i, input) in psbt.psbt.clone().inputs.iter().enumerate() {
+                    if input.final_script_witness.is_none() && input.final_script_sig.is_none() {
+                        if let Err(e) = psbt.psbt.finalize_inp_mut(&secp, i) {
+                            errors.push(format!("input {}: {}", i, e));
+                        }
+                    }
+                }
+                if !errors.is_empty() {
+                    return Err(format!("Could not finalize psbt: {}", errors.join("; ")).into());
+                }
+                let tx = psbt.psbt.extract(&secp)?;
+                let client =
+                    rpc::Client::new(config.active.api_node.url, config.active.api_node.auth)
+                        .await?;
+                let accepted = client.test_mempool_accept(&[&tx]).await?;
+                let result = accepted
+                    .first()
+                    .ok_or("Node did not check the transaction")?;
+                if !result.allowed {
+                    return Err(format!(
+                        "Transaction {} would be rejected: {}",
+                        result.txid,
+                        result.reject_reason.as_deref().unwrap_or("unknown reason")
+                    )
+                    .into());
+                }
+                if args.is_present("dry_run") {
+                    println!(
+                        "{}",
+                        serde_json::json!({"txid": result.txid, "allowed": true, "broadcast": false})
+                    );
+                } else {
+                    let index = AsyncBitcoinNodeIndex {
+                        client,
+                        can_add: true,
+                    };
+                    let txid = AsyncTxIndex::add_tx(&index, Arc::new(tx)).await?;
+                    println!(
+                        "{}",
+                        serde_json::json!({"txid": txid, "allowed": true, "broadcast": true})
+                    );
+                }
+            }
+            Some(("analyze", args)) => {
+                let network = config(custom_config).await?.network;
+                let (psbt, _) = get_psbt_from(args.value_of("psbt")).await?;
+                let analysis = sapio_psbt::analyze::analyze(&psbt.psbt, network);
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&analysis)?);
+                } else {
+                    print!("{}", analysis);
+                }
+            }
+            Some(("convert", args)) => {
+                let psbt_str = args.value_of("psbt");
+                let version = PsbtVersion::from_str(args.value_of("psbt_version").unwrap())?;
+                let (psbt, _) = get_psbt_from(psbt_str).await?;
+                println!("{}", base64::encode(psbt.encode(version)));
+            }
             _ => unreachable!(),
         },
         Some(("studio", matches)) => match matches.subcommand() {
             Some(("server", args)) => {
                 let from_stdin = args.is_present("stdin");
                 if from_stdin {
-                    run_server_stdin().await?;
+                    let config = config(custom_config).await?;
+                    run_server_stdin(host_config(&config.active)).await?;
                 } else {
                     args.value_of("interface");
                 }
@@ -384,12 +831,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 p
             };
             let network = config.network;
+            let host = host_config(&config.active);
             let emulator_args = config.active.emulator_nodes;
+            let plugin_limits = config.active.plugin_limits;
             let plugin_map = config.active.plugin_map.map(|x| {
                 x.into_iter()
                     .map(|(x, y)| (x.into_bytes(), y.into()))
                     .collect()
             });
+            let plugin_lockfile = config.active.plugin_lockfile;
+            let plugin_lock = match &plugin_lockfile {
+                Some(p) if p.exists() => Some(PluginLockfile::load(p)?),
+                Some(p) if !matches!(matches.subcommand(), Some(("lock", _))) => {
+                    return Err(format!(
+                        "No plugin lockfile at {}, create one with `contract lock`",
+                        p.display()
+                    )
+                    .into())
+                }
+                _ => None,
+            };
+            if plugin_lock.is_some() && plugin_map.is_some() {
+                eprintln!("Warning: plugin_map is ignored as plugin_lockfile is set");
+            }
             let context = |args: &clap::ArgMatches| -> Result<Common, &'static str> {
                 let module_locator = args
                     .value_of("file")
@@ -406,6 +870,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     module_locator,
                     net: network,
                     plugin_map,
+                    plugin_limits,
+                    plugin_lock: plugin_lock.clone(),
+                    host_queries: None,
+                    debug: false,
+                    host: host.clone(),
                 })
             };
             let (server, send_server, shutdown_server) = Server::new();
@@ -432,8 +901,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         tokio::io::stdin().read_to_string(&mut s).await?;
                         serde_json::from_str(&s)?
                     };
+                    let mut context = context(args)?;
+                    context.debug = args.is_present("debug");
+                    if let Some(replay) = args.value_of("replay") {
+                        context.host_queries =
+                            Some(serde_json::from_slice(&tokio::fs::read(replay).await?)?);
+                    }
                     Request {
-                        context: context(args)?,
+                        context,
                         command: Command::Call(Call { params }),
                     }
                 }
@@ -453,6 +928,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     context: context(args)?,
                     command: Command::Load(Load),
                 },
+                Some(("lock", args)) => Request {
+                    context: context(args)?,
+                    command: Command::Lock(Lock {
+                        output: args
+                            .value_of("output")
+                            .map(Into::into)
+                            .or_else(|| plugin_lockfile.clone()),
+                        update: args.is_present("update"),
+                    }),
+                },
                 _ => unreachable!(),
             };
             server.run();
@@ -467,7 +952,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn run_server_stdin() -> Result<(), Box<dyn Error>> {
+/// the settings requests may not choose, from the host's configuration
+fn host_config(config: &NetworkConfig) -> HostConfig {
+    HostConfig {
+        signer: config.signer.clone(),
+        plugin_trust: config.plugin_trust.clone(),
+        chain_node: Some(config.api_node.clone()),
+        oracles: config.oracles.clone(),
+        plugin_log: config.plugin_log.clone(),
+    }
+}
+
+async fn run_server_stdin(host: HostConfig) -> Result<(), Box<dyn Error>> {
     let (server, send_server, shutdown_server) = Server::new();
     server.run();
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
@@ -482,8 +978,10 @@ async fn run_server_stdin() -> Result<(), Box<dyn Error>> {
     });
     while let Some(json) = rx.recv().await {
         let (b_tx, b_rx) = oneshot::channel();
+        let mut request = json?;
+        request.context.host = host.clone();
         send_server
-            .send((json?, b_tx))
+            .send((request, b_tx))
             .map_err(|_e| "Failed to Send")?;
 
         println!("{}", serde_json::to_string_pretty(&b_rx.await?)?);
@@ -506,6 +1004,11 @@ async fn bind_command(
         .map(serde_json::from_str)
         .transpose()?;
     let use_txn = args.value_of("txn").map(String::from);
+    let psbt_version = args
+        .value_of("psbt_version")
+        .map(PsbtVersion::from_str)
+        .transpose()?
+        .unwrap_or_default();
     let compiled: Compiled = if let Some(json) = args.value_of("json") {
         serde_json::from_str(json)?
     } else {
@@ -521,6 +1024,7 @@ async fn bind_command(
         outpoint,
         use_txn,
         compiled,
-        ordinals_info
+        ordinals_info,
+        psbt_version,
     }))
 }
@@ -4,11 +4,16 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use bitcoin::consensus::{deserialize, Decodable};
+use bitcoin::consensus::deserialize;
 use bitcoin::hashes::Hash;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio_base::psbt_v2::{PsbtV2, PsbtVersion};
+use sapio_psbt::keystore::Keystore;
 use std::error::Error;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::io::AsyncReadExt;
 /// Checks that a file exists during argument parsing
 ///
@@ -17,6 +22,12 @@ pub fn check_file(p: &str) -> Result<(), String> {
     std::fs::metadata(p).map_err(|_| String::from("File doesn't exist"))?;
     Ok(())
 }
+/// Checks that a PSBT version is one we can produce
+pub fn check_psbt_version(v: &str) -> Result<(), String> {
+    PsbtVersion::from_str(v)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
 /// Checks that a file does not exist during argument parsing
 ///
 /// **Race Conditions** if file is created after this call
@@ -38,20 +49,53 @@ pub fn decode_psbt_file(
     Ok(psbt)
 }
 
-/// Reads a PSBT either from a string or from stdin
+/// Reads a PSBT of either version from a string or from stdin
 pub async fn get_psbt_from(
     psbt_str: Option<&str>,
-) -> Result<PartiallySignedTransaction, Box<dyn Error>> {
-    let psbt: PartiallySignedTransaction = PartiallySignedTransaction::consensus_decode(
-        &base64::decode(&if let Some(psbt) = psbt_str {
-            psbt.into()
-        } else {
-            let mut s = String::new();
-            tokio::io::stdin().read_to_string(&mut s).await?;
-            s
-        })?[..],
-    )?;
-    Ok(psbt)
+) -> Result<(PsbtV2, PsbtVersion), Box<dyn Error>> {
+    let s = if let Some(psbt) = psbt_str {
+        psbt.into()
+    } else {
+        let mut s = String::new();
+        tokio::io::stdin().read_to_string(&mut s).await?;
+        s
+    };
+    Ok(PsbtV2::decode_any(&base64::decode(s.trim())?)?)
+}
+
+/// Reads a keystore passphrase from `SAPIO_KEYSTORE_PASSPHRASE`, or else
+/// prompts for it on the terminal (falling back to stdin). The passphrase is
+/// echoed when typed.
+pub fn read_passphrase(confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(p) = std::env::var("SAPIO_KEYSTORE_PASSPHRASE") {
+        return Ok(p);
+    }
+    let mut input: Box<dyn BufRead> = match std::fs::File::open("/dev/tty") {
+        Ok(tty) => Box::new(std::io::BufReader::new(tty)),
+        Err(_) => Box::new(std::io::BufReader::new(std::io::stdin())),
+    };
+    let mut prompt = |p: &str| -> Result<String, Box<dyn Error>> {
+        eprint!("{}", p);
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        Ok(line.trim_end_matches(&['\r', '\n'][..]).into())
+    };
+    let passphrase = prompt("Keystore passphrase: ")?;
+    if confirm && prompt("Confirm passphrase: ")? != passphrase {
+        return Err("Passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+/// Opens an encrypted keystore, returning it with its passphrase so it can be
+/// saved again. With `create`, a missing keystore is created empty.
+pub fn open_keystore(path: &OsStr, create: bool) -> Result<(Keystore, String), Box<dyn Error>> {
+    let path = Path::new(path);
+    if create && !path.exists() {
+        return Ok((Keystore::default(), read_passphrase(true)?));
+    }
+    let passphrase = read_passphrase(false)?;
+    Ok((Keystore::load(path, &passphrase)?, passphrase))
 }
 
 /// get the path for the compiled modules
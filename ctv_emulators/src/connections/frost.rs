@@ -0,0 +1,500 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a federation of oracles sharing a single FROST threshold key
+//!
+//! Unlike `FederatedEmulatorConnection`, which requires a k-of-n multisig of
+//! per oracle keys in every CTV branch, a FROST federation presents a single
+//! key per template hash and produces a single aggregated signature for it.
+use super::*;
+use crate::frost::*;
+use crate::msgs::{FrostRequest, FrostResponse, FrostSignItem};
+use crate::transport::Channel;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::sighash::SchnorrSighashType;
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{SchnorrSig, TxOut, XOnlyPublicKey};
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::runtime::Handle;
+
+/// How to reach a member of a FROST federation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FrostOracleInfo {
+    /// the participant index of the oracle in the federation
+    pub id: ParticipantId,
+    /// resolved address of the oracle
+    pub address: SocketAddr,
+    /// the oracle's static transport key, pinned on connection
+    pub identity: PublicKey,
+}
+
+/// FrostEmulatorConnection coordinates a federation of `FrostOracle`s.
+///
+/// Like `HDOracleEmulatorConnection`, it uses block_in_place/block_on
+/// internally because the CTVEmulator trait is not async.
+pub struct FrostEmulatorConnection {
+    /// the runtime, if not executing in a runtime already
+    pub runtime: Option<Arc<tokio::runtime::Runtime>>,
+    /// handle to either current_runtime or the runtime owned above
+    pub handle: tokio::runtime::Handle,
+    oracles: Vec<FrostOracleInfo>,
+    public: PublicKeyPackage,
+}
+
+/// one signature the federation must produce
+struct SignTarget {
+    input: usize,
+    leaf: TapLeafHash,
+    ctv_hash: Sha256,
+    key: XOnlyPublicKey,
+    msg: [u8; 32],
+    hash_ty: SchnorrSighashType,
+}
+
+fn unexpected(r: FrostResponse) -> std::io::Error {
+    match r {
+        FrostResponse::Error(e) => input_err(&format!("FROST Oracle Error: {}", e)),
+        _ => input_err("Unexpected FROST Oracle Response"),
+    }
+}
+
+fn frost_err(e: FrostError) -> std::io::Error {
+    input_err(&e.to_string())
+}
+
+impl FrostEmulatorConnection {
+    /// Creates a connection to a federation which has already run key
+    /// generation, with its resulting public key package.
+    pub fn new(
+        oracles: Vec<FrostOracleInfo>,
+        public: PublicKeyPackage,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+    ) -> Self {
+        FrostEmulatorConnection {
+            handle: Handle::try_current().unwrap_or_else(|_e| {
+                runtime
+                    .as_ref()
+                    .expect("Must pass a runtime if not in async context")
+                    .handle()
+                    .clone()
+            }),
+            runtime,
+            oracles,
+            public,
+        }
+    }
+
+    /// Runs distributed key generation among `oracles` (which are assigned
+    /// participant ids in order, starting from 1) so that any `threshold` of
+    /// them can sign. Each oracle is given with the admin token its operator
+    /// configured it to accept key generation from.
+    ///
+    /// Shares are encrypted between oracles, so the coordinator learns only the
+    /// public key package, which every oracle must agree on.
+    pub async fn keygen(
+        oracles: Vec<(SocketAddr, PublicKey, String)>,
+        threshold: usize,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+    ) -> Result<Self, std::io::Error> {
+        let (oracles, tokens): (Vec<FrostOracleInfo>, Vec<String>) = oracles
+            .into_iter()
+            .zip(1..)
+            .map(|((address, identity, token), id)| {
+                let info = FrostOracleInfo {
+                    id,
+                    address,
+                    identity,
+                };
+                (info, token)
+            })
+            .unzip();
+        let n = oracles.len();
+        let mut session = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut session);
+        let session = Sha256::from_inner(session);
+        let mut channels = vec![];
+        for o in &oracles {
+            channels.push(Self::connect(o).await?);
+        }
+        let mut round1 = vec![];
+        for ((o, c), token) in oracles.iter().zip(channels.iter_mut()).zip(&tokens) {
+            let r = FrostRequest::DkgRound1 {
+                token: token.clone(),
+                id: o.id,
+                threshold,
+                n,
+                session,
+            };
+            match Self::request(c, &r).await? {
+                FrostResponse::DkgRound1(p) if p.id == o.id => round1.push(p),
+                r => return Err(unexpected(r)),
+            }
+        }
+        // every oracle echoes the hash of the round 1 messages it was given,
+        // and checks the shares it receives were made from the same set
+        let set = round1_hash(&round1[..]);
+        let mut shares = vec![];
+        for (c, token) in channels.iter_mut().zip(&tokens) {
+            let r = FrostRequest::DkgRound2 {
+                token: token.clone(),
+                packages: round1.clone(),
+            };
+            match Self::request(c, &r).await? {
+                FrostResponse::DkgRound2(s) if s.iter().all(|s| s.round1 == set) => {
+                    shares.extend(s)
+                }
+                FrostResponse::DkgRound2(_) => return input_error("Oracles Disagree on Round 1"),
+                r => return Err(unexpected(r)),
+            }
+        }
+        let mut public: Option<PublicKeyPackage> = None;
+        for ((o, c), token) in oracles.iter().zip(channels.iter_mut()).zip(&tokens) {
+            let r = FrostRequest::DkgFinish {
+                token: token.clone(),
+                packages: round1.clone(),
+                shares: shares.iter().filter(|s| s.to == o.id).cloned().collect(),
+            };
+            match Self::request(c, &r).await? {
+                FrostResponse::DkgFinish(h, _) if h != set => {
+                    return input_error("Oracles Disagree on Round 1")
+                }
+                FrostResponse::DkgFinish(_, p) => match &public {
+                    Some(q) if *q != p => return input_error("Oracles Disagree on Group Key"),
+                    _ => public = Some(p),
+                },
+                r => return Err(unexpected(r)),
+            }
+        }
+        let public = public.ok_or_else(|| input_err("No Oracles Given"))?;
+        Ok(Self::new(oracles, public, runtime))
+    }
+
+    /// the federation's public key package, which should be saved to
+    /// reconnect to the federation with `new`.
+    pub fn public(&self) -> &PublicKeyPackage {
+        &self.public
+    }
+
+    /// the members of the federation
+    pub fn oracles(&self) -> &[FrostOracleInfo] {
+        &self.oracles[..]
+    }
+
+    /// the key used in place of a CTV for template hash `h`
+    fn template_key(&self, h: &Sha256) -> Result<XOnlyPublicKey, std::io::Error> {
+        to_x_only(&self.public.template_key(h)).map_err(frost_err)
+    }
+
+    async fn connect(o: &FrostOracleInfo) -> Result<Channel, std::io::Error> {
+        let stream = TcpStream::connect(&o.address).await?;
+        Channel::connect(stream, &o.identity).await.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "Could not authenticate FROST oracle {} at {}: {}",
+                    o.id, o.address, e
+                ),
+            )
+        })
+    }
+
+    async fn request(c: &mut Channel, r: &FrostRequest) -> Result<FrostResponse, std::io::Error> {
+        c.send(r).await?;
+        c.recv(MAX_JSON_MSG).await
+    }
+
+    /// find every tap leaf using a template key, and the message to sign for it
+    fn targets(&self, b: &PartiallySignedTransaction) -> Result<Vec<SignTarget>, std::io::Error> {
+        let tx = b.clone().extract_tx();
+        let utxos: Option<Vec<TxOut>> = b.inputs.iter().map(|o| o.witness_utxo.clone()).collect();
+        let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
+        let mut targets = vec![];
+        for (idx, input) in b.inputs.iter().enumerate() {
+            let h = tx.get_ctv_hash(idx as u32);
+            let key = self.template_key(&h)?;
+            let leaves: Vec<TapLeafHash> = input
+                .tap_scripts
+                .values()
+                .filter(|(script, _)| script_has_key(script, &key))
+                .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+                .collect();
+            if leaves.is_empty() {
+                continue;
+            }
+            let (hash_ty, prevouts) = sighash_prevouts(input, idx, utxos.as_deref())?;
+            for leaf in leaves {
+                let msg = sighash
+                    .taproot_signature_hash(idx, &prevouts, None, Some((leaf, 0xffffffff)), hash_ty)
+                    .map_err(|_| input_err("Could Not Compute Signature Hash"))?;
+                targets.push(SignTarget {
+                    input: idx,
+                    leaf,
+                    ctv_hash: h,
+                    key,
+                    msg: msg.into_inner(),
+                    hash_ty,
+                });
+            }
+        }
+        Ok(targets)
+    }
+
+    /// Signs every leaf of the PSBT which uses a template key with the first
+    /// `threshold` oracles that respond.
+    async fn sign_async(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let targets = self.targets(&b)?;
+        if targets.is_empty() {
+            return Ok(b);
+        }
+        // round 1: collect nonce commitments from enough live oracles
+        let mut signers = vec![];
+        for o in &self.oracles {
+            if signers.len() == self.public.threshold {
+                break;
+            }
+            let commit = async {
+                let mut c = Self::connect(o).await?;
+                match Self::request(&mut c, &FrostRequest::Commit(targets.len())).await? {
+                    FrostResponse::Commitments(v) if v.len() == targets.len() => Ok((c, v)),
+                    r => Err(unexpected(r)),
+                }
+            };
+            // an unavailable oracle is skipped, another may take its place
+            if let Ok((c, v)) = commit.await {
+                signers.push((o.id, c, v));
+            }
+        }
+        if signers.len() < self.public.threshold {
+            return input_error("Not Enough FROST Oracles Available");
+        }
+        let commitments: Vec<BTreeMap<ParticipantId, NonceCommitment>> = (0..targets.len())
+            .map(|i| signers.iter().map(|(id, _, v)| (*id, v[i].1)).collect())
+            .collect();
+        // round 2: collect signature shares
+        let mut shares: Vec<BTreeMap<ParticipantId, Secret>> = vec![BTreeMap::new(); targets.len()];
+        for (id, c, v) in signers.iter_mut() {
+            let items = targets
+                .iter()
+                .zip(v.iter())
+                .zip(commitments.iter())
+                .map(|((t, (nonce, _)), commitments)| FrostSignItem {
+                    input: t.input,
+                    leaf: t.leaf,
+                    nonce: *nonce,
+                    commitments: commitments.clone(),
+                })
+                .collect();
+            let r = FrostRequest::Sign(msgs::PSBT(b.clone()), items);
+            match Self::request(c, &r).await? {
+                FrostResponse::Shares(s) if s.len() == targets.len() => {
+                    for (i, s) in s.into_iter().enumerate() {
+                        shares[i].insert(*id, s);
+                    }
+                }
+                r => return Err(unexpected(r)),
+            }
+        }
+        for (i, t) in targets.iter().enumerate() {
+            let tweak = self.public.tweak(&t.ctv_hash);
+            let sig = aggregate(&self.public, &tweak, &t.msg, &commitments[i], &shares[i])
+                .map_err(frost_err)?;
+            b.inputs[t.input].tap_script_sigs.insert(
+                (t.key, t.leaf),
+                SchnorrSig {
+                    sig,
+                    hash_ty: t.hash_ty,
+                },
+            );
+        }
+        Ok(b)
+    }
+}
+
+impl CTVEmulator for FrostEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.template_key(&h)?))
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(tokio::task::block_in_place(|| {
+            self.handle.block_on(self.sign_async(b))
+        })?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::servers::frost::FrostOracle;
+    use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::secp256k1::SecretKey;
+    use bitcoin::util::sighash::Prevouts;
+    use bitcoin::util::taproot::{LeafVersion, TaprootBuilder};
+    use bitcoin::{OutPoint, Script, Transaction, TxIn};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn federation_signs_with_threshold() {
+        let secp = Secp256k1::new();
+        let mut servers = vec![];
+        let mut oracles = vec![];
+        for _ in 0..3 {
+            let identity = SecretKey::new(&mut rand::thread_rng());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            oracles.push((
+                listener.local_addr().unwrap(),
+                PublicKey::from_secret_key(&secp, &identity),
+                "token".to_string(),
+            ));
+            servers.push(tokio::spawn(
+                FrostOracle::new(identity, false)
+                    .with_admin_token("token".into())
+                    .listen(listener),
+            ));
+        }
+        let federation = FrostEmulatorConnection::keygen(oracles, 2, None)
+            .await
+            .unwrap();
+        // one oracle going offline does not prevent signing
+        servers[0].abort();
+        assert!(servers.remove(0).await.is_err());
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(bitcoin::Txid::from_inner([1; 32]), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let h = tx.get_ctv_hash(0);
+        let pk = match federation.get_signer_for(h).unwrap() {
+            Clause::Key(pk) => pk,
+            _ => panic!("expected a single key"),
+        };
+        let script = Builder::new()
+            .push_slice(&pk.serialize())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let internal = federation
+            .template_key(&Sha256::hash(b"unspendable"))
+            .unwrap();
+        let info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&secp, internal)
+            .unwrap();
+        let leaf = (script, LeafVersion::TapScript);
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: Script::new_v1_p2tr_tweaked(info.output_key()),
+        });
+        psbt.inputs[0]
+            .tap_scripts
+            .insert(info.control_block(&leaf).unwrap(), leaf.clone());
+
+        let signed = federation.sign(psbt).unwrap();
+        let tlh = TapLeafHash::from_script(&leaf.0, leaf.1);
+        let sig = signed.inputs[0].tap_script_sigs[&(pk, tlh)];
+        let msg = bitcoin::util::sighash::SighashCache::new(&signed.unsigned_tx)
+            .taproot_signature_hash(
+                0,
+                &Prevouts::All(&[signed.inputs[0].witness_utxo.clone().unwrap()]),
+                None,
+                Some((tlh, 0xffffffff)),
+                sig.hash_ty,
+            )
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&msg[..]).unwrap();
+        secp.verify_schnorr(&sig.sig, &msg, &pk).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keygen_requires_admin_token() {
+        let secp = Secp256k1::new();
+        let mut oracles = vec![];
+        for admin_token in [Some("token"), None] {
+            let identity = SecretKey::new(&mut rand::thread_rng());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let mut oracle = FrostOracle::new(identity, false);
+            if let Some(t) = admin_token {
+                oracle = oracle.with_admin_token(t.into());
+            }
+            tokio::spawn(oracle.listen(listener));
+            oracles.push((address, PublicKey::from_secret_key(&secp, &identity)));
+        }
+        let with_tokens = |tokens: [&str; 2]| -> Vec<_> {
+            oracles
+                .iter()
+                .zip(tokens)
+                .map(|((a, pk), t)| (*a, *pk, t.to_string()))
+                .collect()
+        };
+        // a client without the token can't start key generation
+        let e = FrostEmulatorConnection::keygen(with_tokens(["wrong", "token"]), 2, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("Unauthorized"));
+        // and an oracle with no token configured never runs it
+        let e = FrostEmulatorConnection::keygen(with_tokens(["token", "token"]), 2, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("Key Generation Not Enabled"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn key_share_persists() {
+        let secp = Secp256k1::new();
+        let mut oracles = vec![];
+        let mut files = vec![];
+        for i in 0..2 {
+            let identity = SecretKey::new(&mut rand::thread_rng());
+            let mut path = std::env::temp_dir();
+            path.push(format!("sapio-frost-{}-{}.json", std::process::id(), i));
+            let _ = std::fs::remove_file(&path);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            oracles.push((
+                listener.local_addr().unwrap(),
+                PublicKey::from_secret_key(&secp, &identity),
+                "token".to_string(),
+            ));
+            let oracle = FrostOracle::new(identity, false)
+                .with_admin_token("token".into())
+                .with_key_file(path.clone())
+                .await
+                .unwrap();
+            tokio::spawn(oracle.listen(listener));
+            files.push((identity, path));
+        }
+        let federation = FrostEmulatorConnection::keygen(oracles, 2, None)
+            .await
+            .unwrap();
+        for (identity, path) in files {
+            // a restarted oracle picks up its share
+            let oracle = FrostOracle::new(identity, false)
+                .with_key_file(path.clone())
+                .await
+                .unwrap();
+            assert_eq!(&oracle.key().unwrap().public, federation.public());
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
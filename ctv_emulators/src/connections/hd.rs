@@ -7,6 +7,8 @@
 //! Hierarchical Deterministic Emulator Connection
 
 use super::*;
+use crate::servers::policy::{AdminCommand, AdminResponse};
+use crate::transport::Channel;
 /// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
 /// with a key to be able to talk to an Oracle server.
 ///
@@ -20,11 +22,13 @@ pub struct HDOracleEmulatorConnection {
     pub runtime: Option<Arc<tokio::runtime::Runtime>>,
     /// handle to either current_runtime or the runtime owned above
     pub handle: tokio::runtime::Handle,
-    /// connection to the reconnect SocketAddr
-    pub connection: Mutex<Option<TcpStream>>,
+    /// authenticated connection to the reconnect SocketAddr
+    pub connection: Mutex<Option<Channel>>,
     /// resolved address to the oracle
     pub reconnect: SocketAddr,
-    /// the root key signatures will come from
+    /// the root key signatures will come from. The public key of `root` is
+    /// also the pinned static identity of the server; connections to a server
+    /// which cannot prove it holds the corresponding private key are refused.
     pub root: ExtendedPubKey,
     /// a secp context
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
@@ -76,24 +80,31 @@ impl HDOracleEmulatorConnection {
         })
     }
 
-    /// make a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
+    /// opens a connection and authenticates the server as the holder of `root`.
+    async fn connect(&self) -> Result<Channel, std::io::Error> {
+        let stream = TcpStream::connect(&self.reconnect).await?;
+        Channel::connect(stream, &self.root.public_key)
+            .await
+            .map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Could not authenticate emulator {} as {}: {}",
+                        self.reconnect, self.root, e
+                    ),
+                )
+            })
     }
-    /// receive a response via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        let t: T = serde_json::from_slice::<T>(&v[..])?;
-        Ok(t)
+
+    /// send a command to the admin API of a policy restricted server.
+    pub async fn admin(
+        &self,
+        token: String,
+        command: AdminCommand,
+    ) -> Result<AdminResponse, std::io::Error> {
+        let mut conn = self.connect().await?;
+        conn.send(&msgs::Request::Admin { token, command }).await?;
+        conn.recv(MAX_JSON_MSG).await
     }
 }
 
@@ -112,12 +123,19 @@ impl CTVEmulator for HDOracleEmulatorConnection {
                     let mut mconn = self.connection.lock().await;
                     loop {
                         if let Some(conn) = &mut *mconn {
-                            Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
-                                .await?;
-                            conn.flush().await?;
-                            return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
+                            let res = async {
+                                conn.send(&msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
+                                    .await?;
+                                conn.recv::<msgs::PSBT>(MAX_JSON_MSG).await
+                            }
+                            .await;
+                            if res.is_err() {
+                                // drop the channel so that a later call reconnects
+                                *mconn = None;
+                            }
+                            return Ok(res?.0);
                         } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
+                            *mconn = Some(self.connect().await?);
                         }
                     }
                 })
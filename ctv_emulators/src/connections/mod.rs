@@ -8,4 +8,5 @@
 
 use super::*;
 pub mod federated;
+pub mod frost;
 pub mod hd;
@@ -0,0 +1,793 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! FROST threshold Schnorr signatures for federated emulators
+//!
+//! A set of `n` oracles runs a Pedersen distributed key generation (with
+//! proofs of knowledge, as in the FROST paper) so that any `threshold` of them
+//! can jointly produce a BIP-340 signature for the group key, while no
+//! smaller subset learns anything about the group secret.
+//!
+//! Per template keys are derived from the group key `Y` with an additive
+//! tweak, `Y_h = Y + H(Y || h)G`. Because Lagrange coefficients sum to one,
+//! every oracle can add the tweak to its own share and the shares still
+//! interpolate to the tweaked secret, so a CTV branch costs a single
+//! `Clause::Key` and a single 64 byte signature regardless of the federation
+//! size.
+//!
+//! Signing follows FROST's two rounds: every signer first commits to a pair of
+//! nonces, then, given the commitments of all signers, produces a signature
+//! share. Shares are checked against the signer's verifying share before
+//! aggregation so a misbehaving oracle is identified.
+//!
+//! Key shares, polynomial coefficients and nonces are zeroized when dropped.
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::XOnlyPublicKey;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::AffineCoordinates;
+use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use k256::elliptic_curve::{Field, Group, PrimeField};
+use k256::{AffinePoint, EncodedPoint, ProjectivePoint, Scalar};
+use serde::de::Error as _;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The index of a participant, used as the x coordinate of its share.
+/// Must be non zero.
+pub type ParticipantId = u16;
+
+/// A curve point, serialized as a hex compressed public key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Point(pub ProjectivePoint);
+
+/// A scalar, serialized as 32 hex bytes. As this type is used for secrets, it
+/// is zeroized when dropped and isn't shown by `Debug`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(pub Scalar);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl serde::Serialize for Point {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use bitcoin::hashes::hex::ToHex;
+        let p = self.0.to_affine().to_encoded_point(true);
+        s.serialize_str(&p.as_bytes().to_hex())
+    }
+}
+impl<'de> serde::Deserialize<'de> for Point {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        use bitcoin::hashes::hex::FromHex;
+        let s = <String as serde::Deserialize>::deserialize(d)?;
+        let b = Vec::<u8>::from_hex(&s).map_err(D::Error::custom)?;
+        point_from_bytes(&b[..]).ok_or_else(|| D::Error::custom("Invalid Point"))
+    }
+}
+impl serde::Serialize for Secret {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use bitcoin::hashes::hex::ToHex;
+        s.serialize_str(&Zeroizing::new(self.0.to_bytes().to_hex()))
+    }
+}
+impl<'de> serde::Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        use bitcoin::hashes::hex::FromHex;
+        let s = Zeroizing::new(<String as serde::Deserialize>::deserialize(d)?);
+        let b = Zeroizing::new(<[u8; 32]>::from_hex(&s).map_err(D::Error::custom)?);
+        Option::from(Scalar::from_repr((*b).into()))
+            .map(Secret)
+            .ok_or_else(|| D::Error::custom("Invalid Scalar"))
+    }
+}
+
+fn point_from_bytes(b: &[u8]) -> Option<Point> {
+    let e = EncodedPoint::from_bytes(b).ok()?;
+    Option::<AffinePoint>::from(AffinePoint::from_encoded_point(&e))
+        .map(|p| Point(ProjectivePoint::from(p)))
+}
+
+fn point_bytes(p: &ProjectivePoint) -> Vec<u8> {
+    p.to_affine().to_encoded_point(true).as_bytes().to_vec()
+}
+
+fn has_even_y(p: &ProjectivePoint) -> bool {
+    !bool::from(p.to_affine().y_is_odd())
+}
+
+/// the BIP-340 x only encoding of a point
+pub fn to_x_only(p: &ProjectivePoint) -> Result<XOnlyPublicKey, FrostError> {
+    XOnlyPublicKey::from_slice(&p.to_affine().x()[..]).map_err(|_| FrostError::InvalidPoint)
+}
+
+/// BIP-340 style tagged hash
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for p in parts {
+        engine.input(p);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+fn hash_to_scalar(tag: &str, parts: &[&[u8]]) -> Scalar {
+    <Scalar as Reduce<k256::U256>>::reduce_bytes(&tagged_hash(tag, parts).into())
+}
+
+fn random_scalar() -> Scalar {
+    Scalar::random(&mut rand::thread_rng())
+}
+
+/// Errors arising during key generation or signing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrostError {
+    /// A participant's proof of knowledge for its secret did not verify
+    InvalidProof(ParticipantId),
+    /// A share sent by a participant did not match its commitments
+    InvalidShare(ParticipantId),
+    /// A signature share did not match the signer's verifying share
+    InvalidSignatureShare(ParticipantId),
+    /// The wrong number of participants, commitments, or shares was given
+    WrongCount,
+    /// A participant was referenced that is not part of the group
+    UnknownParticipant(ParticipantId),
+    /// A participant was sent a different set of round 1 messages than ours
+    Equivocation(ParticipantId),
+    /// Point encoding or decoding failed
+    InvalidPoint,
+}
+impl fmt::Display for FrostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for FrostError {}
+
+/// Evaluate a polynomial given by its commitments at `x`, in the exponent.
+fn eval_commitments(commitments: &[Point], x: ParticipantId) -> ProjectivePoint {
+    let x = Scalar::from(x as u64);
+    commitments
+        .iter()
+        .rev()
+        .fold(ProjectivePoint::IDENTITY, |acc, c| acc * x + c.0)
+}
+
+/// Evaluate a polynomial given by its coefficients at `x`.
+fn eval_polynomial(coefficients: &[Scalar], x: ParticipantId) -> Scalar {
+    let x = Scalar::from(x as u64);
+    coefficients
+        .iter()
+        .rev()
+        .fold(Scalar::ZERO, |acc, c| acc * x + c)
+}
+
+/// The Lagrange coefficient at 0 for `i` in the set of signers `set`.
+fn lagrange<'a>(i: ParticipantId, set: impl Iterator<Item = &'a ParticipantId>) -> Scalar {
+    let xi = Scalar::from(i as u64);
+    let (num, den) = set
+        .filter(|j| **j != i)
+        .fold((Scalar::ONE, Scalar::ONE), |(num, den), j| {
+            let xj = Scalar::from(*j as u64);
+            (num * xj, den * (xj - xi))
+        });
+    num * Option::<Scalar>::from(den.invert()).unwrap_or(Scalar::ZERO)
+}
+
+/// The public message each participant broadcasts in the first round of key
+/// generation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DkgRound1 {
+    /// the sender
+    pub id: ParticipantId,
+    /// commitments to the coefficients of the sender's secret polynomial
+    pub commitments: Vec<Point>,
+    /// proof of knowledge of the constant term: a nonce commitment ...
+    pub proof_r: Point,
+    /// ... and response
+    pub proof_mu: Secret,
+    /// key used to encrypt the shares sent to this participant
+    pub encryption_key: Point,
+}
+
+/// Commits to a set of round 1 messages, regardless of their order.
+///
+/// Every participant must be given the same set, or the coordinator could
+/// give participants inconsistent views of the group key.
+pub fn round1_hash(packages: &[DkgRound1]) -> sha256::Hash {
+    let mut sorted: Vec<&DkgRound1> = packages.iter().collect();
+    sorted.sort_by_key(|p| p.id);
+    let mut parts: Vec<Vec<u8>> = vec![];
+    for p in sorted {
+        parts.push(p.id.to_be_bytes().to_vec());
+        parts.push((p.commitments.len() as u64).to_be_bytes().to_vec());
+        parts.extend(p.commitments.iter().map(|c| point_bytes(&c.0)));
+        parts.push(point_bytes(&p.proof_r.0));
+        parts.push(p.proof_mu.0.to_bytes().to_vec());
+        parts.push(point_bytes(&p.encryption_key.0));
+    }
+    let parts: Vec<&[u8]> = parts.iter().map(|p| &p[..]).collect();
+    sha256::Hash::from_inner(tagged_hash("sapio/frost/dkg-round1", &parts[..]))
+}
+
+/// A share of a participant's secret polynomial, encrypted to its recipient
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EncryptedShare {
+    /// the sender
+    pub from: ParticipantId,
+    /// the recipient
+    pub to: ParticipantId,
+    /// the `round1_hash` of the round 1 messages the sender was given, which
+    /// is also authenticated by the ciphertext
+    pub round1: sha256::Hash,
+    /// the encrypted & authenticated share
+    #[serde(with = "hex_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+mod hex_bytes {
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use serde::de::Error;
+    use serde::{Deserializer, Serializer};
+    pub fn serialize<S: Serializer>(b: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&b.to_hex())
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        Vec::<u8>::from_hex(&<String as serde::Deserialize>::deserialize(d)?)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Secret state a participant keeps between rounds of key generation, which
+/// is zeroized when dropped.
+pub struct DkgState {
+    id: ParticipantId,
+    threshold: usize,
+    n: usize,
+    context: Vec<u8>,
+    coefficients: Vec<Scalar>,
+    encryption_secret: Scalar,
+}
+
+impl Drop for DkgState {
+    fn drop(&mut self) {
+        self.coefficients.zeroize();
+        self.encryption_secret.zeroize();
+    }
+}
+
+fn pok_challenge(id: ParticipantId, context: &[u8], c0: &Point, r: &Point) -> Scalar {
+    hash_to_scalar(
+        "sapio/frost/dkg-pok",
+        &[
+            &id.to_be_bytes()[..],
+            context,
+            &point_bytes(&c0.0)[..],
+            &point_bytes(&r.0)[..],
+        ],
+    )
+}
+
+fn share_cipher(
+    secret: &Scalar,
+    public: &Point,
+    context: &[u8],
+    from: ParticipantId,
+    to: ParticipantId,
+) -> ChaCha20Poly1305 {
+    let dh = Zeroizing::new(point_bytes(&(public.0 * secret)));
+    let k = Zeroizing::new(tagged_hash(
+        "sapio/frost/dkg-share",
+        &[
+            &dh[..],
+            context,
+            &from.to_be_bytes()[..],
+            &to.to_be_bytes()[..],
+        ],
+    ));
+    ChaCha20Poly1305::new(Key::from_slice(&k[..]))
+}
+
+impl DkgState {
+    /// begin key generation as participant `id` of `n`, `threshold` of which
+    /// will be required to sign. `context` must be unique to this key
+    /// generation session, and shared by all participants.
+    pub fn new(
+        id: ParticipantId,
+        threshold: usize,
+        n: usize,
+        context: Vec<u8>,
+    ) -> Result<(Self, DkgRound1), FrostError> {
+        if id == 0 || id as usize > n || threshold == 0 || threshold > n {
+            return Err(FrostError::WrongCount);
+        }
+        let coefficients: Vec<Scalar> = (0..threshold).map(|_| random_scalar()).collect();
+        let commitments: Vec<Point> = coefficients
+            .iter()
+            .map(|a| Point(ProjectivePoint::GENERATOR * a))
+            .collect();
+        let k = Zeroizing::new(random_scalar());
+        let proof_r = Point(ProjectivePoint::GENERATOR * *k);
+        let c = pok_challenge(id, &context[..], &commitments[0], &proof_r);
+        let proof_mu = Secret(*k + coefficients[0] * c);
+        let encryption_secret = random_scalar();
+        let round1 = DkgRound1 {
+            id,
+            commitments,
+            proof_r,
+            proof_mu,
+            encryption_key: Point(ProjectivePoint::GENERATOR * encryption_secret),
+        };
+        Ok((
+            DkgState {
+                id,
+                threshold,
+                n,
+                context,
+                coefficients,
+                encryption_secret,
+            },
+            round1,
+        ))
+    }
+
+    /// check every participant's round 1 message, indexing them by sender
+    fn check_round1<'a>(
+        &self,
+        packages: &'a [DkgRound1],
+    ) -> Result<BTreeMap<ParticipantId, &'a DkgRound1>, FrostError> {
+        let by_id: BTreeMap<_, _> = packages.iter().map(|p| (p.id, p)).collect();
+        if by_id.len() != self.n || packages.len() != self.n {
+            return Err(FrostError::WrongCount);
+        }
+        for (id, p) in by_id.iter() {
+            if *id == 0 || *id as usize > self.n {
+                return Err(FrostError::UnknownParticipant(*id));
+            }
+            if p.commitments.len() != self.threshold {
+                return Err(FrostError::WrongCount);
+            }
+            let c = pok_challenge(*id, &self.context[..], &p.commitments[0], &p.proof_r);
+            if ProjectivePoint::GENERATOR * p.proof_mu.0 != p.proof_r.0 + p.commitments[0].0 * c {
+                return Err(FrostError::InvalidProof(*id));
+            }
+        }
+        Ok(by_id)
+    }
+
+    /// given every participant's round 1 message (including our own), verify
+    /// them and produce a share of our polynomial for every other participant.
+    ///
+    /// Each share is bound to the `round1_hash` of `packages`, so its
+    /// recipient rejects it unless they were given the same messages.
+    pub fn round2(&self, packages: &[DkgRound1]) -> Result<Vec<EncryptedShare>, FrostError> {
+        let by_id = self.check_round1(packages)?;
+        let round1 = round1_hash(packages);
+        by_id
+            .iter()
+            .filter(|(id, _)| **id != self.id)
+            .map(|(to, p)| {
+                let share = Zeroizing::new(eval_polynomial(&self.coefficients[..], *to));
+                let cipher = share_cipher(
+                    &self.encryption_secret,
+                    &p.encryption_key,
+                    &self.context[..],
+                    self.id,
+                    *to,
+                );
+                let share = Zeroizing::new(share.to_bytes());
+                let payload = Payload {
+                    msg: &share[..],
+                    aad: &round1[..],
+                };
+                let ciphertext = cipher
+                    .encrypt(&Nonce::default(), payload)
+                    .map_err(|_| FrostError::InvalidShare(*to))?;
+                Ok(EncryptedShare {
+                    from: self.id,
+                    to: *to,
+                    round1,
+                    ciphertext,
+                })
+            })
+            .collect()
+    }
+
+    /// given every participant's round 1 message and the shares sent to us,
+    /// derive our key share.
+    ///
+    /// Fails with `Equivocation` if any sender was given different round 1
+    /// messages than `packages`.
+    pub fn finish(
+        self,
+        packages: &[DkgRound1],
+        shares: &[EncryptedShare],
+    ) -> Result<KeyShare, FrostError> {
+        let by_id = self.check_round1(packages)?;
+        let round1 = round1_hash(packages);
+        let mut secret = Secret(eval_polynomial(&self.coefficients[..], self.id));
+        let mut seen = std::collections::BTreeSet::new();
+        for s in shares {
+            if s.to != self.id || s.from == self.id || !seen.insert(s.from) {
+                return Err(FrostError::WrongCount);
+            }
+            if s.round1 != round1 {
+                return Err(FrostError::Equivocation(s.from));
+            }
+            let sender = by_id
+                .get(&s.from)
+                .ok_or(FrostError::UnknownParticipant(s.from))?;
+            let cipher = share_cipher(
+                &self.encryption_secret,
+                &sender.encryption_key,
+                &self.context[..],
+                s.from,
+                self.id,
+            );
+            let payload = Payload {
+                msg: &s.ciphertext[..],
+                aad: &round1[..],
+            };
+            let plain = Zeroizing::new(
+                cipher
+                    .decrypt(&Nonce::default(), payload)
+                    .map_err(|_| FrostError::Equivocation(s.from))?,
+            );
+            let bytes: Zeroizing<[u8; 32]> = Zeroizing::new(
+                plain[..]
+                    .try_into()
+                    .map_err(|_| FrostError::InvalidShare(s.from))?,
+            );
+            let share: Secret = Option::from(Scalar::from_repr((*bytes).into()))
+                .map(Secret)
+                .ok_or(FrostError::InvalidShare(s.from))?;
+            if ProjectivePoint::GENERATOR * share.0
+                != eval_commitments(&sender.commitments[..], self.id)
+            {
+                return Err(FrostError::InvalidShare(s.from));
+            }
+            secret.0 += share.0;
+        }
+        if seen.len() + 1 != self.n {
+            return Err(FrostError::WrongCount);
+        }
+        let group_key = by_id
+            .values()
+            .fold(ProjectivePoint::IDENTITY, |acc, p| acc + p.commitments[0].0);
+        let verifying_shares = (1..=self.n as ParticipantId)
+            .map(|m| {
+                let y = by_id.values().fold(ProjectivePoint::IDENTITY, |acc, p| {
+                    acc + eval_commitments(&p.commitments[..], m)
+                });
+                (m, Point(y))
+            })
+            .collect();
+        Ok(KeyShare {
+            id: self.id,
+            secret,
+            public: PublicKeyPackage {
+                threshold: self.threshold,
+                group_key: Point(group_key),
+                verifying_shares,
+            },
+        })
+    }
+}
+
+/// The public result of key generation, identical for all participants.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PublicKeyPackage {
+    /// number of participants required to sign
+    pub threshold: usize,
+    /// the untweaked group key
+    pub group_key: Point,
+    /// each participant's public share, for checking its signature shares
+    pub verifying_shares: BTreeMap<ParticipantId, Point>,
+}
+
+impl PublicKeyPackage {
+    /// the additive tweak for template hash `h`
+    pub fn tweak(&self, h: &sha256::Hash) -> Scalar {
+        hash_to_scalar(
+            "sapio/frost/template",
+            &[&point_bytes(&self.group_key.0)[..], &h[..]],
+        )
+    }
+    /// the full key used for template hash `h`
+    pub fn template_key(&self, h: &sha256::Hash) -> ProjectivePoint {
+        self.group_key.0 + ProjectivePoint::GENERATOR * self.tweak(h)
+    }
+}
+
+/// A participant's share of the group secret. The secret share is zeroized
+/// when dropped.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyShare {
+    /// this participant
+    pub id: ParticipantId,
+    /// the secret share
+    pub secret: Secret,
+    /// the group's public information
+    pub public: PublicKeyPackage,
+}
+
+/// A commitment to a signer's nonces, published in the first signing round
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NonceCommitment {
+    /// commitment to the hiding nonce
+    pub hiding: Point,
+    /// commitment to the binding nonce
+    pub binding: Point,
+}
+
+/// A signer's secret nonces. They must be used for at most one signature,
+/// and are zeroized when dropped.
+pub struct SigningNonces {
+    hiding: Scalar,
+    binding: Scalar,
+}
+
+impl Drop for SigningNonces {
+    fn drop(&mut self) {
+        self.hiding.zeroize();
+        self.binding.zeroize();
+    }
+}
+
+impl SigningNonces {
+    /// generate fresh nonces and their commitment
+    pub fn new() -> (Self, NonceCommitment) {
+        let (hiding, binding) = (random_scalar(), random_scalar());
+        (
+            SigningNonces { hiding, binding },
+            NonceCommitment {
+                hiding: Point(ProjectivePoint::GENERATOR * hiding),
+                binding: Point(ProjectivePoint::GENERATOR * binding),
+            },
+        )
+    }
+}
+
+/// Everything derived from the public inputs of a signing session
+struct SessionContext {
+    key: ProjectivePoint,
+    binding_factors: BTreeMap<ParticipantId, Scalar>,
+    r: ProjectivePoint,
+    challenge: Scalar,
+}
+
+impl SessionContext {
+    fn new(
+        public: &PublicKeyPackage,
+        tweak: &Scalar,
+        msg: &[u8; 32],
+        commitments: &BTreeMap<ParticipantId, NonceCommitment>,
+    ) -> Result<Self, FrostError> {
+        let key = public.group_key.0 + ProjectivePoint::GENERATOR * tweak;
+        let x_only = to_x_only(&key)?.serialize();
+        let mut encoded = vec![];
+        for (id, c) in commitments {
+            if !public.verifying_shares.contains_key(id) {
+                return Err(FrostError::UnknownParticipant(*id));
+            }
+            encoded.extend(id.to_be_bytes());
+            encoded.extend(point_bytes(&c.hiding.0));
+            encoded.extend(point_bytes(&c.binding.0));
+        }
+        let encoded = sha256::Hash::hash(&encoded[..]);
+        let binding_factors: BTreeMap<ParticipantId, Scalar> = commitments
+            .keys()
+            .map(|id| {
+                let rho = hash_to_scalar(
+                    "sapio/frost/binding",
+                    &[&id.to_be_bytes()[..], &x_only[..], &msg[..], &encoded[..]],
+                );
+                (*id, rho)
+            })
+            .collect();
+        let r = commitments
+            .iter()
+            .fold(ProjectivePoint::IDENTITY, |acc, (id, c)| {
+                acc + c.hiding.0 + c.binding.0 * binding_factors[id]
+            });
+        if bool::from(r.is_identity()) {
+            return Err(FrostError::InvalidPoint);
+        }
+        let challenge = hash_to_scalar(
+            "BIP0340/challenge",
+            &[&r.to_affine().x()[..], &x_only[..], &msg[..]],
+        );
+        Ok(SessionContext {
+            key,
+            binding_factors,
+            r,
+            challenge,
+        })
+    }
+    /// BIP-340 uses the even y points for R and the key, so the nonce and
+    /// secret contributions get negated when they have odd y.
+    fn nonce_sign(&self) -> Scalar {
+        if has_even_y(&self.r) {
+            Scalar::ONE
+        } else {
+            -Scalar::ONE
+        }
+    }
+    fn key_sign(&self) -> Scalar {
+        if has_even_y(&self.key) {
+            Scalar::ONE
+        } else {
+            -Scalar::ONE
+        }
+    }
+}
+
+impl KeyShare {
+    /// produce our signature share over `msg` for the key tweaked by `tweak`.
+    /// `commitments` are the nonce commitments of every signer, including us,
+    /// and `nonces` must be the ones we committed to.
+    pub fn sign(
+        &self,
+        tweak: &Scalar,
+        msg: &[u8; 32],
+        nonces: SigningNonces,
+        commitments: &BTreeMap<ParticipantId, NonceCommitment>,
+    ) -> Result<Secret, FrostError> {
+        if commitments.len() < self.public.threshold || !commitments.contains_key(&self.id) {
+            return Err(FrostError::WrongCount);
+        }
+        let ctx = SessionContext::new(&self.public, tweak, msg, commitments)?;
+        let lambda = lagrange(self.id, commitments.keys());
+        let nonce = Zeroizing::new(
+            ctx.nonce_sign() * (nonces.hiding + nonces.binding * ctx.binding_factors[&self.id]),
+        );
+        let secret = Zeroizing::new(ctx.key_sign() * (self.secret.0 + tweak));
+        Ok(Secret(*nonce + lambda * *secret * ctx.challenge))
+    }
+}
+
+/// check every signature share and combine them into a BIP-340 signature for
+/// the key tweaked by `tweak`.
+pub fn aggregate(
+    public: &PublicKeyPackage,
+    tweak: &Scalar,
+    msg: &[u8; 32],
+    commitments: &BTreeMap<ParticipantId, NonceCommitment>,
+    shares: &BTreeMap<ParticipantId, Secret>,
+) -> Result<Signature, FrostError> {
+    if shares.len() != commitments.len() || commitments.len() < public.threshold {
+        return Err(FrostError::WrongCount);
+    }
+    let ctx = SessionContext::new(public, tweak, msg, commitments)?;
+    let tweak_point = ProjectivePoint::GENERATOR * tweak;
+    let mut z = Scalar::ZERO;
+    for (id, c) in commitments {
+        let share = shares.get(id).ok_or(FrostError::WrongCount)?;
+        let lambda = lagrange(*id, commitments.keys());
+        let r_i = (c.hiding.0 + c.binding.0 * ctx.binding_factors[id]) * ctx.nonce_sign();
+        let y_i = (public.verifying_shares[id].0 + tweak_point) * ctx.key_sign();
+        if ProjectivePoint::GENERATOR * share.0 != r_i + y_i * (lambda * ctx.challenge) {
+            return Err(FrostError::InvalidSignatureShare(*id));
+        }
+        z += share.0;
+    }
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&ctx.r.to_affine().x()[..]);
+    sig[32..].copy_from_slice(&z.to_bytes()[..]);
+    Signature::from_slice(&sig[..]).map_err(|_| FrostError::InvalidPoint)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{Message, Secp256k1};
+
+    fn keygen(threshold: usize, n: usize) -> Vec<KeyShare> {
+        let (states, round1): (Vec<_>, Vec<_>) = (1..=n as ParticipantId)
+            .map(|id| DkgState::new(id, threshold, n, b"test".to_vec()).unwrap())
+            .unzip();
+        let shares: Vec<EncryptedShare> = states
+            .iter()
+            .flat_map(|s| s.round2(&round1[..]).unwrap())
+            .collect();
+        states
+            .into_iter()
+            .map(|s| {
+                let mine: Vec<_> = shares.iter().filter(|e| e.to == s.id).cloned().collect();
+                s.finish(&round1[..], &mine[..]).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_equivocation() {
+        let (states, round1): (Vec<_>, Vec<_>) = (1..=3)
+            .map(|id| DkgState::new(id, 2, 3, b"test".to_vec()).unwrap())
+            .unzip();
+        // participant 3 is shown a different message from participant 1
+        let (_, other) = DkgState::new(1, 2, 3, b"test".to_vec()).unwrap();
+        let mut forked = round1.clone();
+        forked[0] = other;
+        assert_ne!(round1_hash(&round1[..]), round1_hash(&forked[..]));
+        let shares: Vec<EncryptedShare> = states[..2]
+            .iter()
+            .flat_map(|s| s.round2(&round1[..]).unwrap())
+            .chain(states[2].round2(&forked[..]).unwrap())
+            .collect();
+        let mut states = states.into_iter();
+        let first = states.next().unwrap();
+        let mine: Vec<_> = shares.iter().filter(|e| e.to == 1).cloned().collect();
+        assert_eq!(
+            first.finish(&round1[..], &mine[..]).unwrap_err(),
+            FrostError::Equivocation(3)
+        );
+        // rewriting the hash a share claims doesn't help, as the ciphertext
+        // is bound to it
+        let second = states.next().unwrap();
+        let mut mine: Vec<_> = shares.iter().filter(|e| e.to == 2).cloned().collect();
+        for s in mine.iter_mut() {
+            s.round1 = round1_hash(&round1[..]);
+        }
+        assert_eq!(
+            second.finish(&round1[..], &mine[..]).unwrap_err(),
+            FrostError::Equivocation(3)
+        );
+    }
+
+    #[test]
+    fn threshold_signature_verifies() {
+        let secp = Secp256k1::new();
+        let keys = keygen(2, 3);
+        assert!(keys.iter().all(|k| k.public == keys[0].public));
+        let h = sha256::Hash::hash(b"template");
+        let tweak = keys[0].public.tweak(&h);
+        let msg = [7u8; 32];
+        // try every pair of signers
+        for skip in 0..3 {
+            let signers: Vec<&KeyShare> = keys.iter().filter(|k| k.id != skip + 1).collect();
+            let (nonces, commitments): (Vec<_>, BTreeMap<_, _>) = signers
+                .iter()
+                .map(|k| {
+                    let (n, c) = SigningNonces::new();
+                    (n, (k.id, c))
+                })
+                .unzip();
+            let shares: BTreeMap<_, _> = signers
+                .iter()
+                .zip(nonces)
+                .map(|(k, n)| (k.id, k.sign(&tweak, &msg, n, &commitments).unwrap()))
+                .collect();
+            let sig = aggregate(&keys[0].public, &tweak, &msg, &commitments, &shares).unwrap();
+            let key = to_x_only(&keys[0].public.template_key(&h)).unwrap();
+            secp.verify_schnorr(&sig, &Message::from_digest_slice(&msg).unwrap(), &key)
+                .unwrap();
+
+            let mut bad = shares.clone();
+            let id = signers[0].id;
+            bad.insert(id, Secret(bad[&id].0 + Scalar::ONE));
+            assert_eq!(
+                aggregate(&keys[0].public, &tweak, &msg, &commitments, &bad),
+                Err(FrostError::InvalidSignatureShare(id))
+            );
+        }
+    }
+}
@@ -25,10 +25,15 @@ use bitcoin::util::psbt::PartiallySignedTransaction;
 use sapio_base::CTVHash;
 use std::sync::Arc;
 const MAX_MSG: usize = 1_000_000;
+/// PSBTs are json encoded as an array of byte values, which is up to 4
+/// characters per byte, plus some slack for the enclosing message.
+const MAX_JSON_MSG: usize = 4 * MAX_MSG + 1024;
 
 pub mod connections;
+pub mod frost;
 mod msgs;
 pub mod servers;
+pub mod transport;
 
 thread_local! {
     /// global SECP instance anyone can use
@@ -71,3 +76,50 @@ fn hash_to_child_vec(h: Sha256) -> Vec<ChildNumber> {
     );
     c
 }
+
+/// checks if a tapscript pushes the x-only key `pk` anywhere, i.e., if the
+/// leaf could require a signature from it.
+fn script_has_key(script: &bitcoin::Script, pk: &bitcoin::XOnlyPublicKey) -> bool {
+    let pk = pk.serialize();
+    script.instructions().any(|i| match i {
+        Ok(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b == &pk[..],
+        _ => false,
+    })
+}
+
+/// Picks the sighash type and prevouts to sign input `idx` with.
+///
+/// Only types that commit to every output are allowed (`Default`, `All`, and
+/// `AllPlusAnyoneCanPay`), as CTV commits to all outputs.
+/// `AllPlusAnyoneCanPay` only requires the UTXO being spent, otherwise
+/// `utxos` must contain every input's UTXO.
+fn sighash_prevouts<'a>(
+    input: &bitcoin::util::psbt::Input,
+    idx: usize,
+    utxos: Option<&'a [bitcoin::TxOut]>,
+) -> Result<
+    (
+        bitcoin::util::sighash::SchnorrSighashType,
+        bitcoin::util::sighash::Prevouts<'a, bitcoin::TxOut>,
+    ),
+    std::io::Error,
+> {
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType};
+    let hash_ty = input
+        .schnorr_hash_ty()
+        .map_err(|_| input_err("Invalid Sighash Type"))?;
+    let prevouts = match hash_ty {
+        SchnorrSighashType::Default | SchnorrSighashType::All => Prevouts::All(
+            utxos.ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?,
+        ),
+        SchnorrSighashType::AllPlusAnyoneCanPay => Prevouts::One(
+            idx,
+            input
+                .witness_utxo
+                .clone()
+                .ok_or_else(|| input_err("Could not find the UTXO to be signed over"))?,
+        ),
+        _ => return input_error("Sighash Type Must Commit to All Outputs"),
+    };
+    Ok((hash_ty, prevouts))
+}
@@ -5,12 +5,14 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use crate::frost::*;
 use bitcoin::consensus::encode::{Decodable, Encodable};
 use miniscript::serde;
 use sapio_base::miniscript;
 use serde::de::Visitor;
 use serde::de::*;
 use serde::*;
+use std::collections::BTreeMap;
 use std::fmt;
 
 const MAX_MSG: usize = 1_000_000;
@@ -24,6 +26,69 @@ pub struct PSBT(pub PartiallySignedTransaction);
 #[derive(Serialize, Deserialize)]
 pub enum Request {
     SignPSBT(PSBT),
+    /// commands for servers running with a contract registry, authorized by
+    /// the server's admin token
+    Admin {
+        token: String,
+        command: crate::servers::policy::AdminCommand,
+    },
+}
+
+/// A request to sign with the threshold key for the template of one input
+#[derive(Serialize, Deserialize)]
+pub struct FrostSignItem {
+    /// the input being signed
+    pub input: usize,
+    /// the tap leaf of that input being signed
+    pub leaf: bitcoin::util::taproot::TapLeafHash,
+    /// the id of the nonce (from a `FrostResponse::Commitments`) to use
+    pub nonce: u64,
+    /// the nonce commitments of every signer in this signing session
+    pub commitments: BTreeMap<ParticipantId, NonceCommitment>,
+}
+
+/// Requests to a FROST oracle
+#[derive(Serialize, Deserialize)]
+pub enum FrostRequest {
+    /// begin key generation as participant `id`. Every key generation
+    /// request carries the oracle's admin token.
+    DkgRound1 {
+        token: String,
+        id: ParticipantId,
+        threshold: usize,
+        n: usize,
+        session: Sha256,
+    },
+    /// given all participants' round 1 messages, return shares for the others
+    DkgRound2 {
+        token: String,
+        packages: Vec<DkgRound1>,
+    },
+    /// given all participants' round 1 messages and the shares sent to this
+    /// participant, finish key generation
+    DkgFinish {
+        token: String,
+        packages: Vec<DkgRound1>,
+        shares: Vec<EncryptedShare>,
+    },
+    /// generate this many single use nonces
+    Commit(usize),
+    /// sign every item of the PSBT
+    Sign(PSBT, Vec<FrostSignItem>),
+}
+
+/// Responses from a FROST oracle
+#[derive(Serialize, Deserialize)]
+pub enum FrostResponse {
+    DkgRound1(DkgRound1),
+    DkgRound2(Vec<EncryptedShare>),
+    /// the `round1_hash` of the round 1 messages the oracle was given, and
+    /// the group's public key package
+    DkgFinish(Sha256, PublicKeyPackage),
+    Commitments(Vec<(u64, NonceCommitment)>),
+    /// one signature share per `FrostSignItem`, in order
+    Shares(Vec<Secret>),
+    Error(String),
 }
 
 /// A visitor tage for a SafePSBT type that is size limited
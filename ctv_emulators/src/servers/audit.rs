@@ -0,0 +1,265 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! append-only, hash chained audit log of every signature an emulator issues
+//!
+//! The log is a file of json lines. Each line commits to the hash of the line
+//! before it, so truncating, reordering or editing any entry is detected by
+//! [`AuditLog::verify`]. Every record is also signed with the server's
+//! identity key, so the log can't be rewritten wholesale by anyone without
+//! that key. Publishing (or timestamping) the head hash returned by
+//! verification periodically additionally lets third parties check the
+//! server itself did not rewrite it.
+use super::*;
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::{Message, SecretKey};
+use bitcoin::{KeyPair, Txid, XOnlyPublicKey};
+use serde_derive::{Deserialize, Serialize};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single signing event.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// position in the log, starting from 0
+    pub seq: u64,
+    /// unix time (seconds) when the signature was issued
+    pub time: u64,
+    /// the peer the signature was sent to
+    pub client: SocketAddr,
+    /// the transaction that was signed
+    pub txid: Txid,
+    /// the input that was signed
+    pub input: usize,
+    /// the template hash the signing key was derived from
+    pub ctv_hash: Sha256,
+    /// hash of the previous record, or all zeros for the first
+    pub prev: Sha256,
+}
+
+/// An `AuditEntry` along with its hash and the server's signature of the
+/// hash, as stored on disk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AuditRecord {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    hash: Sha256,
+    sig: Signature,
+}
+
+impl AuditEntry {
+    fn hash(&self) -> Result<Sha256, std::io::Error> {
+        Ok(Sha256::hash(&serde_json::to_vec(self)?[..]))
+    }
+}
+
+/// Errors found while verifying an audit log
+#[derive(Debug)]
+pub enum AuditError {
+    /// The log could not be read or parsed
+    Io(std::io::Error),
+    /// The record at this line does not follow from the record before it
+    BrokenChain(u64),
+    /// The record at this line is not signed by the server's identity key
+    BadSignature(u64),
+}
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for AuditError {}
+impl From<std::io::Error> for AuditError {
+    fn from(e: std::io::Error) -> Self {
+        AuditError::Io(e)
+    }
+}
+impl From<serde_json::Error> for AuditError {
+    fn from(e: serde_json::Error) -> Self {
+        AuditError::Io(e.into())
+    }
+}
+
+/// Handle to an open audit log. Writes are serialized, so it can be shared
+/// between connections.
+#[derive(Clone)]
+pub struct AuditLog {
+    inner: Arc<tokio::sync::Mutex<AuditLogInner>>,
+}
+
+struct AuditLogInner {
+    file: tokio::fs::File,
+    key: KeyPair,
+    next_seq: u64,
+    head: Sha256,
+}
+
+impl AuditLog {
+    /// opens (or creates) the log at `path`, whose records are signed with
+    /// the server identity key `key`, verifying any existing entries so that
+    /// new entries extend a valid chain.
+    pub async fn open<P: AsRef<Path>>(path: P, key: &SecretKey) -> Result<Self, AuditError> {
+        let path: PathBuf = path.as_ref().into();
+        let key = SECP.with(|secp| KeyPair::from_secret_key(secp, key));
+        let (next_seq, head) = if path.exists() {
+            let p = path.clone();
+            let identity = key.x_only_public_key().0;
+            tokio::task::spawn_blocking(move || Self::verify(p, &identity))
+                .await
+                .map_err(std::io::Error::other)??
+        } else {
+            (0, Sha256::from_inner([0u8; 32]))
+        };
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        Ok(AuditLog {
+            inner: Arc::new(tokio::sync::Mutex::new(AuditLogInner {
+                file,
+                key,
+                next_seq,
+                head,
+            })),
+        })
+    }
+
+    /// durably appends a record of a signature. Callers should not release
+    /// the signature if this fails.
+    pub async fn record(
+        &self,
+        client: SocketAddr,
+        txid: Txid,
+        input: usize,
+        ctv_hash: Sha256,
+    ) -> Result<(), std::io::Error> {
+        let mut inner = self.inner.lock().await;
+        let entry = AuditEntry {
+            seq: inner.next_seq,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            client,
+            txid,
+            input,
+            ctv_hash,
+            prev: inner.head,
+        };
+        let hash = entry.hash()?;
+        let sig = SECP.with(|secp| secp.sign_schnorr_no_aux_rand(&head_message(&hash), &inner.key));
+        let mut line = serde_json::to_vec(&AuditRecord { entry, hash, sig })?;
+        line.push(b'\n');
+        inner.file.write_all(&line[..]).await?;
+        inner.file.sync_data().await?;
+        inner.next_seq += 1;
+        inner.head = hash;
+        Ok(())
+    }
+
+    /// checks every record in the log at `path` chains correctly and is
+    /// signed by `identity`, returning the number of records and the head
+    /// hash.
+    pub fn verify<P: AsRef<Path>>(
+        path: P,
+        identity: &XOnlyPublicKey,
+    ) -> Result<(u64, Sha256), AuditError> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut head = Sha256::from_inner([0u8; 32]);
+        let mut seq = 0;
+        for line in file.lines() {
+            let record: AuditRecord = serde_json::from_str(&line?)?;
+            if record.entry.seq != seq
+                || record.entry.prev != head
+                || record.entry.hash()? != record.hash
+            {
+                return Err(AuditError::BrokenChain(seq));
+            }
+            SECP.with(|secp| {
+                secp.verify_schnorr(&record.sig, &head_message(&record.hash), identity)
+            })
+            .map_err(|_| AuditError::BadSignature(seq))?;
+            head = record.hash;
+            seq += 1;
+        }
+        Ok((seq, head))
+    }
+}
+
+fn head_message(hash: &Sha256) -> Message {
+    Message::from_digest_slice(&hash[..]).expect("a sha256 is a valid message")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn detects_tampering() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("sapio-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let client: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let txid = Txid::from_inner([1u8; 32]);
+        let key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let identity = SECP.with(|secp| key.x_only_public_key(secp).0);
+        {
+            let log = AuditLog::open(&path, &key).await.unwrap();
+            for i in 0..3u8 {
+                log.record(client, txid, 0, Sha256::hash(&[i]))
+                    .await
+                    .unwrap();
+            }
+        }
+        // reopening extends the existing chain
+        let log = AuditLog::open(&path, &key).await.unwrap();
+        log.record(client, txid, 1, Sha256::hash(&[3]))
+            .await
+            .unwrap();
+        let (n, _) = AuditLog::verify(&path, &identity).unwrap();
+        assert_eq!(n, 4);
+
+        // a log signed by some other key is rejected
+        let other = SecretKey::from_slice(&[8u8; 32]).unwrap();
+        let other = SECP.with(|secp| other.x_only_public_key(secp).0);
+        assert!(matches!(
+            AuditLog::verify(&path, &other),
+            Err(AuditError::BadSignature(0))
+        ));
+        // as is a rewritten log which chains correctly but isn't signed
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let unsigned: Vec<String> = contents
+            .lines()
+            .map(|l| {
+                let mut v: serde_json::Value = serde_json::from_str(l).unwrap();
+                v["sig"] = serde_json::to_value(SECP.with(|secp| {
+                    secp.sign_schnorr_no_aux_rand(
+                        &head_message(&Sha256::hash(&[0])),
+                        &KeyPair::from_secret_key(secp, &key),
+                    )
+                }))
+                .unwrap();
+                v.to_string()
+            })
+            .collect();
+        std::fs::write(&path, unsigned.join("\n") + "\n").unwrap();
+        assert!(matches!(
+            AuditLog::verify(&path, &identity),
+            Err(AuditError::BadSignature(0))
+        ));
+        std::fs::write(&path, &contents).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let tampered = contents.replacen("\"input\":1", "\"input\":2", 1);
+        std::fs::write(&path, tampered).unwrap();
+        assert!(matches!(
+            AuditLog::verify(&path, &identity),
+            Err(AuditError::BrokenChain(3))
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a single participant in a FROST threshold emulator federation
+use super::limits::{ConnectionTracker, RateLimiter, ServerLimits};
+use super::*;
+use crate::frost::*;
+use crate::msgs::{FrostRequest, FrostResponse, FrostSignItem};
+use crate::transport::Channel;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::TxOut;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::time::timeout;
+use zeroize::Zeroizing;
+
+/// the most nonces a single oracle will keep outstanding at once
+const MAX_NONCES: usize = 4096;
+
+#[derive(Default)]
+struct FrostState {
+    dkg: Option<DkgState>,
+    key: Option<KeyShare>,
+    nonces: BTreeMap<u64, (SigningNonces, NonceCommitment)>,
+    next_nonce: u64,
+}
+
+/// An oracle holding one share of a federation's threshold key.
+///
+/// The oracle does not trust the coordinator: for every signature share it
+/// recomputes the template hash of the input from the transaction, derives
+/// the template key, and checks that the leaf being signed uses that key, so
+/// the share is only ever useful for the transaction the key commits to.
+///
+/// Key generation picks the federation's key, so it is only run for a
+/// coordinator presenting the operator's admin token (see
+/// `with_admin_token`), and only once; afterwards the share must be
+/// persisted, either by the oracle itself (see `with_key_file`) or by the
+/// caller (see `key`) and supplied with `with_key` on restart.
+#[derive(Clone)]
+pub struct FrostOracle {
+    identity: SecretKey,
+    debug: bool,
+    key_file: Option<PathBuf>,
+    admin_token: Option<String>,
+    connections: ConnectionTracker,
+    state: Arc<Mutex<FrostState>>,
+}
+
+impl FrostOracle {
+    /// create an oracle with no key share, which authenticates itself to
+    /// clients with `identity`.
+    ///
+    /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
+    pub fn new(identity: SecretKey, debug: bool) -> Self {
+        FrostOracle {
+            identity,
+            debug,
+            key_file: None,
+            admin_token: None,
+            connections: ConnectionTracker::new(ServerLimits::default()),
+            state: Default::default(),
+        }
+    }
+    /// use a key share from a previous key generation
+    pub fn with_key(self, key: KeyShare) -> Self {
+        self.lock().key = Some(key);
+        self
+    }
+    /// persist the key share to `path`, loading it from there if key
+    /// generation has already been run. The share is saved before key
+    /// generation is reported to have finished.
+    pub async fn with_key_file(mut self, path: PathBuf) -> Result<Self, std::io::Error> {
+        if tokio::fs::try_exists(&path).await? {
+            let data = Zeroizing::new(tokio::fs::read(&path).await?);
+            let key: KeyShare = serde_json::from_slice(&data[..])?;
+            self = self.with_key(key);
+        }
+        self.key_file = Some(path);
+        Ok(self)
+    }
+    /// allow key generation by clients presenting `token`. Without one, key
+    /// generation is refused.
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        self.admin_token = Some(token);
+        self
+    }
+    /// replace the default `ServerLimits` for this server
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.connections = ConnectionTracker::new(limits);
+        self
+    }
+    /// the key share, once key generation has finished
+    pub fn key(&self) -> Option<KeyShare> {
+        self.lock().key.clone()
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, FrostState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// write the key share to the key file, if there is one. If it can't be
+    /// written the share is forgotten, so key generation can be rerun.
+    async fn save_key(&self) -> Result<(), std::io::Error> {
+        let (path, key) = match (&self.key_file, self.key()) {
+            (Some(path), Some(key)) => (path.clone(), key),
+            _ => return Ok(()),
+        };
+        let r = tokio::task::spawn_blocking(move || {
+            let data = Zeroizing::new(serde_json::to_vec(&key)?);
+            let tmp = path.with_extension("tmp");
+            {
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                let mut f = options.open(&tmp)?;
+                std::io::Write::write_all(&mut f, &data[..])?;
+                f.sync_all()?;
+            }
+            std::fs::rename(&tmp, &path)
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|r| r);
+        if r.is_err() {
+            self.lock().key = None;
+        }
+        r
+    }
+
+    /// binds a FrostOracle to a socket interface and runs the server
+    pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
+        self.listen(TcpListener::bind(a).await?).await
+    }
+
+    /// runs the server on an already bound listener.
+    ///
+    /// This will only return when debug = false if The TcpListener fails.
+    /// When debug = true, then we join each connection one at a time and return
+    /// any errors.
+    pub async fn listen(self, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let guard = match self.connections.admit(peer.ip()) {
+                Some(guard) => guard,
+                None => continue,
+            };
+            let this = self.clone();
+            let j: tokio::task::JoinHandle<Result<(), std::io::Error>> = tokio::spawn(async move {
+                let _guard = guard;
+                let limits = this.connections.limits().clone();
+                let mut channel =
+                    timeout(limits.idle_timeout, Channel::accept(socket, &this.identity)).await??;
+                let mut rate = RateLimiter::new(limits.max_requests_per_minute);
+                loop {
+                    timeout(limits.idle_timeout, channel.stream().readable()).await??;
+                    if !rate.check() {
+                        return input_error("Rate Limit Exceeded");
+                    }
+                    let request = channel.recv::<FrostRequest>(limits.max_request).await?;
+                    let response = match this.handle(request) {
+                        Ok(r @ FrostResponse::DkgFinish(..)) => this.save_key().await.map(|()| r),
+                        r => r,
+                    }
+                    .unwrap_or_else(|e| FrostResponse::Error(e.to_string()));
+                    channel.send(&response).await?;
+                }
+            });
+            if self.debug {
+                tokio::join!(j).0??;
+            }
+        }
+    }
+
+    /// the main server business logic. Errors are returned to the client as
+    /// `FrostResponse::Error` rather than closing the connection.
+    fn handle(&self, request: FrostRequest) -> Result<FrostResponse, std::io::Error> {
+        let frost_err = |e: FrostError| input_err(&e.to_string());
+        let authorize = |token: &str| match &self.admin_token {
+            Some(admin_token) if constant_time_eq(token, admin_token) => Ok(()),
+            Some(_) => input_error("Unauthorized"),
+            None => input_error("Key Generation Not Enabled"),
+        };
+        let mut state = self.lock();
+        match request {
+            FrostRequest::DkgRound1 {
+                token,
+                id,
+                threshold,
+                n,
+                session,
+            } => {
+                authorize(&token)?;
+                if state.key.is_some() {
+                    return input_error("Key Already Generated");
+                }
+                let (dkg, round1) =
+                    DkgState::new(id, threshold, n, session.to_vec()).map_err(frost_err)?;
+                state.dkg = Some(dkg);
+                Ok(FrostResponse::DkgRound1(round1))
+            }
+            FrostRequest::DkgRound2 { token, packages } => {
+                authorize(&token)?;
+                let dkg = state
+                    .dkg
+                    .as_ref()
+                    .ok_or_else(|| input_err("No Key Generation In Progress"))?;
+                Ok(FrostResponse::DkgRound2(
+                    dkg.round2(&packages[..]).map_err(frost_err)?,
+                ))
+            }
+            FrostRequest::DkgFinish {
+                token,
+                packages,
+                shares,
+            } => {
+                authorize(&token)?;
+                let dkg = state
+                    .dkg
+                    .take()
+                    .ok_or_else(|| input_err("No Key Generation In Progress"))?;
+                let key = dkg.finish(&packages[..], &shares[..]).map_err(frost_err)?;
+                let public = key.public.clone();
+                state.key = Some(key);
+                Ok(FrostResponse::DkgFinish(round1_hash(&packages[..]), public))
+            }
+            FrostRequest::Commit(count) => {
+                if count > MAX_NONCES - state.nonces.len() {
+                    return input_error("Too Many Outstanding Nonces");
+                }
+                let commitments = (0..count)
+                    .map(|_| {
+                        let (nonces, commitment) = SigningNonces::new();
+                        let id = state.next_nonce;
+                        state.next_nonce += 1;
+                        state.nonces.insert(id, (nonces, commitment));
+                        (id, commitment)
+                    })
+                    .collect();
+                Ok(FrostResponse::Commitments(commitments))
+            }
+            FrostRequest::Sign(msgs::PSBT(psbt), items) => {
+                let state = &mut *state;
+                let key = state
+                    .key
+                    .as_ref()
+                    .ok_or_else(|| input_err("No Key Generated"))?;
+                let tx = psbt.clone().extract_tx();
+                let utxos: Option<Vec<TxOut>> =
+                    psbt.inputs.iter().map(|o| o.witness_utxo.clone()).collect();
+                let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
+                let mut shares = vec![];
+                for FrostSignItem {
+                    input: idx,
+                    leaf,
+                    nonce,
+                    commitments,
+                } in items
+                {
+                    // nonces are removed before anything else so they are never
+                    // reused, even if signing fails
+                    let (nonces, commitment) = state
+                        .nonces
+                        .remove(&nonce)
+                        .ok_or_else(|| input_err("Unknown Nonce"))?;
+                    if commitments.get(&key.id) != Some(&commitment) {
+                        return input_error("Nonce Commitment Mismatch");
+                    }
+                    let input = psbt
+                        .inputs
+                        .get(idx)
+                        .ok_or_else(|| input_err("No Such Input"))?;
+                    let h = tx.get_ctv_hash(idx as u32);
+                    let pk = to_x_only(&key.public.template_key(&h)).map_err(frost_err)?;
+                    if !input.tap_scripts.values().any(|(script, ver)| {
+                        TapLeafHash::from_script(script, *ver) == leaf
+                            && script_has_key(script, &pk)
+                    }) {
+                        return input_error("Leaf Does Not Use Template Key");
+                    }
+                    let (hash_ty, prevouts) = sighash_prevouts(input, idx, utxos.as_deref())?;
+                    let msg = sighash
+                        .taproot_signature_hash(
+                            idx,
+                            &prevouts,
+                            None,
+                            Some((leaf, 0xffffffff)),
+                            hash_ty,
+                        )
+                        .map_err(|_| input_err("Could Not Compute Signature Hash"))?;
+                    let share = key
+                        .sign(
+                            &key.public.tweak(&h),
+                            &msg.into_inner(),
+                            nonces,
+                            &commitments,
+                        )
+                        .map_err(frost_err)?;
+                    shares.push(share);
+                }
+                Ok(FrostResponse::Shares(shares))
+            }
+        }
+    }
+}
@@ -5,20 +5,29 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! definitions for oracle servers
+use super::audit::AuditLog;
+use super::limits::{ConnectionTracker, RateLimiter, ServerLimits};
+use super::policy::{AdminResponse, ContractRegistry, SharedRegistry};
 use super::*;
-use bitcoin::util::sighash::Prevouts;
+use crate::transport::Channel;
 use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::util::taproot::TapSighashHash;
 use bitcoin::SchnorrSig;
 use bitcoin::Script;
 use bitcoin::TxOut;
 use bitcoin::XOnlyPublicKey;
+use tokio::time::timeout;
 
 /// hierarchical deterministic oracle emulator
 #[derive(Clone)]
 pub struct HDOracleEmulator {
     root: ExtendedPrivKey,
     debug: bool,
+    connections: ConnectionTracker,
+    audit: Option<AuditLog>,
+    policy: Option<(SharedRegistry, String)>,
+    /// serializes admin commands, so their changes are saved in order
+    admin: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl HDOracleEmulator {
@@ -26,24 +35,64 @@ impl HDOracleEmulator {
     ///
     /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
     pub fn new(root: ExtendedPrivKey, debug: bool) -> Self {
-        HDOracleEmulator { root, debug }
+        HDOracleEmulator {
+            root,
+            debug,
+            connections: ConnectionTracker::new(ServerLimits::default()),
+            audit: None,
+            policy: None,
+            admin: Default::default(),
+        }
+    }
+    /// replace the default `ServerLimits` for this server
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.connections = ConnectionTracker::new(limits);
+        self
+    }
+    /// record every signature this server issues to `log`
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+    /// only sign for templates approved by `registry`. The registry can be
+    /// administered remotely by clients presenting `admin_token`.
+    pub fn with_policy(mut self, registry: SharedRegistry, admin_token: String) -> Self {
+        self.policy = Some((registry, admin_token));
+        self
     }
     /// binds a HDOracleEmulator to a socket interface and runs the server
     ///
     /// This will only return when debug = false if The TcpListener fails.
     /// When debug = true, then we join each connection one at a time and return
     /// any errors.
+    ///
+    /// Connections beyond the configured `ServerLimits` are closed immediately.
     pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
         let listener = TcpListener::bind(a).await?;
         loop {
-            let (mut socket, _) = listener.accept().await?;
+            let (socket, peer) = listener.accept().await?;
+            let guard = match self.connections.admit(peer.ip()) {
+                Some(guard) => guard,
+                None => continue,
+            };
             {
                 let this = self.clone();
                 let j: tokio::task::JoinHandle<Result<(), std::io::Error>> =
                     tokio::spawn(async move {
+                        let _guard = guard;
+                        let limits = this.connections.limits().clone();
+                        let mut channel = timeout(
+                            limits.idle_timeout,
+                            Channel::accept(socket, &this.root.private_key),
+                        )
+                        .await??;
+                        let mut rate = RateLimiter::new(limits.max_requests_per_minute);
                         loop {
-                            socket.readable().await?;
-                            this.handle(&mut socket).await?;
+                            timeout(limits.idle_timeout, channel.stream().readable()).await??;
+                            if !rate.check() {
+                                return input_error("Rate Limit Exceeded");
+                            }
+                            this.handle(&mut channel, peer, limits.max_request).await?;
                         }
                     });
                 if self.debug {
@@ -60,96 +109,368 @@ impl HDOracleEmulator {
 
     /// Signs a PSBT with the correct derived key.
     ///
-    /// Always signs for spending index 0.
+    /// Every input is considered: the template hash is computed for that
+    /// input's index, and the key derived from it is used to sign the key path
+    /// (if the output is a taproot output tweaked from that key) and every tap
+    /// leaf which contains that key. Inputs which use neither are left as is,
+    /// so a PSBT can carry inputs belonging to other signers.
+    ///
+    /// The signature hash type is taken from the PSBT input. Only types that
+    /// commit to every output are allowed, as CTV commits to all outputs (see
+    /// `sighash_prevouts`). `AllPlusAnyoneCanPay` (which, like CTV, does not
+    /// commit to other inputs' outpoints) only requires the UTXO being spent,
+    /// so other inputs can be used for fee bumping.
+    ///
+    /// If a registry is given, refuses to sign any input whose template hash
+    /// the registry does not approve.
+    ///
+    /// Returns the (input, template hash) of every input that was signed.
     ///
     /// May fail to sign if the PSBT is not properly formatted
     fn sign(
         &self,
         mut b: PartiallySignedTransaction,
+        policy: Option<&ContractRegistry>,
         secp: &Secp256k1<All>,
-    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+    ) -> Result<(PartiallySignedTransaction, Vec<(usize, Sha256)>), std::io::Error> {
+        use bitcoin::schnorr::TapTweak;
         let tx = b.clone().extract_tx();
-        let h = tx.get_ctv_hash(0);
-        let utxos: Vec<TxOut> = b
-            .inputs
-            .iter()
-            .map(|o| o.witness_utxo.clone())
-            .collect::<Option<Vec<TxOut>>>()
-            .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
-        let key = self
-            .derive(h, secp)
-            .map_err(|_| input_err("Could Not Derive Key"))?;
-        let untweaked = key.to_keypair(secp);
-        let pk = XOnlyPublicKey::from_keypair(&untweaked);
+        let utxos: Option<Vec<TxOut>> = b.inputs.iter().map(|o| o.witness_utxo.clone()).collect();
         let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
-        let input_zero = &mut b.inputs[0];
-        use bitcoin::schnorr::TapTweak;
-        let tweaked = untweaked
-            .tap_tweak(secp, input_zero.tap_merkle_root)
-            .into_inner();
-        let tweaked_pk = tweaked.public_key();
-        let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
-        let prevouts = &Prevouts::All(&utxos);
-        let mut get_sig = |path, kp| {
-            let annex = None;
-            let sighash: TapSighashHash = sighash
-                .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
-                .expect("Signature hash cannot fail...");
-            let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..])
-                .expect("Size must be correct.");
-            let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-            SchnorrSig { sig, hash_ty }
-        };
-        if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
-            v.script_pubkey
-                == Script::new_v1_p2tr_tweaked(
-                    XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
-                )
-        }) {
-            let sig = get_sig(None, &tweaked);
-            input_zero.tap_key_sig = Some(sig);
-        }
-        for tlh in input_zero
-            .tap_scripts
-            .values()
-            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
-        {
-            let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
-            input_zero.tap_script_sigs.insert((pk.0, tlh), sig);
+        let mut signed = vec![];
+        for (idx, input) in b.inputs.iter_mut().enumerate() {
+            let h = tx.get_ctv_hash(idx as u32);
+            let key = self
+                .derive(h, secp)
+                .map_err(|_| input_err("Could Not Derive Key"))?;
+            let untweaked = key.to_keypair(secp);
+            let pk = XOnlyPublicKey::from_keypair(&untweaked).0;
+            let tweaked = untweaked
+                .tap_tweak(secp, input.tap_merkle_root)
+                .into_inner();
+            let tweaked_pk = XOnlyPublicKey::from_keypair(&tweaked).0;
+            let key_spend = input.witness_utxo.as_ref().map(|v| {
+                v.script_pubkey
+                    == Script::new_v1_p2tr_tweaked(tweaked_pk.dangerous_assume_tweaked())
+            }) == Some(true);
+            let leaves: Vec<TapLeafHash> = input
+                .tap_scripts
+                .values()
+                .filter(|(script, _)| script_has_key(script, &pk))
+                .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+                .collect();
+            if !key_spend && leaves.is_empty() {
+                continue;
+            }
+            if let Some(registry) = policy {
+                if !registry.approves(&h, idx) {
+                    return input_error("Template Not Approved");
+                }
+            }
+            let (hash_ty, prevouts) = sighash_prevouts(input, idx, utxos.as_deref())?;
+            let mut get_sig = |path, kp| -> Result<SchnorrSig, std::io::Error> {
+                let annex = None;
+                let sighash: TapSighashHash = sighash
+                    .taproot_signature_hash(idx, &prevouts, annex, path, hash_ty)
+                    .map_err(|_| input_err("Could Not Compute Signature Hash"))?;
+                let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..])
+                    .expect("Size must be correct.");
+                let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+                Ok(SchnorrSig { sig, hash_ty })
+            };
+            if key_spend {
+                input.tap_key_sig = Some(get_sig(None, &tweaked)?);
+            }
+            for tlh in leaves {
+                let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked)?;
+                input.tap_script_sigs.insert((pk, tlh), sig);
+            }
+            signed.push((idx, h));
         }
-        Ok(b)
+        Ok((b, signed))
+    }
+
+    /// `sign`, restricted by this server's registry if it has one
+    fn sign_with_policy(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<(PartiallySignedTransaction, Vec<(usize, Sha256)>), std::io::Error> {
+        let registry = match &self.policy {
+            Some((registry, _)) => Some(
+                registry
+                    .read()
+                    .map_err(|_| std::io::Error::other("Registry Poisoned"))?,
+            ),
+            None => None,
+        };
+        SECP.with(|secp| self.sign(b, registry.as_deref(), secp))
     }
 
     /// the main server business logic.
     ///
-    /// - on receiving Request::SignPSBT, signs the PSBT.
-    async fn handle(&self, t: &mut TcpStream) -> Result<(), std::io::Error> {
-        let request = Self::requested(t).await?;
+    /// - on receiving Request::SignPSBT, signs the PSBT. If an audit log is
+    ///   configured, the signature is only returned once it has been logged.
+    async fn handle(
+        &self,
+        t: &mut Channel,
+        peer: SocketAddr,
+        max_request: usize,
+    ) -> Result<(), std::io::Error> {
+        let request = t.recv::<msgs::Request>(max_request).await?;
         match request {
             msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
-                let psbt = SECP.with(|secp| self.sign(unsigned, secp))?;
-                Self::respond(t, &msgs::PSBT(psbt)).await
+                let (psbt, signed) = self.sign_with_policy(unsigned)?;
+                if let Some(log) = &self.audit {
+                    let txid = psbt.unsigned_tx.txid();
+                    for (input, h) in signed {
+                        log.record(peer, txid, input, h).await?;
+                    }
+                }
+                t.send(&msgs::PSBT(psbt)).await
+            }
+            msgs::Request::Admin { token, command } => {
+                let response = match &self.policy {
+                    Some((registry, admin_token)) if constant_time_eq(&token, admin_token) => {
+                        let _admin = self.admin.lock().await;
+                        let (response, snapshot) = registry
+                            .write()
+                            .map_err(|_| std::io::Error::other("Registry Poisoned"))?
+                            .admin(command);
+                        // write with the registry unlocked, off the async workers
+                        match snapshot {
+                            Some(snapshot) => tokio::task::spawn_blocking(move || snapshot.write())
+                                .await
+                                .map_err(std::io::Error::other)?
+                                .map(|()| response)
+                                .unwrap_or_else(|e| AdminResponse::Error(e.to_string())),
+                            None => response,
+                        }
+                    }
+                    Some(_) => AdminResponse::Error("Unauthorized".into()),
+                    None => AdminResponse::Error("No Registry Configured".into()),
+                };
+                t.send(&response).await
             }
         }
     }
+}
 
-    /// receive a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
-    async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        Ok(serde_json::from_slice(&v[..])?)
-    }
-
-    /// respond via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await?;
-        t.flush().await
+/// An `HDOracleEmulator` can be used directly as an in-process emulator, e.g.
+/// for tests, without running a server. Signing applies the same checks as the
+/// server (including the policy registry, if any), but signatures are not
+/// recorded to the audit log.
+impl CTVEmulator for HDOracleEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        SECP.with(|secp| {
+            let key = self.derive(h, secp)?.to_keypair(secp);
+            Ok(Clause::Key(XOnlyPublicKey::from_keypair(&key).0))
+        })
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(self.sign_with_policy(b)?.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType};
+    use bitcoin::util::taproot::{LeafVersion, TaprootBuilder};
+    use bitcoin::{OutPoint, Transaction, TxIn};
+    use sapio::contract::*;
+    use sapio::*;
+    use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::RelTime;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+
+    #[test]
+    fn signs_covenant_input_at_any_index() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1u8; 32]).unwrap();
+        let emulator = HDOracleEmulator::new(root, false);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..2u8)
+                .map(|i| TxIn {
+                    previous_output: OutPoint::new(bitcoin::Txid::from_inner([i; 32]), 0),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        // the covenant coin is spent at input 1, input 0 is a fee input
+        let h = tx.get_ctv_hash(1);
+        let pk =
+            XOnlyPublicKey::from_keypair(&emulator.derive(h, &secp).unwrap().to_keypair(&secp)).0;
+        let script = Builder::new()
+            .push_slice(&pk.serialize())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let internal = XOnlyPublicKey::from_keypair(
+            &ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[2u8; 32])
+                .unwrap()
+                .to_keypair(&secp),
+        )
+        .0;
+        let info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&secp, internal)
+            .unwrap();
+        let leaf = (script, LeafVersion::TapScript);
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let input = &mut psbt.inputs[1];
+        input.witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: Script::new_v1_p2tr_tweaked(info.output_key()),
+        });
+        input.tap_merkle_root = info.merkle_root();
+        input
+            .tap_scripts
+            .insert(info.control_block(&leaf).unwrap(), leaf.clone());
+        input.sighash_type = Some(SchnorrSighashType::AllPlusAnyoneCanPay.into());
+
+        let (signed_psbt, signed) = emulator.sign(psbt, None, &secp).unwrap();
+        assert_eq!(signed, vec![(1, h)]);
+        assert!(signed_psbt.inputs[0].tap_script_sigs.is_empty());
+        let tlh = TapLeafHash::from_script(&leaf.0, leaf.1);
+        let sig = signed_psbt.inputs[1].tap_script_sigs[&(pk, tlh)];
+        assert_eq!(sig.hash_ty, SchnorrSighashType::AllPlusAnyoneCanPay);
+        let msg = bitcoin::util::sighash::SighashCache::new(&signed_psbt.unsigned_tx)
+            .taproot_signature_hash(
+                1,
+                &Prevouts::One(1, signed_psbt.inputs[1].witness_utxo.clone().unwrap()),
+                None,
+                Some((tlh, 0xffffffff)),
+                sig.hash_ty,
+            )
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&msg[..]).unwrap();
+        secp.verify_schnorr(&sig.sig, &msg, &pk).unwrap();
+    }
+
+    /// pays `amount` to `to` after a relative `timeout`
+    struct Delay<T> {
+        to: T,
+        amount: bitcoin::Amount,
+        timeout: u16,
+    }
+
+    impl<T: Compilable + 'static> Delay<T> {
+        #[then]
+        fn complete(self, ctx: Context) {
+            ctx.template()
+                .add_output(self.amount, &self.to, None)?
+                .set_sequence(0, RelTime::from(self.timeout).into())?
+                .into()
+        }
+    }
+
+    impl<T: Compilable + 'static> Contract for Delay<T> {
+        declare! {then, Self::complete}
+        declare! {non updatable}
+    }
+
+    /// binds `compiled` to a fake coin, finalizes every resulting transaction
+    /// and checks it against the template it claims to be.
+    fn check_spendable(compiled: &Compiled, emulator: &dyn CTVEmulator) {
+        use sapio::contract::abi::studio::SapioStudioFormat;
+        use sapio::miniscript::psbt::PsbtExt;
+        use sapio::template::Template;
+        use sapio::util::interpreter::*;
+        use sapio_base::txindex::{TxIndex, TxIndexLogger};
+        let secp = Secp256k1::new();
+        let mut templates: BTreeMap<Sha256, &Template> = BTreeMap::new();
+        let mut stack = vec![compiled];
+        while let Some(c) = stack.pop() {
+            for t in c.ctv_to_tx.values() {
+                stack.extend(t.outputs.iter().map(|o| &o.contract));
+                templates.insert(t.ctv, t);
+            }
+        }
+        let txindex: Rc<dyn TxIndex> = Rc::new(TxIndexLogger::new());
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000_000,
+                script_pubkey: compiled.address.clone().into(),
+            }],
+        };
+        let txid = txindex.add_tx(Arc::new(funding)).unwrap();
+        let program = compiled
+            .bind_psbt(OutPoint::new(txid, 0), BTreeMap::new(), txindex, emulator)
+            .unwrap();
+        let mut checked = 0;
+        for obj in program.program.values() {
+            for SapioStudioFormat::LinkedPSBT { psbt, .. } in &obj.txs {
+                let mut psbt: PartiallySignedTransaction =
+                    bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()).unwrap();
+                psbt.finalize_mut(&secp).unwrap();
+                let prevouts: Vec<TxOut> = psbt
+                    .inputs
+                    .iter()
+                    .map(|i| i.witness_utxo.clone().unwrap())
+                    .collect();
+                let tx = psbt.extract_tx();
+                let t = templates[&tx.get_ctv_hash(0)];
+                verify_template_spend(t, &tx, &prevouts[..]).unwrap();
+
+                let mut amended = tx.clone();
+                amended.output[0].value -= 1;
+                assert!(matches!(
+                    verify_template_spend(t, &amended, &prevouts[..]),
+                    Err(InterpreterError::TemplateMismatch { .. })
+                ));
+                // even if the template is not checked, the script rejects it
+                assert!(verify_input(&amended, 0, &prevouts[..]).is_err());
+                checked += 1;
+            }
+        }
+        assert_eq!(checked, templates.len());
+    }
+
+    #[test]
+    fn compiled_contract_is_spendable() {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[3u8; 32]).unwrap();
+        let amount = bitcoin::Amount::from_sat(100_000_000);
+        let contract = || Delay {
+            to: Delay {
+                to: Compiled::from_op_return(&b"done"[..]).unwrap(),
+                amount,
+                timeout: 6,
+            },
+            amount,
+            timeout: 4,
+        };
+        let emulators: Vec<Arc<dyn CTVEmulator>> = vec![
+            Arc::new(HDOracleEmulator::new(root, false)),
+            Arc::new(CTVAvailable),
+        ];
+        for emulator in emulators {
+            let compiled = contract()
+                .compile(Context::new(
+                    bitcoin::Network::Regtest,
+                    amount,
+                    emulator.clone(),
+                    EffectPath::try_from("test").unwrap(),
+                    Default::default(),
+                    None,
+                ))
+                .unwrap();
+            check_spendable(&compiled, emulator.as_ref());
+        }
     }
 }
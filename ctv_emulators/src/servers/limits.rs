@@ -0,0 +1,159 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! resource limits for emulator servers run as shared infrastructure
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits applied by a server to every connection it accepts.
+#[derive(Clone, Debug)]
+pub struct ServerLimits {
+    /// the largest request (in bytes of json) that will be read off the wire
+    pub max_request: usize,
+    /// the maximum number of connections open at once across all peers
+    pub max_connections: usize,
+    /// the maximum number of connections open at once from a single IP
+    pub max_connections_per_peer: usize,
+    /// how long a connection may sit without sending a request (or take to
+    /// finish the handshake) before it is closed
+    pub idle_timeout: Duration,
+    /// the maximum number of requests a single connection may make per minute
+    pub max_requests_per_minute: u32,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_request: crate::MAX_JSON_MSG,
+            max_connections: 256,
+            max_connections_per_peer: 8,
+            idle_timeout: Duration::from_secs(60),
+            max_requests_per_minute: 120,
+        }
+    }
+}
+
+/// Shared accounting of open connections, enforcing a `ServerLimits`.
+#[derive(Clone)]
+pub(crate) struct ConnectionTracker {
+    limits: ServerLimits,
+    global: Arc<Semaphore>,
+    per_peer: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Held for the duration of a connection, releases its slots on drop.
+pub(crate) struct ConnectionGuard {
+    _permit: OwnedSemaphorePermit,
+    peer: IpAddr,
+    per_peer: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut m = self.per_peer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = m.get_mut(&self.peer) {
+            *n -= 1;
+            if *n == 0 {
+                m.remove(&self.peer);
+            }
+        }
+    }
+}
+
+impl ConnectionTracker {
+    pub(crate) fn new(limits: ServerLimits) -> Self {
+        ConnectionTracker {
+            global: Arc::new(Semaphore::new(limits.max_connections)),
+            per_peer: Default::default(),
+            limits,
+        }
+    }
+    pub(crate) fn limits(&self) -> &ServerLimits {
+        &self.limits
+    }
+    /// reserve a slot for a connection from `peer`, or None if either the
+    /// global or the per peer limit has been reached.
+    pub(crate) fn admit(&self, peer: IpAddr) -> Option<ConnectionGuard> {
+        let permit = self.global.clone().try_acquire_owned().ok()?;
+        let mut m = self.per_peer.lock().unwrap_or_else(|e| e.into_inner());
+        let n = m.entry(peer).or_insert(0);
+        if *n >= self.limits.max_connections_per_peer {
+            return None;
+        }
+        *n += 1;
+        Some(ConnectionGuard {
+            _permit: permit,
+            peer,
+            per_peer: self.per_peer.clone(),
+        })
+    }
+}
+
+/// Fixed window request counter for a single connection.
+pub(crate) struct RateLimiter {
+    max: u32,
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_per_minute: u32) -> Self {
+        RateLimiter {
+            max: max_per_minute,
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+    /// records a request, returning false if the connection is over its limit.
+    pub(crate) fn check(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(60) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= self.max
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn caps_connections_per_peer() {
+        let tracker = ConnectionTracker::new(ServerLimits {
+            max_connections: 3,
+            max_connections_per_peer: 2,
+            ..Default::default()
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = tracker.admit(a).unwrap();
+        let _second = tracker.admit(a).unwrap();
+        assert!(tracker.admit(a).is_none());
+        // other peers still get in, up to the global limit
+        let _third = tracker.admit(b).unwrap();
+        assert!(tracker.admit(b).is_none());
+        // closing a connection frees its slot
+        drop(first);
+        assert!(tracker.admit(a).is_some());
+    }
+
+    #[test]
+    fn limits_requests_per_window() {
+        let mut limiter = RateLimiter::new(3);
+        assert!((0..3).all(|_| limiter.check()));
+        assert!(!limiter.check());
+        // a new window resets the count
+        limiter.window_start -= Duration::from_secs(61);
+        assert!(limiter.check());
+        assert_eq!(limiter.count, 1);
+    }
+}
@@ -7,4 +7,17 @@
 //! server for an emulator
 
 use super::*;
+pub mod audit;
+pub mod frost;
 pub mod hd;
+pub mod limits;
+pub mod policy;
+
+/// compares two tokens without leaking where they first differ
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -0,0 +1,350 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a registry of approved contracts, restricting what an emulator will sign
+//!
+//! Without a registry, an HD oracle signs for whatever template hash it is
+//! asked about. With a registry, the oracle only signs an input if the
+//! template hash for that input's index is one of the `ctv_to_tx` templates of
+//! a registered `Compiled` (or of any contract nested in its outputs).
+use super::*;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::Script;
+use sapio::contract::Compiled;
+use sapio::template::Template;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Identifier for a registered contract: the hex sha256 of its json encoding.
+pub type ContractId = String;
+
+/// Summary of a registered contract, returned by the admin API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContractSummary {
+    /// the registry id for this contract
+    pub id: ContractId,
+    /// the contract's output script
+    pub script_pubkey: Script,
+    /// the number of templates (including those of nested contracts)
+    /// approved by this contract
+    pub templates: usize,
+}
+
+/// Errors from registering contracts
+#[derive(Debug)]
+pub enum PolicyError {
+    /// a template's precomputed hash does not match its transaction, so it
+    /// has been amended after compilation
+    AmendedTemplate(Sha256),
+    /// the registry could not be loaded or saved
+    Io(std::io::Error),
+}
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for PolicyError {}
+impl From<std::io::Error> for PolicyError {
+    fn from(e: std::io::Error) -> Self {
+        PolicyError::Io(e)
+    }
+}
+impl From<serde_json::Error> for PolicyError {
+    fn from(e: serde_json::Error) -> Self {
+        PolicyError::Io(e.into())
+    }
+}
+
+/// The set of contracts an emulator may sign for, optionally saved to disk
+/// after every change.
+#[derive(Default)]
+pub struct ContractRegistry {
+    contracts: BTreeMap<ContractId, Compiled>,
+    /// template hash to (index, contracts approving it)
+    approved: BTreeMap<Sha256, (u32, Vec<ContractId>)>,
+    path: Option<PathBuf>,
+}
+
+/// Registry shared between all of a server's connections
+pub type SharedRegistry = Arc<std::sync::RwLock<ContractRegistry>>;
+
+impl ContractRegistry {
+    /// opens the registry persisted at `path`, creating an empty one if the
+    /// file does not exist yet.
+    pub fn open(path: PathBuf) -> Result<Self, PolicyError> {
+        let contracts: BTreeMap<ContractId, Compiled> = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?[..])?
+        } else {
+            Default::default()
+        };
+        let mut registry = ContractRegistry {
+            path: Some(path),
+            ..Default::default()
+        };
+        for (id, c) in contracts {
+            registry.insert(id, c)?;
+        }
+        Ok(registry)
+    }
+
+    /// the registry as it should be saved, if it is persisted. Writing the
+    /// snapshot may block, so callers holding a lock on the registry should
+    /// take the snapshot and release the lock before writing it.
+    pub fn snapshot(&self) -> Result<Option<RegistrySnapshot>, PolicyError> {
+        Ok(match &self.path {
+            Some(path) => Some(RegistrySnapshot {
+                path: path.clone(),
+                contents: serde_json::to_vec(&self.contracts)?,
+            }),
+            None => None,
+        })
+    }
+
+    fn save(&self) -> Result<(), PolicyError> {
+        match self.snapshot()? {
+            Some(snapshot) => snapshot.write(),
+            None => Ok(()),
+        }
+    }
+
+    /// collects every CTV template of a contract and the contracts it pays to
+    fn templates(c: &Compiled) -> Vec<&Template> {
+        let mut stack = vec![c];
+        let mut templates = vec![];
+        while let Some(c) = stack.pop() {
+            for t in c.ctv_to_tx.values() {
+                stack.extend(t.outputs.iter().map(|o| &o.contract));
+                templates.push(t);
+            }
+        }
+        templates
+    }
+
+    fn insert(&mut self, id: ContractId, c: Compiled) -> Result<ContractSummary, PolicyError> {
+        let templates = Self::templates(&c);
+        for t in &templates {
+            if t.tx.get_ctv_hash(t.ctv_index) != t.ctv {
+                return Err(PolicyError::AmendedTemplate(t.ctv));
+            }
+        }
+        let summary = ContractSummary {
+            id: id.clone(),
+            script_pubkey: (&c.address).into(),
+            templates: templates.len(),
+        };
+        if !self.contracts.contains_key(&id) {
+            for t in templates {
+                let e = self
+                    .approved
+                    .entry(t.ctv)
+                    .or_insert_with(|| (t.ctv_index, vec![]));
+                e.1.push(id.clone());
+            }
+            self.contracts.insert(id, c);
+        }
+        Ok(summary)
+    }
+
+    /// approve every template of `c`, returning a summary of it.
+    ///
+    /// Fails if any template has been amended after compilation.
+    pub fn register(&mut self, c: Compiled) -> Result<ContractSummary, PolicyError> {
+        let summary = self.register_unsaved(c)?;
+        self.save()?;
+        Ok(summary)
+    }
+
+    fn register_unsaved(&mut self, c: Compiled) -> Result<ContractSummary, PolicyError> {
+        let id = Sha256::hash(&serde_json::to_vec(&c)?[..]).to_hex();
+        self.insert(id, c)
+    }
+
+    /// summarize every registered contract
+    pub fn list(&self) -> Vec<ContractSummary> {
+        self.contracts
+            .iter()
+            .map(|(id, c)| ContractSummary {
+                id: id.clone(),
+                script_pubkey: (&c.address).into(),
+                templates: Self::templates(c).len(),
+            })
+            .collect()
+    }
+
+    /// remove a contract, returning whether it had been registered. Templates
+    /// shared with other registered contracts remain approved.
+    pub fn revoke(&mut self, id: &str) -> Result<bool, PolicyError> {
+        if !self.revoke_unsaved(id) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn revoke_unsaved(&mut self, id: &str) -> bool {
+        if self.contracts.remove(id).is_none() {
+            return false;
+        }
+        self.approved.retain(|_, (_, ids)| {
+            ids.retain(|i| i != id);
+            !ids.is_empty()
+        });
+        true
+    }
+
+    /// is the template hash `h`, computed at input `idx`, approved?
+    pub fn approves(&self, h: &Sha256, idx: usize) -> bool {
+        matches!(self.approved.get(h), Some((i, _)) if *i as usize == idx)
+    }
+}
+
+/// Commands accepted by the admin API of a policy restricted server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AdminCommand {
+    /// approve all templates of a contract
+    Register(Box<Compiled>),
+    /// list all registered contracts
+    List,
+    /// revoke a contract by id
+    Revoke(ContractId),
+}
+
+/// Responses from the admin API
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AdminResponse {
+    /// the contract was registered
+    Registered(ContractSummary),
+    /// all registered contracts
+    Contracts(Vec<ContractSummary>),
+    /// whether a contract was found and revoked
+    Revoked(bool),
+    /// the command failed
+    Error(String),
+}
+
+/// A copy of a registry's contents to be written to its file
+pub struct RegistrySnapshot {
+    path: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl RegistrySnapshot {
+    /// atomically replace the registry file with this snapshot
+    pub fn write(self) -> Result<(), PolicyError> {
+        let mut tmp = self.path.clone();
+        tmp.set_extension("tmp");
+        std::fs::write(&tmp, &self.contents[..])?;
+        std::fs::rename(tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl ContractRegistry {
+    /// run an admin command against the registry, without saving it.
+    ///
+    /// If the command changed the registry, also returns the snapshot which
+    /// must be written for the change to persist.
+    pub fn admin(&mut self, command: AdminCommand) -> (AdminResponse, Option<RegistrySnapshot>) {
+        let r = match command {
+            AdminCommand::Register(c) => self
+                .register_unsaved(*c)
+                .map(|s| (AdminResponse::Registered(s), true)),
+            AdminCommand::List => Ok((AdminResponse::Contracts(self.list()), false)),
+            AdminCommand::Revoke(id) => {
+                let revoked = self.revoke_unsaved(&id);
+                Ok((AdminResponse::Revoked(revoked), revoked))
+            }
+        };
+        let r = r.and_then(|(response, changed)| {
+            Ok((response, if changed { self.snapshot()? } else { None }))
+        });
+        r.unwrap_or_else(|e| (AdminResponse::Error(e.to_string()), None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{Amount, Transaction, TxOut};
+    use sapio::template::TemplateMetadata;
+
+    fn template(value: u64) -> Template {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        };
+        Template {
+            guards: vec![],
+            ctv: tx.get_ctv_hash(0),
+            ctv_index: 0,
+            max: Amount::from_sat(value),
+            min_feerate_sats_vbyte: None,
+            metadata_map_s2s: TemplateMetadata::new(),
+            tx,
+            outputs: vec![],
+            inputs: vec![],
+        }
+    }
+
+    fn contract(t: Template) -> Compiled {
+        let mut c = Compiled::from_op_return(&b"policy"[..]).unwrap();
+        c.ctv_to_tx.insert(t.ctv, t);
+        c
+    }
+
+    #[test]
+    fn register_and_revoke() {
+        let mut registry = ContractRegistry::default();
+        let t = template(1000);
+        let h = t.ctv;
+        let summary = registry.register(contract(t.clone())).unwrap();
+        assert_eq!(summary.templates, 1);
+        assert!(registry.approves(&h, 0));
+        // the template hash is only valid at the index it was computed for
+        assert!(!registry.approves(&h, 1));
+        assert!(!registry.approves(&template(999).ctv, 0));
+
+        let mut amended = t;
+        amended.tx.output[0].value = 2000;
+        assert!(matches!(
+            registry.register(contract(amended)),
+            Err(PolicyError::AmendedTemplate(_))
+        ));
+
+        assert_eq!(registry.list(), vec![summary.clone()]);
+        assert!(registry.revoke(&summary.id).unwrap());
+        assert!(!registry.approves(&h, 0));
+        assert!(!registry.revoke(&summary.id).unwrap());
+    }
+
+    #[test]
+    fn admin_changes_persist_once_written() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("sapio-registry-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut registry = ContractRegistry::open(path.clone()).unwrap();
+        let t = template(1000);
+        let (response, snapshot) =
+            registry.admin(AdminCommand::Register(Box::new(contract(t.clone()))));
+        assert!(matches!(response, AdminResponse::Registered(_)));
+        // nothing is written until the snapshot is
+        assert!(!path.exists());
+        snapshot.unwrap().write().unwrap();
+        assert!(ContractRegistry::open(path.clone())
+            .unwrap()
+            .approves(&t.ctv, 0));
+        // listing doesn't change anything to save
+        assert!(registry.admin(AdminCommand::List).1.is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! authenticated & encrypted transport between emulator clients and servers
+//!
+//! The handshake is the Noise `NK` pattern instantiated over secp256k1 with
+//! ChaCha20-Poly1305 and SHA256 (in the spirit of BOLT-8). The client must know
+//! the server's static key ahead of time -- for an HD oracle this is the public
+//! key of the root `ExtendedPubKey` -- so a server which cannot prove that it
+//! holds the matching private key fails the handshake and is never sent a PSBT.
+//!
+//! ```text
+//! <- s
+//! ...
+//! -> e, es
+//! <- e, ee
+//! ```
+//!
+//! After the handshake every message is sent as `length:u32 ciphertext:[u8;length]`
+//! where the length is checked against a maximum before any allocation.
+use super::*;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, HashEngine};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand::RngCore;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NK_secp256k1_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"sapio-ctv-emulator-v1";
+/// size of a Poly1305 authentication tag
+const TAG_LEN: usize = 16;
+/// size of a compressed secp256k1 point
+const KEY_LEN: usize = 33;
+/// both handshake messages are an ephemeral key and an encrypted empty payload
+const HANDSHAKE_LEN: usize = KEY_LEN + TAG_LEN;
+
+/// A key and a nonce counter. Nonces are never reused because the channel is
+/// torn down before the counter could wrap.
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    n: u64,
+}
+
+impl CipherState {
+    fn new(k: [u8; 32]) -> Self {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&k[..])),
+            n: 0,
+        }
+    }
+    fn nonce(&mut self) -> Result<Nonce, std::io::Error> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        self.n = self
+            .n
+            .checked_add(1)
+            .ok_or_else(|| input_err("Nonce Exhausted"))?;
+        Ok(*Nonce::from_slice(&nonce[..]))
+    }
+    fn encrypt(&mut self, ad: &[u8], msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = self.nonce()?;
+        self.cipher
+            .encrypt(&nonce, Payload { msg, aad: ad })
+            .map_err(|_| input_err("Encryption Failed"))
+    }
+    fn decrypt(&mut self, ad: &[u8], msg: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = self.nonce()?;
+        self.cipher
+            .decrypt(&nonce, Payload { msg, aad: ad })
+            .map_err(|_| auth_err())
+    }
+}
+
+fn auth_err() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "Emulator Channel Authentication Failed",
+    )
+}
+
+/// HMAC-SHA256 based HKDF as specified by Noise, returning two outputs
+fn hkdf(ck: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let hmac = |k: &[u8], parts: &[&[u8]]| -> [u8; 32] {
+        let mut engine = HmacEngine::<sha256::Hash>::new(k);
+        for p in parts {
+            engine.input(p);
+        }
+        Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+    };
+    let temp = hmac(&ck[..], &[ikm]);
+    let out1 = hmac(&temp[..], &[&[1u8]]);
+    let out2 = hmac(&temp[..], &[&out1[..], &[2u8]]);
+    (out1, out2)
+}
+
+/// Handshake transcript state (chaining key, handshake hash, and the current
+/// handshake cipher, if a key has been mixed in yet).
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new(rs: &PublicKey) -> Self {
+        let h = Sha256::hash(PROTOCOL_NAME).into_inner();
+        let mut s = SymmetricState { ck: h, h, k: None };
+        s.mix_hash(PROLOGUE);
+        s.mix_hash(&rs.serialize()[..]);
+        s
+    }
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut engine = Sha256::engine();
+        engine.input(&self.h[..]);
+        engine.input(data);
+        self.h = Sha256::from_engine(engine).into_inner();
+    }
+    fn mix_key(&mut self, pk: &PublicKey, sk: &SecretKey) {
+        let dh = SharedSecret::new(pk, sk);
+        let (ck, k) = hkdf(&self.ck, &dh.secret_bytes()[..]);
+        self.ck = ck;
+        self.k = Some(CipherState::new(k));
+    }
+    /// encrypts an empty payload, binding the transcript so far
+    fn encrypt_and_hash(&mut self) -> Result<Vec<u8>, std::io::Error> {
+        let h = self.h;
+        let c = self
+            .k
+            .as_mut()
+            .ok_or_else(|| input_err("No Handshake Key"))?
+            .encrypt(&h[..], &[])?;
+        self.mix_hash(&c[..]);
+        Ok(c)
+    }
+    /// decrypts an empty payload, failing if the transcripts differ
+    fn decrypt_and_hash(&mut self, c: &[u8]) -> Result<(), std::io::Error> {
+        let h = self.h;
+        self.k
+            .as_mut()
+            .ok_or_else(|| input_err("No Handshake Key"))?
+            .decrypt(&h[..], c)?;
+        self.mix_hash(c);
+        Ok(())
+    }
+    /// returns the (initiator -> responder, responder -> initiator) ciphers
+    fn split(&self) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        (CipherState::new(k1), CipherState::new(k2))
+    }
+}
+
+fn ephemeral(secp: &Secp256k1<All>) -> (SecretKey, PublicKey) {
+    let mut rng = rand::thread_rng();
+    loop {
+        let mut b = [0u8; 32];
+        rng.fill_bytes(&mut b);
+        if let Ok(sk) = SecretKey::from_slice(&b[..]) {
+            return (sk, PublicKey::from_secret_key(secp, &sk));
+        }
+    }
+}
+
+fn split_handshake(m: &[u8; HANDSHAKE_LEN]) -> Result<(PublicKey, &[u8]), std::io::Error> {
+    let e = PublicKey::from_slice(&m[..KEY_LEN]).map_err(|_| input_err("Invalid Ephemeral Key"))?;
+    Ok((e, &m[KEY_LEN..]))
+}
+
+/// An established, encrypted & authenticated connection.
+pub struct Channel {
+    stream: TcpStream,
+    send: CipherState,
+    recv: CipherState,
+}
+
+impl Channel {
+    /// Runs the initiator side of the handshake, authenticating the remote as
+    /// the holder of the secret key for `server`.
+    ///
+    /// Fails with `PermissionDenied` if the remote is not `server`.
+    pub async fn connect(
+        mut stream: TcpStream,
+        server: &PublicKey,
+    ) -> Result<Self, std::io::Error> {
+        let mut state = SymmetricState::new(server);
+        let (e, e_pub) = SECP.with(ephemeral);
+        // -> e, es
+        state.mix_hash(&e_pub.serialize()[..]);
+        state.mix_key(server, &e);
+        let mut m1 = e_pub.serialize().to_vec();
+        m1.extend(state.encrypt_and_hash()?);
+        stream.write_all(&m1[..]).await?;
+        stream.flush().await?;
+        // <- e, ee
+        let mut m2 = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut m2[..]).await?;
+        let (re, c) = split_handshake(&m2)?;
+        state.mix_hash(&re.serialize()[..]);
+        state.mix_key(&re, &e);
+        state.decrypt_and_hash(c)?;
+        let (send, recv) = state.split();
+        Ok(Channel { stream, send, recv })
+    }
+
+    /// Runs the responder side of the handshake with the server's static key.
+    pub async fn accept(mut stream: TcpStream, key: &SecretKey) -> Result<Self, std::io::Error> {
+        let s = SECP.with(|secp| PublicKey::from_secret_key(secp, key));
+        let mut state = SymmetricState::new(&s);
+        // -> e, es
+        let mut m1 = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut m1[..]).await?;
+        let (re, c) = split_handshake(&m1)?;
+        state.mix_hash(&re.serialize()[..]);
+        state.mix_key(&re, key);
+        state.decrypt_and_hash(c)?;
+        // <- e, ee
+        let (e, e_pub) = SECP.with(ephemeral);
+        state.mix_hash(&e_pub.serialize()[..]);
+        state.mix_key(&re, &e);
+        let mut m2 = e_pub.serialize().to_vec();
+        m2.extend(state.encrypt_and_hash()?);
+        stream.write_all(&m2[..]).await?;
+        stream.flush().await?;
+        let (recv, send) = state.split();
+        Ok(Channel { stream, send, recv })
+    }
+
+    /// the underlying socket, e.g. for waiting on readability
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// encrypt and send a json serializable message
+    pub async fn send<T: Serialize>(&mut self, r: &T) -> Result<(), std::io::Error> {
+        let v = self.send.encrypt(&[], &serde_json::to_vec(r)?[..])?;
+        self.stream.write_u32(v.len() as u32).await?;
+        self.stream.write_all(&v[..]).await?;
+        self.stream.flush().await
+    }
+
+    /// receive and decrypt a json message, refusing messages whose plaintext
+    /// would be larger than `max` before allocating for them.
+    pub async fn recv<T: DeserializeOwned>(&mut self, max: usize) -> Result<T, std::io::Error> {
+        let l = self.stream.read_u32().await? as usize;
+        if l > max + TAG_LEN {
+            return input_error("Message Exceeded Maximum Length");
+        }
+        let mut v = vec![0u8; l];
+        self.stream.read_exact(&mut v[..]).await?;
+        let m = self.recv.decrypt(&[], &v[..])?;
+        Ok(serde_json::from_slice(&m[..])?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[tokio::test]
+    async fn handshake_pins_server_key() {
+        let secp = Secp256k1::new();
+        let (server_key, server_pub) = ephemeral(&secp);
+        let (_, wrong_pub) = ephemeral(&secp);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut c = Channel::accept(s, &server_key).await.unwrap();
+            let m: String = c.recv(100).await.unwrap();
+            c.send(&m).await.unwrap();
+            let (s, _) = listener.accept().await.unwrap();
+            assert!(Channel::accept(s, &server_key).await.is_err());
+        });
+        let s = TcpStream::connect(addr).await.unwrap();
+        let mut c = Channel::connect(s, &server_pub).await.unwrap();
+        c.send(&"hello").await.unwrap();
+        assert_eq!(c.recv::<String>(100).await.unwrap(), "hello");
+        let s = TcpStream::connect(addr).await.unwrap();
+        let err = Channel::connect(s, &wrong_pub).await.err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::UnexpectedEof
+        ));
+        server.await.unwrap();
+    }
+}
@@ -152,4 +152,4 @@ impl From<Versions> for TreePay {
         }
     }
 }
-REGISTER![[TreePay, Versions], "logo.png"];
+REGISTER![[TreePay, Versions], "logo.png"; traits = [BatchingTraitVersion0_1_1]];
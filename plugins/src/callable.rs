@@ -0,0 +1,26 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! types which can be called as a plugin, by a WASM client or natively by a
+//! host
+use sapio::contract::{Compilable, CompilationError, Compiled, Context};
+
+/// Represents any type which can be treated as a module
+pub trait Callable {
+    /// The result type to be produced
+    type Output;
+    /// Call the function
+    fn call(&self, ctx: Context) -> Result<Self::Output, CompilationError>;
+}
+impl<T> Callable for T
+where
+    T: Compilable,
+{
+    type Output = Compiled;
+    fn call(&self, ctx: Context) -> Result<Compiled, CompilationError> {
+        self.compile(ctx)
+    }
+}
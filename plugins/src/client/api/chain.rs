@@ -0,0 +1,72 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Read-only chain data and oracle prices from the host.
+//!
+//! The host records every answer in the compiled contract's metadata, and
+//! may answer from a previous recording, so contracts using these still
+//! compile reproducibly.
+use super::*;
+use crate::host_query::HostAnswer;
+use bitcoin::{OutPoint, TxOut};
+use sapio::contract::CompilationError;
+
+/// read the host's answer to a query
+fn read_answer(p: i32) -> Result<HostAnswer, CompilationError> {
+    if p == 0 {
+        return Err(CompilationError::InternalModuleError(
+            "Host Query Failed".into(),
+        ));
+    }
+    let cs = unsafe { CString::from_raw(p as *mut c_char) };
+    let answer: Result<HostAnswer, String> =
+        serde_json::from_slice(cs.as_bytes()).map_err(CompilationError::DeserializationError)?;
+    answer.map_err(CompilationError::InternalModuleError)
+}
+
+fn unexpected(answer: HostAnswer) -> CompilationError {
+    CompilationError::InternalModuleError(format!("Unexpected Host Answer: {:?}", answer))
+}
+
+/// Get the height of the host's chain tip
+pub fn tip_height() -> Result<u32, CompilationError> {
+    match read_answer(unsafe { sapio_v1_wasm_plugin_chain_tip_height() })? {
+        HostAnswer::TipHeight(h) => Ok(h),
+        a => Err(unexpected(a)),
+    }
+}
+
+/// Look up an output in the host's transaction index, e.g. to get its value
+pub fn lookup_output(out: &OutPoint) -> Result<TxOut, CompilationError> {
+    let s = serde_json::to_string(out).map_err(CompilationError::SerializationError)?;
+    let p = unsafe { sapio_v1_wasm_plugin_chain_lookup_output(s.as_ptr() as i32, s.len() as i32) };
+    match read_answer(p)? {
+        HostAnswer::Output(o) => Ok(o),
+        a => Err(unexpected(a)),
+    }
+}
+
+/// Get the price of `symbol` from the host's oracle named `oracle`, as the
+/// oracle reports it
+pub fn oracle_price<T>(oracle: &str, symbol: &str) -> Result<T, CompilationError>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    let p = unsafe {
+        sapio_v1_wasm_plugin_oracle_price(
+            oracle.as_ptr() as i32,
+            oracle.len() as i32,
+            symbol.as_ptr() as i32,
+            symbol.len() as i32,
+        )
+    };
+    match read_answer(p)? {
+        HostAnswer::OraclePrice(v) => {
+            serde_json::from_value(v).map_err(CompilationError::DeserializationError)
+        }
+        a => Err(unexpected(a)),
+    }
+}
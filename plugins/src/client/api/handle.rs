@@ -12,14 +12,53 @@ use core::convert::TryFrom;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 use sapio_base::Clause;
-use sapio_trait::SapioJSONTrait;
+use sapio_trait::{CompiledSchema, SapioJSONTrait};
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// The schema of an output type, generated the first time it is needed
+fn output_schema<R: JsonSchema + 'static>() -> Result<Arc<CompiledSchema>, String> {
+    static SCHEMAS: OnceLock<Mutex<HashMap<TypeId, Arc<CompiledSchema>>>> = OnceLock::new();
+    let mut schemas = SCHEMAS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(schema) = schemas.get(&TypeId::of::<R>()) {
+        return Ok(schema.clone());
+    }
+    let schema = serde_json::to_value(schemars::schema_for!(R)).map_err(|e| e.to_string())?;
+    let schema = Arc::new(CompiledSchema::new(schema)?);
+    schemas.insert(TypeId::of::<R>(), schema.clone());
+    Ok(schema)
+}
+
+/// Check that the output schema a module declares is the schema of `R`, e.g.
+/// so that a module returning a Clause can't be used as a ContractModule.
+fn check_output_schema<R: JsonSchema + 'static>(
+    declared: &schemars::schema::RootSchema,
+) -> Result<(), String> {
+    let expected = output_schema::<R>()?;
+    let expected = expected.schema();
+    let declared = serde_json::to_value(declared).map_err(|e| e.to_string())?;
+    if &declared != expected {
+        return Err(format!(
+            "Module returns {}, expected {}",
+            declared["title"], expected["title"]
+        ));
+    }
+    Ok(())
+}
 
 /// A Type which represents a validated module the host can resolve and execute
 /// with a given API
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 #[serde(try_from = "SapioHostAPIVerifier<T, R>")]
-pub struct SapioHostAPI<T: SapioJSONTrait + Clone, R: for<'a> Deserialize<'a> + JsonSchema> {
+pub struct SapioHostAPI<
+    T: SapioJSONTrait + Clone,
+    R: for<'a> Deserialize<'a> + JsonSchema + 'static,
+> {
     /// The module's locator
     pub which_plugin: LookupFrom,
     /// when resolved, the hash of the module
@@ -36,7 +75,7 @@ pub type ClauseModule<T> = SapioHostAPI<T, Clause>;
 
 impl<T: SapioJSONTrait + Clone, R> PluginHandle for SapioHostAPI<T, R>
 where
-    R: for<'a> Deserialize<'a> + JsonSchema,
+    R: for<'a> Deserialize<'a> + JsonSchema + 'static,
 {
     type Input = CreateArgs<T>;
     type Output = R;
@@ -45,7 +84,11 @@ where
         path: &EffectPath,
         c: &Self::Input,
     ) -> Result<Self::Output, CompilationError> {
-        call_path(path, &self.key, c.clone())
+        let v: serde_json::Value = call_path(path, &self.key, c.clone())?;
+        output_schema::<R>()
+            .and_then(|schema| schema.validate(&v))
+            .map_err(CompilationError::ModuleFailedOutputCheck)?;
+        serde_json::from_value(v).map_err(CompilationError::DeserializationError)
     }
     fn get_api(&mut self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
         get_api(&self.key)
@@ -84,7 +127,7 @@ struct SapioHostAPIVerifier<T: SapioJSONTrait + Clone, R: for<'a> Deserialize<'a
 
 impl<T, R> TryFrom<LookupFrom> for SapioHostAPI<T, R>
 where
-    R: JsonSchema + for<'a> Deserialize<'a>,
+    R: JsonSchema + for<'a> Deserialize<'a> + 'static,
     T: SapioJSONTrait + Clone,
 {
     type Error = CompilationError;
@@ -97,7 +140,7 @@ where
 }
 impl<T, R> TryFrom<SapioHostAPIVerifier<T, R>> for SapioHostAPI<T, R>
 where
-    R: schemars::JsonSchema + for<'a> Deserialize<'a>,
+    R: schemars::JsonSchema + for<'a> Deserialize<'a> + 'static,
     T: SapioJSONTrait + Clone,
 {
     type Error = CompilationError;
@@ -120,6 +163,35 @@ where
             &serde_json::to_value(api.input()).map_err(CompilationError::SerializationError)?,
         )
         .map_err(CompilationError::ModuleFailedAPICheck)?;
+        // Modules declare the schema of what they return, so check that it is
+        // the schema of the type expected
+        check_output_schema::<R>(api.output())
+            .map_err(CompilationError::ModuleFailedOutputCheck)?;
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_schema_check() {
+        assert!(check_output_schema::<Compiled>(&schemars::schema_for!(Compiled)).is_ok());
+        assert!(check_output_schema::<Compiled>(&schemars::schema_for!(Clause)).is_err());
+        // a schema with the right title but a different shape
+        let mut renamed = schemars::schema_for!(Clause);
+        renamed.schema.metadata().title = Some("Compiled".into());
+        assert!(check_output_schema::<Compiled>(&renamed).is_err());
+    }
+
+    #[test]
+    fn output_schema_is_generated_once() {
+        let first = output_schema::<Compiled>().unwrap();
+        assert!(Arc::ptr_eq(&first, &output_schema::<Compiled>().unwrap()));
+        assert!(!Arc::ptr_eq(&first, &output_schema::<Clause>().unwrap()));
+        assert!(first
+            .validate(&serde_json::json!({"not": "compiled"}))
+            .is_err());
+    }
+}
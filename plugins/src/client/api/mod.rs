@@ -7,11 +7,13 @@
 //! Wraps the external API with friendly methods
 use super::*;
 
+pub mod chain;
 pub mod emulator;
 pub mod handle;
 pub mod lookup;
 pub mod util;
 
+pub use chain::*;
 pub use emulator::*;
 pub use handle::*;
 pub use lookup::*;
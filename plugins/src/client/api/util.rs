@@ -7,16 +7,32 @@
 //! Various utils for working with modules
 use super::*;
 
+use crate::plugin_log::{LogLevel, LogRecord};
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 
-/// Print a &str to the parent's console.
+/// Log a &str with the host, at debug level.
 pub fn log(s: &str) {
     unsafe {
         sapio_v1_wasm_plugin_debug_log_string(s.as_ptr() as i32, s.len() as i32);
     }
 }
 
+/// Send a structured log record to the host, e.g.
+/// `log_record(&LogRecord::new(LogLevel::Info, "treepay", "splitting").field("n", 10))`
+pub fn log_record(record: &LogRecord) {
+    if let Ok(s) = serde_json::to_string(record) {
+        unsafe {
+            sapio_v1_wasm_plugin_log(s.as_ptr() as i32, s.len() as i32);
+        }
+    }
+}
+
+/// Log a message about the contract at `path` with the host
+pub fn log_at(level: LogLevel, target: &str, path: &EffectPath, message: &str) {
+    log_record(&LogRecord::new(level, target, message).at(path))
+}
+
 /// Given a 32 byte plugin identifier, create a new contract instance.
 pub fn call_path<S: Serialize, T>(
     path: &EffectPath,
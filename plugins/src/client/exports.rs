@@ -6,6 +6,7 @@
 
 //! Functions that are made visible to the host to call inside the WASM module.
 use super::*;
+use crate::manifest::PluginManifest;
 
 /// a stub to make the compiler happy
 fn sapio_v1_wasm_plugin_client_get_create_arguments_nullptr() -> *mut c_char {
@@ -25,6 +26,23 @@ unsafe fn sapio_v1_wasm_plugin_client_create_nullptr(
 pub(crate) static mut SAPIO_V1_WASM_PLUGIN_CLIENT_GET_CREATE_ARGUMENTS_PTR: fn() -> *mut c_char =
     sapio_v1_wasm_plugin_client_get_create_arguments_nullptr;
 
+/// a stub for plugins registered without a manifest
+fn sapio_v1_wasm_plugin_client_get_manifest_nullptr() -> Result<PluginManifest, String> {
+    Ok(PluginManifest::from_package(
+        unsafe { SAPIO_PLUGIN_NAME },
+        "0.0.0",
+        "",
+        "",
+    ))
+}
+
+/// a static mut that gets set when a Plugin::register method gets called
+/// in order to enable binding when the type is registered
+pub(crate) static mut SAPIO_V1_WASM_PLUGIN_CLIENT_GET_MANIFEST_PTR: fn() -> Result<
+    PluginManifest,
+    String,
+> = sapio_v1_wasm_plugin_client_get_manifest_nullptr;
+
 /// a static mut that gets set when a Plugin::register method gets called
 /// in order to enable binding when the type is registered
 pub(crate) static mut SAPIO_V1_WASM_PLUGIN_CLIENT_CREATE_PTR: unsafe fn(
@@ -74,6 +92,15 @@ unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_name() -> *mut c_char {
         .into_raw()
 }
 
+/// Gets the plugin's manifest, or why it could not be made, as JSON.
+/// host must drop the returned pointer.
+#[no_mangle]
+unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_manifest() -> *mut c_char {
+    CString::new(serde_json::to_string(&SAPIO_V1_WASM_PLUGIN_CLIENT_GET_MANIFEST_PTR()).unwrap())
+        .unwrap()
+        .into_raw()
+}
+
 pub(crate) static mut SAPIO_PLUGIN_LOGO: &[u8] = include_bytes!("logo.png");
 /// Gets a name for the plugin.
 /// host must drop the returned pointer.
@@ -13,6 +13,20 @@ extern "C" {
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(hash: i32) -> i32;
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(a: i32, len: i32);
+    /// log a structured record, passed as JSON. The host may make this a no-op.
+    pub fn sapio_v1_wasm_plugin_log(record: i32, len: i32);
+    /// get the height of the host's chain tip
+    pub fn sapio_v1_wasm_plugin_chain_tip_height() -> i32;
+    /// look up an output, passed as a JSON outpoint, in the host's
+    /// transaction index
+    pub fn sapio_v1_wasm_plugin_chain_lookup_output(outpoint: i32, len: i32) -> i32;
+    /// get the price of a symbol from the host's oracle with this name
+    pub fn sapio_v1_wasm_plugin_oracle_price(
+        oracle: i32,
+        oracle_len: i32,
+        symbol: i32,
+        symbol_len: i32,
+    ) -> i32;
     /// Create an instance of a contract by "trampolining" through the host to use another
     /// plugin identified by key.
     pub fn sapio_v1_wasm_plugin_create_contract(
@@ -10,7 +10,10 @@ use super::*;
 pub use api::*;
 
 use ext::*;
-use sapio::contract::Compiled;
+use sapio::contract::{Compiled, Context};
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::sync::Arc;
 
 pub mod api;
 mod exports;
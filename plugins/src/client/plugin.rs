@@ -6,6 +6,7 @@
 
 //! binding for making a type into a plugin
 use super::*;
+use crate::manifest::PluginManifest;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
@@ -13,22 +14,7 @@ use sapio_base::serialization_helpers::SArc;
 
 use std::convert::TryFrom;
 
-/// Represents any type which can be treated as a module
-pub trait Callable {
-    /// The result type to be produced
-    type Output;
-    /// Call the function
-    fn call(&self, ctx: Context) -> Result<Self::Output, CompilationError>;
-}
-impl<T> Callable for T
-where
-    T: Compilable,
-{
-    type Output = Compiled;
-    fn call(&self, ctx: Context) -> Result<Compiled, CompilationError> {
-        self.compile(ctx)
-    }
-}
+pub use crate::callable::Callable;
 
 /// The `Plugin` trait is used to provide bindings for a WASM Plugin.
 /// It's not intended to be used internally, just as bindings.
@@ -73,7 +59,7 @@ where
                     network,
                     amount,
                     effects,
-                    ordinals_info
+                    ordinals_info,
                 },
         } = serde_json::from_slice(s.to_bytes()).map_err(CompilationError::DeserializationError)?;
         // TODO: In theory, these trampoline bounds are robust/serialization safe...
@@ -106,15 +92,28 @@ where
             path,
             // TODO: load database?
             Arc::new(effects),
-            ordinals_info
+            ordinals_info,
         );
         let converted = Self::try_from(arguments)?;
         converted.call(ctx)
     }
     /// binds this type to the wasm interface, must be called before the plugin can be used.
-    unsafe fn register(name: &'static str, logo: Option<&'static [u8]>) {
+    ///
+    /// # Safety
+    ///
+    /// Writes the plugin's `static mut` bindings, so it must only be called
+    /// from the plugin's entry point, before the host calls any other export
+    /// and while nothing else reads them. WASM plugins are single threaded,
+    /// so this holds when called once from `sapio_v1_wasm_plugin_entry_point`
+    /// as [`crate::REGISTER`] does.
+    unsafe fn register(
+        name: &'static str,
+        logo: Option<&'static [u8]>,
+        manifest: fn() -> Result<PluginManifest, String>,
+    ) {
         SAPIO_V1_WASM_PLUGIN_CLIENT_GET_CREATE_ARGUMENTS_PTR = Self::get_api_inner;
         SAPIO_V1_WASM_PLUGIN_CLIENT_CREATE_PTR = Self::create;
+        SAPIO_V1_WASM_PLUGIN_CLIENT_GET_MANIFEST_PTR = manifest;
         SAPIO_PLUGIN_NAME = name;
         if let Some(logo) = logo {
             SAPIO_PLUGIN_LOGO = logo;
@@ -134,16 +133,31 @@ fn encode_json<S: Serialize>(s: &S) -> *mut c_char {
 /// A helper macro to implement the plugin interface for a plugin-type
 /// and register it to the plugin entry point.
 ///
+/// The plugin's [`PluginManifest`] is made from the plugin crate's package
+/// metadata, plus optionally the trait versions it implements and the plugins
+/// it depends on:
+///
+/// ```ignore
+/// REGISTER![[TreePay, Versions], "logo.png";
+///     traits = [BatchingTraitVersion0_1_1];
+///     dependencies = {"batching" => "^0.1"}];
+/// ```
+///
+/// The plugin's name, as returned to the host and used in its manifest, is
+/// the crate's name. An invalid dependency requirement is reported to the
+/// host as an error getting the manifest.
+///
 /// U.B. to call REGISTER more than once because of the internal #[no_mangle]
 #[macro_export]
 macro_rules! REGISTER {
-    [$plugin:ident$(, $logo:expr)?] => {
-        REGISTER![[$plugin, $plugin]$(, $logo)*];
+    [$plugin:ident $($rest:tt)*] => {
+        REGISTER![[$plugin, $plugin] $($rest)*];
     };
-    [[$to:ident,$wrapper:ident]$(, $logo:expr)?] => {
+    [[$to:ident,$wrapper:ident]$(, $logo:expr)?$(; traits = [$($trait:ty),*$(,)?])?$(; dependencies = {$($dep:literal => $req:literal),*$(,)?})?] => {
         const _ : () = {
             use sapio_wasm_plugin::client::Plugin;
             use sapio_wasm_plugin::client::plugin::Callable;
+            use sapio_wasm_plugin::manifest::PluginManifest;
             use schemars::JsonSchema;
             use serde::*;
             use core::convert::TryFrom;
@@ -170,9 +184,24 @@ macro_rules! REGISTER {
             impl Plugin for SapioInternalWrapperAroundCallable {
                 type InputWrapper = SapioInternalWrapperAroundInput;
             }
+            fn sapio_internal_manifest() -> Result<PluginManifest, String> {
+                let mut manifest = PluginManifest::from_package(
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION"),
+                    env!("CARGO_PKG_DESCRIPTION"),
+                    env!("CARGO_PKG_AUTHORS"),
+                );
+                $($(manifest.traits.push(<$trait as JsonSchema>::schema_name());)*)?
+                $($(manifest.depend_on($dep, $req)?;)*)?
+                Ok(manifest)
+            }
             #[no_mangle]
             unsafe fn sapio_v1_wasm_plugin_entry_point() {
-                SapioInternalWrapperAroundCallable::register(stringify!($to), optional_logo!($($logo)*));
+                SapioInternalWrapperAroundCallable::register(
+                    env!("CARGO_PKG_NAME"),
+                    optional_logo!($($logo)*),
+                    sapio_internal_manifest,
+                );
             }
         };
     };
@@ -0,0 +1,167 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! fuel metering and memory limits for running plugins.
+//!
+//! Every module compiled by a store from [`PluginLimits::store`] is
+//! instrumented by wasmer's [`Metering`] middleware to burn one unit of fuel
+//! per instruction. The remaining fuel lives in an exported global so that
+//! the host can refill it per invocation and share it with nested plugin
+//! calls.
+//!
+//! The same stores also export each module's mutable globals and tables so
+//! that pooled instances can be reset.
+use crate::host::pool::ExportInstanceState;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::ptr::NonNull;
+use std::sync::Arc;
+use wasmer::sys::{BaseTunables, EngineBuilder};
+use wasmer::vm::{
+    MemoryStyle, TableStyle, VMMemory, VMMemoryDefinition, VMTable, VMTableDefinition,
+};
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    CompilerConfig, Cranelift, MemoryError, MemoryType, Pages, Store, TableType, Target, Tunables,
+};
+use wasmer_middlewares::Metering;
+
+/// the exported global holding the fuel a module has left
+pub const REMAINING_FUEL_EXPORT: &str = "wasmer_metering_remaining_points";
+
+/// Default fuel for one plugin invocation (roughly one per wasm instruction)
+pub const DEFAULT_FUEL: u64 = 10_000_000_000;
+/// Default maximum memory for a plugin instance, in 64KiB pages (1GiB)
+pub const DEFAULT_MAX_MEMORY_PAGES: u32 = 16384;
+
+/// Resource limits for running a plugin.
+///
+/// The fuel budget covers a single invocation of a plugin (creating a
+/// contract, getting its API, ...) including any plugins it calls into, which
+/// draw from the caller's remaining fuel. The memory limit applies to every
+/// plugin instance separately.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PluginLimits {
+    /// the fuel available per invocation, None for no limit
+    #[serde(default)]
+    pub fuel: Option<u64>,
+    /// the most 64KiB pages a plugin's memory may grow to, None for no limit
+    #[serde(default)]
+    pub max_memory_pages: Option<u32>,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        PluginLimits {
+            fuel: Some(DEFAULT_FUEL),
+            max_memory_pages: Some(DEFAULT_MAX_MEMORY_PAGES),
+        }
+    }
+}
+
+impl PluginLimits {
+    /// No limits at all. Modules are still metered, but never run out.
+    pub fn unlimited() -> Self {
+        PluginLimits {
+            fuel: None,
+            max_memory_pages: None,
+        }
+    }
+
+    /// the fuel to give an invocation, u64::MAX standing in for unlimited
+    pub fn fuel_budget(&self) -> u64 {
+        self.fuel.unwrap_or(u64::MAX)
+    }
+
+    /// Create a store which meters the modules compiled with it and caps the
+    /// memories instantiated in it.
+    pub fn store(&self) -> Store {
+        let mut compiler = Cranelift::default();
+        // a Metering instance may only compile one module, so every store
+        // gets its own
+        compiler.push_middleware(Arc::new(Metering::new(DEFAULT_FUEL, fuel_cost)));
+        // export the state an instance is reset to before it is reused
+        compiler.push_middleware(Arc::new(ExportInstanceState));
+        let mut engine = EngineBuilder::new(compiler).engine();
+        engine.set_tunables(LimitingTunables {
+            base: BaseTunables::for_target(&Target::default()),
+            max_pages: self.max_memory_pages.map(Pages),
+        });
+        Store::new(engine)
+    }
+}
+
+/// every instruction costs one unit of fuel
+fn fuel_cost(_: &Operator) -> u64 {
+    1
+}
+
+/// Tunables which lower the maximum of every memory to `max_pages`.
+///
+/// A plugin hitting the limit sees `memory.grow` fail, which for Rust plugins
+/// surfaces as an allocation failure trap.
+struct LimitingTunables {
+    base: BaseTunables,
+    max_pages: Option<Pages>,
+}
+
+impl LimitingTunables {
+    fn adjust(&self, ty: &MemoryType) -> Result<MemoryType, MemoryError> {
+        let mut ty = *ty;
+        if let Some(max) = self.max_pages {
+            if ty.minimum > max {
+                return Err(MemoryError::Generic(format!(
+                    "Module requires {} memory pages, more than the limit of {}",
+                    ty.minimum.0, max.0
+                )));
+            }
+            ty.maximum = Some(ty.maximum.map_or(max, |m| std::cmp::min(m, max)));
+        }
+        Ok(ty)
+    }
+}
+
+impl Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base
+            .memory_style(&self.adjust(memory).unwrap_or(*memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<VMMemory, MemoryError> {
+        self.base.create_host_memory(&self.adjust(ty)?, style)
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<VMMemory, MemoryError> {
+        self.base
+            .create_vm_memory(&self.adjust(ty)?, style, vm_definition_location)
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<VMTable, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<VMTable, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! lockfiles pinning plugin names to the exact modules (and versions) used.
+//!
+//! A [`PluginLockfile`] is made from the [`PluginManifest`]s of the plugins a
+//! host has loaded, and checks that every plugin a locked plugin depends on is
+//! locked at a version it accepts. Plugins calling other plugins by name are
+//! resolved through the lockfile.
+use super::plugin_handle::WASMCacheID;
+use crate::manifest::PluginManifest;
+use schemars::JsonSchema;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Errors from making, checking or using a lockfile
+#[derive(Debug)]
+pub enum LockError {
+    /// The key locked for a plugin is not a valid module hash
+    InvalidKey(String),
+    /// Two different modules have the same name and highest version
+    Ambiguous(String, Version),
+    /// A plugin depends on a plugin which is not locked
+    UnlockedDependency {
+        /// the plugin with the dependency
+        plugin: String,
+        /// the plugin depended on
+        dependency: String,
+        /// the versions accepted
+        requirement: VersionReq,
+    },
+    /// A plugin depends on a plugin locked at a version it does not accept
+    UnsatisfiedDependency {
+        /// the plugin with the dependency
+        plugin: String,
+        /// the plugin depended on
+        dependency: String,
+        /// the versions accepted
+        requirement: VersionReq,
+        /// the version locked
+        locked: Version,
+    },
+    /// A plugin's name is locked to another module
+    WrongModule {
+        /// the plugin's name
+        name: String,
+        /// the key locked for it
+        locked: String,
+        /// the key of the module used
+        found: String,
+    },
+    /// A plugin run under the lockfile has no manifest to check, e.g. as it
+    /// was built before manifests were added
+    MissingManifest(String),
+    /// The lockfile could not be read or written
+    Io(std::io::Error),
+    /// The lockfile could not be parsed
+    Serialization(serde_json::Error),
+}
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for LockError {}
+impl From<std::io::Error> for LockError {
+    fn from(e: std::io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+impl From<serde_json::Error> for LockError {
+    fn from(e: serde_json::Error) -> Self {
+        LockError::Serialization(e)
+    }
+}
+
+/// A plugin pinned by a lockfile
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct LockedPlugin {
+    /// the hex cache id of the module
+    pub key: String,
+    /// the module's version
+    #[schemars(with = "String")]
+    pub version: Version,
+    /// the module's dependencies, as declared in its manifest
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+/// Plugins by name, pinned to the modules used.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PluginLockfile {
+    /// the locked plugins, by manifest name
+    pub plugins: BTreeMap<String, LockedPlugin>,
+}
+
+impl PluginLockfile {
+    /// Lock the plugins given, picking the highest version of each name.
+    ///
+    /// Plugins locked in `previous` stay locked to the same module if it is
+    /// still available.
+    pub fn resolve<I>(manifests: I, previous: Option<&PluginLockfile>) -> Result<Self, LockError>
+    where
+        I: IntoIterator<Item = (WASMCacheID, PluginManifest)>,
+    {
+        let mut candidates: BTreeMap<String, Vec<LockedPlugin>> = BTreeMap::new();
+        for (key, manifest) in manifests {
+            candidates
+                .entry(manifest.name)
+                .or_default()
+                .push(LockedPlugin {
+                    key: key.to_string(),
+                    version: manifest.version,
+                    dependencies: manifest.dependencies,
+                });
+        }
+        let mut plugins = BTreeMap::new();
+        for (name, mut versions) in candidates {
+            let pinned = previous
+                .and_then(|p| p.plugins.get(&name))
+                .and_then(|locked| versions.iter().position(|v| v.key == locked.key));
+            let chosen = match pinned {
+                Some(i) => versions.swap_remove(i),
+                None => {
+                    versions.sort_by(|a, b| b.version.cmp(&a.version));
+                    if versions.len() > 1
+                        && versions[0].version == versions[1].version
+                        && versions[0].key != versions[1].key
+                    {
+                        return Err(LockError::Ambiguous(name, versions[0].version.clone()));
+                    }
+                    versions.swap_remove(0)
+                }
+            };
+            plugins.insert(name, chosen);
+        }
+        let lockfile = PluginLockfile { plugins };
+        lockfile.check()?;
+        Ok(lockfile)
+    }
+
+    /// Check that every dependency of every locked plugin is locked at a
+    /// version it accepts.
+    pub fn check(&self) -> Result<(), LockError> {
+        for (name, locked) in &self.plugins {
+            self.check_dependencies(name, &locked.dependencies)?;
+        }
+        Ok(())
+    }
+
+    /// Check that a plugin about to be run agrees with the lockfile: if its
+    /// name is locked it must be the locked module, and its dependencies
+    /// must be locked at versions it accepts.
+    pub fn check_plugin(
+        &self,
+        key: &WASMCacheID,
+        manifest: &PluginManifest,
+    ) -> Result<(), LockError> {
+        if let Some(locked) = self.plugins.get(&manifest.name) {
+            if locked.key != key.to_string() {
+                return Err(LockError::WrongModule {
+                    name: manifest.name.clone(),
+                    locked: locked.key.clone(),
+                    found: key.to_string(),
+                });
+            }
+        }
+        self.check_dependencies(&manifest.name, &manifest.dependencies)
+    }
+
+    fn check_dependencies(
+        &self,
+        plugin: &str,
+        dependencies: &BTreeMap<String, VersionReq>,
+    ) -> Result<(), LockError> {
+        for (dependency, requirement) in dependencies {
+            match self.plugins.get(dependency) {
+                None => {
+                    return Err(LockError::UnlockedDependency {
+                        plugin: plugin.into(),
+                        dependency: dependency.clone(),
+                        requirement: requirement.clone(),
+                    })
+                }
+                Some(locked) if !requirement.matches(&locked.version) => {
+                    return Err(LockError::UnsatisfiedDependency {
+                        plugin: plugin.into(),
+                        dependency: dependency.clone(),
+                        requirement: requirement.clone(),
+                        locked: locked.version.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The mapping of names to module hashes plugins are resolved with
+    pub fn plugin_map(&self) -> Result<BTreeMap<Vec<u8>, [u8; 32]>, LockError> {
+        self.plugins
+            .iter()
+            .map(|(name, locked)| {
+                let mut key = [0u8; 32];
+                hex::decode_to_slice(&locked.key, &mut key)
+                    .map_err(|_| LockError::InvalidKey(name.clone()))?;
+                Ok((name.clone().into_bytes(), key))
+            })
+            .collect()
+    }
+
+    /// read a lockfile
+    pub fn load(path: &Path) -> Result<Self, LockError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// write a lockfile
+    pub fn save(&self, path: &Path) -> Result<(), LockError> {
+        Ok(std::fs::write(path, serde_json::to_string_pretty(self)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(name: &str, version: &str, dependencies: &[(&str, &str)]) -> PluginManifest {
+        let mut manifest = PluginManifest::from_package(name, version, "", "");
+        for (dependency, requirement) in dependencies {
+            manifest.depend_on(dependency, requirement).unwrap();
+        }
+        manifest
+    }
+
+    fn key(n: u8) -> WASMCacheID {
+        WASMCacheID::generate(&[n])
+    }
+
+    #[test]
+    fn resolves_highest_version() {
+        let lock = PluginLockfile::resolve(
+            vec![
+                (key(0), manifest("pay", "0.1.0", &[])),
+                (key(1), manifest("pay", "0.2.1", &[])),
+                (key(2), manifest("pay", "0.2.0", &[])),
+                (key(3), manifest("batch", "1.0.0", &[("pay", "^0.2")])),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(lock.plugins["pay"].key, key(1).to_string());
+        assert_eq!(lock.plugins["pay"].version, Version::new(0, 2, 1));
+        assert_eq!(lock.plugins["batch"].key, key(3).to_string());
+        assert_eq!(lock.plugin_map().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn keeps_previous_pins() {
+        let manifests = || {
+            vec![
+                (key(0), manifest("pay", "0.1.0", &[])),
+                (key(1), manifest("pay", "0.2.0", &[])),
+            ]
+        };
+        let old =
+            PluginLockfile::resolve(vec![(key(0), manifest("pay", "0.1.0", &[]))], None).unwrap();
+        let lock = PluginLockfile::resolve(manifests(), Some(&old)).unwrap();
+        assert_eq!(lock.plugins["pay"].key, key(0).to_string());
+        // a pin to a module which is gone is re-resolved
+        let gone =
+            PluginLockfile::resolve(vec![(key(9), manifest("pay", "0.3.0", &[]))], None).unwrap();
+        let lock = PluginLockfile::resolve(manifests(), Some(&gone)).unwrap();
+        assert_eq!(lock.plugins["pay"].key, key(1).to_string());
+    }
+
+    #[test]
+    fn refuses_ambiguous_versions() {
+        let e = PluginLockfile::resolve(
+            vec![
+                (key(0), manifest("pay", "0.1.0", &[])),
+                (key(1), manifest("pay", "0.1.0", &[])),
+            ],
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(e, LockError::Ambiguous(name, _) if name == "pay"));
+    }
+
+    #[test]
+    fn refuses_unresolvable_dependencies() {
+        let e = PluginLockfile::resolve(
+            vec![
+                (key(0), manifest("pay", "0.1.0", &[])),
+                (key(1), manifest("batch", "1.0.0", &[("pay", "^0.2")])),
+            ],
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e,
+            LockError::UnsatisfiedDependency { ref plugin, ref dependency, ref locked, .. }
+                if plugin == "batch" && dependency == "pay" && *locked == Version::new(0, 1, 0)
+        ));
+        let e = PluginLockfile::resolve(
+            vec![(key(1), manifest("batch", "1.0.0", &[("pay", "^0.2")]))],
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e,
+            LockError::UnlockedDependency { ref dependency, .. } if dependency == "pay"
+        ));
+    }
+
+    #[test]
+    fn checks_plugins_against_the_lock() {
+        let lock = PluginLockfile::resolve(
+            vec![
+                (key(0), manifest("pay", "0.1.0", &[])),
+                (key(1), manifest("batch", "1.0.0", &[("pay", "^0.1")])),
+            ],
+            None,
+        )
+        .unwrap();
+        lock.check().unwrap();
+        lock.check_plugin(&key(0), &manifest("pay", "0.1.0", &[]))
+            .unwrap();
+        assert!(matches!(
+            lock.check_plugin(&key(2), &manifest("pay", "0.1.1", &[])),
+            Err(LockError::WrongModule { .. })
+        ));
+        // plugins whose names aren't locked only need their dependencies
+        lock.check_plugin(&key(3), &manifest("other", "0.1.0", &[("pay", "0.1")]))
+            .unwrap();
+        assert!(matches!(
+            lock.check_plugin(&key(3), &manifest("other", "0.1.0", &[("pay", "^1")])),
+            Err(LockError::UnsatisfiedDependency { .. })
+        ));
+        assert!(matches!(
+            lock.check_plugin(&key(3), &manifest("other", "0.1.0", &[("escrow", "*")])),
+            Err(LockError::UnlockedDependency { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_requirements_are_errors() {
+        let mut manifest = manifest("batch", "1.0.0", &[]);
+        assert!(manifest.depend_on("pay", "not a version").is_err());
+        assert!(manifest.dependencies.is_empty());
+    }
+}
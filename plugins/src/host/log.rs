@@ -0,0 +1,207 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! where log records from plugins go.
+//!
+//! Records are never written to stdout, which hosts like the studio server
+//! use for their responses.
+use crate::plugin_log::{LogLevel, LogRecord};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Somewhere log records from plugins are sent
+pub trait LogSink: Send + Sync {
+    /// handle a record
+    fn log(&self, record: &LogRecord);
+}
+
+/// Writes records at or above a level to stderr, one per line
+pub struct StderrSink(pub LogLevel);
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &LogRecord) {
+        if record.level <= self.0 {
+            eprintln!("{}", record);
+        }
+    }
+}
+
+/// Appends records at or above a level to a file, as one JSON object per line
+pub struct FileSink {
+    level: LogLevel,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    /// open (or create) the file to append to
+    pub fn open(path: &Path, level: LogLevel) -> std::io::Result<Self> {
+        Ok(FileSink {
+            level,
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+        })
+    }
+}
+
+impl LogSink for FileSink {
+    fn log(&self, record: &LogRecord) {
+        if record.level > self.level {
+            return;
+        }
+        if let (Ok(mut file), Ok(line)) = (self.file.lock(), serde_json::to_string(record)) {
+            // logging must not fail compilation
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+/// Keeps every record, e.g. to return them with a response
+#[derive(Default)]
+pub struct LogCollector(Mutex<Vec<LogRecord>>);
+
+impl LogCollector {
+    /// take the records collected so far
+    pub fn take(&self) -> Vec<LogRecord> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl LogSink for LogCollector {
+    fn log(&self, record: &LogRecord) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(record.clone());
+    }
+}
+
+/// Sends records to every sink in a list
+pub struct Tee(pub Vec<Arc<dyn LogSink>>);
+
+impl LogSink for Tee {
+    fn log(&self, record: &LogRecord) {
+        for sink in &self.0 {
+            sink.log(record);
+        }
+    }
+}
+
+/// Where to send records
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogDestination {
+    /// to stderr
+    Stderr,
+    /// appended to a file, as JSON lines
+    File(PathBuf),
+    /// nowhere
+    Discard,
+}
+
+/// Configures the sink for records from plugins
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// the least severe level sent
+    #[serde(default = "LogConfig::default_level")]
+    pub level: LogLevel,
+    /// where records are sent
+    #[serde(default = "LogConfig::default_destination")]
+    pub destination: LogDestination,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Self::default_level(),
+            destination: Self::default_destination(),
+        }
+    }
+}
+
+impl LogConfig {
+    fn default_level() -> LogLevel {
+        LogLevel::Debug
+    }
+    fn default_destination() -> LogDestination {
+        LogDestination::Stderr
+    }
+
+    /// make the configured sink
+    pub fn sink(&self) -> std::io::Result<Arc<dyn LogSink>> {
+        Ok(match &self.destination {
+            LogDestination::Stderr => Arc::new(StderrSink(self.level)),
+            LogDestination::File(path) => Arc::new(FileSink::open(path, self.level)?),
+            LogDestination::Discard => Arc::new(Tee(vec![])),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(level: LogLevel) -> LogRecord {
+        LogRecord::new(level, "test", "message").field("n", 1)
+    }
+
+    #[test]
+    fn file_sink_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("sapio-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = FileSink::open(&path, LogLevel::Info).unwrap();
+        sink.log(&record(LogLevel::Warn));
+        sink.log(&record(LogLevel::Debug));
+        // reopening appends rather than truncating
+        FileSink::open(&path, LogLevel::Info)
+            .unwrap()
+            .log(&record(LogLevel::Info));
+        let lines: Vec<LogRecord> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, vec![record(LogLevel::Warn), record(LogLevel::Info)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tee_sends_to_every_sink() {
+        let (a, b) = (
+            Arc::new(LogCollector::default()),
+            Arc::new(LogCollector::default()),
+        );
+        let tee = Tee(vec![a.clone(), b.clone()]);
+        tee.log(&record(LogLevel::Trace));
+        assert_eq!(a.take(), vec![record(LogLevel::Trace)]);
+        assert_eq!(b.take(), vec![record(LogLevel::Trace)]);
+        assert!(a.take().is_empty());
+    }
+
+    #[test]
+    fn config_format() {
+        assert_eq!(
+            serde_json::from_str::<LogConfig>("{}").unwrap(),
+            LogConfig::default()
+        );
+        let config: LogConfig = serde_json::from_value(serde_json::json!({
+            "level": "warn",
+            "destination": {"file": "/tmp/plugins.log"}
+        }))
+        .unwrap();
+        assert_eq!(config.level, LogLevel::Warn);
+        assert_eq!(
+            config.destination,
+            LogDestination::File("/tmp/plugins.log".into())
+        );
+        let discard: LogConfig =
+            serde_json::from_value(serde_json::json!({ "destination": "discard" })).unwrap();
+        assert_eq!(discard.destination, LogDestination::Discard);
+        assert!(discard.sink().is_ok());
+    }
+}
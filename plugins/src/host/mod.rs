@@ -6,21 +6,35 @@
 
 //! host interface for modules
 
+use crate::host::limits::PluginLimits;
+use crate::host::lockfile::PluginLockfile;
+use crate::host::log::LogSink;
+use crate::host::plugin_handle::PluginConfig;
+use crate::host::pool::{ModulePool, WeakModulePool};
+use crate::host::queries::HostQueries;
+use crate::host::trust::TrustStore;
+use crate::host_query::HostQuery;
 pub use crate::plugin_handle::PluginHandle;
+use crate::plugin_log::{LogLevel, LogRecord};
 use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::util::psbt::PartiallySignedTransaction;
-pub use plugin_handle::WasmPluginHandle;
-use sapio::contract::CompilationError;
+pub use plugin_handle::{NativePluginHandle, WasmPluginHandle};
+use sapio::contract::{CompilationError, ResourceLimit};
 use sapio_base::plugin_args::CreateArgs;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::collections::BTreeMap;
-use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 use wasmer::*;
 
+pub mod limits;
+pub mod lockfile;
+pub mod log;
 pub mod plugin_handle;
+pub mod pool;
+pub mod queries;
+pub mod trust;
 pub mod wasm_cache;
 
 /// The state that host-side functions need to be able to use
@@ -37,6 +51,22 @@ pub struct HostEnvironmentInner {
     pub net: bitcoin::Network,
     /// an emulator plugin for CTV functionality
     pub emulator: Arc<dyn CTVEmulator>,
+    /// the limits plugins are run under
+    pub limits: PluginLimits,
+    /// reference to the currently running module's remaining fuel
+    pub fuel: Option<Global>,
+    /// a limit exceeded by a plugin called from this one, and that plugin's key
+    pub exceeded: Option<(String, ResourceLimit)>,
+    /// the pool to take plugins called from this one from
+    pub pool: WeakModulePool,
+    /// the trust store plugins called from this one are checked against
+    pub trust: Arc<TrustStore>,
+    /// the lockfile plugins called from this one are checked against, if any
+    pub lock: Option<Arc<PluginLockfile>>,
+    /// answers queries from this plugin and those called from it
+    pub queries: Arc<HostQueries>,
+    /// where log records from this plugin and those called from it go
+    pub log: Arc<dyn LogSink>,
     /// reference to the environment's memory space
     pub memory: Option<Memory>,
     /// reference to allocation creation function
@@ -68,8 +98,6 @@ mod exports {
     //! They must be manually bound when instantiating the client.
     use std::str::FromStr;
 
-    use crate::host::plugin_handle::SyncModuleLocator;
-
     use super::*;
     use sapio_base::effects::EffectPath;
     /// lookup a plugin key from a human reable name.
@@ -86,15 +114,16 @@ mod exports {
         let (env, store) = env.data_and_store_mut();
         let m_hash = {
             if key == 0 && len == 0 {
-                Some(&env.this)
+                Some(env.this)
             } else {
-                let mut buf = vec![0u8; len as usize];
-                env.memory
-                    .as_ref()
-                    .unwrap()
-                    .view(&store)
-                    .read(key as u64, &mut buf[..]);
-                env.module_map.get(&buf)
+                let view = env.memory.as_ref().unwrap().view(&store);
+                // names in the module map take precedence over native plugins
+                read_memory(&view, key, len as i64).ok().and_then(|buf| {
+                    env.module_map
+                        .get(&buf)
+                        .copied()
+                        .or_else(|| NativePluginHandle::lookup_name(&String::from_utf8_lossy(&buf)))
+                })
             }
         };
         let is_ok = if let Some(b) = m_hash {
@@ -102,7 +131,7 @@ mod exports {
                 .as_ref()
                 .unwrap()
                 .view(&store)
-                .write(out as u64, b);
+                .write(out as u64, &b);
             1
         } else {
             0
@@ -193,110 +222,245 @@ mod exports {
                 json_len,
                 ..
             } => {
-                // use this buffer twice, so make it the max size
-                let mut v = vec![0u8; std::cmp::max(json_len, path_len) as usize];
-                env.memory
-                    .as_ref()
-                    .unwrap()
-                    .view(&store)
-                    .read(json as u64, &mut v[..json_len as usize]);
-                let create_args = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..json_len as usize]).to_owned(),
-                )
-                .map_err(CompilationError::DeserializationError);
-
-                env.memory
-                    .as_ref()
-                    .unwrap()
-                    .view(&store)
-                    .read(path as u64, &mut v[..path_len as usize]);
-                let effectpath: Result<EffectPath, _> = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..path_len as usize]).to_owned(),
-                )
-                .map_err(CompilationError::DeserializationError);
+                let view = env.memory.as_ref().unwrap().view(&store);
+                let read = |p: i32, len: i32| {
+                    read_memory(&view, p, len as i64)
+                        .map_err(|e| CompilationError::ModuleFailedToGetMemory(e.into()))
+                };
+                let create_args = read(json, json_len).and_then(|v| {
+                    serde_json::from_str(&String::from_utf8_lossy(&v))
+                        .map_err(CompilationError::DeserializationError)
+                });
+                let effectpath: Result<EffectPath, _> = read(path, path_len).and_then(|v| {
+                    serde_json::from_str(&String::from_utf8_lossy(&v))
+                        .map_err(CompilationError::DeserializationError)
+                });
 
                 create_args.and_then(|c| effectpath.map(|e| InternalAction::Create(c, e)))
             }
         };
-        let emulator = env.emulator.clone();
-        let mmap = env.module_map.clone();
-        let path = env.path.clone();
-        let net = env.net;
-        let key = wasmer_cache::Hash::from_str(&h).map(SyncModuleLocator::Key);
-        // Use serde_json::Value for the WasmPluginHandle Output type
-        match key.map(|module_locator| {
-            WasmPluginHandle::<serde_json::Value>::new(
-                path,
-                &emulator,
-                module_locator,
-                net,
-                Some(mmap),
-            )
-        }) {
-            Ok(Ok(mut sph)) => {
-                let comp_s = (move || -> Result<serde_json::Value, CompilationError> {
-                    let value = match action_to_take? {
-                        InternalAction::GetName => Ok(sph.get_name().and_then(|m| {
-                            serde_json::to_value(m).map_err(CompilationError::DeserializationError)
-                        })),
-                        InternalAction::GetLogo => Ok(sph.get_logo().and_then(|m| {
-                            serde_json::to_value(m).map_err(CompilationError::DeserializationError)
-                        })),
-                        InternalAction::GetAPI => Ok(sph.get_api().and_then(|m| {
-                            serde_json::to_value(m).map_err(CompilationError::DeserializationError)
-                        })),
-                        InternalAction::Create(create_args, path) => {
-                            sph.call(&path, &create_args).map(|comp| {
-                                serde_json::to_value(comp)
-                                    .map_err(CompilationError::DeserializationError)
-                            })
+        // the called plugin runs on the fuel this plugin has left
+        let fuel = env.fuel.clone();
+        let config = PluginConfig {
+            path: env.path.clone(),
+            emulator: env.emulator.clone(),
+            net: env.net,
+            plugin_map: env.module_map.clone(),
+            limits: PluginLimits {
+                fuel: env
+                    .limits
+                    .fuel
+                    .and(fuel.as_ref().map(|f| f.get(&mut store).unwrap_i64() as u64)),
+                ..env.limits
+            },
+            trust: env.trust.clone(),
+            lock: env.lock.clone(),
+            queries: env.queries.clone(),
+            log: env.log.clone(),
+        };
+        // the pool only goes away with the handle that made it, so this should
+        // not need a fresh one while plugins are running.
+        let pool = env.pool.upgrade().unwrap_or_else(ModulePool::shared);
+        let key = match wasmer_cache::Hash::from_str(&h) {
+            Ok(key) => key,
+            Err(_) => return 0,
+        };
+        fn run<H>(
+            sph: &mut H,
+            action: Result<InternalAction, CompilationError>,
+        ) -> Result<serde_json::Value, CompilationError>
+        where
+            H: PluginHandle<Input = CreateArgs<serde_json::Value>, Output = serde_json::Value>,
+        {
+            match action? {
+                InternalAction::GetName => sph.get_name().and_then(|m| {
+                    serde_json::to_value(m).map_err(CompilationError::DeserializationError)
+                }),
+                InternalAction::GetLogo => sph.get_logo().and_then(|m| {
+                    serde_json::to_value(m).map_err(CompilationError::DeserializationError)
+                }),
+                InternalAction::GetAPI => sph.get_api().and_then(|m| {
+                    serde_json::to_value(m).map_err(CompilationError::DeserializationError)
+                }),
+                InternalAction::Create(create_args, path) => sph.call(&path, &create_args),
+            }
+        }
+        // native plugins are called in process, and don't use any fuel
+        let native = match NativePluginHandle::get(&key, &config) {
+            Ok(native) => native,
+            Err(e) => {
+                let refused: Result<serde_json::Value, _> = Err(e.to_string());
+                return pass_json(env, &mut store, &refused).unwrap_or(0);
+            }
+        };
+        let comp_s = if let Some(mut native) = native {
+            run(&mut native, action_to_take)
+        } else {
+            // Use serde_json::Value for the WasmPluginHandle Output type
+            match WasmPluginHandle::<serde_json::Value>::from_pool(&pool, key, config) {
+                Ok(mut sph) => {
+                    let comp_s = run(&mut sph, action_to_take);
+                    // charge this plugin for what the called plugin used
+                    let left = match &comp_s {
+                        Err(CompilationError::ModuleExceededLimit(key, limit)) => {
+                            env.exceeded = Some((key.clone(), *limit));
+                            0
                         }
+                        _ => sph.remaining_fuel(),
                     };
-                    value?
-                })();
-                (move || -> Result<i32, CompilationError> {
-                    // serialize the reuslt, not just the output.
-                    let comp_s = serde_json::to_string(&comp_s.map_err(|s| s.to_string()))
-                        .map_err(CompilationError::SerializationError)?;
-                    let bytes: i32 = env
-                        .sapio_v1_wasm_plugin_client_allocate_bytes
-                        .as_ref()
-                        .ok_or_else(|| {
-                            CompilationError::ModuleCouldNotFindFunction(
-                                "allocate_wasm_bytes".into(),
-                            )
-                        })?
-                        .call(&mut store, comp_s.len() as i32)
-                        .map_err(|e| {
-                            CompilationError::ModuleCouldNotAllocateError(
-                                comp_s.len() as i32,
-                                e.into(),
-                            )
-                        })?;
-                    env.memory
-                        .as_ref()
-                        .unwrap()
-                        .view(&store)
-                        .write(bytes as u64, comp_s.as_bytes());
-                    Ok(bytes)
-                })()
-                .unwrap_or(0)
+                    set_fuel(&mut store, &fuel, left);
+                    // instances which failed may be in a bad state, so only reuse
+                    // ones which succeeded
+                    if comp_s.is_ok() {
+                        if let Ok(mut pool) = pool.lock() {
+                            pool.checkin(sph);
+                        }
+                    }
+                    comp_s
+                }
+                Err(e) => {
+                    if let Some(CompilationError::ModuleExceededLimit(key, limit)) =
+                        e.downcast_ref()
+                    {
+                        env.exceeded = Some((key.clone(), *limit));
+                        set_fuel(&mut store, &fuel, 0);
+                    }
+                    let failed: Result<serde_json::Value, _> = Err(e.to_string());
+                    return pass_json(env, &mut store, &failed).unwrap_or(0);
+                }
             }
-            _ => 0,
+        };
+        // serialize the reuslt, not just the output.
+        pass_json(env, &mut store, &comp_s.map_err(|s| s.to_string())).unwrap_or(0)
+    }
+
+    /// serialize `v` into a new allocation in the plugin's memory
+    fn pass_json<T: serde::Serialize>(
+        env: &HostEnvironmentInner,
+        store: &mut StoreMut,
+        v: &T,
+    ) -> Result<i32, CompilationError> {
+        let s = serde_json::to_string(v).map_err(CompilationError::SerializationError)?;
+        let bytes: i32 = env
+            .sapio_v1_wasm_plugin_client_allocate_bytes
+            .as_ref()
+            .ok_or_else(|| {
+                CompilationError::ModuleCouldNotFindFunction("allocate_wasm_bytes".into())
+            })?
+            .call(store, s.len() as i32)
+            .map_err(|e| CompilationError::ModuleCouldNotAllocateError(s.len() as i32, e.into()))?;
+        env.memory
+            .as_ref()
+            .ok_or_else(|| CompilationError::ModuleFailedToGetMemory("Memory Missing".into()))?
+            .view(store)
+            .write(bytes as u64, s.as_bytes())
+            .map_err(|e| CompilationError::ModuleFailedToGetMemory(e.into()))?;
+        Ok(bytes)
+    }
+
+    /// read `len` bytes at `p` from the plugin's memory
+    fn read_bytes(env: &mut HostEnvironment, p: i32, len: i32) -> Result<Vec<u8>, String> {
+        let (env, store) = env.data_and_store_mut();
+        let view = env.memory.as_ref().ok_or("Memory Missing")?.view(&store);
+        read_memory(&view, p, len as i64)
+    }
+
+    /// read `len` bytes at `p` from a view of the plugin's memory. Both come
+    /// from the plugin, so they are checked against the size of its memory
+    /// before anything is allocated.
+    pub(crate) fn read_memory(view: &MemoryView, p: i32, len: i64) -> Result<Vec<u8>, String> {
+        // wasm32 addresses are unsigned
+        let p = p as u32 as u64;
+        let len = u64::try_from(len).map_err(|_| format!("Negative Length {}", len))?;
+        if p.checked_add(len)
+            .map_or(true, |end| end > view.data_size())
+        {
+            return Err(format!("{} Bytes At {} Are Outside Memory", len, p));
+        }
+        let mut buf = vec![0u8; len as usize];
+        view.read(p, &mut buf[..]).map_err(|e| e.to_string())?;
+        Ok(buf)
+    }
+
+    /// Answer a query from the plugin, returning the answer (or why there
+    /// isn't one) as JSON, or 0 if it can't be returned.
+    fn host_query(mut env: HostEnvironment, query: Result<HostQuery, String>) -> i32 {
+        let (env, mut store) = env.data_and_store_mut();
+        let answer = query.and_then(|q| env.queries.answer(&q));
+        pass_json(env, &mut store, &answer).unwrap_or(0)
+    }
+
+    /// get the height of the host's chain tip
+    pub fn sapio_v1_wasm_plugin_chain_tip_height(env: HostEnvironment) -> i32 {
+        host_query(env, Ok(HostQuery::TipHeight))
+    }
+
+    /// look up an output, passed as a JSON outpoint, in the host's
+    /// transaction index
+    pub fn sapio_v1_wasm_plugin_chain_lookup_output(
+        mut env: HostEnvironment,
+        outpoint: i32,
+        len: i32,
+    ) -> i32 {
+        let query = read_bytes(&mut env, outpoint, len).and_then(|b| {
+            serde_json::from_slice(&b)
+                .map(HostQuery::Output)
+                .map_err(|e| e.to_string())
+        });
+        host_query(env, query)
+    }
+
+    /// get the price of a symbol from the host's oracle with this name
+    pub fn sapio_v1_wasm_plugin_oracle_price(
+        mut env: HostEnvironment,
+        oracle: i32,
+        oracle_len: i32,
+        symbol: i32,
+        symbol_len: i32,
+    ) -> i32 {
+        let query = read_bytes(&mut env, oracle, oracle_len).and_then(|oracle| {
+            read_bytes(&mut env, symbol, symbol_len).map(|symbol| HostQuery::OraclePrice {
+                oracle: String::from_utf8_lossy(&oracle).into_owned(),
+                symbol: String::from_utf8_lossy(&symbol).into_owned(),
+            })
+        });
+        host_query(env, query)
+    }
+
+    /// set the remaining fuel of the running plugin
+    fn set_fuel(store: &mut StoreMut, fuel: &Option<Global>, left: u64) {
+        if let Some(fuel) = fuel {
+            // only fails if the global is not an i64, which metering guarantees
+            let _ = fuel.set(store, Value::I64(left as i64));
         }
     }
 
-    /// use the hosts stdout to log a string. The host may make this a no-op.
+    /// log a string, as a debug level record. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(mut env: HostEnvironment, a: i32, len: i32) {
-        let (env, store) = env.data_and_store_mut();
-        let stdout = std::io::stdout();
-        let lock = stdout.lock();
-        let mut w = std::io::BufWriter::new(lock);
-        let mem = env.memory.as_ref().unwrap().view(&store);
-        let mut v = vec![0; len as usize];
-        mem.read(a as u64, &mut v[..]);
-        w.write_all(&v[..]);
-        w.write_all("\n".as_bytes()).unwrap();
+        if let Ok(v) = read_bytes(&mut env, a, len) {
+            let record = LogRecord::new(
+                LogLevel::Debug,
+                "debug_log_string",
+                &String::from_utf8_lossy(&v),
+            );
+            log_record(env, record);
+        }
+    }
+
+    /// log a structured record, passed as JSON. The host may make this a no-op.
+    pub fn sapio_v1_wasm_plugin_log(mut env: HostEnvironment, record: i32, len: i32) {
+        let record = read_bytes(&mut env, record, len)
+            .and_then(|v| serde_json::from_slice::<LogRecord>(&v).map_err(|e| e.to_string()));
+        if let Ok(record) = record {
+            log_record(env, record);
+        }
+    }
+
+    /// send a record to the sink, noting which plugin it came from
+    fn log_record(env: HostEnvironment, mut record: LogRecord) {
+        let env = env.data();
+        record.module = Some(hex::encode(env.this));
+        env.log.log(&record);
     }
 
     /// for the provided hash value, get the clause the oracle will satisfy
@@ -338,13 +502,11 @@ mod exports {
         len: u32,
     ) -> i32 {
         let (env, mut store) = env.data_and_store_mut();
-        let mut buf = vec![0u8; len as usize];
-        let psbt = psbt as u64;
-        env.memory
-            .as_ref()
-            .unwrap()
-            .view(&store)
-            .read(psbt, &mut buf[..]);
+        let view = env.memory.as_ref().unwrap().view(&store);
+        let mut buf = match read_memory(&view, psbt, len as i64) {
+            Ok(buf) => buf,
+            Err(_) => return 0,
+        };
         let psbt: PartiallySignedTransaction = serde_json::from_slice(&buf[..]).unwrap();
         let psbt = env.emulator.sign(psbt).unwrap();
         buf.clear();
@@ -363,3 +525,26 @@ mod exports {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::exports::read_memory;
+    use super::*;
+
+    #[test]
+    fn memory_reads_are_bounds_checked() {
+        let mut store = Store::default();
+        let memory = Memory::new(&mut store, MemoryType::new(1, None, false)).unwrap();
+        let view = memory.view(&store);
+        view.write(10, b"hello").unwrap();
+        assert_eq!(read_memory(&view, 10, 5).unwrap(), b"hello");
+        assert_eq!(read_memory(&view, 0, 0).unwrap(), b"");
+        // exactly up to the end of the page is fine, one past is not
+        assert_eq!(read_memory(&view, 65535, 1).unwrap().len(), 1);
+        assert!(read_memory(&view, 65535, 2).is_err());
+        assert!(read_memory(&view, 10, -1).is_err());
+        assert!(read_memory(&view, 0, i32::MAX as i64).is_err());
+        // negative pointers are large addresses, not wrapped around
+        assert!(read_memory(&view, -1, 1).is_err());
+    }
+}
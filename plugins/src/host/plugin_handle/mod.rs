@@ -8,8 +8,8 @@
 
 use super::wasm_cache;
 use crate::CreateArgs;
-use sapio_ctv_emulator_trait::NullEmulator;
 
+pub use native::*;
 use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -17,4 +17,5 @@ pub use wasm::*;
 use wasmer::{imports, Function, Instance, Module, Store};
 pub use wasmer_cache::Hash as WASMCacheID;
 
+mod native;
 mod wasm;
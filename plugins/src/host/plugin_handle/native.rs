@@ -0,0 +1,366 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a plugin handle for plugins linked into the host, which are called in
+//! process rather than loaded as WASM.
+//!
+//! Native plugins are linked into the host when it is built, with
+//! [`crate::NATIVE_PLUGIN`], and are then available wherever a WASM plugin
+//! would be: by key, or by name from other plugins through `LookupFrom::Name`.
+//!
+//! Native plugins run as part of the host, so they are not metered, have no
+//! memory limits and send no log records. They have no module to check
+//! against a lockfile or a publisher's signature either, so a host which
+//! checks plugins with a lockfile or trust store refuses to run them.
+use super::*;
+use crate::callable::Callable;
+use crate::host::trust::TrustStore;
+use crate::plugin_handle::PluginHandle;
+use crate::API;
+use bitcoin::hashes::{sha256, Hash};
+use sapio::contract::{CompilationError, Context};
+use sapio_base::effects::{EffectPath, PathFragment};
+use sapio_base::serialization_helpers::SArc;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+
+#[doc(hidden)]
+pub use ::linkme;
+
+/// The logo shown for native plugins
+const NATIVE_PLUGIN_LOGO: &[u8] = include_bytes!("../../client/logo.png");
+
+/// A type erased native plugin
+trait NativePlugin: Send + Sync {
+    /// the API, as [`API<CreateArgs<T>, Output>`] would serialize
+    fn get_api(&self) -> Result<Value, CompilationError>;
+    /// create an instance from the arguments, serializing the output
+    fn call(
+        &self,
+        path: EffectPath,
+        args: &CreateArgs<Value>,
+        emulator: Arc<dyn CTVEmulator>,
+    ) -> Result<Value, CompilationError>;
+}
+
+/// A [`NativePlugin`] for `T`, created from the arguments `W`
+struct Native<T, W> {
+    _pd: PhantomData<fn() -> (T, W)>,
+}
+
+impl<T, W> NativePlugin for Native<T, W>
+where
+    T: Callable + TryFrom<W>,
+    W: JsonSchema + for<'a> Deserialize<'a>,
+    T::Output: Serialize + JsonSchema,
+    CompilationError: From<<T as TryFrom<W>>::Error>,
+{
+    fn get_api(&self) -> Result<Value, CompilationError> {
+        serde_json::to_value(API::<CreateArgs<W>, T::Output>::new())
+            .map_err(CompilationError::SerializationError)
+    }
+    fn call(
+        &self,
+        path: EffectPath,
+        args: &CreateArgs<Value>,
+        emulator: Arc<dyn CTVEmulator>,
+    ) -> Result<Value, CompilationError> {
+        let CreateArgs { arguments, context } = args.clone();
+        let arguments: W =
+            serde_json::from_value(arguments).map_err(CompilationError::DeserializationError)?;
+        let ctx = Context::new(
+            context.network,
+            context.amount,
+            emulator,
+            path,
+            Arc::new(context.effects),
+            context.ordinals_info,
+        );
+        let output = T::try_from(arguments)?.call(ctx)?;
+        serde_json::to_value(output).map_err(CompilationError::SerializationError)
+    }
+}
+
+/// A native plugin linked into the host, made with [`crate::NATIVE_PLUGIN`]
+pub struct NativePluginEntry {
+    name: &'static str,
+    plugin: &'static dyn NativePlugin,
+}
+
+impl NativePluginEntry {
+    /// An entry for the type `T`, created from the arguments `W`, named
+    /// `name`. For a type implementing [`crate::client::Plugin`], `W` is its
+    /// `InputWrapper`.
+    pub const fn new<T, W>(name: &'static str) -> Self
+    where
+        T: Callable + TryFrom<W> + 'static,
+        W: JsonSchema + for<'a> Deserialize<'a> + 'static,
+        T::Output: Serialize + JsonSchema,
+        CompilationError: From<<T as TryFrom<W>>::Error>,
+    {
+        NativePluginEntry {
+            name,
+            plugin: &Native::<T, W> { _pd: PhantomData },
+        }
+    }
+}
+
+/// Every native plugin linked into the host
+#[linkme::distributed_slice]
+pub static NATIVE_PLUGINS: [NativePluginEntry];
+
+/// the native plugins, by key. If two are linked in with the same name, the
+/// first is used.
+fn registry() -> &'static BTreeMap<[u8; 32], &'static NativePluginEntry> {
+    static REGISTRY: OnceLock<BTreeMap<[u8; 32], &'static NativePluginEntry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = BTreeMap::new();
+        for entry in NATIVE_PLUGINS {
+            registry.entry(native_key(entry.name)).or_insert(entry);
+        }
+        registry
+    })
+}
+
+/// the key of the native plugin named `name`
+fn native_key(name: &str) -> [u8; 32] {
+    sha256::Hash::hash(format!("sapio-native-plugin:{}", name).as_bytes()).into_inner()
+}
+
+/// Link the type `$plugin` into the host as a native plugin named `$name`.
+/// The type is created from its own arguments, or from `$args` if given,
+/// which for a type implementing [`crate::client::Plugin`] is its
+/// `InputWrapper`:
+///
+/// ```ignore
+/// NATIVE_PLUGIN![TreePay, "treepay"];
+/// NATIVE_PLUGIN![[UndoSendInternal, UndoSendInternal], "undo_send"];
+/// ```
+#[macro_export]
+macro_rules! NATIVE_PLUGIN {
+    [[$plugin:ty, $args:ty], $name:literal] => {
+        const _: () = {
+            use $crate::host::plugin_handle::{linkme, NativePluginEntry, NATIVE_PLUGINS};
+            #[linkme::distributed_slice(NATIVE_PLUGINS)]
+            #[linkme(crate = linkme)]
+            static NATIVE_PLUGIN: NativePluginEntry = NativePluginEntry::new::<$plugin, $args>($name);
+        };
+    };
+    [$plugin:ty, $name:literal] => {
+        $crate::NATIVE_PLUGIN![[$plugin, $plugin], $name];
+    };
+}
+
+/// Why a native plugin can't be run
+#[derive(Debug)]
+pub enum NativePluginError {
+    /// Plugins are checked against a lockfile, which can't check native
+    /// plugins
+    Locked(String),
+    /// Plugins are checked against a trust store, which can't check native
+    /// plugins
+    Untrusted(String),
+}
+impl fmt::Display for NativePluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for NativePluginError {}
+
+/// A handle to a plugin linked into the host
+#[derive(Clone)]
+pub struct NativePluginHandle {
+    key: [u8; 32],
+    entry: &'static NativePluginEntry,
+    emulator: Arc<dyn CTVEmulator>,
+}
+
+impl NativePluginHandle {
+    /// get a handle to the native plugin with this key, if there is one,
+    /// failing if `config` checks plugins in a way native plugins can't be.
+    pub fn get(
+        key: &WASMCacheID,
+        config: &PluginConfig,
+    ) -> Result<Option<Self>, NativePluginError> {
+        let mut bytes = [0u8; 32];
+        if hex::decode_to_slice(key.to_string(), &mut bytes).is_err() {
+            return Ok(None);
+        }
+        match registry().get(&bytes) {
+            Some(entry) => {
+                Self::allowed(entry.name, config)?;
+                Ok(Some(NativePluginHandle {
+                    key: bytes,
+                    entry,
+                    emulator: config.emulator.clone(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// get handles to every native plugin, or none if `config` checks plugins
+    /// in a way native plugins can't be
+    pub fn all(config: &PluginConfig) -> Vec<Self> {
+        registry()
+            .iter()
+            .filter(|(_, entry)| Self::allowed(entry.name, config).is_ok())
+            .map(|(key, entry)| NativePluginHandle {
+                key: *key,
+                entry,
+                emulator: config.emulator.clone(),
+            })
+            .collect()
+    }
+
+    /// the key of the native plugin named `name`, if one is linked in
+    pub fn lookup_name(name: &str) -> Option<[u8; 32]> {
+        let key = native_key(name);
+        registry().contains_key(&key).then_some(key)
+    }
+
+    /// native plugins can't be run if plugins are checked with a lockfile or
+    /// a trust store
+    fn allowed(name: &str, config: &PluginConfig) -> Result<(), NativePluginError> {
+        if config.lock.is_some() {
+            return Err(NativePluginError::Locked(name.into()));
+        }
+        if *config.trust != TrustStore::default() {
+            return Err(NativePluginError::Untrusted(name.into()));
+        }
+        Ok(())
+    }
+
+    /// the key this plugin is known by
+    pub fn id(&self) -> WASMCacheID {
+        WASMCacheID::new(self.key)
+    }
+}
+
+impl PluginHandle for NativePluginHandle {
+    type Input = CreateArgs<Value>;
+    type Output = Value;
+    fn call(
+        &mut self,
+        path: &EffectPath,
+        c: &Self::Input,
+    ) -> Result<Self::Output, CompilationError> {
+        // paths are extended as a WASM plugin would, with the plugin's key
+        let path = EffectPath::push_owned(
+            Some(EffectPath::push(
+                Some(Arc::new(path.clone())),
+                PathFragment::Root,
+            )),
+            PathFragment::Named(SArc(Arc::new(self.id().to_string()))),
+        );
+        self.entry.plugin.call(path, c, self.emulator.clone())
+    }
+    fn get_api(&mut self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
+        serde_json::from_value(self.entry.plugin.get_api()?)
+            .map_err(CompilationError::DeserializationError)
+    }
+    fn get_name(&mut self) -> Result<String, CompilationError> {
+        Ok(self.entry.name.into())
+    }
+    fn get_logo(&mut self) -> Result<String, CompilationError> {
+        Ok(base64::encode(NATIVE_PLUGIN_LOGO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::lockfile::PluginLockfile;
+    use crate::host::trust::TrustPolicy;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::json;
+
+    /// a plugin which doubles its argument
+    #[derive(Deserialize, JsonSchema)]
+    struct Double {
+        value: u64,
+    }
+    impl Callable for Double {
+        type Output = u64;
+        fn call(&self, _ctx: Context) -> Result<u64, CompilationError> {
+            Ok(self.value * 2)
+        }
+    }
+    crate::NATIVE_PLUGIN![Double, "test_double"];
+
+    fn config() -> PluginConfig {
+        PluginConfig {
+            path: std::env::temp_dir(),
+            emulator: Arc::new(CTVAvailable),
+            net: bitcoin::Network::Regtest,
+            plugin_map: Default::default(),
+            limits: Default::default(),
+            trust: Default::default(),
+            lock: None,
+            queries: Default::default(),
+            log: Arc::new(crate::host::log::LogCollector::default()),
+        }
+    }
+
+    fn key() -> WASMCacheID {
+        WASMCacheID::new(NativePluginHandle::lookup_name("test_double").unwrap())
+    }
+
+    #[test]
+    fn callable_through_the_handle_api() {
+        let mut handle: Box<dyn PluginHandle<Input = CreateArgs<Value>, Output = Value>> =
+            Box::new(NativePluginHandle::get(&key(), &config()).unwrap().unwrap());
+        assert_eq!(handle.get_name().unwrap(), "test_double");
+        let api = handle.get_api().unwrap();
+        assert!(serde_json::to_string(api.input())
+            .unwrap()
+            .contains("value"));
+        let args = serde_json::from_value(json!({
+            "arguments": {"value": 21},
+            "context": {"network": "Regtest", "amount": 0}
+        }))
+        .unwrap();
+        assert_eq!(
+            handle.call(&PathFragment::Root.into(), &args).unwrap(),
+            json!(42)
+        );
+        assert!(NativePluginHandle::all(&config())
+            .iter()
+            .any(|p| p.id() == key()));
+        assert!(NativePluginHandle::lookup_name("not_linked").is_none());
+    }
+
+    #[test]
+    fn refused_when_plugins_are_checked() {
+        let locked = PluginConfig {
+            lock: Some(Arc::new(PluginLockfile::default())),
+            ..config()
+        };
+        assert!(matches!(
+            NativePluginHandle::get(&key(), &locked),
+            Err(NativePluginError::Locked(_))
+        ));
+        assert!(NativePluginHandle::all(&locked).is_empty());
+        let trusting = PluginConfig {
+            trust: Arc::new(TrustStore {
+                unknown: TrustPolicy::Refuse,
+                ..Default::default()
+            }),
+            ..config()
+        };
+        assert!(matches!(
+            NativePluginHandle::get(&key(), &trusting),
+            Err(NativePluginError::Untrusted(_))
+        ));
+    }
+}
@@ -6,12 +6,25 @@
 
 //!  a plugin handle for a wasm plugin.
 use super::*;
+use crate::host::limits::{PluginLimits, REMAINING_FUEL_EXPORT};
+use crate::host::lockfile::{LockError, PluginLockfile};
+use crate::host::log::LogSink;
+use crate::host::pool::{
+    InstanceSnapshot, ModulePool, SharedModulePool, WeakModulePool, MAX_RETAINED_GROWTH_PAGES,
+};
+use crate::host::queries::HostQueries;
+use crate::host::trust::{
+    PluginSignature, TrustDecision, TrustError, TrustStore, VerifiedPublisher,
+};
 use crate::host::wasm_cache::get_all_keys_from_fs;
 use crate::host::HostEnvironmentInner;
 use crate::host::{exports::*, HostEnvironmentT};
+use crate::host_query::QUERY_RECORD_METADATA_KEY;
+use crate::manifest::PluginManifest;
 use crate::plugin_handle::PluginHandle;
+use crate::plugin_log::{LogLevel, LogRecord};
 use crate::API;
-use sapio::contract::CompilationError;
+use sapio::contract::{CompilationError, ResourceLimit};
 use sapio_base::effects::EffectPath;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use schemars::JsonSchema;
@@ -19,7 +32,8 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::marker::PhantomData;
 use std::path::PathBuf;
-use wasmer::{FunctionEnv, Memory, TypedFunction};
+use wasmer::{FunctionEnv, Memory, RuntimeError, TypedFunction};
+use wasmer_middlewares::metering::{get_remaining_points, set_remaining_points, MeteringPoints};
 
 /// Helper to resolve modules
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -30,6 +44,8 @@ pub enum ModuleLocator {
     FileName(String),
     /// The Raw Uncompiled Bytes of a Module
     Bytes(Vec<u8>),
+    /// The Raw Uncompiled Bytes of a Module, with its publisher's signature
+    SignedBytes(Vec<u8>, PluginSignature),
     /// Not Known
     Unknown,
 }
@@ -41,8 +57,20 @@ impl ModuleLocator {
                 let key = WASMCacheID::from_str(&k)?;
                 Ok(SyncModuleLocator::Key(key))
             }
-            ModuleLocator::FileName(f) => Ok(SyncModuleLocator::Bytes(tokio::fs::read(f).await?)),
-            ModuleLocator::Bytes(b) => Ok(SyncModuleLocator::Bytes(b)),
+            ModuleLocator::FileName(f) => {
+                // a signature may be shipped next to the module
+                let signature = match tokio::fs::read(format!("{}.sig", f)).await {
+                    Ok(s) => Some(serde_json::from_slice(&s)?),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                };
+                Ok(SyncModuleLocator::Bytes(
+                    tokio::fs::read(f).await?,
+                    signature,
+                ))
+            }
+            ModuleLocator::Bytes(b) => Ok(SyncModuleLocator::Bytes(b, None)),
+            ModuleLocator::SignedBytes(b, s) => Ok(SyncModuleLocator::Bytes(b, Some(s))),
             ModuleLocator::Unknown => Err(Err(CompilationError::UnknownModule)?),
         }
     }
@@ -52,8 +80,33 @@ impl ModuleLocator {
 pub enum SyncModuleLocator {
     /// Module is in Cache
     Key(wasmer_cache::Hash),
-    /// Module is here
-    Bytes(Vec<u8>),
+    /// Module is here, maybe with its publisher's signature
+    Bytes(Vec<u8>, Option<PluginSignature>),
+}
+
+/// The host settings a plugin instance is set up with, which are passed on to
+/// the plugins it calls.
+#[derive(Clone)]
+pub struct PluginConfig {
+    /// the directory of the module cache
+    pub path: PathBuf,
+    /// the emulator for CTV
+    pub emulator: Arc<dyn CTVEmulator>,
+    /// the network contracts are compiled for
+    pub net: bitcoin::Network,
+    /// the names plugins may look other plugins up by
+    pub plugin_map: BTreeMap<Vec<u8>, [u8; 32]>,
+    /// the limits each invocation runs under
+    pub limits: PluginLimits,
+    /// the publishers trusted to run plugins
+    pub trust: Arc<TrustStore>,
+    /// the lockfile every plugin run must agree with, if any
+    pub lock: Option<Arc<PluginLockfile>>,
+    /// answers queries from plugins, by default with no chain data or oracles
+    pub queries: Arc<HostQueries>,
+    /// where log records from plugins go, from the moment their entry point
+    /// runs
+    pub log: Arc<dyn LogSink>,
 }
 
 /// A handle that holds a WASM Module instance
@@ -64,6 +117,14 @@ pub struct WasmPluginHandle<Output> {
     instance: Instance,
     key: wasmer_cache::Hash,
     net: bitcoin::Network,
+    /// the limits each invocation runs under
+    pub(crate) limits: PluginLimits,
+    /// the pool of plugins called from this one, if this handle owns it
+    pool: Option<SharedModulePool>,
+    /// the state to restore when reusing the instance
+    snapshot: Option<InstanceSnapshot>,
+    /// the trusted publisher who signed this plugin, if any
+    publisher: Option<VerifiedPublisher>,
     _pd: PhantomData<Output>,
     /// reference to allocation creation function
     pub sapio_v1_wasm_plugin_client_allocate_bytes: TypedFunction<i32, i32>,
@@ -73,6 +134,9 @@ pub struct WasmPluginHandle<Output> {
     pub sapio_v1_wasm_plugin_client_get_name: TypedFunction<(), i32>,
     /// reference to get_logo function
     pub sapio_v1_wasm_plugin_client_get_logo: TypedFunction<(), i32>,
+    /// reference to get_manifest function, absent in plugins built before
+    /// manifests were added
+    pub sapio_v1_wasm_plugin_client_get_manifest: Option<TypedFunction<(), i32>>,
     /// reference to allocation drop function
     pub sapio_v1_wasm_plugin_client_drop_allocation: TypedFunction<i32, ()>,
     /// reference to create function
@@ -85,16 +149,27 @@ impl<T> WasmPluginHandle<T> {
     /// Clone with a new memory space/instance
     pub fn fresh_clone(&self) -> Result<Self, Box<dyn Error>> {
         let env = self.env.as_ref(&self.store);
-        Ok(Self::setup_plugin_inner(
-            Store::default(),
-            env.path.clone(),
-            env.this,
-            Some(env.module_map.clone()),
-            self.net,
-            &env.emulator,
+        let config = PluginConfig {
+            path: env.path.clone(),
+            emulator: env.emulator.clone(),
+            net: self.net,
+            plugin_map: env.module_map.clone(),
+            limits: self.limits,
+            trust: env.trust.clone(),
+            lock: env.lock.clone(),
+            queries: env.queries.clone(),
+            log: env.log.clone(),
+        };
+        // modules may only be instantiated with the engine which compiled them
+        let mut handle = Self::setup_plugin_inner(
+            Store::new(self.store.engine().clone()),
             self.module.clone(),
             self.key,
-        )?)
+            env.pool.clone(),
+            config,
+        )?;
+        handle.publisher = self.publisher.clone();
+        Ok(handle)
     }
 }
 impl<Output> WasmPluginHandle<Output> {
@@ -103,60 +178,132 @@ impl<Output> WasmPluginHandle<Output> {
         self.key
     }
 
-    /// load all the cached keys as plugins upfront.
-    pub fn load_all_keys<I: Into<PathBuf> + Clone>(
-        path: I,
-        emulator: NullEmulator,
-        net: bitcoin::Network,
-        plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
-    ) -> Result<Vec<Self>, Box<dyn Error>> {
+    /// the trusted publisher who signed this plugin, if any
+    pub fn publisher(&self) -> Option<&VerifiedPublisher> {
+        self.publisher.as_ref()
+    }
+
+    /// remember who published this plugin, and log the trust store's warning
+    /// about running it, if any
+    fn trusted(&mut self, decision: TrustDecision) {
+        if let Some(warning) = decision.warning {
+            let mut record = LogRecord::new(LogLevel::Warn, "trust", &warning);
+            record.module = Some(self.key.to_string());
+            self.env.as_ref(&self.store).log.log(&record);
+        }
+        self.publisher = decision.publisher;
+    }
+
+    /// load all the cached keys as plugins upfront, skipping plugins the trust
+    /// store refuses to run.
+    pub fn load_all_keys(config: PluginConfig) -> Result<Vec<Self>, Box<dyn Error>> {
         let mut r = vec![];
-        for key in get_all_keys_from_fs(path.clone())? {
+        for key in get_all_keys_from_fs(config.path.clone())? {
             let wph = Self::new(
-                path.clone(),
-                &emulator,
                 SyncModuleLocator::Key(WASMCacheID::from_str(&key)?),
-                net,
-                plugin_map.clone(),
-            )?;
-            r.push(wph)
+                config.clone(),
+            );
+            match wph {
+                Ok(wph) => r.push(wph),
+                Err(e) if matches!(e.downcast_ref(), Some(TrustError::Refused(_))) => {}
+                Err(e) => return Err(e),
+            }
         }
         Ok(r)
     }
 
     /// Create a new module using async module resolution
-    pub async fn new_async<I: Into<PathBuf> + Clone>(
-        path: I,
-        emulator: &Arc<dyn CTVEmulator>,
+    pub async fn new_async(
         module_locator: ModuleLocator,
-        net: bitcoin::Network,
-        plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+        config: PluginConfig,
     ) -> Result<Self, Box<dyn Error>> {
-        Self::new(
-            path,
-            emulator,
-            module_locator.locate().await?,
-            net,
-            plugin_map,
-        )
+        Self::new(module_locator.locate().await?, config)
     }
     /// Create an plugin handle. Only one of key or file should be set, and one
     /// should be set.
     /// TODO: Revert to async?
-    pub fn new<I: Into<PathBuf> + Clone>(
-        path: I,
-        emulator: &Arc<dyn CTVEmulator>,
+    pub fn new(
         module_locator: SyncModuleLocator,
-        net: bitcoin::Network,
-        plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+        config: PluginConfig,
     ) -> Result<Self, Box<dyn Error>> {
-        let store = Store::default();
+        let store = config.limits.store();
+
+        let (module, key) = load_module_from_cache(module_locator, &config.path, &store)?;
+        let trusted = check_trust(&config.path, key, &config.trust)?;
+        let pool = ModulePool::shared();
+        pool.lock().map_err(|e| e.to_string())?.add_module(
+            key,
+            module.clone(),
+            store.engine().clone(),
+        );
 
-        let (module, key) = load_module_from_cache(module_locator, &path, &store)?;
+        let mut handle =
+            Self::setup_plugin_inner(store, module, key, Arc::downgrade(&pool), config)?;
+        handle.pool = Some(pool);
+        handle.trusted(trusted);
+        Ok(handle)
+    }
 
-        let mut this = [0; 32];
-        this.clone_from_slice(&hex::decode(key.to_string())?);
-        Self::setup_plugin_inner(store, path, this, plugin_map, net, emulator, module, key)
+    /// Restore the instance to how it was just after its entry point ran,
+    /// returning if the instance can be reused.
+    ///
+    /// Memory can't shrink, so instances which have grown too much are not
+    /// reusable, nor are instances which were not snapshotted.
+    pub(crate) fn reset(&mut self) -> bool {
+        let memory = match (&self.snapshot, self.get_memory()) {
+            (Some(_), Ok(memory)) => memory.clone(),
+            _ => return false,
+        };
+        let snapshot = self.snapshot.as_ref().unwrap();
+        if memory.view(&self.store).size().0 > snapshot.pages() + MAX_RETAINED_GROWTH_PAGES
+            || !snapshot.restore(&mut self.store, &memory)
+        {
+            return false;
+        }
+        self.env.as_mut(&mut self.store).exceeded = None;
+        true
+    }
+
+    /// the fuel left for the current invocation
+    pub fn remaining_fuel(&mut self) -> u64 {
+        match self.env.as_ref(&self.store).fuel.clone() {
+            Some(fuel) => fuel.get(&mut self.store).unwrap_i64() as u64,
+            None => self.limits.fuel_budget(),
+        }
+    }
+
+    /// reset the fuel to the full budget, done at the start of each invocation
+    fn refuel(&mut self) -> Result<(), CompilationError> {
+        set_remaining_points(&mut self.store, &self.instance, self.limits.fuel_budget());
+        Ok(())
+    }
+
+    /// Convert a trap from the plugin into an error, preferring to report a
+    /// limit that was exceeded (by this plugin or one it called) over the trap.
+    fn runtime_error<F>(&mut self, e: RuntimeError, otherwise: F) -> CompilationError
+    where
+        F: FnOnce(Box<dyn Error>) -> CompilationError,
+    {
+        if let Some((key, limit)) = self.env.as_mut(&mut self.store).exceeded.take() {
+            return CompilationError::ModuleExceededLimit(key, limit);
+        }
+        if let MeteringPoints::Exhausted = get_remaining_points(&mut self.store, &self.instance) {
+            return CompilationError::ModuleExceededLimit(
+                self.key.to_string(),
+                ResourceLimit::Fuel(self.limits.fuel_budget()),
+            );
+        }
+        if let (Some(max), Ok(memory)) = (self.limits.max_memory_pages, self.get_memory()) {
+            // a failed memory.grow is not a trap, but it is the likely cause
+            // of one when the memory is already as large as allowed.
+            if memory.view(&self.store).size().0 >= max {
+                return CompilationError::ModuleExceededLimit(
+                    self.key.to_string(),
+                    ResourceLimit::MemoryPages(max),
+                );
+            }
+        }
+        otherwise(e.into())
     }
 
     /// forget an allocated pointer
@@ -224,24 +371,42 @@ impl<Output> WasmPluginHandle<Output> {
         Ok(v)
     }
 
-    fn setup_plugin_inner<I: Into<PathBuf> + Clone>(
+    fn setup_plugin_inner(
         mut store: Store,
-        path: I,
-        this: [u8; 32],
-        plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
-        net: bitcoin::Network,
-        emulator: &Arc<dyn CTVEmulator>,
         module: Module,
         key: WASMCacheID,
+        pool: WeakModulePool,
+        config: PluginConfig,
     ) -> Result<Self, Box<dyn Error>> {
+        let PluginConfig {
+            path,
+            emulator,
+            net,
+            plugin_map,
+            limits,
+            trust,
+            lock,
+            queries,
+            log,
+        } = config;
+        let mut this = [0; 32];
+        this.clone_from_slice(&hex::decode(key.to_string())?);
         let host_env = FunctionEnv::new(
             &mut store,
             HostEnvironmentInner {
-                path: path.into(),
+                path,
                 this,
-                module_map: plugin_map.unwrap_or_default(),
+                module_map: plugin_map,
                 net,
-                emulator: emulator.clone(),
+                emulator,
+                limits,
+                fuel: None,
+                exceeded: None,
+                pool,
+                trust,
+                lock: lock.clone(),
+                queries,
+                log,
                 memory: None,
                 sapio_v1_wasm_plugin_client_get_create_arguments: None,
                 sapio_v1_wasm_plugin_client_get_name: None,
@@ -269,6 +434,10 @@ impl<Output> WasmPluginHandle<Output> {
             sapio_v1_wasm_plugin_ctv_emulator_signer_for,
             sapio_v1_wasm_plugin_ctv_emulator_sign,
             sapio_v1_wasm_plugin_debug_log_string,
+            sapio_v1_wasm_plugin_log,
+            sapio_v1_wasm_plugin_chain_tip_height,
+            sapio_v1_wasm_plugin_chain_lookup_output,
+            sapio_v1_wasm_plugin_oracle_price,
             sapio_v1_wasm_plugin_create_contract,
             sapio_v1_wasm_plugin_get_api,
             sapio_v1_wasm_plugin_get_name,
@@ -281,6 +450,7 @@ impl<Output> WasmPluginHandle<Output> {
         let (data_mut, mut store_mut) = env_mut.data_and_store_mut();
 
         data_mut.memory = Some(instance.exports.get_memory("memory")?.clone());
+        data_mut.fuel = Some(instance.exports.get_global(REMAINING_FUEL_EXPORT)?.clone());
         macro_rules! create_exports {
         ($store:ident, $env:ident, $instance:ident $(,$names:ident)*) =>
         {
@@ -300,12 +470,6 @@ impl<Output> WasmPluginHandle<Output> {
             sapio_v1_wasm_plugin_entry_point
         );
 
-        data_mut
-            .sapio_v1_wasm_plugin_entry_point
-            .as_ref()
-            .ok_or("No Init Function Specified")?
-            .call(&mut store_mut)?;
-
         macro_rules! create_handle_exports {
         ($store:ident,  $instance:ident $(,$names:ident)*) =>
         {
@@ -323,45 +487,166 @@ impl<Output> WasmPluginHandle<Output> {
             sapio_v1_wasm_plugin_client_create,
             sapio_v1_wasm_plugin_entry_point
         );
-        Ok(WasmPluginHandle {
+        let sapio_v1_wasm_plugin_client_get_manifest = instance
+            .exports
+            .get_typed_function(&store_mut, "sapio_v1_wasm_plugin_client_get_manifest")
+            .ok();
+        let mut handle = WasmPluginHandle {
             sapio_v1_wasm_plugin_client_allocate_bytes,
             sapio_v1_wasm_plugin_client_get_create_arguments,
             sapio_v1_wasm_plugin_client_get_name,
             sapio_v1_wasm_plugin_client_get_logo,
+            sapio_v1_wasm_plugin_client_get_manifest,
             sapio_v1_wasm_plugin_client_drop_allocation,
             sapio_v1_wasm_plugin_client_create,
             sapio_v1_wasm_plugin_entry_point,
             env: env_mut.as_ref(),
             store,
             net,
+            limits,
+            pool: None,
+            snapshot: None,
+            publisher: None,
             module,
             instance,
             key,
             _pd: Default::default(),
-        })
+        };
+        handle.refuel()?;
+        let entry_point = handle.sapio_v1_wasm_plugin_entry_point.clone();
+        entry_point
+            .call(&mut handle.store)
+            .map_err(|e| handle.runtime_error(e, CompilationError::ModuleRuntimeError))?;
+        // every plugin run, however it was found, must agree with the lockfile
+        if let Some(lock) = lock {
+            match handle.manifest()? {
+                Some(manifest) => lock.check_plugin(&key, &manifest)?,
+                None => Err(LockError::MissingManifest(key.to_string()))?,
+            }
+        }
+        Ok(handle)
+    }
+
+    /// The plugin's manifest, or `None` if the plugin was built before
+    /// manifests were added
+    fn manifest(&mut self) -> Result<Option<PluginManifest>, CompilationError> {
+        let get_manifest = match self.sapio_v1_wasm_plugin_client_get_manifest.clone() {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        self.refuel()?;
+        let p = get_manifest
+            .call(&mut self.store)
+            .map_err(|e| self.runtime_error(e, CompilationError::ModuleCouldNotGetManifest))?;
+        let v = self.read_to_vec(p)?;
+        self.forget(p)?;
+        let manifest: Result<PluginManifest, String> =
+            serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)?;
+        manifest
+            .map(Some)
+            .map_err(|e| CompilationError::ModuleCouldNotGetManifest(e.into()))
+    }
+}
+impl WasmPluginHandle<serde_json::Value> {
+    /// Get an instance of a module for a call from another plugin, reusing an
+    /// idle instance or the compiled module from the pool if possible.
+    ///
+    /// The fuel budget of the returned handle is what is left of `limits.fuel`
+    /// after running the entry point, if it had to be run.
+    pub(crate) fn from_pool(
+        pool: &SharedModulePool,
+        key: WASMCacheID,
+        config: PluginConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let idle = pool.lock().map_err(|e| e.to_string())?.checkout(&key);
+        if let Some(mut handle) = idle {
+            handle.limits = config.limits;
+            return Ok(handle);
+        }
+        let cached = pool.lock().map_err(|e| e.to_string())?.module(&key);
+        let (module, store) = match cached {
+            Some((module, engine)) => (module, Store::new(engine)),
+            None => {
+                let store = config.limits.store();
+                let (module, _) =
+                    load_module_from_cache(SyncModuleLocator::Key(key), &config.path, &store)?;
+                pool.lock().map_err(|e| e.to_string())?.add_module(
+                    key,
+                    module.clone(),
+                    store.engine().clone(),
+                );
+                (module, store)
+            }
+        };
+        let trusted = check_trust(&config.path, key, &config.trust)?;
+        let mut handle =
+            Self::setup_plugin_inner(store, module, key, Arc::downgrade(pool), config)?;
+        handle.trusted(trusted);
+        let memory = handle.get_memory()?.clone();
+        handle.snapshot = Some(InstanceSnapshot::take(
+            &mut handle.store,
+            &handle.instance,
+            &memory,
+        )?);
+        if handle.limits.fuel.is_some() {
+            handle.limits.fuel = Some(handle.remaining_fuel());
+        }
+        Ok(handle)
     }
 }
+
 fn load_module_from_cache<I: Into<PathBuf> + Clone>(
     module_locator: SyncModuleLocator,
     path: &I,
     store: &Store,
 ) -> Result<(Module, WASMCacheID), Box<dyn Error>> {
     let (module, key) = match module_locator {
-        SyncModuleLocator::Bytes(wasm_bytes) => {
+        SyncModuleLocator::Bytes(wasm_bytes, signature) => {
+            if let Some(signature) = signature {
+                let key = WASMCacheID::generate(&wasm_bytes);
+                signature.verify(&bitcoin::secp256k1::Secp256k1::verification_only(), &key)?;
+                wasm_cache::store_signature(path.clone(), key, &signature)?;
+            }
             match wasm_cache::load_module(path.clone(), store, &wasm_bytes[..]) {
-                Ok(module) => module,
-                Err(_) => {
+                Ok(module) if is_metered(&module.0) => module,
+                // not cached, or cached before plugins were metered
+                _ => {
                     let module = Module::new(&store, &wasm_bytes)?;
                     let key = wasm_cache::store_module(path.clone(), &module, &wasm_bytes)?;
                     (module, key)
                 }
             }
         }
-        SyncModuleLocator::Key(key) => wasm_cache::load_module_key(path.clone(), store, key)?,
+        SyncModuleLocator::Key(key) => {
+            let (module, key) = wasm_cache::load_module_key(path.clone(), store, key)?;
+            if !is_metered(&module) {
+                return Err(format!(
+                    "Module {} was cached without fuel metering, load it again from its wasm file",
+                    key
+                )
+                .into());
+            }
+            (module, key)
+        }
     };
     Ok((module, key))
 }
 
+/// check a cached module's signature against the trust store
+fn check_trust<I: Into<PathBuf> + Clone>(
+    path: &I,
+    key: WASMCacheID,
+    trust: &TrustStore,
+) -> Result<TrustDecision, Box<dyn Error>> {
+    let signature = wasm_cache::load_signature(path.clone(), key)?;
+    Ok(trust.check(&key, signature.as_ref())?)
+}
+
+/// check that a module was compiled with fuel metering
+fn is_metered(module: &Module) -> bool {
+    module.exports().any(|e| e.name() == REMAINING_FUEL_EXPORT)
+}
+
 impl<GOutput> PluginHandle for WasmPluginHandle<GOutput>
 where
     GOutput: for<'a> Deserialize<'a>,
@@ -373,52 +658,499 @@ where
         path: &EffectPath,
         c: &Self::Input,
     ) -> Result<Self::Output, CompilationError> {
+        self.refuel()?;
         let arg_str = serde_json::to_string(c).map_err(CompilationError::SerializationError)?;
         let args_ptr = self.pass_string(&arg_str)?;
         let path_str = serde_json::to_string(path).map_err(CompilationError::SerializationError)?;
         let path_ptr = self.pass_string(&path_str)?;
-        let _env = self.env.as_mut(&mut self.store);
-        let create_func = { &self.sapio_v1_wasm_plugin_client_create };
+        let create_func = self.sapio_v1_wasm_plugin_client_create.clone();
         let result_ptr = create_func
             .call(&mut self.store, path_ptr, args_ptr)
             .map_err(|e| {
-                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
+                self.runtime_error(e, |e| {
+                    CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e)
+                })
             })?;
         let buf = self.read_to_vec(result_ptr)?;
         self.forget(result_ptr)?;
-        let v: Result<Self::Output, String> =
+        let v: Result<serde_json::Value, String> =
             serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
-        v.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+        let mut v = v.map_err(CompilationError::ModuleCompilationErrorUnsendable)?;
+        // record who published the plugin in the compiled contract's metadata,
+        // never trusting a publisher the plugin claims for itself
+        if let Some(metadata) = v
+            .get_mut("metadata")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            metadata.remove(VerifiedPublisher::METADATA_KEY);
+            if let Some(publisher) = &self.publisher {
+                metadata.insert(
+                    VerifiedPublisher::METADATA_KEY.into(),
+                    serde_json::to_value(publisher)
+                        .map_err(CompilationError::SerializationError)?,
+                );
+            }
+        }
+        // and the answers to every query made of the host, never trusting a
+        // record the plugin made itself
+        let record = self.env.as_ref(&self.store).queries.record();
+        if let Some(metadata) = v
+            .get_mut("metadata")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            metadata.remove(QUERY_RECORD_METADATA_KEY);
+            if !record.is_empty() {
+                metadata.insert(
+                    QUERY_RECORD_METADATA_KEY.into(),
+                    serde_json::to_value(record).map_err(CompilationError::SerializationError)?,
+                );
+            }
+        }
+        serde_json::from_value(v).map_err(CompilationError::DeserializationError)
     }
     fn get_api(&mut self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
-        let _env = self.env.as_mut(&mut self.store);
+        self.refuel()?;
         let p = self
             .sapio_v1_wasm_plugin_client_get_create_arguments
+            .clone()
             .call(&mut self.store)
-            .map_err(|e| CompilationError::ModuleCouldNotGetAPI(e.into()))?;
+            .map_err(|e| self.runtime_error(e, CompilationError::ModuleCouldNotGetAPI))?;
         let v = self.read_to_vec(p)?;
         self.forget(p)?;
         serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)
     }
     fn get_name(&mut self) -> Result<String, CompilationError> {
-        let _env = self.env.as_mut(&mut self.store);
+        self.refuel()?;
         let p = self
             .sapio_v1_wasm_plugin_client_get_name
+            .clone()
             .call(&mut self.store)
-            .map_err(|e| CompilationError::ModuleCouldNotGetName(e.into()))?;
+            .map_err(|e| self.runtime_error(e, CompilationError::ModuleCouldNotGetName))?;
         let v = self.read_to_vec(p)?;
         self.forget(p)?;
         Ok(String::from_utf8_lossy(&v).to_string())
     }
 
     fn get_logo(&mut self) -> Result<String, CompilationError> {
-        let _env = self.env.as_mut(&mut self.store);
+        self.refuel()?;
         let p = self
             .sapio_v1_wasm_plugin_client_get_logo
+            .clone()
             .call(&mut self.store)
-            .map_err(|e| CompilationError::ModuleCouldNotGetLogo(e.into()))?;
+            .map_err(|e| self.runtime_error(e, CompilationError::ModuleCouldNotGetLogo))?;
         let v = self.read_to_vec(p)?;
         self.forget(p)?;
         Ok(String::from_utf8_lossy(&v).to_string())
     }
+
+    /// The plugin's manifest, or `None` if the plugin was built before
+    /// manifests were added
+    fn get_manifest(&mut self) -> Result<Option<PluginManifest>, CompilationError> {
+        self.manifest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Weak;
+    use wasmer::Value;
+
+    /// a module exporting everything the host needs, with `entry` as the body
+    /// of its entry point and `extra` added to the module. Creating a contract
+    /// returns the string at 3072.
+    fn plugin_wat(entry: &str, extra: &str) -> String {
+        format!(
+            r#"(module
+  (memory (export "memory") 1)
+  (data (i32.const 2048) "name\00")
+  (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) (i32.const 2048))
+  (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+  (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32) (i32.const 3072))
+  (func (export "sapio_v1_wasm_plugin_entry_point") {})
+  {})"#,
+            entry, extra
+        )
+    }
+
+    fn config(limits: PluginLimits) -> PluginConfig {
+        PluginConfig {
+            path: std::env::temp_dir(),
+            emulator: Arc::new(CTVAvailable),
+            net: bitcoin::Network::Regtest,
+            plugin_map: Default::default(),
+            limits,
+            trust: Default::default(),
+            lock: None,
+            queries: Default::default(),
+            log: Arc::new(crate::host::log::StderrSink(LogLevel::Debug)),
+        }
+    }
+
+    fn instantiate(
+        wat: &str,
+        limits: PluginLimits,
+    ) -> Result<WasmPluginHandle<serde_json::Value>, Box<dyn Error>> {
+        instantiate_with(wat, config(limits))
+    }
+
+    fn instantiate_with(
+        wat: &str,
+        config: PluginConfig,
+    ) -> Result<WasmPluginHandle<serde_json::Value>, Box<dyn Error>> {
+        let store = config.limits.store();
+        let module = Module::new(&store, wat)?;
+        let key = WASMCacheID::generate(wat.as_bytes());
+        WasmPluginHandle::setup_plugin_inner(store, module, key, Weak::new(), config)
+    }
+
+    /// a plugin whose manifest export returns `manifest`, as JSON escaped
+    /// for a WAT string
+    fn plugin_with_manifest(manifest: &str) -> String {
+        plugin_wat(
+            "",
+            &format!(
+                r#"(data (i32.const 4096) "{}\00")
+  (func (export "sapio_v1_wasm_plugin_client_get_manifest") (result i32) (i32.const 4096))"#,
+                manifest
+            ),
+        )
+    }
+
+    /// a lockfile locking the plugin `locked` at 0.1.0 to `key`
+    fn lock(key: String) -> Option<Arc<PluginLockfile>> {
+        let mut lock = PluginLockfile::default();
+        lock.plugins.insert(
+            "locked".into(),
+            crate::host::lockfile::LockedPlugin {
+                key,
+                version: semver::Version::new(0, 1, 0),
+                dependencies: Default::default(),
+            },
+        );
+        Some(Arc::new(lock))
+    }
+
+    fn exceeded(e: Box<dyn Error>) -> ResourceLimit {
+        match e.downcast::<CompilationError>().map(|e| *e) {
+            Ok(CompilationError::ModuleExceededLimit(_, limit)) => limit,
+            other => panic!("expected an exceeded limit, got {:?}", other),
+        }
+    }
+
+    /// call an exported test function of the plugin
+    fn call(handle: &mut WasmPluginHandle<serde_json::Value>, name: &str, args: &[Value]) -> i32 {
+        let f = handle.instance.exports.get_function(name).unwrap().clone();
+        match f.call(&mut handle.store, args).unwrap().first() {
+            Some(Value::I32(v)) => *v,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn infinite_loop_runs_out_of_fuel() {
+        let limits = PluginLimits {
+            fuel: Some(100_000),
+            ..PluginLimits::default()
+        };
+        let e = instantiate(&plugin_wat("(loop $l (br $l))", ""), limits)
+            .err()
+            .expect("an infinite loop can't finish");
+        assert!(matches!(exceeded(e), ResourceLimit::Fuel(100_000)));
+    }
+
+    #[test]
+    fn memory_grow_past_the_cap_fails() {
+        let limits = PluginLimits {
+            max_memory_pages: Some(2),
+            ..PluginLimits::default()
+        };
+        let grow = "(if (i32.eq (memory.grow (i32.const 1)) (i32.const -1)) (then unreachable))";
+        // growing to the cap works, growing past it returns -1
+        let e = instantiate(&plugin_wat(&format!("{} {}", grow, grow), ""), limits)
+            .err()
+            .expect("memory can't grow past the cap");
+        assert!(matches!(exceeded(e), ResourceLimit::MemoryPages(2)));
+        assert!(instantiate(&plugin_wat(grow, ""), limits).is_ok());
+    }
+
+    #[test]
+    fn plugin_cannot_claim_a_publisher() {
+        let created =
+            r#"(data (i32.const 3072) "{\"Ok\":{\"metadata\":{\"plugin_publisher\":\"me\"}}}\00")"#;
+        let mut handle = instantiate(&plugin_wat("", created), PluginLimits::default()).unwrap();
+        let args = CreateArgs {
+            arguments: serde_json::Value::Null,
+            context: sapio_base::plugin_args::ContextualArguments {
+                network: bitcoin::Network::Regtest,
+                amount: bitcoin::Amount::ZERO,
+                effects: Default::default(),
+                ordinals_info: None,
+            },
+        };
+        let path = sapio_base::effects::PathFragment::Root.into();
+        let v = handle.call(&path, &args).unwrap();
+        assert_eq!(v["metadata"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn entry_point_logs_go_to_the_configured_sink() {
+        let record = r#"{"level":"info","target":"plugin","message":"starting"}"#;
+        let wat = format!(
+            r#"(module
+  (import "env" "sapio_v1_wasm_plugin_log" (func $log (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "{}")
+  (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+  (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_entry_point") (call $log (i32.const 1024) (i32.const {}))))"#,
+            record.replace('"', "\\\""),
+            record.len()
+        );
+        let logs = Arc::new(crate::host::log::LogCollector::default());
+        let mut config = config(PluginLimits::default());
+        config.log = logs.clone();
+        let handle = instantiate_with(&wat, config).unwrap();
+        let logged = logs.take();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].message, "starting");
+        assert_eq!(logged[0].module, Some(handle.id().to_string()));
+
+        // plugins set up from this one log to the same sink
+        handle.fresh_clone().unwrap();
+        assert_eq!(logs.take().len(), 1);
+    }
+
+    #[test]
+    fn logs_outside_plugin_memory_are_dropped() {
+        let record = r#"{"level":"info","target":"plugin","message":"valid"}"#;
+        let wat = format!(
+            r#"(module
+  (import "env" "sapio_v1_wasm_plugin_log" (func $log (param i32 i32)))
+  (import "env" "sapio_v1_wasm_plugin_debug_log_string" (func $debug (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "{}")
+  (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+  (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_entry_point")
+    (call $log (i32.const 1024) (i32.const -1))
+    (call $log (i32.const 1024) (i32.const 0x7fffffff))
+    (call $debug (i32.const 1024) (i32.const -1))
+    (call $debug (i32.const -1) (i32.const 16))
+    (call $log (i32.const 1024) (i32.const {}))))"#,
+            record.replace('"', "\\\""),
+            record.len()
+        );
+        let logs = Arc::new(crate::host::log::LogCollector::default());
+        let mut config = config(PluginLimits::default());
+        config.log = logs.clone();
+        instantiate_with(&wat, config).unwrap();
+        let logged = logs.take();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].message, "valid");
+    }
+
+    #[test]
+    fn failing_to_load_a_called_plugin_is_an_error_result() {
+        let args = CreateArgs {
+            arguments: serde_json::Value::Null,
+            context: sapio_base::plugin_args::ContextualArguments {
+                network: bitcoin::Network::Regtest,
+                amount: bitcoin::Amount::ZERO,
+                effects: Default::default(),
+                ordinals_info: None,
+            },
+        };
+        let path: EffectPath = sapio_base::effects::PathFragment::Root.into();
+        let path_json = serde_json::to_string(&path).unwrap();
+        let args_json = serde_json::to_string(&args).unwrap();
+        // calls the plugin whose key is all zeros, which doesn't exist
+        let wat = format!(
+            r#"(module
+  (import "env" "sapio_v1_wasm_plugin_create_contract" (func $create (param i32 i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 1024) "{}")
+  (data (i32.const 2048) "{}")
+  (global $next (mut i32) (i32.const 8192))
+  (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param $n i32) (result i32)
+    global.get $next
+    global.get $next
+    local.get $n
+    i32.const 1
+    i32.add
+    i32.add
+    global.set $next)
+  (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) (i32.const 0))
+  (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+  (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32)
+    (call $create (i32.const 1024) (i32.const {}) (i32.const 512) (i32.const 2048) (i32.const {})))
+  (func (export "sapio_v1_wasm_plugin_entry_point")))"#,
+            path_json.replace('"', "\\\""),
+            args_json.replace('"', "\\\""),
+            path_json.len(),
+            args_json.len()
+        );
+        let mut handle = instantiate(&wat, PluginLimits::default()).unwrap();
+        // the calling plugin is told why, rather than getting a null pointer
+        match handle.call(&path, &args) {
+            Err(CompilationError::ModuleCompilationErrorUnsendable(e)) => {
+                assert!(e.contains("No such file"), "{}", e)
+            }
+            r => panic!("expected the load error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn plugin_cannot_forge_host_queries() {
+        let created =
+            r#"(data (i32.const 3072) "{\"Ok\":{\"metadata\":{\"host_queries\":[]}}}\00")"#;
+        let args = CreateArgs {
+            arguments: serde_json::Value::Null,
+            context: sapio_base::plugin_args::ContextualArguments {
+                network: bitcoin::Network::Regtest,
+                amount: bitcoin::Amount::ZERO,
+                effects: Default::default(),
+                ordinals_info: None,
+            },
+        };
+        let path = sapio_base::effects::PathFragment::Root.into();
+        let mut handle = instantiate(&plugin_wat("", created), PluginLimits::default()).unwrap();
+        let v = handle.call(&path, &args).unwrap();
+        assert_eq!(v["metadata"], serde_json::json!({}));
+
+        // the host's own record replaces the plugin's
+        let record = vec![crate::host_query::AnsweredQuery {
+            query: crate::host_query::HostQuery::TipHeight,
+            answer: crate::host_query::HostAnswer::TipHeight(1),
+        }];
+        let mut config = config(PluginLimits::default());
+        config.queries = Arc::new(HostQueries::default().replaying(record.clone()));
+        let mut handle = instantiate_with(&plugin_wat("", created), config).unwrap();
+        let v = handle.call(&path, &args).unwrap();
+        assert_eq!(
+            v["metadata"][QUERY_RECORD_METADATA_KEY],
+            serde_json::to_value(record).unwrap()
+        );
+    }
+
+    #[test]
+    fn fuel_resets_per_call() {
+        let limits = PluginLimits {
+            fuel: Some(100_000),
+            ..PluginLimits::default()
+        };
+        let mut handle = instantiate(&plugin_wat("", ""), limits).unwrap();
+        assert_eq!(handle.get_name().unwrap(), "name");
+        let left = handle.remaining_fuel();
+        assert!(left < 100_000);
+        for _ in 0..3 {
+            handle.get_name().unwrap();
+            assert_eq!(handle.remaining_fuel(), left);
+        }
+    }
+
+    #[test]
+    fn reused_instance_sees_clean_state() {
+        let state = r#"
+  (global $g (mut i32) (i32.const 7))
+  (table $t 1 funcref)
+  (elem (i32.const 0) $noop)
+  (data (i32.const 1024) "\2a")
+  (func $noop)
+  (func (export "dirty")
+    (global.set $g (i32.const 8))
+    (i32.store8 (i32.const 1024) (i32.const 0))
+    (drop (memory.grow (i32.const 1)))
+    (i32.store8 (i32.const 65536) (i32.const 1))
+    (table.set $t (i32.const 0) (ref.null func)))
+  (func (export "global") (result i32) (global.get $g))
+  (func (export "cell") (param i32) (result i32) (i32.load8_u (local.get 0)))
+  (func (export "slot_is_null") (result i32) (ref.is_null (table.get $t (i32.const 0))))"#;
+        let wat = plugin_wat("", state);
+        let limits = PluginLimits::default();
+        let key = WASMCacheID::generate(wat.as_bytes());
+        let pool = ModulePool::shared();
+        let store = limits.store();
+        let module = Module::new(&store, &wat).unwrap();
+        pool.lock()
+            .unwrap()
+            .add_module(key, module, store.engine().clone());
+
+        let mut handle = WasmPluginHandle::from_pool(&pool, key, config(limits)).unwrap();
+        call(&mut handle, "dirty", &[]);
+        assert_eq!(call(&mut handle, "global", &[]), 8);
+        assert_eq!(call(&mut handle, "cell", &[Value::I32(1024)]), 0);
+        assert_eq!(call(&mut handle, "cell", &[Value::I32(65536)]), 1);
+        assert_eq!(call(&mut handle, "slot_is_null", &[]), 1);
+        pool.lock().unwrap().checkin(handle);
+        assert_eq!(pool.lock().unwrap().idle_count(&key), 1);
+
+        let mut handle = WasmPluginHandle::from_pool(&pool, key, config(limits)).unwrap();
+        assert_eq!(pool.lock().unwrap().idle_count(&key), 0);
+        assert_eq!(call(&mut handle, "global", &[]), 7);
+        assert_eq!(call(&mut handle, "cell", &[Value::I32(1024)]), 0x2a);
+        // the grown page can't be dropped, but it is zeroed
+        assert_eq!(call(&mut handle, "cell", &[Value::I32(65536)]), 0);
+        assert_eq!(call(&mut handle, "slot_is_null", &[]), 0);
+    }
+
+    #[test]
+    fn lockfile_is_checked_on_instantiation() {
+        let wat = plugin_with_manifest(r#"{\"Ok\":{\"name\":\"locked\",\"version\":\"0.1.0\"}}"#);
+        let key = WASMCacheID::generate(wat.as_bytes());
+        let mut config = config(PluginLimits::default());
+        config.lock = lock(key.to_string());
+        assert!(instantiate_with(&wat, config.clone()).is_ok());
+
+        config.lock = lock("00".repeat(32));
+        let e = instantiate_with(&wat, config)
+            .err()
+            .expect("the name is locked to another module");
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(crate::host::lockfile::LockError::WrongModule { .. })
+        ));
+    }
+
+    #[test]
+    fn plugins_without_a_manifest_are_refused_when_locked() {
+        let wat = plugin_wat("", "");
+        let key = WASMCacheID::generate(wat.as_bytes());
+        let mut config = config(PluginLimits::default());
+        assert!(instantiate_with(&wat, config.clone()).is_ok());
+
+        // even if its key is the one locked
+        config.lock = lock(key.to_string());
+        let e = instantiate_with(&wat, config)
+            .err()
+            .expect("the plugin can't be checked");
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(LockError::MissingManifest(k)) if *k == key.to_string()
+        ));
+    }
+
+    #[test]
+    fn invalid_manifest_is_an_error() {
+        let wat = plugin_with_manifest(r#"{\"Err\":\"invalid version requirement\"}"#);
+        let mut handle = instantiate(&wat, PluginLimits::default()).unwrap();
+        assert!(matches!(
+            handle.get_manifest(),
+            Err(CompilationError::ModuleCouldNotGetManifest(_))
+        ));
+    }
 }
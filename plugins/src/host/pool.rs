@@ -0,0 +1,210 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a pool of compiled modules and idle instances for plugins called by other
+//! plugins, so that composing plugins does not reload and reinstantiate a
+//! module for every call.
+use super::plugin_handle::{WASMCacheID, WasmPluginHandle};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use wasmer::{
+    AsStoreMut, Engine, ExportIndex, Extern, FunctionMiddleware, Global, Instance,
+    LocalFunctionIndex, Memory, MemoryAccessError, MiddlewareError, Module, ModuleMiddleware,
+    Mutability, Table, Type, Value,
+};
+use wasmer_types::ModuleInfo;
+
+/// How many idle instances of one module are kept
+pub const MAX_IDLE_INSTANCES: usize = 4;
+/// How many pages an instance's memory may grow past its initial snapshot
+/// before it is discarded instead of reused (memory can't shrink, and pages
+/// grown before a reset are never reused by the plugin's allocator).
+pub const MAX_RETAINED_GROWTH_PAGES: u32 = 256;
+
+/// The granularity at which memory is compared to its snapshot and restored
+const RESET_CHUNK: usize = 4096;
+/// The prefix of the exports added for a module's globals and tables
+const STATE_EXPORT_PREFIX: &str = "sapio_v1_state_";
+
+/// A pool shared by a plugin handle and every plugin called from it
+pub type SharedModulePool = Arc<Mutex<ModulePool>>;
+/// The reference a plugin environment holds to its pool. This is weak as the
+/// pool holds the idle instances (and so their environments).
+pub type WeakModulePool = Weak<Mutex<ModulePool>>;
+
+/// Compiled modules and idle instances, keyed by module.
+///
+/// Instances are reset to their state as of just after their entry point ran
+/// before being pooled, so every call sees a freshly initialized plugin.
+#[derive(Default)]
+pub struct ModulePool {
+    modules: HashMap<WASMCacheID, (Module, Engine)>,
+    idle: HashMap<WASMCacheID, Vec<WasmPluginHandle<serde_json::Value>>>,
+}
+
+impl ModulePool {
+    /// create a new, empty, shared pool
+    pub fn shared() -> SharedModulePool {
+        Arc::new(Mutex::new(ModulePool::default()))
+    }
+
+    /// get a compiled module, and the engine it must be instantiated with,
+    /// if it has been seen before
+    pub fn module(&self, key: &WASMCacheID) -> Option<(Module, Engine)> {
+        self.modules.get(key).cloned()
+    }
+
+    /// remember a compiled module and the engine it was compiled with
+    pub fn add_module(&mut self, key: WASMCacheID, module: Module, engine: Engine) {
+        self.modules.entry(key).or_insert((module, engine));
+    }
+
+    /// take an idle instance of a module, if there is one
+    pub fn checkout(&mut self, key: &WASMCacheID) -> Option<WasmPluginHandle<serde_json::Value>> {
+        self.idle.get_mut(key).and_then(Vec::pop)
+    }
+
+    /// Return an instance to the pool after resetting it. Instances which
+    /// can't be reset, or which aren't needed, are dropped.
+    pub fn checkin(&mut self, mut handle: WasmPluginHandle<serde_json::Value>) {
+        let idle = self.idle.entry(handle.id()).or_default();
+        if idle.len() < MAX_IDLE_INSTANCES && handle.reset() {
+            idle.push(handle)
+        }
+    }
+
+    /// the number of idle instances of a module
+    pub fn idle_count(&self, key: &WASMCacheID) -> usize {
+        self.idle.get(key).map(Vec::len).unwrap_or(0)
+    }
+}
+
+/// Module middleware which exports every mutable global and every table of a
+/// module, so that an [`InstanceSnapshot`] covers state the module keeps
+/// private (e.g. the stack pointer of a Rust plugin).
+#[derive(Debug)]
+pub(crate) struct ExportInstanceState;
+
+impl ModuleMiddleware for ExportInstanceState {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(PassThrough)
+    }
+
+    fn transform_module_info(&self, info: &mut ModuleInfo) -> Result<(), MiddlewareError> {
+        let globals: Vec<_> = info
+            .globals
+            .iter()
+            .filter(|(_, ty)| ty.mutability == Mutability::Var)
+            .map(|(index, _)| index)
+            .collect();
+        for index in globals {
+            info.exports.insert(
+                format!("{}global_{}", STATE_EXPORT_PREFIX, index.as_u32()),
+                ExportIndex::Global(index),
+            );
+        }
+        let tables: Vec<_> = info.tables.keys().collect();
+        for index in tables {
+            info.exports.insert(
+                format!("{}table_{}", STATE_EXPORT_PREFIX, index.as_u32()),
+                ExportIndex::Table(index),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct PassThrough;
+impl FunctionMiddleware for PassThrough {}
+
+/// The state of an instance just after its entry point ran: its memory, and
+/// the globals and tables exported by [`ExportInstanceState`].
+pub(crate) struct InstanceSnapshot {
+    memory: Vec<u8>,
+    globals: Vec<(Global, Value)>,
+    tables: Vec<(Table, Vec<Option<Value>>)>,
+}
+
+impl InstanceSnapshot {
+    /// snapshot an instance's memory, mutable globals and tables
+    pub(crate) fn take(
+        store: &mut impl AsStoreMut,
+        instance: &Instance,
+        memory: &Memory,
+    ) -> Result<Self, MemoryAccessError> {
+        let memory = memory.view(store).copy_to_vec()?;
+        let mut globals = vec![];
+        let mut tables = vec![];
+        for (_, export) in instance.exports.iter() {
+            match export {
+                Extern::Global(g) if g.ty(store).mutability == Mutability::Var => {
+                    globals.push((g.clone(), g.get(store)))
+                }
+                Extern::Table(t) => {
+                    let elements = (0..t.size(store)).map(|i| t.get(store, i)).collect();
+                    tables.push((t.clone(), elements))
+                }
+                _ => {}
+            }
+        }
+        Ok(InstanceSnapshot {
+            memory,
+            globals,
+            tables,
+        })
+    }
+
+    /// the size of the snapshotted memory in pages
+    pub(crate) fn pages(&self) -> u32 {
+        (self.memory.len() / wasmer::WASM_PAGE_SIZE) as u32
+    }
+
+    /// Restore an instance to the snapshot, returning if it could be.
+    ///
+    /// Memory can't shrink, so pages grown since the snapshot are zeroed as
+    /// they were when first grown. Only the chunks of memory which differ
+    /// from the snapshot are written back.
+    pub(crate) fn restore(&self, store: &mut impl AsStoreMut, memory: &Memory) -> bool {
+        let view = memory.view(store);
+        let size = view.data_size() as usize;
+        let zeros = [0u8; RESET_CHUNK];
+        let mut current = [0u8; RESET_CHUNK];
+        for start in (0..size).step_by(RESET_CHUNK) {
+            let end = std::cmp::min(start + RESET_CHUNK, size);
+            let clean = self.memory.get(start..end).unwrap_or(&zeros[..end - start]);
+            let current = &mut current[..end - start];
+            if view.read(start as u64, current).is_err() {
+                return false;
+            }
+            if current != clean && view.write(start as u64, clean).is_err() {
+                return false;
+            }
+        }
+        for (global, value) in &self.globals {
+            if global.set(store, value.clone()).is_err() {
+                return false;
+            }
+        }
+        for (table, elements) in &self.tables {
+            for index in 0..table.size(store) {
+                let element = match elements.get(index as usize) {
+                    Some(Some(element)) => element.clone(),
+                    // grown since the snapshot
+                    _ => match table.ty(store).ty {
+                        Type::FuncRef => Value::FuncRef(None),
+                        Type::ExternRef => Value::ExternRef(None),
+                        _ => return false,
+                    },
+                };
+                if table.set(store, index, element).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! answering the read-only queries plugins make of the host.
+//!
+//! A [`HostQueries`] is shared by a plugin and every plugin called from it,
+//! so the same query always gets the same answer within a compilation.
+use crate::host_query::{AnsweredQuery, HostAnswer, HostQuery, QueryRecord};
+use bitcoin::{OutPoint, TxOut};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of chain data for plugins
+pub trait ChainData: Send + Sync {
+    /// the height of the chain tip
+    fn tip_height(&self) -> Result<u32, String>;
+    /// look up an output
+    fn lookup_output(&self, out: &OutPoint) -> Result<TxOut, String>;
+}
+
+/// A source of prices for plugins
+pub trait PriceOracle: Send + Sync {
+    /// the current price of `symbol`
+    fn price(&self, symbol: &str) -> Result<serde_json::Value, String>;
+}
+
+/// the most an oracle may print on stdout. Only this much of stderr is kept.
+pub const MAX_ORACLE_OUTPUT: u64 = 1024 * 1024;
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+/// An oracle which is a program, run once per query with the symbol as its
+/// last argument, which prints the price as JSON on stdout.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct CommandOracle {
+    /// the program to run
+    pub command: String,
+    /// arguments to pass to the program, before the symbol
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// how long to wait for the program, after which it is killed
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl CommandOracle {
+    /// An oracle run as `command args... symbol` with the default timeout
+    pub fn new(command: impl Into<String>, args: Vec<String>) -> Self {
+        CommandOracle {
+            command: command.into(),
+            args,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+/// reads up to [`MAX_ORACLE_OUTPUT`] + 1 bytes of `r` on another thread, then
+/// discards the rest so the program is never blocked writing.
+fn read_bounded(mut r: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = (&mut r).take(MAX_ORACLE_OUTPUT + 1).read_to_end(&mut buf);
+        let _ = tx.send(buf);
+        let _ = std::io::copy(&mut r, &mut std::io::sink());
+    });
+    rx
+}
+
+impl PriceOracle for CommandOracle {
+    fn price(&self, symbol: &str) -> Result<serde_json::Value, String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .arg(symbol)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let stdout = read_bounded(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_bounded(child.stderr.take().expect("stderr is piped"));
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let start = Instant::now();
+        let stop = |child: &mut Child, e: String| {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        };
+        let timed_out = format!("Oracle {} timed out", self.command);
+        let stdout = match stdout.recv_timeout(timeout) {
+            Ok(stdout) if stdout.len() as u64 > MAX_ORACLE_OUTPUT => {
+                let e = format!(
+                    "Oracle {} printed more than {} bytes",
+                    self.command, MAX_ORACLE_OUTPUT
+                );
+                return stop(&mut child, e);
+            }
+            Ok(stdout) => stdout,
+            Err(_) => return stop(&mut child, timed_out),
+        };
+        // stdout may be closed before the program exits
+        let status = loop {
+            match child.try_wait().map_err(|e| e.to_string())? {
+                Some(status) => break status,
+                None if start.elapsed() >= timeout => return stop(&mut child, timed_out),
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        if !status.success() {
+            let stderr = stderr
+                .recv_timeout(Duration::from_millis(100))
+                .unwrap_or_default();
+            return Err(format!(
+                "Oracle {} exited with {}: {}",
+                self.command,
+                status,
+                String::from_utf8_lossy(&stderr)
+            ));
+        }
+        serde_json::from_slice(&stdout).map_err(|e| e.to_string())
+    }
+}
+
+/// The host's sources for answering queries, and every answer given.
+#[derive(Default)]
+pub struct HostQueries {
+    chain: Option<Arc<dyn ChainData>>,
+    oracles: BTreeMap<String, Arc<dyn PriceOracle>>,
+    answered: Mutex<Vec<AnsweredQuery>>,
+    /// only answer from the record, never asking the sources
+    replay: bool,
+}
+
+impl HostQueries {
+    /// Answer queries from `chain` and `oracles`, by name
+    pub fn new(
+        chain: Option<Arc<dyn ChainData>>,
+        oracles: BTreeMap<String, Arc<dyn PriceOracle>>,
+    ) -> Self {
+        HostQueries {
+            chain,
+            oracles,
+            answered: Default::default(),
+            replay: false,
+        }
+    }
+
+    /// Answer only the queries in `record`, as they were answered before,
+    /// rather than asking the sources. Any other query is an error, so a
+    /// replayed compilation can't see different data than the recorded one.
+    pub fn replaying(self, record: QueryRecord) -> Self {
+        HostQueries {
+            answered: Mutex::new(record),
+            replay: true,
+            ..self
+        }
+    }
+
+    /// Answer a query, giving the same answer as before if it has been
+    /// answered already.
+    ///
+    /// The sources are asked without holding the lock, so a slow oracle
+    /// doesn't hold up other queries.
+    pub fn answer(&self, query: &HostQuery) -> Result<HostAnswer, String> {
+        if let Some(a) = self.answered(query)? {
+            return Ok(a);
+        }
+        if self.replay {
+            return Err(format!("{:?} is not in the replayed record", query));
+        }
+        let answer = match query {
+            HostQuery::TipHeight => HostAnswer::TipHeight(self.chain()?.tip_height()?),
+            HostQuery::Output(out) => HostAnswer::Output(self.chain()?.lookup_output(out)?),
+            HostQuery::OraclePrice { oracle, symbol } => HostAnswer::OraclePrice(
+                self.oracles
+                    .get(oracle)
+                    .ok_or_else(|| format!("No oracle named {}", oracle))?
+                    .price(symbol)?,
+            ),
+        };
+        let mut answered = self.answered.lock().map_err(|e| e.to_string())?;
+        // if the same query was answered meanwhile, that answer stands
+        if let Some(a) = answered.iter().find(|a| &a.query == query) {
+            return Ok(a.answer.clone());
+        }
+        answered.push(AnsweredQuery {
+            query: query.clone(),
+            answer: answer.clone(),
+        });
+        Ok(answer)
+    }
+
+    fn answered(&self, query: &HostQuery) -> Result<Option<HostAnswer>, String> {
+        let answered = self.answered.lock().map_err(|e| e.to_string())?;
+        Ok(answered
+            .iter()
+            .find(|a| &a.query == query)
+            .map(|a| a.answer.clone()))
+    }
+
+    /// every query answered (or replayed) so far
+    pub fn record(&self) -> QueryRecord {
+        self.answered
+            .lock()
+            .map(|a| a.clone())
+            .unwrap_or_else(|e| e.into_inner().clone())
+    }
+
+    fn chain(&self) -> Result<&Arc<dyn ChainData>, String> {
+        self.chain
+            .as_ref()
+            .ok_or_else(|| "No chain data available".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, Txid};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// chain data which counts how often it is asked
+    #[derive(Default)]
+    struct StubChain(AtomicUsize);
+    impl ChainData for StubChain {
+        fn tip_height(&self) -> Result<u32, String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(100)
+        }
+        fn lookup_output(&self, out: &OutPoint) -> Result<TxOut, String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(TxOut {
+                value: out.vout as u64,
+                script_pubkey: Script::new(),
+            })
+        }
+    }
+
+    struct StubOracle;
+    impl PriceOracle for StubOracle {
+        fn price(&self, symbol: &str) -> Result<serde_json::Value, String> {
+            Ok(serde_json::json!({ "symbol": symbol, "price": 1 }))
+        }
+    }
+
+    fn queries() -> Vec<HostQuery> {
+        vec![
+            HostQuery::TipHeight,
+            HostQuery::Output(OutPoint::new(Txid::from_inner([1; 32]), 7)),
+            HostQuery::OraclePrice {
+                oracle: "stub".into(),
+                symbol: "BTCUSD".into(),
+            },
+        ]
+    }
+
+    fn live(chain: Arc<StubChain>) -> HostQueries {
+        let mut oracles: BTreeMap<String, Arc<dyn PriceOracle>> = BTreeMap::new();
+        oracles.insert("stub".into(), Arc::new(StubOracle));
+        HostQueries::new(Some(chain), oracles)
+    }
+
+    #[test]
+    fn answers_each_query_once() {
+        let chain = Arc::new(StubChain::default());
+        let host = live(chain.clone());
+        for _ in 0..2 {
+            assert_eq!(
+                host.answer(&HostQuery::TipHeight),
+                Ok(HostAnswer::TipHeight(100))
+            );
+        }
+        assert_eq!(chain.0.load(Ordering::SeqCst), 1);
+        assert_eq!(host.record().len(), 1);
+        assert!(HostQueries::default()
+            .answer(&HostQuery::TipHeight)
+            .is_err());
+        assert!(host
+            .answer(&HostQuery::OraclePrice {
+                oracle: "missing".into(),
+                symbol: "BTCUSD".into()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn replays_a_record() {
+        let host = live(Arc::new(StubChain::default()));
+        let answers: Vec<_> = queries().iter().map(|q| host.answer(q).unwrap()).collect();
+        let record: QueryRecord =
+            serde_json::from_str(&serde_json::to_string(&host.record()).unwrap()).unwrap();
+        assert_eq!(record, host.record());
+
+        // replaying asks nothing of the sources, even if there are some
+        let chain = Arc::new(StubChain::default());
+        let replayed = live(chain.clone()).replaying(record);
+        for (q, a) in queries().iter().zip(answers) {
+            assert_eq!(replayed.answer(q), Ok(a));
+        }
+        assert_eq!(chain.0.load(Ordering::SeqCst), 0);
+        // and anything not recorded is an error
+        assert!(replayed
+            .answer(&HostQuery::Output(OutPoint::new(
+                Txid::from_inner([2; 32]),
+                0
+            )))
+            .is_err());
+        assert_eq!(chain.0.load(Ordering::SeqCst), 0);
+        assert_eq!(replayed.record().len(), 3);
+    }
+
+    #[test]
+    fn command_oracle() {
+        let oracle = CommandOracle::new("echo", vec![]);
+        assert_eq!(oracle.price("42"), Ok(serde_json::json!(42)));
+        let failing = CommandOracle::new("false", vec![]);
+        assert!(failing.price("BTCUSD").is_err());
+    }
+
+    #[test]
+    fn command_oracle_is_bounded() {
+        let hung = CommandOracle {
+            timeout_secs: 1,
+            ..CommandOracle::new("sleep", vec![])
+        };
+        let start = Instant::now();
+        assert_eq!(hung.price("10"), Err("Oracle sleep timed out".into()));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // prints its argument forever
+        let chatty = CommandOracle::new("yes", vec![]);
+        assert!(chatty.price("1").unwrap_err().contains("more than"));
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! signatures by plugin publishers and the host's store of trusted publishers.
+//!
+//! A plugin may ship with a detached [`PluginSignature`] (by convention in a
+//! file next to it named `<plugin>.wasm.sig`), a BIP-340 signature over the
+//! plugin's module hash and some metadata. Before running a plugin the host
+//! checks it against its [`TrustStore`].
+use super::plugin_handle::WASMCacheID;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{schnorr, Keypair, Message, Secp256k1, Signing, Verification};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The tag for the message a publisher signs
+const SIGNATURE_TAG: &[u8] = b"sapio-plugin-signature/v1";
+
+/// Errors from checking a plugin's signature
+#[derive(Debug)]
+pub enum TrustError {
+    /// The signature is for another module
+    WrongModule(String),
+    /// The signature does not verify
+    InvalidSignature,
+    /// The policy for this publisher (or unknown publishers) is to refuse
+    Refused(String),
+    /// The metadata could not be serialized
+    Serialization(serde_json::Error),
+}
+impl fmt::Display for TrustError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for TrustError {}
+impl From<serde_json::Error> for TrustError {
+    fn from(e: serde_json::Error) -> Self {
+        TrustError::Serialization(e)
+    }
+}
+
+/// A publisher's detached signature over a module and metadata about it.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct PluginSignature {
+    /// the publisher's key
+    #[schemars(with = "String")]
+    pub publisher: XOnlyPublicKey,
+    /// the hex cache id of the module signed for
+    pub module: String,
+    /// whatever the publisher vouches for, e.g. name and version
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// BIP-340 signature by `publisher`
+    #[schemars(with = "String")]
+    pub signature: schnorr::Signature,
+}
+
+impl PluginSignature {
+    /// The message signed: a tagged hash of the module id and the metadata
+    fn message(
+        module: &WASMCacheID,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<Message, TrustError> {
+        let tag = sha256::Hash::hash(SIGNATURE_TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(module.to_string().as_bytes());
+        engine.input(serde_json::to_string(metadata)?.as_bytes());
+        let h = sha256::Hash::from_engine(engine);
+        Ok(Message::from_digest_slice(&h[..]).expect("32 byte hash"))
+    }
+
+    /// Sign a module as the publisher with key `kp`
+    pub fn sign<C: Signing>(
+        secp: &Secp256k1<C>,
+        kp: &Keypair,
+        module: &WASMCacheID,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<Self, TrustError> {
+        let msg = Self::message(module, &metadata)?;
+        Ok(PluginSignature {
+            publisher: XOnlyPublicKey::from_keypair(kp).0,
+            module: module.to_string(),
+            metadata,
+            signature: secp.sign_schnorr_no_aux_rand(&msg, kp),
+        })
+    }
+
+    /// Check that this is a valid signature for `module`
+    pub fn verify<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        module: &WASMCacheID,
+    ) -> Result<(), TrustError> {
+        if self.module != module.to_string() {
+            return Err(TrustError::WrongModule(self.module.clone()));
+        }
+        let msg = Self::message(module, &self.metadata)?;
+        secp.verify_schnorr(&self.signature, &msg, &self.publisher)
+            .map_err(|_| TrustError::InvalidSignature)
+    }
+}
+
+/// What to do with a plugin, depending on who signed it
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustPolicy {
+    /// run it
+    #[default]
+    Allow,
+    /// run it, but warn about it
+    Warn,
+    /// do not run it
+    Refuse,
+}
+
+/// A publisher known to the host
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Publisher {
+    /// the key the publisher signs with
+    #[schemars(with = "String")]
+    pub key: XOnlyPublicKey,
+    /// what to do with this publisher's plugins, defaults to allow
+    #[serde(default)]
+    pub policy: TrustPolicy,
+}
+
+/// The publishers a host knows about and what to do with their plugins.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct TrustStore {
+    /// known publishers, by name
+    #[serde(default)]
+    pub publishers: BTreeMap<String, Publisher>,
+    /// what to do with plugins which are unsigned or signed by an unknown key
+    #[serde(default = "TrustStore::default_unknown")]
+    pub unknown: TrustPolicy,
+}
+
+impl Default for TrustStore {
+    fn default() -> Self {
+        TrustStore {
+            publishers: Default::default(),
+            unknown: Self::default_unknown(),
+        }
+    }
+}
+
+/// A publisher whose signature on a plugin was verified, recorded in the
+/// metadata of the contracts the plugin creates.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedPublisher {
+    /// the publisher's name in the trust store
+    pub name: String,
+    /// the publisher's key
+    #[schemars(with = "String")]
+    pub key: XOnlyPublicKey,
+    /// the hex cache id of the plugin
+    pub module: String,
+    /// the metadata the publisher signed
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl VerifiedPublisher {
+    /// the key in [`sapio::contract::object::ObjectMetadata`] it is recorded under
+    pub const METADATA_KEY: &'static str = "plugin_publisher";
+}
+
+/// The outcome of checking a plugin which may run
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrustDecision {
+    /// the known publisher who signed the plugin, if any
+    pub publisher: Option<VerifiedPublisher>,
+    /// why the host should warn about running the plugin, if the policy is to
+    /// warn
+    pub warning: Option<String>,
+}
+
+impl TrustStore {
+    fn default_unknown() -> TrustPolicy {
+        TrustPolicy::Warn
+    }
+
+    /// Decide if `module` may run given its signature, if any.
+    ///
+    /// If it may run, returns the known publisher who signed it (if any) and
+    /// the warning to log if the policy is to warn. Returns an error if it may
+    /// not run or the signature is invalid.
+    pub fn check(
+        &self,
+        module: &WASMCacheID,
+        signature: Option<&PluginSignature>,
+    ) -> Result<TrustDecision, TrustError> {
+        let known = match signature {
+            Some(sig) => {
+                sig.verify(&Secp256k1::verification_only(), module)?;
+                self.publishers
+                    .iter()
+                    .find(|(_, p)| p.key == sig.publisher)
+                    .map(|(name, p)| (name, p, sig))
+            }
+            None => None,
+        };
+        match known {
+            Some((name, publisher, sig)) => {
+                let warning =
+                    Self::apply(publisher.policy, module, &format!("published by {}", name))?;
+                Ok(TrustDecision {
+                    publisher: Some(VerifiedPublisher {
+                        name: name.clone(),
+                        key: sig.publisher,
+                        module: module.to_string(),
+                        metadata: sig.metadata.clone(),
+                    }),
+                    warning,
+                })
+            }
+            None => {
+                let why = match signature {
+                    Some(sig) => format!("signed by unknown publisher {}", sig.publisher),
+                    None => "unsigned".into(),
+                };
+                let warning = Self::apply(self.unknown, module, &why)?;
+                Ok(TrustDecision {
+                    publisher: None,
+                    warning,
+                })
+            }
+        }
+    }
+
+    /// the warning for running a plugin under `policy`, if any
+    fn apply(
+        policy: TrustPolicy,
+        module: &WASMCacheID,
+        why: &str,
+    ) -> Result<Option<String>, TrustError> {
+        match policy {
+            TrustPolicy::Allow => Ok(None),
+            TrustPolicy::Warn => Ok(Some(format!("Running plugin {} which is {}", module, why))),
+            TrustPolicy::Refuse => Err(TrustError::Refused(format!(
+                "Refusing to run plugin {} which is {}",
+                module, why
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair(byte: u8) -> Keypair {
+        Keypair::from_seckey_slice(&Secp256k1::new(), &[byte; 32]).unwrap()
+    }
+
+    fn signed(kp: &Keypair, module: &WASMCacheID) -> PluginSignature {
+        let metadata = [("name".to_string(), "test".to_string())].into();
+        PluginSignature::sign(&Secp256k1::new(), kp, module, metadata).unwrap()
+    }
+
+    fn store(key: &Keypair, policy: TrustPolicy, unknown: TrustPolicy) -> TrustStore {
+        TrustStore {
+            publishers: [(
+                "judica".to_string(),
+                Publisher {
+                    key: XOnlyPublicKey::from_keypair(key).0,
+                    policy,
+                },
+            )]
+            .into(),
+            unknown,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let kp = keypair(1);
+        let module = WASMCacheID::generate(b"module");
+        let decision = store(&kp, TrustPolicy::Allow, TrustPolicy::Refuse)
+            .check(&module, Some(&signed(&kp, &module)))
+            .unwrap();
+        let publisher = decision.publisher.unwrap();
+        assert_eq!(publisher.name, "judica");
+        assert_eq!(publisher.module, module.to_string());
+        assert_eq!(publisher.metadata["name"], "test");
+        assert_eq!(decision.warning, None);
+    }
+
+    #[test]
+    fn rejects_tampered_module() {
+        let kp = keypair(1);
+        let module = WASMCacheID::generate(b"module");
+        let other = WASMCacheID::generate(b"tampered");
+        let trust = store(&kp, TrustPolicy::Allow, TrustPolicy::Allow);
+        let sig = signed(&kp, &module);
+        assert!(matches!(
+            trust.check(&other, Some(&sig)),
+            Err(TrustError::WrongModule(_))
+        ));
+        // claiming the other module without re-signing
+        let forged = PluginSignature {
+            module: other.to_string(),
+            ..sig
+        };
+        assert!(matches!(
+            trust.check(&other, Some(&forged)),
+            Err(TrustError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn unknown_key_uses_unknown_policy() {
+        let module = WASMCacheID::generate(b"module");
+        let sig = signed(&keypair(2), &module);
+        let trust = store(&keypair(1), TrustPolicy::Allow, TrustPolicy::Refuse);
+        assert!(matches!(
+            trust.check(&module, Some(&sig)),
+            Err(TrustError::Refused(_))
+        ));
+        let trust = store(&keypair(1), TrustPolicy::Allow, TrustPolicy::Warn);
+        let decision = trust.check(&module, Some(&sig)).unwrap();
+        assert_eq!(decision.publisher, None);
+        assert!(decision.warning.unwrap().contains("unknown publisher"));
+    }
+
+    #[test]
+    fn unsigned_module_policies() {
+        let module = WASMCacheID::generate(b"module");
+        let kp = keypair(1);
+        let allowed = store(&kp, TrustPolicy::Refuse, TrustPolicy::Allow)
+            .check(&module, None)
+            .unwrap();
+        assert_eq!(allowed, TrustDecision::default());
+        let warned = store(&kp, TrustPolicy::Refuse, TrustPolicy::Warn)
+            .check(&module, None)
+            .unwrap();
+        assert_eq!(warned.publisher, None);
+        assert!(warned.warning.unwrap().contains("unsigned"));
+        assert!(matches!(
+            store(&kp, TrustPolicy::Allow, TrustPolicy::Refuse).check(&module, None),
+            Err(TrustError::Refused(_))
+        ));
+    }
+}
@@ -5,15 +5,26 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! tools for caching compilations of wasm plugins to disk
+use super::trust::PluginSignature;
 use std::path::PathBuf;
 use wasmer::{DeserializeError, Module, SerializeError, Store};
 use wasmer_cache::{Cache, FileSystemCache, Hash};
 
+/// the extension of a module's signature file
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
 /// look at the cache and get all of the keys (as Strings) for plugins
 pub fn get_all_keys_from_fs<I: Into<PathBuf>>(
     path: I,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     std::fs::read_dir(path.into())?
+        // skip signatures stored alongside modules
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .map(|e| e.path().extension() != Some(SIGNATURE_EXTENSION.as_ref()))
+                .unwrap_or(true)
+        })
         .map(|entry| {
             match entry.map(|x| {
                 x.path()
@@ -65,3 +76,29 @@ pub fn store_module<I: Into<PathBuf>>(
     cache.store(key, module)?;
     Ok(key)
 }
+
+/// store a module's signature into the cache, next to the module
+pub fn store_signature<I: Into<PathBuf>>(
+    path: I,
+    key: Hash,
+    signature: &PluginSignature,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut p = path.into();
+    p.push(format!("{}.{}", key, SIGNATURE_EXTENSION));
+    std::fs::write(p, serde_json::to_vec_pretty(signature)?)?;
+    Ok(())
+}
+
+/// load a module's signature from the cache, if it has one
+pub fn load_signature<I: Into<PathBuf>>(
+    path: I,
+    key: Hash,
+) -> Result<Option<PluginSignature>, Box<dyn std::error::Error>> {
+    let mut p = path.into();
+    p.push(format!("{}.{}", key, SIGNATURE_EXTENSION));
+    match std::fs::read(p) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
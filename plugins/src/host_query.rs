@@ -0,0 +1,103 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! read-only queries plugins may make of their host, e.g. for chain data.
+//!
+//! Every answer the host gives is recorded in the metadata of the compiled
+//! contract, and a host may be given a recording to answer from instead, so
+//! that compiling a plugin which queried the host is reproducible.
+use bitcoin::{OutPoint, TxOut};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A query a plugin makes of its host
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum HostQuery {
+    /// the height of the host's chain tip
+    TipHeight,
+    /// an output, looked up in the host's transaction index
+    Output(#[schemars(with = "String")] OutPoint),
+    /// a price from one of the host's oracles
+    OraclePrice {
+        /// the name the host knows the oracle by
+        oracle: String,
+        /// what to price, as the oracle understands it (e.g. `BTCUSD`)
+        symbol: String,
+    },
+}
+
+/// The host's answer to a [`HostQuery`]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostAnswer {
+    /// answers [`HostQuery::TipHeight`]
+    TipHeight(u32),
+    /// answers [`HostQuery::Output`]
+    Output(#[schemars(with = "serde_json::Value")] TxOut),
+    /// answers [`HostQuery::OraclePrice`], as the oracle reported it
+    OraclePrice(serde_json::Value),
+}
+
+/// A query answered by the host
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct AnsweredQuery {
+    /// what was asked
+    pub query: HostQuery,
+    /// what the host answered
+    pub answer: HostAnswer,
+}
+
+/// The queries answered while compiling a contract, in the order first asked
+pub type QueryRecord = Vec<AnsweredQuery>;
+
+/// the key in [`sapio::contract::object::ObjectMetadata`] a [`QueryRecord`]
+/// is recorded under
+pub const QUERY_RECORD_METADATA_KEY: &str = "host_queries";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, Txid};
+    use serde_json::json;
+
+    #[test]
+    fn record_format() {
+        let out = OutPoint::new(Txid::from_inner([0; 32]), 1);
+        let record: QueryRecord = vec![
+            AnsweredQuery {
+                query: HostQuery::TipHeight,
+                answer: HostAnswer::TipHeight(7),
+            },
+            AnsweredQuery {
+                query: HostQuery::Output(out),
+                answer: HostAnswer::Output(TxOut {
+                    value: 1000,
+                    script_pubkey: Script::new(),
+                }),
+            },
+            AnsweredQuery {
+                query: HostQuery::OraclePrice {
+                    oracle: "o".into(),
+                    symbol: "BTCUSD".into(),
+                },
+                answer: HostAnswer::OraclePrice(json!("1.5")),
+            },
+        ];
+        let v = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            v[0],
+            json!({"query": "tip_height", "answer": {"tip_height": 7}})
+        );
+        assert_eq!(v[1]["query"], json!({ "output": out.to_string() }));
+        assert_eq!(
+            v[2]["query"],
+            json!({"oracle_price": {"oracle": "o", "symbol": "BTCUSD"}})
+        );
+        assert_eq!(serde_json::from_value::<QueryRecord>(v).unwrap(), record);
+    }
+}
@@ -6,21 +6,21 @@
 
 #![deny(missing_docs)]
 //! module interfaces for sapio clients and hosts
-use sapio::contract::{Compilable, Context};
 pub use sapio_base::plugin_args::*;
 use schemars::schema::RootSchema;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::ffi::CString;
 use std::marker::PhantomData;
-use std::os::raw::c_char;
-use std::sync::Arc;
 
+pub mod callable;
 #[cfg(feature = "client")]
 pub mod client;
 #[cfg(feature = "host")]
 pub mod host;
+pub mod host_query;
+pub mod manifest;
 pub mod plugin_handle;
+pub mod plugin_log;
 
 /// A bundle of input/output types
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -0,0 +1,86 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a manifest describing a plugin, embedded in the plugin's module
+use schemars::JsonSchema;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Describes a plugin: what it is, what trait versions it implements, and the
+/// other plugins it calls out to.
+///
+/// [`crate::REGISTER`] fills in the name, version, description and authors
+/// from the plugin crate's Cargo.toml. The name is the crate's name, which is
+/// also the name the plugin reports to the host.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct PluginManifest {
+    /// # Name
+    /// The name other plugins and lockfiles refer to the plugin by
+    pub name: String,
+    /// # Version
+    /// The plugin's semantic version
+    #[schemars(with = "String")]
+    pub version: Version,
+    /// # Description
+    #[serde(default)]
+    pub description: String,
+    /// # Authors
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// # Implemented Traits
+    /// The trait versions (e.g. `BatchingTraitVersion0_1_1`) the plugin
+    /// accepts as arguments
+    #[serde(default)]
+    pub traits: Vec<String>,
+    /// # Dependencies
+    /// The plugins, by name, this plugin calls and the versions it works with
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+impl PluginManifest {
+    /// Create a manifest from a crate's package metadata, where `authors` is
+    /// colon separated as in `CARGO_PKG_AUTHORS`. An unparsable version is
+    /// treated as 0.0.0.
+    pub fn from_package(name: &str, version: &str, description: &str, authors: &str) -> Self {
+        PluginManifest {
+            name: name.into(),
+            version: Version::parse(version).unwrap_or_else(|_| Version::new(0, 0, 0)),
+            description: description.into(),
+            authors: authors
+                .split(':')
+                .filter(|a| !a.is_empty())
+                .map(String::from)
+                .collect(),
+            traits: vec![],
+            dependencies: BTreeMap::new(),
+        }
+    }
+
+    /// Depend on the plugin `name` at the versions matching `requirement`,
+    /// e.g. `^0.1`.
+    pub fn depend_on(&mut self, name: &str, requirement: &str) -> Result<(), String> {
+        let requirement = VersionReq::parse(requirement).map_err(|e| {
+            format!(
+                "invalid version requirement {:?} for {}: {}",
+                requirement, name, e
+            )
+        })?;
+        self.dependencies.insert(name.into(), requirement);
+        Ok(())
+    }
+
+    /// Check that `version` of the plugin `name` satisfies this plugin's
+    /// requirement for it, if it has one.
+    pub fn accepts(&self, name: &str, version: &Version) -> bool {
+        self.dependencies
+            .get(name)
+            .map(|req| req.matches(version))
+            .unwrap_or(true)
+    }
+}
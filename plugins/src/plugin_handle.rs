@@ -6,6 +6,7 @@
 
 //! generic plugin handle interface available to client and host
 
+use crate::manifest::PluginManifest;
 use crate::API;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
@@ -30,4 +31,8 @@ pub trait PluginHandle {
     fn get_name(&mut self) -> Result<String, CompilationError>;
     /// get logo metadata
     fn get_logo(&mut self) -> Result<String, CompilationError>;
+    /// get manifest metadata, if the plugin has a manifest
+    fn get_manifest(&mut self) -> Result<Option<PluginManifest>, CompilationError> {
+        Ok(None)
+    }
 }
@@ -0,0 +1,152 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! structured log records sent from plugins to their host
+use sapio_base::effects::EffectPath;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How severe a log record is. Levels are ordered from most to least severe.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    /// something failed
+    Error,
+    /// something may be wrong
+    Warn,
+    /// progress worth noting
+    Info,
+    /// detail useful when debugging a plugin
+    Debug,
+    /// very detailed tracing
+    Trace,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A structured log record
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// how severe the record is
+    pub level: LogLevel,
+    /// what the record is about, e.g. the plugin or contract name
+    pub target: String,
+    /// the message
+    pub message: String,
+    /// structured data about the event
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_json::Value>,
+    /// the path of the contract being compiled, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<EffectPath>,
+    /// the hex cache id of the plugin which logged the record, filled in by
+    /// the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+}
+
+impl LogRecord {
+    /// Create a record with no fields or path
+    pub fn new(level: LogLevel, target: &str, message: &str) -> Self {
+        LogRecord {
+            level,
+            target: target.into(),
+            message: message.into(),
+            fields: BTreeMap::new(),
+            path: None,
+            module: None,
+        }
+    }
+
+    /// add a field, skipping values which can't be serialized
+    pub fn field<T: Serialize>(mut self, name: &str, value: T) -> Self {
+        if let Ok(v) = serde_json::to_value(value) {
+            self.fields.insert(name.into(), v);
+        }
+        self
+    }
+
+    /// record the path of the contract being compiled
+    pub fn at(mut self, path: &EffectPath) -> Self {
+        self.path = Some(path.clone());
+        self
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.level, self.target, self.message)?;
+        for (k, v) in &self.fields {
+            write!(f, " {}={}", k, v)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " path={}", String::from(path.clone()))?;
+        }
+        if let Some(module) = &self.module {
+            write!(f, " module={}", module)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sapio_base::effects::PathFragment;
+
+    #[test]
+    fn levels_are_ordered_by_severity() {
+        assert!(LogLevel::Error < LogLevel::Warn);
+        assert!(LogLevel::Warn < LogLevel::Info);
+        assert!(LogLevel::Info < LogLevel::Debug);
+        assert!(LogLevel::Debug < LogLevel::Trace);
+        assert_eq!(serde_json::to_value(LogLevel::Warn).unwrap(), "warn");
+    }
+
+    #[test]
+    fn record_format() {
+        let record = LogRecord::new(LogLevel::Info, "plugin", "compiled")
+            .field("outputs", 2)
+            .at(&PathFragment::Root.into());
+        assert_eq!(
+            record.to_string(),
+            "INFO plugin: compiled outputs=2 path=@root"
+        );
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["fields"], serde_json::json!({"outputs": 2}));
+        assert!(json.get("module").is_none());
+        assert_eq!(serde_json::from_value::<LogRecord>(json).unwrap(), record);
+        // records with no fields or path leave them out
+        let bare = serde_json::to_value(LogRecord::new(LogLevel::Error, "t", "m")).unwrap();
+        assert_eq!(
+            bare,
+            serde_json::json!({"level": "error", "target": "t", "message": "m"})
+        );
+    }
+
+    #[test]
+    fn unserializable_fields_are_skipped() {
+        let mut map = std::collections::HashMap::new();
+        map.insert((1, 2), 3);
+        let record = LogRecord::new(LogLevel::Debug, "t", "m").field("map", map);
+        assert!(record.fields.is_empty());
+    }
+}
@@ -12,6 +12,7 @@ use bitcoin::XOnlyPublicKey;
 pub use util::CTVHash;
 pub use miniscript;
 pub mod plugin_args;
+pub mod psbt_v2;
 pub mod simp;
 
 /// Helpers for making correct time locks
@@ -0,0 +1,631 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! PSBT version 2 (BIP-370), layered over the version 0 types.
+//!
+//! A [`PsbtV2`] keeps a regular `PartiallySignedTransaction` whose
+//! `unsigned_tx` is assembled from the per input and per output fields, so
+//! everything which works on version 0 PSBTs (signing, finalizing) works on
+//! `PsbtV2::psbt` too. Encoding and decoding only rewrite the fields which
+//! differ between the versions and leave every other key untouched.
+use bitcoin::consensus::encode::{self, deserialize, serialize, Decodable, Encodable, VarInt};
+use bitcoin::psbt::{Input, Output, PartiallySignedTransaction};
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Cursor;
+use std::str::FromStr;
+
+const MAGIC: &[u8; 5] = b"psbt\xff";
+
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const GLOBAL_TX_VERSION: u8 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const GLOBAL_INPUT_COUNT: u8 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const GLOBAL_VERSION: u8 = 0xFB;
+
+const IN_PREVIOUS_TXID: u8 = 0x0e;
+const IN_OUTPUT_INDEX: u8 = 0x0f;
+const IN_SEQUENCE: u8 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+
+const OUT_AMOUNT: u8 = 0x03;
+const OUT_SCRIPT: u8 = 0x04;
+
+/// lock times below this are block heights, at or above it are timestamps
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// `tx_modifiable` flag: inputs may be added or removed
+pub const INPUTS_MODIFIABLE: u8 = 1 << 0;
+/// `tx_modifiable` flag: outputs may be added or removed
+pub const OUTPUTS_MODIFIABLE: u8 = 1 << 1;
+/// `tx_modifiable` flag: some signature uses SIGHASH_SINGLE, so inputs and
+/// outputs must be added in pairs
+pub const HAS_SIGHASH_SINGLE: u8 = 1 << 2;
+
+/// Which PSBT version to produce or was parsed
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PsbtVersion {
+    /// BIP-174, with a global unsigned transaction
+    #[default]
+    #[serde(rename = "0")]
+    V0,
+    /// BIP-370, with per input and per output transaction fields
+    #[serde(rename = "2")]
+    V2,
+}
+
+impl FromStr for PsbtVersion {
+    type Err = PsbtV2Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(PsbtVersion::V0),
+            "2" => Ok(PsbtVersion::V2),
+            _ => Err(PsbtV2Error::Invalid("psbt version must be 0 or 2")),
+        }
+    }
+}
+
+impl Display for PsbtVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PsbtVersion::V0 => write!(f, "0"),
+            PsbtVersion::V2 => write!(f, "2"),
+        }
+    }
+}
+
+/// The lock times an input requires, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RequiredLocktime {
+    /// a minimum timestamp lock time
+    pub time: Option<u32>,
+    /// a minimum block height lock time
+    pub height: Option<u32>,
+}
+
+impl RequiredLocktime {
+    fn is_none(&self) -> bool {
+        self.time.is_none() && self.height.is_none()
+    }
+}
+
+/// Errors converting or modifying a version 2 PSBT
+#[derive(Debug)]
+pub enum PsbtV2Error {
+    /// the underlying encoding was invalid
+    Encode(encode::Error),
+    /// a required field was absent
+    Missing(&'static str),
+    /// a field had an invalid value
+    Invalid(&'static str),
+    /// the PSBT is not a version this module understands
+    UnsupportedVersion(u32),
+    /// the inputs require both a height and a time lock time
+    LockTimeConflict,
+    /// the `tx_modifiable` flags do not allow the change
+    NotModifiable,
+}
+
+impl Display for PsbtV2Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for PsbtV2Error {}
+
+impl From<encode::Error> for PsbtV2Error {
+    fn from(e: encode::Error) -> Self {
+        PsbtV2Error::Encode(e)
+    }
+}
+
+/// A version 2 PSBT.
+///
+/// `psbt.unsigned_tx` is kept in sync with the other fields, so it should
+/// not be modified directly; use `add_input` and `add_output` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsbtV2 {
+    /// the equivalent version 0 PSBT
+    pub psbt: PartiallySignedTransaction,
+    /// the lock time used when no input requires one
+    pub fallback_locktime: Option<u32>,
+    /// `INPUTS_MODIFIABLE`, `OUTPUTS_MODIFIABLE` and `HAS_SIGHASH_SINGLE`
+    pub tx_modifiable: u8,
+    /// the lock time requirements of each input
+    pub required_locktimes: Vec<RequiredLocktime>,
+}
+
+impl From<PartiallySignedTransaction> for PsbtV2 {
+    fn from(psbt: PartiallySignedTransaction) -> Self {
+        let lock_time = psbt.unsigned_tx.lock_time;
+        PsbtV2 {
+            required_locktimes: vec![Default::default(); psbt.inputs.len()],
+            psbt,
+            fallback_locktime: (lock_time != 0).then_some(lock_time),
+            tx_modifiable: 0,
+        }
+    }
+}
+
+impl From<PsbtV2> for PartiallySignedTransaction {
+    fn from(p: PsbtV2) -> Self {
+        p.psbt
+    }
+}
+
+impl PsbtV2 {
+    /// An empty PSBT to which inputs and outputs can be added
+    pub fn new(tx_version: i32, fallback_locktime: Option<u32>) -> Self {
+        let tx = Transaction {
+            version: tx_version,
+            lock_time: fallback_locktime.unwrap_or(0),
+            input: vec![],
+            output: vec![],
+        };
+        PsbtV2 {
+            psbt: PartiallySignedTransaction {
+                unsigned_tx: tx,
+                version: 0,
+                xpub: Default::default(),
+                proprietary: Default::default(),
+                unknown: Default::default(),
+                inputs: vec![],
+                outputs: vec![],
+            },
+            fallback_locktime,
+            tx_modifiable: INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE,
+            required_locktimes: vec![],
+        }
+    }
+
+    /// Adds an input, if inputs are modifiable and its lock time requirement
+    /// is compatible with the other inputs'.
+    pub fn add_input(
+        &mut self,
+        txin: TxIn,
+        input: Input,
+        required: RequiredLocktime,
+    ) -> Result<(), PsbtV2Error> {
+        if self.tx_modifiable & INPUTS_MODIFIABLE == 0 {
+            return Err(PsbtV2Error::NotModifiable);
+        }
+        check_required(&required)?;
+        self.required_locktimes.push(required);
+        let lock_time = match self.lock_time() {
+            Ok(l) => l,
+            Err(e) => {
+                self.required_locktimes.pop();
+                return Err(e);
+            }
+        };
+        self.psbt.unsigned_tx.input.push(TxIn {
+            script_sig: Script::new(),
+            witness: Default::default(),
+            ..txin
+        });
+        self.psbt.unsigned_tx.lock_time = lock_time;
+        self.psbt.inputs.push(input);
+        Ok(())
+    }
+
+    /// Adds an output, if outputs are modifiable.
+    pub fn add_output(&mut self, txout: TxOut, output: Output) -> Result<(), PsbtV2Error> {
+        if self.tx_modifiable & OUTPUTS_MODIFIABLE == 0 {
+            return Err(PsbtV2Error::NotModifiable);
+        }
+        self.psbt.unsigned_tx.output.push(txout);
+        self.psbt.outputs.push(output);
+        Ok(())
+    }
+
+    /// The lock time of the transaction, as determined by BIP-370: the
+    /// largest required height if every input requiring a lock time accepts
+    /// a height, otherwise the largest required time.
+    pub fn lock_time(&self) -> Result<u32, PsbtV2Error> {
+        let required: Vec<&RequiredLocktime> = self
+            .required_locktimes
+            .iter()
+            .filter(|r| !r.is_none())
+            .collect();
+        if required.is_empty() {
+            Ok(self.fallback_locktime.unwrap_or(0))
+        } else if required.iter().all(|r| r.height.is_some()) {
+            Ok(required.iter().filter_map(|r| r.height).max().unwrap_or(0))
+        } else if required.iter().all(|r| r.time.is_some()) {
+            Ok(required.iter().filter_map(|r| r.time).max().unwrap_or(0))
+        } else {
+            Err(PsbtV2Error::LockTimeConflict)
+        }
+    }
+
+    /// Combines the signatures and other data of another PSBT for the same
+    /// transaction into this one (the BIP-174 Combiner). The result is only
+    /// as modifiable as both.
+    pub fn combine(&mut self, other: PsbtV2) -> Result<(), PsbtV2Error> {
+        if self.fallback_locktime != other.fallback_locktime {
+            return Err(PsbtV2Error::Invalid("fallback lock times differ"));
+        }
+        self.psbt
+            .combine(other.psbt)
+            .map_err(|e| PsbtV2Error::Encode(e.into()))?;
+        let modifiable = INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE;
+        self.tx_modifiable = (self.tx_modifiable & other.tx_modifiable & modifiable)
+            | ((self.tx_modifiable | other.tx_modifiable) & HAS_SIGHASH_SINGLE);
+        for (mine, theirs) in self
+            .required_locktimes
+            .iter_mut()
+            .zip(other.required_locktimes)
+        {
+            mine.time = mine.time.or(theirs.time);
+            mine.height = mine.height.or(theirs.height);
+        }
+        Ok(())
+    }
+
+    /// Decodes a PSBT of either version, returning which version it was.
+    pub fn decode_any(bytes: &[u8]) -> Result<(PsbtV2, PsbtVersion), PsbtV2Error> {
+        let mut r = Cursor::new(bytes);
+        read_magic(&mut r)?;
+        let global = read_map(&mut r)?;
+        match global.get(&vec![GLOBAL_VERSION]) {
+            Some(v) if read_u32(v)? == 2 => Ok((Self::deserialize(bytes)?, PsbtVersion::V2)),
+            Some(v) if read_u32(v)? != 0 => Err(PsbtV2Error::UnsupportedVersion(read_u32(v)?)),
+            _ => {
+                let psbt: PartiallySignedTransaction = deserialize(bytes)?;
+                Ok((psbt.into(), PsbtVersion::V0))
+            }
+        }
+    }
+
+    /// Encodes as the given version. Version 0 drops the v2 only fields.
+    pub fn encode(&self, version: PsbtVersion) -> Vec<u8> {
+        match version {
+            PsbtVersion::V0 => serialize(&self.psbt),
+            PsbtVersion::V2 => self.serialize(),
+        }
+    }
+
+    /// Encodes as a version 2 PSBT
+    pub fn serialize(&self) -> Vec<u8> {
+        let v0 = serialize(&self.psbt);
+        let tx = &self.psbt.unsigned_tx;
+        let (mut global, mut inputs, mut outputs) =
+            read_v0_maps(&v0).expect("a serialized psbt must parse");
+        global.remove(&vec![GLOBAL_UNSIGNED_TX]);
+        global.insert(vec![GLOBAL_TX_VERSION], serialize(&tx.version));
+        if let Some(l) = self.fallback_locktime {
+            global.insert(vec![GLOBAL_FALLBACK_LOCKTIME], serialize(&l));
+        }
+        global.insert(
+            vec![GLOBAL_INPUT_COUNT],
+            serialize(&VarInt(tx.input.len() as u64)),
+        );
+        global.insert(
+            vec![GLOBAL_OUTPUT_COUNT],
+            serialize(&VarInt(tx.output.len() as u64)),
+        );
+        if self.tx_modifiable != 0 {
+            global.insert(vec![GLOBAL_TX_MODIFIABLE], vec![self.tx_modifiable]);
+        }
+        global.insert(vec![GLOBAL_VERSION], serialize(&2u32));
+        for ((map, txin), required) in inputs
+            .iter_mut()
+            .zip(&tx.input)
+            .zip(&self.required_locktimes)
+        {
+            map.insert(
+                vec![IN_PREVIOUS_TXID],
+                serialize(&txin.previous_output.txid),
+            );
+            map.insert(vec![IN_OUTPUT_INDEX], serialize(&txin.previous_output.vout));
+            if txin.sequence != 0xffff_ffff {
+                map.insert(vec![IN_SEQUENCE], serialize(&txin.sequence));
+            }
+            if let Some(t) = required.time {
+                map.insert(vec![IN_REQUIRED_TIME_LOCKTIME], serialize(&t));
+            }
+            if let Some(h) = required.height {
+                map.insert(vec![IN_REQUIRED_HEIGHT_LOCKTIME], serialize(&h));
+            }
+        }
+        for (map, txout) in outputs.iter_mut().zip(&tx.output) {
+            map.insert(vec![OUT_AMOUNT], serialize(&(txout.value as i64)));
+            map.insert(vec![OUT_SCRIPT], txout.script_pubkey.to_bytes());
+        }
+        write_maps(&global, &inputs, &outputs)
+    }
+
+    /// Decodes a version 2 PSBT
+    pub fn deserialize(bytes: &[u8]) -> Result<PsbtV2, PsbtV2Error> {
+        let mut r = Cursor::new(bytes);
+        read_magic(&mut r)?;
+        let mut global = read_map(&mut r)?;
+        match global.remove(&vec![GLOBAL_VERSION]) {
+            Some(v) if read_u32(&v)? == 2 => {}
+            Some(v) => return Err(PsbtV2Error::UnsupportedVersion(read_u32(&v)?)),
+            None => return Err(PsbtV2Error::UnsupportedVersion(0)),
+        }
+        if global.contains_key(&vec![GLOBAL_UNSIGNED_TX]) {
+            return Err(PsbtV2Error::Invalid("version 2 psbts have no unsigned tx"));
+        }
+        let version: i32 = take(&mut global, GLOBAL_TX_VERSION, "tx version")?;
+        let fallback_locktime: Option<u32> = take_opt(&mut global, GLOBAL_FALLBACK_LOCKTIME)?;
+        let VarInt(n_inputs) = take(&mut global, GLOBAL_INPUT_COUNT, "input count")?;
+        let VarInt(n_outputs) = take(&mut global, GLOBAL_OUTPUT_COUNT, "output count")?;
+        let tx_modifiable: u8 = take_opt(&mut global, GLOBAL_TX_MODIFIABLE)?.unwrap_or(0);
+        let mut inputs = vec![];
+        let mut txins = vec![];
+        let mut required_locktimes = vec![];
+        for _ in 0..n_inputs {
+            let mut map = read_map(&mut r)?;
+            let txid: Txid = take(&mut map, IN_PREVIOUS_TXID, "previous txid")?;
+            let vout: u32 = take(&mut map, IN_OUTPUT_INDEX, "output index")?;
+            let sequence: u32 = take_opt(&mut map, IN_SEQUENCE)?.unwrap_or(0xffff_ffff);
+            let required = RequiredLocktime {
+                time: take_opt(&mut map, IN_REQUIRED_TIME_LOCKTIME)?,
+                height: take_opt(&mut map, IN_REQUIRED_HEIGHT_LOCKTIME)?,
+            };
+            check_required(&required)?;
+            txins.push(TxIn {
+                previous_output: OutPoint::new(txid, vout),
+                script_sig: Script::new(),
+                sequence,
+                witness: Default::default(),
+            });
+            required_locktimes.push(required);
+            inputs.push(map);
+        }
+        let mut outputs = vec![];
+        let mut txouts = vec![];
+        for _ in 0..n_outputs {
+            let mut map = read_map(&mut r)?;
+            let value: i64 = take(&mut map, OUT_AMOUNT, "output amount")?;
+            if value < 0 {
+                return Err(PsbtV2Error::Invalid("negative output amount"));
+            }
+            let script = map
+                .remove(&vec![OUT_SCRIPT])
+                .ok_or(PsbtV2Error::Missing("output script"))?;
+            txouts.push(TxOut {
+                value: value as u64,
+                script_pubkey: Script::from(script),
+            });
+            outputs.push(map);
+        }
+        if (r.position() as usize) != bytes.len() {
+            return Err(PsbtV2Error::Invalid("trailing data"));
+        }
+        let mut psbt = PsbtV2 {
+            psbt: PartiallySignedTransaction::from_unsigned_tx(Transaction {
+                version,
+                lock_time: 0,
+                input: vec![],
+                output: vec![],
+            })
+            .expect("an empty transaction is unsigned"),
+            fallback_locktime,
+            tx_modifiable,
+            required_locktimes,
+        };
+        let tx = Transaction {
+            version,
+            lock_time: psbt.lock_time()?,
+            input: txins,
+            output: txouts,
+        };
+        global.insert(vec![GLOBAL_UNSIGNED_TX], serialize(&tx));
+        psbt.psbt = deserialize(&write_maps(&global, &inputs, &outputs))?;
+        Ok(psbt)
+    }
+}
+
+fn check_required(r: &RequiredLocktime) -> Result<(), PsbtV2Error> {
+    if r.time.is_some_and(|t| t < LOCKTIME_THRESHOLD) {
+        return Err(PsbtV2Error::Invalid("required time lock time is a height"));
+    }
+    if r.height.is_some_and(|h| h == 0 || h >= LOCKTIME_THRESHOLD) {
+        return Err(PsbtV2Error::Invalid("required height lock time is a time"));
+    }
+    Ok(())
+}
+
+/// a key value map, keyed by the full key (type and key data)
+type Map = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn take<T: Decodable>(map: &mut Map, typ: u8, name: &'static str) -> Result<T, PsbtV2Error> {
+    take_opt(map, typ)?.ok_or(PsbtV2Error::Missing(name))
+}
+
+fn take_opt<T: Decodable>(map: &mut Map, typ: u8) -> Result<Option<T>, PsbtV2Error> {
+    map.remove(&vec![typ])
+        .map(|v| deserialize(&v))
+        .transpose()
+        .map_err(PsbtV2Error::from)
+}
+
+fn read_u32(v: &[u8]) -> Result<u32, PsbtV2Error> {
+    Ok(deserialize(v)?)
+}
+
+fn read_magic(r: &mut Cursor<&[u8]>) -> Result<(), PsbtV2Error> {
+    let mut magic = [0u8; 5];
+    std::io::Read::read_exact(r, &mut magic).map_err(encode::Error::Io)?;
+    if &magic != MAGIC {
+        return Err(PsbtV2Error::Invalid("bad psbt magic"));
+    }
+    Ok(())
+}
+
+fn read_bytes(r: &mut Cursor<&[u8]>) -> Result<Vec<u8>, PsbtV2Error> {
+    let VarInt(len) = VarInt::consensus_decode(&mut *r)?;
+    let remaining = r.get_ref().len() as u64 - r.position();
+    if len > remaining {
+        return Err(PsbtV2Error::Invalid("truncated psbt"));
+    }
+    let mut buf = vec![0; len as usize];
+    std::io::Read::read_exact(r, &mut buf).map_err(encode::Error::Io)?;
+    Ok(buf)
+}
+
+fn read_map(r: &mut Cursor<&[u8]>) -> Result<Map, PsbtV2Error> {
+    let mut map = Map::new();
+    loop {
+        let key = read_bytes(r)?;
+        if key.is_empty() {
+            return Ok(map);
+        }
+        let value = read_bytes(r)?;
+        if map.insert(key, value).is_some() {
+            return Err(PsbtV2Error::Invalid("duplicate key"));
+        }
+    }
+}
+
+/// splits a serialized version 0 psbt into its maps
+fn read_v0_maps(bytes: &[u8]) -> Result<(Map, Vec<Map>, Vec<Map>), PsbtV2Error> {
+    let mut r = Cursor::new(bytes);
+    read_magic(&mut r)?;
+    let global = read_map(&mut r)?;
+    let tx: Transaction = deserialize(
+        global
+            .get(&vec![GLOBAL_UNSIGNED_TX])
+            .ok_or(PsbtV2Error::Missing("unsigned tx"))?,
+    )?;
+    let inputs = (0..tx.input.len())
+        .map(|_| read_map(&mut r))
+        .collect::<Result<_, _>>()?;
+    let outputs = (0..tx.output.len())
+        .map(|_| read_map(&mut r))
+        .collect::<Result<_, _>>()?;
+    Ok((global, inputs, outputs))
+}
+
+fn write_maps(global: &Map, inputs: &[Map], outputs: &[Map]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    for map in std::iter::once(global).chain(inputs).chain(outputs) {
+        for (k, v) in map {
+            k.consensus_encode(&mut out).expect("vec writes succeed");
+            v.consensus_encode(&mut out).expect("vec writes succeed");
+        }
+        out.push(0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn txin(n: u8) -> TxIn {
+        TxIn {
+            previous_output: OutPoint::new(Txid::from_inner([n; 32]), n as u32),
+            sequence: 0xffff_fffd,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trips_and_converts() {
+        let mut psbt = PsbtV2::new(2, Some(1000));
+        psbt.add_input(txin(1), Default::default(), Default::default())
+            .unwrap();
+        psbt.add_output(
+            TxOut {
+                value: 5000,
+                script_pubkey: Script::new_op_return(b"hi"),
+            },
+            Default::default(),
+        )
+        .unwrap();
+        psbt.psbt.inputs[0].unknown.insert(
+            bitcoin::psbt::raw::Key {
+                type_value: 0xf0,
+                key: vec![1],
+            },
+            vec![2],
+        );
+        assert_eq!(psbt.psbt.unsigned_tx.lock_time, 1000);
+        let bytes = psbt.serialize();
+        let (decoded, version) = PsbtV2::decode_any(&bytes).unwrap();
+        assert_eq!((&decoded, version), (&psbt, PsbtVersion::V2));
+
+        // v0 round trips lose only the v2 metadata
+        let (v0, version) = PsbtV2::decode_any(&psbt.encode(PsbtVersion::V0)).unwrap();
+        assert_eq!(version, PsbtVersion::V0);
+        assert_eq!(v0.psbt, psbt.psbt);
+        assert_eq!(v0.tx_modifiable, 0);
+        assert_eq!(
+            PsbtV2::deserialize(&v0.serialize()).unwrap().psbt,
+            psbt.psbt
+        );
+    }
+
+    #[test]
+    fn lock_times_and_modifiable() {
+        let mut psbt = PsbtV2::new(2, None);
+        let time = RequiredLocktime {
+            time: Some(LOCKTIME_THRESHOLD + 10),
+            height: None,
+        };
+        let both = RequiredLocktime {
+            time: Some(LOCKTIME_THRESHOLD + 20),
+            height: Some(100),
+        };
+        psbt.add_input(txin(1), Default::default(), both).unwrap();
+        assert_eq!(psbt.psbt.unsigned_tx.lock_time, 100);
+        psbt.add_input(txin(2), Default::default(), time).unwrap();
+        assert_eq!(psbt.psbt.unsigned_tx.lock_time, LOCKTIME_THRESHOLD + 20);
+        let height = RequiredLocktime {
+            time: None,
+            height: Some(5),
+        };
+        assert!(matches!(
+            psbt.add_input(txin(3), Default::default(), height),
+            Err(PsbtV2Error::LockTimeConflict)
+        ));
+        assert_eq!(psbt.psbt.inputs.len(), 2);
+        let decoded = PsbtV2::deserialize(&psbt.serialize()).unwrap();
+        assert_eq!(decoded, psbt);
+
+        psbt.tx_modifiable = 0;
+        assert!(matches!(
+            psbt.add_output(Default::default(), Default::default()),
+            Err(PsbtV2Error::NotModifiable)
+        ));
+    }
+
+    #[test]
+    fn combines() {
+        let mut a = PsbtV2::new(2, None);
+        a.add_input(txin(1), Default::default(), Default::default())
+            .unwrap();
+        let mut b = a.clone();
+        let key = |n: u8| bitcoin::psbt::raw::Key {
+            type_value: 0xf0,
+            key: vec![n],
+        };
+        a.psbt.inputs[0].unknown.insert(key(1), vec![1]);
+        b.psbt.inputs[0].unknown.insert(key(2), vec![2]);
+        b.tx_modifiable = OUTPUTS_MODIFIABLE | HAS_SIGHASH_SINGLE;
+        a.combine(b).unwrap();
+        assert_eq!(a.psbt.inputs[0].unknown.len(), 2);
+        assert_eq!(a.tx_modifiable, OUTPUTS_MODIFIABLE | HAS_SIGHASH_SINGLE);
+
+        let mut other = PsbtV2::new(2, None);
+        other
+            .add_input(txin(2), Default::default(), Default::default())
+            .unwrap();
+        assert!(matches!(a.combine(other), Err(PsbtV2Error::Encode(_))));
+    }
+}
@@ -4,10 +4,14 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use async_trait::async_trait;
 use bitcoin::hash_types::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::task::Poll;
 
 /// Errors in resolving a TXIndex
 #[derive(Debug)]
@@ -18,8 +22,15 @@ pub enum TxIndexError {
     UnknownTxid(Txid),
     /// TXID exists, but the vout index was too high
     IndexTooHigh(u32),
-    /// Error in the Rpc System
-    RpcError(Box<dyn std::error::Error>),
+    /// Error in the Rpc System.
+    ///
+    /// Since sapio-base 0.3 the error must be `Send + Sync`, so that
+    /// `TxIndexError` can cross `.await` points in an `AsyncTxIndex`. Errors
+    /// which aren't can be wrapped, e.g. by formatting them into an
+    /// `std::io::Error`.
+    RpcError(Box<dyn std::error::Error + Send + Sync>),
+    /// Error reading or writing a local store
+    StorageError(std::io::Error),
 }
 impl std::error::Error for TxIndexError {}
 
@@ -44,6 +55,11 @@ pub trait TxIndex {
     }
     /// locally add a tx for tracking
     fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid>;
+    /// store a tx which was looked up from another index, so is already
+    /// known there (unlike one passed to `add_tx`). Defaults to `add_tx`.
+    fn cache_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.add_tx(tx)
+    }
 }
 
 /// a TxIndex which just tracks what it's seen and has no network
@@ -80,8 +96,9 @@ impl TxIndex for TxIndexLogger {
     }
 }
 
-/// a cached txindex checks a cache first and then a primary txindex
-pub struct CachedTxIndex<Cache: TxIndex, Primary: TxIndex> {
+/// a cached txindex checks a cache first and then a primary txindex, which may
+/// be a `TxIndex` or an `AsyncTxIndex`
+pub struct CachedTxIndex<Cache, Primary> {
     /// the cache txindex
     pub cache: Cache,
     /// the main txindex
@@ -98,7 +115,7 @@ where
             Ok(ent)
         } else {
             let ent = self.primary.lookup_tx(b)?;
-            self.cache.add_tx(ent.clone())?;
+            self.cache.cache_tx(ent.clone())?;
             Ok(ent)
         }
     }
@@ -112,3 +129,178 @@ where
         }
     }
 }
+
+/// Asynchronous interface for any txindex, for use from async code without
+/// blocking the executor.
+#[async_trait]
+pub trait AsyncTxIndex: Send + Sync {
+    /// lookup a tx
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>>;
+    /// lookup a particular output
+    async fn lookup_output(&self, b: &bitcoin::OutPoint) -> Result<bitcoin::TxOut> {
+        self.lookup_tx(&b.txid)
+            .await?
+            .output
+            .get(b.vout as usize)
+            .cloned()
+            .ok_or(TxIndexError::IndexTooHigh(b.vout))
+    }
+    /// lookup many outputs, returning a result for each in order.
+    ///
+    /// By default every distinct transaction is looked up concurrently;
+    /// backends which support batching should override this.
+    async fn lookup_outputs(&self, outs: &[bitcoin::OutPoint]) -> Vec<Result<bitcoin::TxOut>> {
+        let txids: Vec<Txid> = outs
+            .iter()
+            .map(|o| o.txid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let txs = join_all(txids.iter().map(|t| self.lookup_tx(t))).await;
+        select_outputs(outs, txids.into_iter().zip(txs).collect())
+    }
+    /// locally add a tx for tracking
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid>;
+}
+
+/// Picks `outs` from the results of looking up the transactions they are in,
+/// for implementing `AsyncTxIndex::lookup_outputs` with batched lookups.
+pub fn select_outputs(
+    outs: &[bitcoin::OutPoint],
+    mut txs: BTreeMap<Txid, Result<Arc<bitcoin::Transaction>>>,
+) -> Vec<Result<bitcoin::TxOut>> {
+    outs.iter()
+        .map(|o| match txs.get(&o.txid) {
+            Some(Ok(tx)) => tx
+                .output
+                .get(o.vout as usize)
+                .cloned()
+                .ok_or(TxIndexError::IndexTooHigh(o.vout)),
+            // errors can't be cloned, so only the first gets the original
+            _ => match txs.remove(&o.txid) {
+                Some(Err(e)) => Err(e),
+                _ => Err(TxIndexError::UnknownTxid(o.txid)),
+            },
+        })
+        .collect()
+}
+
+/// Polls all of `futs` concurrently, returning their outputs in order.
+pub async fn join_all<F: Future>(futs: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futs: Vec<Pin<Box<F>>> = futs.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futs.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut done = true;
+        for (fut, output) in futs.iter_mut().zip(outputs.iter_mut()) {
+            if output.is_none() {
+                match fut.as_mut().poll(cx) {
+                    Poll::Ready(v) => *output = Some(v),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs.into_iter().flatten().collect()
+}
+
+/// Adapts a synchronous TxIndex into an AsyncTxIndex.
+///
+/// Lookups are made inline, so this should only wrap indexes which do not
+/// block (e.g., `TxIndexLogger`).
+pub struct SyncTxIndex<T>(pub T);
+
+#[async_trait]
+impl<T: TxIndex + Send + Sync> AsyncTxIndex for SyncTxIndex<T> {
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        self.0.lookup_tx(b)
+    }
+    async fn lookup_output(&self, b: &bitcoin::OutPoint) -> Result<bitcoin::TxOut> {
+        self.0.lookup_output(b)
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.0.add_tx(tx)
+    }
+}
+
+/// With an async primary, the cache is checked synchronously and anything
+/// missing is fetched from the primary and cached.
+///
+/// Batches of outputs are looked up a transaction at a time through
+/// `lookup_tx`, rather than with the primary's `lookup_outputs`, as only
+/// whole transactions can be cached.
+#[async_trait]
+impl<Cache, Primary> AsyncTxIndex for CachedTxIndex<Cache, Primary>
+where
+    Cache: TxIndex + Send + Sync,
+    Primary: AsyncTxIndex,
+{
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        if let Ok(ent) = self.cache.lookup_tx(b) {
+            Ok(ent)
+        } else {
+            let ent = self.primary.lookup_tx(b).await?;
+            self.cache.cache_tx(ent.clone())?;
+            Ok(ent)
+        }
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        let txid = tx.txid();
+        if self.cache.lookup_tx(&txid).is_ok() {
+            Ok(txid)
+        } else {
+            self.primary.add_tx(tx.clone()).await?;
+            self.cache.add_tx(tx)
+        }
+    }
+}
+
+/// A TxIndex serving outputs fetched ahead of time from an `AsyncTxIndex`,
+/// so that synchronous code (like `bind_psbt`) never waits on the network.
+///
+/// Transactions added are tracked locally, not forwarded.
+pub struct PrefetchedTxIndex {
+    outputs: BTreeMap<bitcoin::OutPoint, bitcoin::TxOut>,
+    added: TxIndexLogger,
+}
+
+impl PrefetchedTxIndex {
+    /// Fetches `outs` from `index` concurrently. Outputs which do not exist
+    /// are skipped, but any other error is returned.
+    pub async fn fetch(index: &dyn AsyncTxIndex, outs: &[bitcoin::OutPoint]) -> Result<Self> {
+        let mut outputs = BTreeMap::new();
+        for (out, r) in outs.iter().zip(index.lookup_outputs(outs).await) {
+            match r {
+                Ok(o) => {
+                    outputs.insert(*out, o);
+                }
+                Err(TxIndexError::UnknownTxid(_)) | Err(TxIndexError::IndexTooHigh(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(PrefetchedTxIndex {
+            outputs,
+            added: TxIndexLogger::new(),
+        })
+    }
+}
+
+impl TxIndex for PrefetchedTxIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        self.added.lookup_tx(b)
+    }
+    fn lookup_output(&self, b: &bitcoin::OutPoint) -> Result<bitcoin::TxOut> {
+        match self.outputs.get(b) {
+            Some(o) => Ok(o.clone()),
+            None => self.added.lookup_output(b),
+        }
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.added.add_tx(tx)
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Explaining what a PSBT does and what it still needs before it can be
+//! finalized, e.g. to review a PSBT from `bind_psbt` sent by a counterparty.
+use super::*;
+use bitcoin::hashes::sha256;
+use bitcoin::{Address, OutPoint, Txid};
+use miniscript::psbt::PsbtInputSatisfier;
+use miniscript::{Miniscript, Tap};
+use sapio_base::util::CTVHash;
+
+/// A summary of a PSBT
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtAnalysis {
+    /// the txid of the transaction
+    pub txid: Txid,
+    /// the transaction version
+    pub version: i32,
+    /// the transaction lock time
+    pub lock_time: u32,
+    /// one per input
+    pub inputs: Vec<InputAnalysis>,
+    /// one per output
+    pub outputs: Vec<OutputAnalysis>,
+    /// the value spent, if every spent output is known
+    pub input_value: Option<u64>,
+    /// the value created
+    pub output_value: u64,
+    /// the fee in sats, if every spent output is known
+    pub fee: Option<u64>,
+    /// the virtual size, counting the witnesses of finalized inputs only
+    pub vsize: u64,
+    /// whether some inputs are not yet finalized, so `vsize` is a lower bound
+    pub vsize_estimated: bool,
+    /// the fee rate in sats/vbyte, if the fee is known
+    pub feerate: Option<f64>,
+    /// problems which will stop the transaction being finalized or accepted
+    pub warnings: Vec<String>,
+}
+
+/// A summary of one input
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputAnalysis {
+    /// the output being spent
+    pub outpoint: OutPoint,
+    /// the input's sequence
+    pub sequence: u32,
+    /// the output being spent, from the witness or non witness utxo
+    pub utxo: Option<TxOut>,
+    /// the address being spent from, if standard
+    pub address: Option<Address>,
+    /// p2tr, p2wsh, p2wpkh, p2sh, p2pkh or unknown
+    pub script_type: String,
+    /// whether the input lacks a witness utxo, which segwit signers need
+    pub missing_witness_utxo: bool,
+    /// the CTV (BIP-119) template hash of the transaction at this input
+    pub ctv_hash: sha256::Hash,
+    /// whether the input has a final witness or script sig
+    pub finalized: bool,
+    /// whether there is a taproot key path signature
+    pub key_path_signed: bool,
+    /// the number of ECDSA partial signatures
+    pub partial_sigs: usize,
+    /// the taproot leaves present in the input
+    pub leaves: Vec<LeafAnalysis>,
+}
+
+/// A summary of one taproot leaf
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeafAnalysis {
+    /// the leaf's hash
+    pub leaf_hash: TapLeafHash,
+    /// the leaf's script, in asm
+    pub script: String,
+    /// the leaf as miniscript, if it is miniscript
+    pub miniscript: Option<String>,
+    /// the keys in the leaf and whether each has signed
+    pub keys: Vec<(XOnlyPublicKey, bool)>,
+    /// whether the leaf can be satisfied with what the PSBT has now
+    pub satisfiable: bool,
+}
+
+/// A summary of one output
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputAnalysis {
+    /// the value in sats
+    pub value: u64,
+    /// the output's script
+    pub script_pubkey: Script,
+    /// the address, if standard
+    pub address: Option<Address>,
+}
+
+fn script_type(s: &Script) -> &'static str {
+    if s.is_v1_p2tr() {
+        "p2tr"
+    } else if s.is_v0_p2wsh() {
+        "p2wsh"
+    } else if s.is_v0_p2wpkh() {
+        "p2wpkh"
+    } else if s.is_p2sh() {
+        "p2sh"
+    } else if s.is_p2pkh() {
+        "p2pkh"
+    } else {
+        "unknown"
+    }
+}
+
+fn analyze_leaf(
+    psbt: &PartiallySignedTransaction,
+    idx: usize,
+    script: &Script,
+    leaf_hash: TapLeafHash,
+) -> LeafAnalysis {
+    let input = &psbt.inputs[idx];
+    let mut leaf = LeafAnalysis {
+        leaf_hash,
+        script: script.asm(),
+        miniscript: None,
+        keys: vec![],
+        satisfiable: false,
+    };
+    if let Ok(ms) = Miniscript::<XOnlyPublicKey, Tap>::parse_insane(script) {
+        leaf.keys = ms
+            .iter_pk()
+            .map(|pk| (pk, input.tap_script_sigs.contains_key(&(pk, leaf_hash))))
+            .collect();
+        leaf.satisfiable = ms.satisfy(PsbtInputSatisfier::new(psbt, idx)).is_ok();
+        leaf.miniscript = Some(ms.to_string());
+    }
+    leaf
+}
+
+fn analyze_input(
+    psbt: &PartiallySignedTransaction,
+    tx: &bitcoin::Transaction,
+    idx: usize,
+    network: Network,
+) -> InputAnalysis {
+    let txin = &psbt.unsigned_tx.input[idx];
+    let input = &psbt.inputs[idx];
+    let utxo = input.witness_utxo.clone().or_else(|| {
+        input
+            .non_witness_utxo
+            .as_ref()
+            .filter(|prev| prev.txid() == txin.previous_output.txid)
+            .and_then(|prev| prev.output.get(txin.previous_output.vout as usize))
+            .cloned()
+    });
+    let leaves = input
+        .tap_scripts
+        .values()
+        .map(|(script, ver)| (script, TapLeafHash::from_script(script, *ver)))
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(script, leaf_hash)| analyze_leaf(psbt, idx, script, leaf_hash))
+        .collect();
+    InputAnalysis {
+        outpoint: txin.previous_output,
+        sequence: txin.sequence,
+        address: utxo
+            .as_ref()
+            .and_then(|u| Address::from_script(&u.script_pubkey, network)),
+        script_type: utxo
+            .as_ref()
+            .map_or("unknown", |u| script_type(&u.script_pubkey))
+            .into(),
+        utxo,
+        missing_witness_utxo: input.witness_utxo.is_none(),
+        ctv_hash: tx.get_ctv_hash(idx as u32),
+        finalized: input.final_script_witness.is_some() || input.final_script_sig.is_some(),
+        key_path_signed: input.tap_key_sig.is_some(),
+        partial_sigs: input.partial_sigs.len(),
+        leaves,
+    }
+}
+
+/// Explains a PSBT, using `network` for addresses
+pub fn analyze(psbt: &PartiallySignedTransaction, network: Network) -> PsbtAnalysis {
+    // includes the final witnesses, so the size is exact once finalized
+    let tx = psbt.clone().extract_tx();
+    let inputs: Vec<InputAnalysis> = (0..psbt.inputs.len())
+        .map(|i| analyze_input(psbt, &tx, i, network))
+        .collect();
+    let outputs = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|o| OutputAnalysis {
+            value: o.value,
+            script_pubkey: o.script_pubkey.clone(),
+            address: Address::from_script(&o.script_pubkey, network),
+        })
+        .collect();
+    let mut warnings = vec![];
+    for (i, input) in inputs.iter().enumerate() {
+        if input.utxo.is_none() {
+            warnings.push(format!("input {} spends an unknown output", i));
+        } else if input.missing_witness_utxo {
+            warnings.push(format!("input {} has no witness utxo", i));
+        }
+        if !input.finalized
+            && !input.key_path_signed
+            && input.partial_sigs == 0
+            && !input.leaves.iter().any(|l| l.satisfiable)
+        {
+            warnings.push(format!("input {} cannot be satisfied yet", i));
+        }
+    }
+    let input_value = inputs
+        .iter()
+        .map(|i| i.utxo.as_ref().map(|u| u.value))
+        .sum::<Option<u64>>();
+    let output_value = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+    let fee = input_value.and_then(|v| v.checked_sub(output_value));
+    if input_value.is_some() && fee.is_none() {
+        warnings.push("outputs are worth more than inputs".into());
+    }
+    let vsize = (tx.weight() as u64).div_ceil(4);
+    PsbtAnalysis {
+        txid: psbt.unsigned_tx.txid(),
+        version: psbt.unsigned_tx.version,
+        lock_time: psbt.unsigned_tx.lock_time,
+        vsize_estimated: inputs.iter().any(|i| !i.finalized),
+        feerate: fee.map(|f| f as f64 / vsize as f64),
+        inputs,
+        outputs,
+        input_value,
+        output_value,
+        fee,
+        vsize,
+        warnings,
+    }
+}
+
+impl Display for PsbtAnalysis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_unknown = |v: Option<u64>| v.map_or("unknown".into(), |v| v.to_string());
+        writeln!(
+            f,
+            "txid {} version {} lock_time {}",
+            self.txid, self.version, self.lock_time
+        )?;
+        writeln!(f, "\ninputs")?;
+        writeln!(
+            f,
+            "{:>3}  {:<75} {:>12}  {:<7} status",
+            "#", "outpoint", "value", "type"
+        )?;
+        for (i, input) in self.inputs.iter().enumerate() {
+            let mut status = vec![];
+            if input.finalized {
+                status.push("finalized".to_string());
+            }
+            if input.key_path_signed {
+                status.push("key path signed".into());
+            }
+            if input.partial_sigs > 0 {
+                status.push(format!("{} partial sigs", input.partial_sigs));
+            }
+            if !input.leaves.is_empty() {
+                status.push(format!(
+                    "{}/{} leaves satisfiable",
+                    input.leaves.iter().filter(|l| l.satisfiable).count(),
+                    input.leaves.len()
+                ));
+            }
+            if input.missing_witness_utxo {
+                status.push("no witness utxo".into());
+            }
+            writeln!(
+                f,
+                "{:>3}  {:<75} {:>12}  {:<7} {}",
+                i,
+                input.outpoint.to_string(),
+                or_unknown(input.utxo.as_ref().map(|u| u.value)),
+                input.script_type,
+                status.join(", ")
+            )?;
+            if let Some(address) = &input.address {
+                writeln!(f, "     address {}", address)?;
+            }
+            writeln!(f, "     ctv {} sequence {}", input.ctv_hash, input.sequence)?;
+            for leaf in &input.leaves {
+                writeln!(
+                    f,
+                    "     leaf {} {}",
+                    leaf.leaf_hash,
+                    if leaf.satisfiable {
+                        "satisfiable"
+                    } else {
+                        "unsatisfied"
+                    }
+                )?;
+                writeln!(
+                    f,
+                    "       {}",
+                    leaf.miniscript.as_ref().unwrap_or(&leaf.script)
+                )?;
+                for (key, signed) in &leaf.keys {
+                    writeln!(
+                        f,
+                        "       {} {}",
+                        key,
+                        if *signed { "signed" } else { "unsigned" }
+                    )?;
+                }
+            }
+        }
+        writeln!(f, "\noutputs")?;
+        writeln!(f, "{:>3}  {:>12}  address or script", "#", "value")?;
+        for (i, output) in self.outputs.iter().enumerate() {
+            let to = output
+                .address
+                .as_ref()
+                .map_or_else(|| output.script_pubkey.asm(), |a| a.to_string());
+            writeln!(f, "{:>3}  {:>12}  {}", i, output.value, to)?;
+        }
+        writeln!(
+            f,
+            "\nin {} out {} fee {}",
+            or_unknown(self.input_value),
+            self.output_value,
+            or_unknown(self.fee)
+        )?;
+        write!(
+            f,
+            "vsize {}{}",
+            self.vsize,
+            if self.vsize_estimated {
+                " (at least, not finalized)"
+            } else {
+                ""
+            }
+        )?;
+        match self.feerate {
+            Some(r) => writeln!(f, " feerate {:.2} sat/vB", r)?,
+            None => writeln!(f)?,
+        }
+        for w in &self.warnings {
+            writeln!(f, "warning: {}", w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::taproot::{LeafVersion, TaprootBuilder};
+    use std::str::FromStr;
+
+    #[test]
+    fn analyzes_ctv_and_key_leaves() {
+        let secp = Secp256k1::new();
+        let key = |b: u8| {
+            KeyPair::from_seckey_slice(&secp, &[b; 32])
+                .unwrap()
+                .x_only_public_key()
+                .0
+        };
+        let mut tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default(), Default::default()],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: Script::new_v1_p2tr(&secp, key(3), None),
+            }],
+        };
+        tx.input[1].previous_output.vout = 1;
+        let ctv = tx.get_ctv_hash(0);
+        let ctv_leaf =
+            Miniscript::<XOnlyPublicKey, Tap>::from_str(&format!("and_v(txtmpl({}),1)", ctv))
+                .unwrap()
+                .encode();
+        let key_leaf = Miniscript::<XOnlyPublicKey, Tap>::from_str(&format!("pk({})", key(2)))
+            .unwrap()
+            .encode();
+        let spend = TaprootBuilder::new()
+            .add_leaf(1, ctv_leaf.clone())
+            .unwrap()
+            .add_leaf(1, key_leaf.clone())
+            .unwrap()
+            .finalize(&secp, key(1))
+            .unwrap();
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: 10_000,
+            script_pubkey: Script::new_v1_p2tr_tweaked(spend.output_key()),
+        });
+        for script in [&ctv_leaf, &key_leaf] {
+            let ver = (script.clone(), LeafVersion::TapScript);
+            let cb = spend.control_block(&ver).unwrap();
+            psbt.inputs[0].tap_scripts.insert(cb, ver);
+        }
+
+        let analysis = analyze(&psbt, Network::Regtest);
+        let input = &analysis.inputs[0];
+        assert_eq!(input.ctv_hash, ctv);
+        assert_eq!(input.script_type, "p2tr");
+        assert_eq!(input.leaves.len(), 2);
+        let satisfiable: Vec<_> = input
+            .leaves
+            .iter()
+            .map(|l| (l.leaf_hash, l.satisfiable))
+            .collect();
+        assert!(satisfiable.contains(&(
+            TapLeafHash::from_script(&ctv_leaf, LeafVersion::TapScript),
+            true
+        )));
+        assert!(satisfiable.contains(&(
+            TapLeafHash::from_script(&key_leaf, LeafVersion::TapScript),
+            false
+        )));
+        assert!(analysis.inputs[1].missing_witness_utxo);
+        assert_eq!(analysis.input_value, None);
+        assert_eq!(analysis.fee, None);
+        assert!(analysis.vsize_estimated);
+        assert_eq!(
+            analysis.warnings,
+            vec![
+                "input 1 spends an unknown output".to_string(),
+                "input 1 cannot be satisfied yet".to_string(),
+            ]
+        );
+        let table = analysis.to_string();
+        assert!(table.contains("1/2 leaves satisfiable"));
+        assert!(table.contains("fee unknown"));
+    }
+}
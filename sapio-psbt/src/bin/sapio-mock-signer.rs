@@ -0,0 +1,38 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A reference external signer for testing, backed by a key file as written
+//! by `sapio-cli signer new`.
+//!
+//! Usage: `sapio-mock-signer <key file>`, with one JSON request on stdin.
+use sapio_psbt::external_signer::{SignerRequest, SignerResponse};
+use sapio_psbt::SigningKey;
+use std::io::Read;
+
+fn main() {
+    let response = match run() {
+        Ok(r) => r,
+        Err(error) => SignerResponse::Error { error },
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&response).expect("responses serialize")
+    );
+}
+
+fn run() -> Result<SignerResponse, String> {
+    let path = std::env::args()
+        .nth(1)
+        .ok_or("usage: sapio-mock-signer <key file>")?;
+    let buf = std::fs::read(path).map_err(|e| e.to_string())?;
+    let key = SigningKey::read_key_from_buf(&buf[..]).map_err(|e| e.to_string())?;
+    let mut input = String::new();
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|e| e.to_string())?;
+    let request: SignerRequest = serde_json::from_str(&input).map_err(|e| e.to_string())?;
+    Ok(key.serve(request))
+}
@@ -3,13 +3,13 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use bitcoin::consensus::serialize;
 use miniscript::psbt::PsbtExt;
 use serde::{Deserialize, Serialize};
 
 use bitcoin::secp256k1::Secp256k1;
 
 use bitcoin::psbt::PartiallySignedTransaction;
+use sapio_base::psbt_v2::{PsbtV2, PsbtVersion};
 
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
@@ -27,8 +27,25 @@ pub enum PSBTApi {
 }
 
 pub fn finalize_psbt_format_api(psbt: PartiallySignedTransaction) -> PSBTApi {
+    finalize_psbt_v2_format_api(psbt.into(), PsbtVersion::V0)
+}
+
+/// Like `finalize_psbt_format_api`, but an unfinished PSBT is returned
+/// encoded as `version`
+pub fn finalize_psbt_v2_format_api(mut psbt: PsbtV2, version: PsbtVersion) -> PSBTApi {
     let secp = Secp256k1::new();
-    psbt.finalize(&secp)
+    let inner = std::mem::replace(
+        &mut psbt.psbt,
+        PartiallySignedTransaction::from_unsigned_tx(bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .expect("an empty transaction is unsigned"),
+    );
+    inner
+        .finalize(&secp)
         .map(|tx| {
             let hex = bitcoin::consensus::encode::serialize_hex(&tx.extract_tx());
             PSBTApi::Finished {
@@ -36,9 +53,10 @@ pub fn finalize_psbt_format_api(psbt: PartiallySignedTransaction) -> PSBTApi {
                 hex,
             }
         })
-        .unwrap_or_else(|(psbt, errors)| {
+        .unwrap_or_else(|(inner, errors)| {
             let errors: Vec<_> = errors.iter().map(|e| format!("{:?}", e)).collect();
-            let encoded_psbt = base64::encode(serialize(&psbt));
+            psbt.psbt = inner;
+            let encoded_psbt = base64::encode(psbt.encode(version));
             PSBTApi::NotFinished {
                 completed: false,
                 psbt: encoded_psbt,
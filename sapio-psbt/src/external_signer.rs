@@ -0,0 +1,305 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Delegating signing to an external signer process.
+//!
+//! In the spirit of HWI, a signer is a program which is run once per request.
+//! It is given one JSON [`SignerRequest`] on stdin and must print one JSON
+//! [`SignerResponse`] on stdout, e.g.:
+//!
+//! ```text
+//! -> {"method": "get_fingerprint"}
+//! <- {"fingerprint": "d34db33f"}
+//! -> {"method": "get_xpub", "path": "m/86'/1'/0'"}
+//! <- {"xpub": "tpub..."}
+//! -> {"method": "sign_tx", "psbt": "cHNidP8B..."}
+//! <- {"psbt": "cHNidP8B..."}
+//! <- {"error": "user rejected the transaction"}
+//! ```
+//!
+//! PSBTs are base64 and may be version 0 or 2; signers return the version
+//! they were given. `sapio-mock-signer` is a reference signer backed by a key
+//! file, built on [`SigningKey::serve`].
+use super::*;
+use schemars::JsonSchema;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// A request to an external signer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum SignerRequest {
+    /// get the fingerprint of the signer's master key
+    GetFingerprint,
+    /// get the xpub at a derivation path
+    GetXpub {
+        /// the path from the master key
+        path: DerivationPath,
+    },
+    /// sign every input the signer has keys for
+    SignTx {
+        /// base64 PSBT
+        psbt: String,
+    },
+}
+
+/// A response from an external signer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SignerResponse {
+    /// the request failed or was refused
+    Error {
+        /// why
+        error: String,
+    },
+    /// response to `get_fingerprint`
+    Fingerprint {
+        /// master key fingerprint
+        fingerprint: Fingerprint,
+    },
+    /// response to `get_xpub`
+    Xpub {
+        /// the derived xpub
+        xpub: ExtendedPubKey,
+    },
+    /// response to `sign_tx`
+    Psbt {
+        /// base64 PSBT, in the version it was sent
+        psbt: String,
+    },
+}
+
+/// Errors talking to an external signer
+#[derive(Debug)]
+pub enum ExternalSignerError {
+    /// the signer could not be run
+    Io(std::io::Error),
+    /// the signer did not respond in time
+    Timeout,
+    /// the signer exited without a valid response
+    Exited {
+        /// exit code, if not killed by a signal
+        code: Option<i32>,
+        /// what the signer wrote to stderr
+        stderr: String,
+    },
+    /// the response was malformed or did not answer the request
+    Protocol(String),
+    /// the signer reported an error
+    Signer(String),
+}
+
+impl Display for ExternalSignerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ExternalSignerError {}
+
+impl From<std::io::Error> for ExternalSignerError {
+    fn from(e: std::io::Error) -> Self {
+        ExternalSignerError::Io(e)
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+/// How to run an external signer
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ExternalSigner {
+    /// the program to run
+    pub command: String,
+    /// arguments to pass to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// how long to wait for each response. Hardware signers may wait for a
+    /// human, so this defaults to 5 minutes.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl ExternalSigner {
+    /// A signer run as `command args...` with the default timeout
+    pub fn new(command: impl Into<String>, args: Vec<String>) -> Self {
+        ExternalSigner {
+            command: command.into(),
+            args,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    /// Runs the signer for one request
+    pub async fn request(
+        &self,
+        request: &SignerRequest,
+    ) -> Result<SignerResponse, ExternalSignerError> {
+        let mut child = tokio::process::Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut line = serde_json::to_vec(request).expect("requests serialize");
+        line.push(b'\n');
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // a signer may exit without reading, which is reported below
+        let _ = stdin.write_all(&line).await;
+        drop(stdin);
+        let output = tokio::time::timeout(
+            Duration::from_secs(self.timeout_secs),
+            child.wait_with_output(),
+        )
+        .await
+        .map_err(|_| ExternalSignerError::Timeout)??;
+        match serde_json::from_slice::<SignerResponse>(&output.stdout) {
+            Ok(SignerResponse::Error { error }) => Err(ExternalSignerError::Signer(error)),
+            Ok(response) => Ok(response),
+            Err(_) if !output.status.success() => Err(ExternalSignerError::Exited {
+                code: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().into(),
+            }),
+            Err(e) => Err(ExternalSignerError::Protocol(e.to_string())),
+        }
+    }
+
+    /// The fingerprint of the signer's master key
+    pub async fn fingerprint(&self) -> Result<Fingerprint, ExternalSignerError> {
+        match self.request(&SignerRequest::GetFingerprint).await? {
+            SignerResponse::Fingerprint { fingerprint } => Ok(fingerprint),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// The xpub at `path`
+    pub async fn get_xpub(
+        &self,
+        path: &DerivationPath,
+    ) -> Result<ExtendedPubKey, ExternalSignerError> {
+        let request = SignerRequest::GetXpub { path: path.clone() };
+        match self.request(&request).await? {
+            SignerResponse::Xpub { xpub } => Ok(xpub),
+            r => Err(unexpected(r)),
+        }
+    }
+
+    /// Has the signer sign `psbt`, sending it as `version`.
+    ///
+    /// The signed PSBT must be for the same transaction, so a signer cannot
+    /// substitute inputs or outputs.
+    pub async fn sign_psbt(
+        &self,
+        psbt: &PsbtV2,
+        version: PsbtVersion,
+    ) -> Result<PsbtV2, ExternalSignerError> {
+        let request = SignerRequest::SignTx {
+            psbt: base64::encode(psbt.encode(version)),
+        };
+        let signed = match self.request(&request).await? {
+            SignerResponse::Psbt { psbt } => psbt,
+            r => return Err(unexpected(r)),
+        };
+        let bytes = base64::decode(signed.trim())
+            .map_err(|e| ExternalSignerError::Protocol(e.to_string()))?;
+        let (signed, _) =
+            PsbtV2::decode_any(&bytes).map_err(|e| ExternalSignerError::Protocol(e.to_string()))?;
+        if signed.psbt.unsigned_tx != psbt.psbt.unsigned_tx {
+            return Err(ExternalSignerError::Protocol(
+                "signer returned a different transaction".into(),
+            ));
+        }
+        Ok(signed)
+    }
+}
+
+fn unexpected(r: SignerResponse) -> ExternalSignerError {
+    ExternalSignerError::Protocol(format!("unexpected response {:?}", r))
+}
+
+impl SigningKey {
+    /// Answers a signer request with the first key, signing with
+    /// `SIGHASH_ALL` unless an input asks otherwise.
+    pub fn serve(&self, request: SignerRequest) -> SignerResponse {
+        let secp = Secp256k1::new();
+        let key = match self.0.first() {
+            Some(key) => key,
+            None => {
+                return SignerResponse::Error {
+                    error: "no keys".into(),
+                }
+            }
+        };
+        let error = |e: &dyn Display| SignerResponse::Error {
+            error: e.to_string(),
+        };
+        match request {
+            SignerRequest::GetFingerprint => SignerResponse::Fingerprint {
+                fingerprint: key.fingerprint(&secp),
+            },
+            SignerRequest::GetXpub { path } => match key.derive_priv(&secp, &path) {
+                Ok(k) => SignerResponse::Xpub {
+                    xpub: ExtendedPubKey::from_priv(&secp, &k),
+                },
+                Err(e) => error(&e),
+            },
+            SignerRequest::SignTx { psbt } => {
+                let bytes = match base64::decode(psbt.trim()) {
+                    Ok(b) => b,
+                    Err(e) => return error(&e),
+                };
+                let (mut psbt, version) = match PsbtV2::decode_any(&bytes) {
+                    Ok(p) => p,
+                    Err(e) => return error(&e),
+                };
+                match self.sign_psbt_v2_mut(&mut psbt, &secp, SchnorrSighashType::All) {
+                    Ok(_) => SignerResponse::Psbt {
+                        psbt: base64::encode(psbt.encode(version)),
+                    },
+                    Err(e) => error(&e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocol_messages() {
+        let request: SignerRequest =
+            serde_json::from_str(r#"{"method": "get_xpub", "path": "m/86'/1'/0'"}"#).unwrap();
+        assert_eq!(
+            request,
+            SignerRequest::GetXpub {
+                path: "m/86'/1'/0'".parse().unwrap()
+            }
+        );
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[1; 32]).unwrap();
+        let key = SigningKey(vec![root]);
+        let response = key.serve(SignerRequest::GetFingerprint);
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"fingerprint":"{}"}}"#,
+                root.fingerprint(&Secp256k1::new())
+            )
+        );
+        assert_eq!(
+            serde_json::from_str::<SignerResponse>(&json).unwrap(),
+            response
+        );
+        assert!(matches!(
+            key.serve(SignerRequest::SignTx { psbt: "?".into() }),
+            SignerResponse::Error { .. }
+        ));
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An encrypted, multi key keystore.
+//!
+//! A keystore holds labeled master keys, each with labeled accounts (derivation
+//! paths from the master). On disk it is a JSON envelope holding the key
+//! derivation parameters and the ChaCha20-Poly1305 encrypted contents; the
+//! key is derived from a passphrase with PBKDF2-HMAC-SHA256.
+//!
+//! Keys are selected with `"<key>"` or `"<key>/<account>"`. Signing with an
+//! account only signs for key origins under the account's path.
+//!
+//! Note that decrypted keys are not zeroized from memory.
+use super::*;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::util::bip32::ChildNumber;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use std::path::Path;
+use std::str::FromStr;
+
+const KEYSTORE_VERSION: u32 = 1;
+/// bound into the ciphertext so the contents can't be moved between formats
+const AAD: &[u8] = b"sapio-keystore-v1";
+/// the default PBKDF2 work factor
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+/// the least PBKDF2 work factor a keystore may be saved or loaded with
+pub const MIN_ITERATIONS: u32 = 100_000;
+
+/// Errors using a keystore
+#[derive(Debug)]
+pub enum KeystoreError {
+    /// reading or writing the keystore file failed
+    Io(std::io::Error),
+    /// the file is not a keystore
+    Format(String),
+    /// the passphrase is wrong (or the file was modified)
+    Decryption,
+    /// no key or account has this label
+    UnknownLabel(String),
+    /// a key or account already has this label
+    DuplicateLabel(String),
+    /// key derivation failed
+    Bip32(bitcoin::util::bip32::Error),
+    /// the keystore's PBKDF2 work factor is below `MIN_ITERATIONS`
+    WeakKdf(u32),
+}
+
+impl Display for KeystoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for KeystoreError {}
+
+impl From<std::io::Error> for KeystoreError {
+    fn from(e: std::io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+impl From<bitcoin::util::bip32::Error> for KeystoreError {
+    fn from(e: bitcoin::util::bip32::Error) -> Self {
+        KeystoreError::Bip32(e)
+    }
+}
+
+/// A labeled master key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyEntry {
+    /// the name of the key
+    pub label: String,
+    /// the master key
+    pub xpriv: ExtendedPrivKey,
+    /// named derivation paths from the master key
+    #[serde(default)]
+    pub accounts: BTreeMap<String, DerivationPath>,
+}
+
+/// What a selector refers to: a key, and possibly one of its accounts
+#[derive(Debug, Clone, Copy)]
+pub struct Selection<'a> {
+    /// the selected key
+    pub key: &'a KeyEntry,
+    /// the selected account's path, if an account was selected
+    pub account: Option<&'a DerivationPath>,
+}
+
+impl<'a> Selection<'a> {
+    /// the path of the selection from the master key
+    pub fn path(&self) -> DerivationPath {
+        self.account.cloned().unwrap_or_default()
+    }
+
+    /// Derives the xpub at `path`, relative to the selection, returning it with
+    /// its origin.
+    pub fn xpub_at<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &DerivationPath,
+    ) -> Result<(KeySource, ExtendedPubKey), KeystoreError> {
+        let full = self.path().extend(path);
+        let xpriv = self.key.xpriv.derive_priv(secp, &full)?;
+        Ok((
+            (self.key.xpriv.fingerprint(secp), full),
+            ExtendedPubKey::from_priv(secp, &xpriv),
+        ))
+    }
+
+    /// Receive and change descriptors for the selection, as
+    /// `kind([origin]xpub/0/*)` and `kind([origin]xpub/1/*)`. `kind` is
+    /// `tr`, `wpkh` or `pkh`.
+    pub fn descriptors<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        kind: &str,
+    ) -> Result<Vec<String>, KeystoreError> {
+        if !matches!(kind, "tr" | "wpkh" | "pkh") {
+            return Err(KeystoreError::Format(format!(
+                "unsupported descriptor type {}",
+                kind
+            )));
+        }
+        let ((fingerprint, path), xpub) = self.xpub_at(secp, &DerivationPath::default())?;
+        let origin = path
+            .into_iter()
+            .map(|c| format!("/{}", c))
+            .collect::<String>();
+        [0, 1]
+            .iter()
+            .map(|branch| {
+                let s = format!("{}([{}{}]{}/{}/*)", kind, fingerprint, origin, xpub, branch);
+                Descriptor::<DescriptorPublicKey>::from_str(&s)
+                    .map(|d| d.to_string())
+                    .map_err(|e| KeystoreError::Format(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Signs every input of `psbt` the selection has keys for. With an
+    /// account selected, only key origins under the account are signed.
+    pub fn sign_psbt_v2_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PsbtV2,
+        secp: &Secp256k1<C>,
+        hash_ty: SchnorrSighashType,
+    ) -> Result<SigningReport, PSBTSigningError> {
+        let key = SigningKey(vec![self.key.xpriv]);
+        let account = match self.account {
+            Some(a) => a,
+            None => return key.sign_psbt_v2_mut(psbt, secp, hash_ty),
+        };
+        let fingerprint = self.key.xpriv.fingerprint(secp);
+        let outside = |(f, path): &KeySource| {
+            *f == fingerprint && !path.as_ref().starts_with(account.as_ref())
+        };
+        // sign a copy without the origins outside the account, then take its
+        // signatures
+        let mut restricted = psbt.clone();
+        for input in restricted.psbt.inputs.iter_mut() {
+            input.tap_key_origins.retain(|_, (_, src)| !outside(src));
+            input.bip32_derivation.retain(|_, src| !outside(src));
+        }
+        let report = key.sign_psbt_v2_mut(&mut restricted, secp, hash_ty)?;
+        for (input, signed) in psbt.psbt.inputs.iter_mut().zip(restricted.psbt.inputs) {
+            if signed.tap_key_sig.is_some() {
+                input.tap_key_sig = signed.tap_key_sig;
+            }
+            input.tap_script_sigs.extend(signed.tap_script_sigs);
+            input.partial_sigs.extend(signed.partial_sigs);
+        }
+        psbt.tx_modifiable = restricted.tx_modifiable;
+        Ok(report)
+    }
+}
+
+/// The decrypted contents of a keystore
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Keystore {
+    /// the keys, in the order added
+    pub keys: Vec<KeyEntry>,
+}
+
+impl Keystore {
+    /// Adds a master key
+    pub fn add_key(&mut self, label: &str, xpriv: ExtendedPrivKey) -> Result<(), KeystoreError> {
+        if label.is_empty() || label.contains('/') {
+            return Err(KeystoreError::Format(
+                "labels must be non empty and not contain '/'".into(),
+            ));
+        }
+        if self.keys.iter().any(|k| k.label == label) {
+            return Err(KeystoreError::DuplicateLabel(label.into()));
+        }
+        self.keys.push(KeyEntry {
+            label: label.into(),
+            xpriv,
+            accounts: Default::default(),
+        });
+        Ok(())
+    }
+
+    /// Adds an account, a labeled path from the master key, to a key
+    pub fn add_account(
+        &mut self,
+        key: &str,
+        label: &str,
+        path: DerivationPath,
+    ) -> Result<(), KeystoreError> {
+        let entry = self
+            .keys
+            .iter_mut()
+            .find(|k| k.label == key)
+            .ok_or_else(|| KeystoreError::UnknownLabel(key.into()))?;
+        if label.is_empty() || label.contains('/') {
+            return Err(KeystoreError::Format(
+                "labels must be non empty and not contain '/'".into(),
+            ));
+        }
+        if entry.accounts.contains_key(label) {
+            return Err(KeystoreError::DuplicateLabel(label.into()));
+        }
+        entry.accounts.insert(label.into(), path);
+        Ok(())
+    }
+
+    /// Selects `"<key>"` or `"<key>/<account>"`
+    pub fn select(&self, selector: &str) -> Result<Selection<'_>, KeystoreError> {
+        let (key, account) = match selector.split_once('/') {
+            Some((k, a)) => (k, Some(a)),
+            None => (selector, None),
+        };
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.label == key)
+            .ok_or_else(|| KeystoreError::UnknownLabel(key.into()))?;
+        let account = account
+            .map(|a| {
+                key.accounts
+                    .get(a)
+                    .ok_or_else(|| KeystoreError::UnknownLabel(selector.into()))
+            })
+            .transpose()?;
+        Ok(Selection { key, account })
+    }
+
+    /// All of the master keys, for signing with any of them
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey(self.keys.iter().map(|k| k.xpriv).collect())
+    }
+
+    /// Encrypts the keystore under `passphrase`
+    pub fn encrypt(&self, passphrase: &str, iterations: u32) -> EncryptedKeystore {
+        let mut rng = rand::thread_rng();
+        let salt: [u8; 16] = rng.gen();
+        let nonce: [u8; 12] = rng.gen();
+        let cipher = cipher(passphrase, &salt, iterations);
+        let plaintext = serde_json::to_vec(self).expect("keystores serialize");
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: AAD,
+                },
+            )
+            .expect("encryption cannot fail");
+        EncryptedKeystore {
+            version: KEYSTORE_VERSION,
+            kdf: Kdf::Pbkdf2HmacSha256 {
+                iterations,
+                salt: salt.to_hex(),
+            },
+            nonce: nonce.to_hex(),
+            ciphertext: ciphertext.to_hex(),
+        }
+    }
+
+    /// Reads and decrypts a keystore file
+    pub fn load(path: &Path, passphrase: &str) -> Result<Keystore, KeystoreError> {
+        let bytes = std::fs::read(path)?;
+        let encrypted: EncryptedKeystore =
+            serde_json::from_slice(&bytes).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        encrypted.decrypt(passphrase)
+    }
+
+    /// Encrypts and writes a keystore file, readable only by the owner.
+    /// `iterations` must be at least `MIN_ITERATIONS`.
+    pub fn save(
+        &self,
+        path: &Path,
+        passphrase: &str,
+        iterations: u32,
+    ) -> Result<(), KeystoreError> {
+        if iterations < MIN_ITERATIONS {
+            return Err(KeystoreError::WeakKdf(iterations));
+        }
+        let data = serde_json::to_vec_pretty(&self.encrypt(passphrase, iterations))
+            .expect("keystores serialize");
+        let tmp = path.with_extension("tmp");
+        {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut f = options.open(&tmp)?;
+            std::io::Write::write_all(&mut f, &data)?;
+            f.sync_all()?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// How the encryption key is derived from the passphrase
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kdf {
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2HmacSha256 {
+        /// the work factor
+        iterations: u32,
+        /// hex salt
+        salt: String,
+    },
+}
+
+/// A keystore as stored on disk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedKeystore {
+    /// the format version
+    pub version: u32,
+    /// key derivation parameters
+    pub kdf: Kdf,
+    /// hex ChaCha20-Poly1305 nonce
+    pub nonce: String,
+    /// hex encrypted JSON `Keystore`
+    pub ciphertext: String,
+}
+
+impl EncryptedKeystore {
+    /// Decrypts the keystore
+    pub fn decrypt(&self, passphrase: &str) -> Result<Keystore, KeystoreError> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::Format(format!(
+                "unsupported keystore version {}",
+                self.version
+            )));
+        }
+        let hex =
+            |s: &str| Vec::<u8>::from_hex(s).map_err(|e| KeystoreError::Format(e.to_string()));
+        let Kdf::Pbkdf2HmacSha256 { iterations, salt } = &self.kdf;
+        if *iterations < MIN_ITERATIONS {
+            return Err(KeystoreError::WeakKdf(*iterations));
+        }
+        let nonce = hex(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(KeystoreError::Format("nonce must be 12 bytes".into()));
+        }
+        let plaintext = cipher(passphrase, &hex(salt)?, *iterations)
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &hex(&self.ciphertext)?,
+                    aad: AAD,
+                },
+            )
+            .map_err(|_| KeystoreError::Decryption)?;
+        serde_json::from_slice(&plaintext).map_err(|e| KeystoreError::Format(e.to_string()))
+    }
+}
+
+fn cipher(passphrase: &str, salt: &[u8], iterations: u32) -> ChaCha20Poly1305 {
+    let key =
+        pbkdf2::pbkdf2_hmac_array::<sha2::Sha256, 32>(passphrase.as_bytes(), salt, iterations);
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Parses a path relative to a selection, e.g. `0/5` or `m/0/5`
+pub fn relative_path(s: &str) -> Result<DerivationPath, KeystoreError> {
+    let s = s.trim_start_matches("m/").trim_start_matches('/');
+    if s.is_empty() || s == "m" {
+        return Ok(DerivationPath::default());
+    }
+    s.split('/')
+        .map(ChildNumber::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .map(DerivationPath::from)
+        .map_err(KeystoreError::Bip32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_weak_kdf() {
+        let encrypted = Keystore::default().encrypt("hunter2", MIN_ITERATIONS - 1);
+        assert!(matches!(
+            encrypted.decrypt("hunter2"),
+            Err(KeystoreError::WeakKdf(_))
+        ));
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(matches!(
+            Keystore::default().save(&dir.path().join("keys.json"), "hunter2", 10),
+            Err(KeystoreError::WeakKdf(10))
+        ));
+    }
+
+    #[test]
+    fn encrypts_selects_and_signs_accounts() {
+        let secp = Secp256k1::new();
+        let mut ks = Keystore::default();
+        let root = ExtendedPrivKey::new_master(Network::Testnet, &[9; 32]).unwrap();
+        ks.add_key("main", root).unwrap();
+        assert!(matches!(
+            ks.add_key("main", root),
+            Err(KeystoreError::DuplicateLabel(_))
+        ));
+        let account = DerivationPath::from_str("m/86'/1'/0'").unwrap();
+        ks.add_account("main", "savings", account.clone()).unwrap();
+
+        let encrypted = ks.encrypt("hunter2", MIN_ITERATIONS);
+        assert!(matches!(
+            encrypted.decrypt("hunter3"),
+            Err(KeystoreError::Decryption)
+        ));
+        let ks = encrypted.decrypt("hunter2").unwrap();
+
+        let savings = ks.select("main/savings").unwrap();
+        let ((_, path), xpub) = savings
+            .xpub_at(&secp, &relative_path("0/1").unwrap())
+            .unwrap();
+        assert_eq!(path, account.extend(relative_path("0/1").unwrap()));
+        assert_eq!(
+            xpub,
+            ExtendedPubKey::from_priv(&secp, &root.derive_priv(&secp, &path).unwrap())
+        );
+        let descriptors = savings.descriptors(&secp, "tr").unwrap();
+        assert!(descriptors[0].starts_with(&format!("tr([{}/86'/1'/0']", root.fingerprint(&secp))));
+        assert!(descriptors[1].contains("/1/*)#"));
+
+        // one input under the account, one outside it
+        let mut psbt = PsbtV2::new(2, None);
+        for (i, p) in ["m/86'/1'/0'/0/0", "m/84'/1'/0'/0/0"].iter().enumerate() {
+            let path = DerivationPath::from_str(p).unwrap();
+            let key = root
+                .derive_priv(&secp, &path)
+                .unwrap()
+                .to_keypair(&secp)
+                .x_only_public_key()
+                .0;
+            let mut input = bitcoin::psbt::Input {
+                tap_internal_key: Some(key),
+                ..Default::default()
+            };
+            input
+                .tap_key_origins
+                .insert(key, (vec![], (root.fingerprint(&secp), path)));
+            input.witness_utxo = Some(TxOut {
+                value: 1000,
+                script_pubkey: Script::new_v1_p2tr(&secp, key, None),
+            });
+            let txin = bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint::new(Default::default(), i as u32),
+                ..Default::default()
+            };
+            psbt.add_input(txin, input, Default::default()).unwrap();
+        }
+        let report = savings
+            .sign_psbt_v2_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0]);
+        assert!(psbt.psbt.inputs[1].tap_key_sig.is_none());
+        // the whole key signs both
+        let report = ks
+            .select("main")
+            .unwrap()
+            .sign_psbt_v2_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0, 1]);
+    }
+}
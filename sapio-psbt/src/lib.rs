@@ -5,23 +5,98 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use bitcoin::consensus::serialize;
+use bitcoin::psbt::PsbtSighashType;
 use bitcoin::schnorr::TapTweak;
 use bitcoin::secp256k1::rand::Rng;
 use bitcoin::secp256k1::{rand, Signing, Verification};
-use bitcoin::util::bip32::{ExtendedPubKey, Fingerprint, KeySource};
+use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey, Fingerprint, KeySource};
 use bitcoin::util::sighash::Prevouts;
+use bitcoin::util::sighash::SighashCache;
 use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::util::taproot::TapSighashHash;
 use bitcoin::XOnlyPublicKey;
 use bitcoin::{
     psbt::PartiallySignedTransaction, secp256k1::Secp256k1, util::bip32::ExtendedPrivKey,
 };
+use bitcoin::{EcdsaSig, EcdsaSighashType, SchnorrSighashType, Script};
 use bitcoin::{KeyPair, TxOut};
 use bitcoin::{Network, SchnorrSig};
-use std::collections::BTreeMap;
+pub use sapio_base::psbt_v2::{PsbtV2, PsbtVersion};
+use sapio_base::psbt_v2::{HAS_SIGHASH_SINGLE, INPUTS_MODIFIABLE, OUTPUTS_MODIFIABLE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::fmt::Display;
+pub mod analyze;
 pub mod external_api;
+pub mod external_signer;
+pub mod keystore;
+
+/// Why (part of) an input was not signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipReason {
+    /// The input has no key origins (taproot or bip32) to sign with
+    NoKeyOrigins,
+    /// The sighash type requested by the input is not valid for its script type
+    InvalidSighashType(u32),
+    /// The output being spent is neither taproot, P2WPKH nor P2WSH
+    UnsupportedScript,
+    /// The witness script is missing or does not hash to the P2WSH output
+    WitnessScriptMismatch,
+    /// One of our keys is not used by the script being spent
+    KeyNotInScript(bitcoin::PublicKey),
+    /// The output spent by this input (or by the input at this index, which
+    /// the sighash commits to) is unknown
+    MissingUtxo(usize),
+    /// None of our keys match the internal key
+    NoInternalKey,
+    /// The tweaked internal key is not the key of the output being spent
+    OutputKeyMismatch,
+    /// A key origin names a leaf which is not one of the input's scripts
+    UnknownLeaf(TapLeafHash),
+    /// None of our keys appear in the input's key origins
+    NoMatchingKeys,
+    /// The signature hash could not be computed
+    Sighash(String),
+}
+
+/// What was signed for one input
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputReport {
+    /// the sighash type signed with
+    pub sighash_type: Option<PsbtSighashType>,
+    /// whether the key path was signed
+    pub key_path: bool,
+    /// the (key, leaf) pairs signed for script paths
+    pub leaves: Vec<(XOnlyPublicKey, TapLeafHash)>,
+    /// the keys which added an ECDSA signature to a segwit v0 input
+    pub ecdsa: Vec<bitcoin::PublicKey>,
+    /// what was not signed, and why
+    pub skipped: Vec<SkipReason>,
+}
+
+impl InputReport {
+    /// whether any signature was added
+    pub fn signed(&self) -> bool {
+        self.key_path || !self.leaves.is_empty() || !self.ecdsa.is_empty()
+    }
+}
+
+/// What was signed for every input of a PSBT
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningReport {
+    /// reports, by input index
+    pub inputs: Vec<InputReport>,
+}
+
+impl SigningReport {
+    /// the indexes of the inputs which received a signature
+    pub fn signed_inputs(&self) -> Vec<usize> {
+        (0..self.inputs.len())
+            .filter(|i| self.inputs[*i].signed())
+            .collect()
+    }
+}
 
 pub struct SigningKey(pub Vec<ExtendedPrivKey>);
 
@@ -46,12 +121,20 @@ impl SigningKey {
     }
     pub fn sign(
         &self,
-        mut psbt: PartiallySignedTransaction,
+        psbt: PartiallySignedTransaction,
         hash_ty: bitcoin::SchnorrSighashType,
     ) -> Result<Vec<u8>, PSBTSigningError> {
-        self.sign_psbt_mut(&mut psbt, &Secp256k1::new(), hash_ty)?;
+        self.sign_with_report(psbt, hash_ty).map(|(bytes, _)| bytes)
+    }
+    /// Like `sign`, but also returns what was signed
+    pub fn sign_with_report(
+        &self,
+        mut psbt: PartiallySignedTransaction,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<(Vec<u8>, SigningReport), PSBTSigningError> {
+        let report = self.sign_psbt_mut(&mut psbt, &Secp256k1::new(), hash_ty)?;
         let bytes = serialize(&psbt);
-        Ok(bytes)
+        Ok((bytes, report))
     }
     pub fn sign_psbt<C: Signing + Verification>(
         &self,
@@ -60,21 +143,50 @@ impl SigningKey {
         hash_ty: bitcoin::SchnorrSighashType,
     ) -> Result<PartiallySignedTransaction, (PartiallySignedTransaction, PSBTSigningError)> {
         match self.sign_psbt_mut(&mut psbt, secp, hash_ty) {
-            Ok(()) => Ok(psbt),
+            Ok(_) => Ok(psbt),
             Err(e) => Err((psbt, e)),
         }
     }
+    /// Signs every input we have keys for. `hash_ty` is used for inputs which
+    /// do not request a sighash type of their own.
     pub fn sign_psbt_mut<C: Signing + Verification>(
         &self,
         psbt: &mut PartiallySignedTransaction,
         secp: &Secp256k1<C>,
         hash_ty: bitcoin::SchnorrSighashType,
-    ) -> Result<(), PSBTSigningError> {
+    ) -> Result<SigningReport, PSBTSigningError> {
         let l = psbt.inputs.len();
+        let mut report = SigningReport::default();
         for idx in 0..l {
-            self.sign_psbt_input_mut(psbt, secp, idx, hash_ty)?;
+            report
+                .inputs
+                .push(self.sign_psbt_input_mut(psbt, secp, idx, hash_ty)?);
+        }
+        Ok(report)
+    }
+    /// Signs a version 2 PSBT, updating its `tx_modifiable` flags as BIP-370
+    /// requires for the signatures added.
+    pub fn sign_psbt_v2_mut<C: Signing + Verification>(
+        &self,
+        psbt: &mut PsbtV2,
+        secp: &Secp256k1<C>,
+        hash_ty: bitcoin::SchnorrSighashType,
+    ) -> Result<SigningReport, PSBTSigningError> {
+        let report = self.sign_psbt_mut(&mut psbt.psbt, secp, hash_ty)?;
+        for input in report.inputs.iter().filter(|i| i.signed()) {
+            let ty = input.sighash_type.map_or(0, |t| t.to_u32());
+            if ty & 0x80 == 0 {
+                psbt.tx_modifiable &= !INPUTS_MODIFIABLE;
+            }
+            // SIGHASH_NONE is 2, SIGHASH_SINGLE is 3
+            if ty & 0x1f != 2 {
+                psbt.tx_modifiable &= !OUTPUTS_MODIFIABLE;
+            }
+            if ty & 0x1f == 3 {
+                psbt.tx_modifiable |= HAS_SIGHASH_SINGLE;
+            }
         }
-        Ok(())
+        Ok(report)
     }
     pub fn sign_psbt_input<C: Signing + Verification>(
         &self,
@@ -84,101 +196,256 @@ impl SigningKey {
         hash_ty: bitcoin::SchnorrSighashType,
     ) -> Result<PartiallySignedTransaction, (PartiallySignedTransaction, PSBTSigningError)> {
         match self.sign_psbt_input_mut(&mut psbt, secp, idx, hash_ty) {
-            Ok(()) => Ok(psbt),
+            Ok(_) => Ok(psbt),
             Err(e) => Err((psbt, e)),
         }
     }
+    /// Signs the key path and any script paths of input `idx` that we have
+    /// keys for, without an annex. Segwit v0 inputs (P2WPKH, or P2WSH with a
+    /// `witness_script`) get ECDSA signatures for the keys in `bip32_derivation`.
+    ///
+    /// The input's own `sighash_type` takes precedence over `hash_ty`. With
+    /// `ANYONECANPAY`, only the output spent by this input needs to be known.
     pub fn sign_psbt_input_mut<C: Signing + Verification>(
         &self,
         psbt: &mut PartiallySignedTransaction,
         secp: &Secp256k1<C>,
         idx: usize,
         hash_ty: bitcoin::SchnorrSighashType,
-    ) -> Result<(), PSBTSigningError> {
-        let tx = psbt.clone().extract_tx();
-        let utxos: Vec<TxOut> = psbt
+    ) -> Result<InputReport, PSBTSigningError> {
+        let mut report = InputReport::default();
+        let input = psbt
+            .inputs
+            .get(idx)
+            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
+        if input.tap_internal_key.is_none() && input.tap_key_origins.is_empty() {
+            if input.bip32_derivation.is_empty() {
+                report.skipped.push(SkipReason::NoKeyOrigins);
+            } else {
+                self.sign_segwit_v0_input(psbt, secp, idx, hash_ty, &mut report);
+            }
+            return Ok(report);
+        }
+        let hash_ty = match input.sighash_type {
+            None => hash_ty,
+            Some(t) => match t.schnorr_hash_ty() {
+                Ok(t) => t,
+                Err(_) => {
+                    report
+                        .skipped
+                        .push(SkipReason::InvalidSighashType(t.to_u32()));
+                    return Ok(report);
+                }
+            },
+        };
+        report.sighash_type = Some(hash_ty.into());
+        let anyone_can_pay = matches!(
+            hash_ty,
+            SchnorrSighashType::AllPlusAnyoneCanPay
+                | SchnorrSighashType::NonePlusAnyoneCanPay
+                | SchnorrSighashType::SinglePlusAnyoneCanPay
+        );
+        let utxos: Vec<TxOut> = match psbt
             .inputs
             .iter()
             .enumerate()
-            .map(|(i, o)| {
-                if let Some(ref utxo) = o.witness_utxo {
-                    Ok(utxo.clone())
-                } else {
-                    Err(i)
-                }
-            })
+            .filter(|(i, _)| !anyone_can_pay || *i == idx)
+            .map(|(i, o)| o.witness_utxo.clone().ok_or(i))
             .collect::<Result<Vec<TxOut>, usize>>()
-            .map_err(PSBTSigningError::NoUTXOAtIndex)?;
-        let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
-        let input = &mut psbt
-            .inputs
-            .get_mut(idx)
-            .ok_or(PSBTSigningError::NoInputAtIndex(idx))?;
-        let prevouts = &Prevouts::All(&utxos);
+        {
+            Ok(utxos) => utxos,
+            Err(i) => {
+                report.skipped.push(SkipReason::MissingUtxo(i));
+                return Ok(report);
+            }
+        };
+        let prevouts = if anyone_can_pay {
+            Prevouts::One(idx, utxos[0].clone())
+        } else {
+            Prevouts::All(&utxos)
+        };
+        let spent = psbt.inputs[idx].witness_utxo.clone();
+        let mut sighash = bitcoin::util::sighash::SighashCache::new(&psbt.unsigned_tx);
+        let input = &mut psbt.inputs[idx];
         let fingerprints_map = self.compute_fingerprint_map(secp);
-        self.sign_taproot_top_key(
-            secp,
-            input,
-            &mut sighash,
-            prevouts,
-            hash_ty,
-            &fingerprints_map,
-        );
-        self.sign_all_tapleaf_branches(
+        let mut signer = InputSigner {
             secp,
-            input,
-            &mut sighash,
-            prevouts,
+            idx,
+            sighash: &mut sighash,
+            prevouts: &prevouts,
             hash_ty,
-            &fingerprints_map,
-        );
-        Ok(())
+            report: &mut report,
+        };
+        if input.tap_internal_key.is_some() {
+            self.sign_taproot_top_key(&mut signer, input, spent.as_ref(), &fingerprints_map);
+        }
+        self.sign_all_tapleaf_branches(&mut signer, input, &fingerprints_map);
+        Ok(report)
     }
 
-    fn sign_all_tapleaf_branches<C: Signing + Verification>(
+    fn sign_segwit_v0_input<C: Signing>(
         &self,
+        psbt: &mut PartiallySignedTransaction,
         secp: &Secp256k1<C>,
+        idx: usize,
+        hash_ty: SchnorrSighashType,
+        report: &mut InputReport,
+    ) {
+        let input = &psbt.inputs[idx];
+        let hash_ty = match input.sighash_type {
+            None if hash_ty == SchnorrSighashType::Default => Ok(EcdsaSighashType::All),
+            None => EcdsaSighashType::from_standard(hash_ty as u32).map_err(|_| hash_ty as u32),
+            Some(t) => t.ecdsa_hash_ty().map_err(|_| t.to_u32()),
+        };
+        let hash_ty = match hash_ty {
+            Ok(t) => t,
+            Err(t) => {
+                report.skipped.push(SkipReason::InvalidSighashType(t));
+                return;
+            }
+        };
+        report.sighash_type = Some(hash_ty.into());
+        let vout = psbt.unsigned_tx.input[idx].previous_output.vout as usize;
+        let utxo = match input.witness_utxo.clone().or_else(|| {
+            input
+                .non_witness_utxo
+                .as_ref()
+                .and_then(|tx| tx.output.get(vout).cloned())
+        }) {
+            Some(utxo) => utxo,
+            None => {
+                report.skipped.push(SkipReason::MissingUtxo(idx));
+                return;
+            }
+        };
+        let spk = &utxo.script_pubkey;
+        let witness_script = if spk.is_v0_p2wsh() {
+            match &input.witness_script {
+                Some(ws) if Script::new_v0_p2wsh(&ws.wscript_hash()) == *spk => Some(ws),
+                _ => {
+                    report.skipped.push(SkipReason::WitnessScriptMismatch);
+                    return;
+                }
+            }
+        } else if spk.is_v0_p2wpkh() {
+            None
+        } else {
+            report.skipped.push(SkipReason::UnsupportedScript);
+            return;
+        };
+        let fingerprints_map = self.compute_fingerprint_map(secp);
+        let mut sighash = SighashCache::new(&psbt.unsigned_tx);
+        let mut sigs = vec![];
+        for (key, source) in &input.bip32_derivation {
+            let sk = match self.find_derived_key(secp, key, source, &fingerprints_map) {
+                Some(sk) => sk,
+                None => continue,
+            };
+            let pk = bitcoin::PublicKey::new(*key);
+            let script_code = match witness_script {
+                Some(ws) if script_uses_key(ws, &pk) => ws.clone(),
+                None if pk
+                    .wpubkey_hash()
+                    .map(|h| Script::new_v0_p2wpkh(&h))
+                    .as_ref()
+                    == Some(spk) =>
+                {
+                    Script::new_p2pkh(&pk.pubkey_hash())
+                }
+                _ => {
+                    report.skipped.push(SkipReason::KeyNotInScript(pk));
+                    continue;
+                }
+            };
+            match sighash.segwit_signature_hash(idx, &script_code, utxo.value, hash_ty) {
+                Ok(h) => {
+                    let msg = bitcoin::secp256k1::Message::from_digest_slice(&h[..])
+                        .expect("Size must be correct.");
+                    let sig = secp.sign_ecdsa(&msg, &sk.private_key);
+                    sigs.push((pk, EcdsaSig { sig, hash_ty }));
+                }
+                Err(e) => report.skipped.push(SkipReason::Sighash(e.to_string())),
+            }
+        }
+        if sigs.is_empty() && report.skipped.is_empty() {
+            report.skipped.push(SkipReason::NoMatchingKeys);
+        }
+        for (pk, sig) in sigs {
+            psbt.inputs[idx].partial_sigs.insert(pk, sig);
+            report.ecdsa.push(pk);
+        }
+    }
+
+    fn sign_all_tapleaf_branches<C: Signing + Verification>(
+        &self,
+        signer: &mut InputSigner<'_, C>,
         input: &mut bitcoin::psbt::Input,
-        sighash: &mut bitcoin::util::sighash::SighashCache<&bitcoin::Transaction>,
-        prevouts: &Prevouts<TxOut>,
-        hash_ty: bitcoin::SchnorrSighashType,
         fingerprints_map: &Vec<(Fingerprint, &ExtendedPrivKey)>,
     ) {
-        let signers = self.compute_matching_keys(secp, &input.tap_key_origins, fingerprints_map);
+        // if the input lists its scripts, only sign for those
+        let known_leaves: BTreeSet<TapLeafHash> = input
+            .tap_scripts
+            .values()
+            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+            .collect();
+        let signers: Vec<_> = self
+            .compute_matching_keys(signer.secp, &input.tap_key_origins, fingerprints_map)
+            .map(|(kp, v)| (kp, v.clone()))
+            .collect();
+        if signers.iter().all(|(_, v)| v.is_empty()) {
+            if !signer.report.key_path {
+                signer.report.skipped.push(SkipReason::NoMatchingKeys);
+            }
+            return;
+        }
         for (kp, vtlh) in signers {
             for tlh in vtlh {
-                let sig = get_sig(
-                    sighash,
-                    prevouts,
-                    hash_ty,
-                    secp,
-                    &kp,
-                    &Some((*tlh, DEFAULT_CODESEP)),
-                );
-                input
-                    .tap_script_sigs
-                    .insert((kp.x_only_public_key().0, *tlh), sig);
+                if !known_leaves.is_empty() && !known_leaves.contains(&tlh) {
+                    signer.report.skipped.push(SkipReason::UnknownLeaf(tlh));
+                    continue;
+                }
+                if let Some(sig) = signer.sign(&kp, Some((tlh, DEFAULT_CODESEP))) {
+                    let pk = kp.x_only_public_key().0;
+                    input.tap_script_sigs.insert((pk, tlh), sig);
+                    signer.report.leaves.push((pk, tlh));
+                }
             }
         }
     }
 
     fn sign_taproot_top_key<C: Signing + Verification>(
         &self,
-        secp: &Secp256k1<C>,
+        signer: &mut InputSigner<'_, C>,
         input: &mut bitcoin::psbt::Input,
-        sighash: &mut bitcoin::util::sighash::SighashCache<&bitcoin::Transaction>,
-        prevouts: &Prevouts<TxOut>,
-        hash_ty: bitcoin::SchnorrSighashType,
+        spent: Option<&TxOut>,
         fingerprints_map: &Vec<(Fingerprint, &ExtendedPrivKey)>,
-    ) -> Option<()> {
+    ) {
         // first attempt to use derivations from the key source map
-        let key = input.tap_internal_key?;
-        let untweaked = self.find_internal_keypair(input, key, fingerprints_map, secp)?;
+        let key = match input.tap_internal_key {
+            Some(key) => key,
+            None => return,
+        };
+        let untweaked = match self.find_internal_keypair(input, key, fingerprints_map, signer.secp)
+        {
+            Some(kp) => kp,
+            None => {
+                signer.report.skipped.push(SkipReason::NoInternalKey);
+                return;
+            }
+        };
         let tweaked = untweaked
-            .tap_tweak(secp, input.tap_merkle_root)
+            .tap_tweak(signer.secp, input.tap_merkle_root)
             .into_inner();
-        input.tap_key_sig = Some(get_sig(sighash, prevouts, hash_ty, secp, &tweaked, &None));
-        Some(())
+        if let Some(spk) = spent.map(|o| &o.script_pubkey) {
+            if spk.is_v1_p2tr() && spk[2..] != tweaked.x_only_public_key().0.serialize()[..] {
+                signer.report.skipped.push(SkipReason::OutputKeyMismatch);
+                return;
+            }
+        }
+        if let Some(sig) = signer.sign(&tweaked, None) {
+            input.tap_key_sig = Some(sig);
+            signer.report.key_path = true;
+        }
     }
 
     fn find_internal_keypair<C: Signing>(
@@ -211,6 +478,23 @@ impl SigningKey {
         None
     }
 
+    /// Finds the key derived from one of ours which matches a bip32 origin
+    fn find_derived_key<C: Signing>(
+        &self,
+        secp: &Secp256k1<C>,
+        key: &bitcoin::secp256k1::PublicKey,
+        (f, path): &KeySource,
+        fingerprints_map: &[(Fingerprint, &ExtendedPrivKey)],
+    ) -> Option<ExtendedPrivKey> {
+        let idx = fingerprints_map.partition_point(|(x, _)| *x < *f);
+        fingerprints_map
+            .iter()
+            .skip(idx)
+            .take_while(|k| k.0 == *f)
+            .filter_map(|(_, xpriv)| xpriv.derive_priv(secp, path).ok())
+            .find(|sk| sk.private_key.public_key(secp) == *key)
+    }
+
     /// Compute keypairs for all matching fingerprints
     fn compute_matching_keys<'a, C: Signing>(
         &'a self,
@@ -230,8 +514,6 @@ impl SigningKey {
                     Ok(kp) => {
                         if kp.public_key().x_only_public_key().0 == *x {
                             return Some((kp, vlth));
-                        } else {
-                            return None;
                         }
                     }
                     Err(_) => continue,
@@ -268,19 +550,290 @@ impl Display for PSBTSigningError {
 impl Error for PSBTSigningError {}
 
 const DEFAULT_CODESEP: u32 = 0xffff_ffff;
-fn get_sig<C: Signing>(
-    sighash: &mut bitcoin::util::sighash::SighashCache<&bitcoin::Transaction>,
-    prevouts: &Prevouts<TxOut>,
+
+/// whether `pk` is pushed anywhere in `script`
+fn script_uses_key(script: &Script, pk: &bitcoin::PublicKey) -> bool {
+    let pk = pk.to_bytes();
+    script.instructions().any(
+        |i| matches!(i, Ok(bitcoin::blockdata::script::Instruction::PushBytes(b)) if b == &pk[..]),
+    )
+}
+
+/// Everything needed to sign one input, and where to record what happened
+struct InputSigner<'a, C: Signing> {
+    secp: &'a Secp256k1<C>,
+    idx: usize,
+    sighash: &'a mut bitcoin::util::sighash::SighashCache<&'a bitcoin::Transaction>,
+    prevouts: &'a Prevouts<'a, TxOut>,
     hash_ty: bitcoin::SchnorrSighashType,
-    secp: &Secp256k1<C>,
-    kp: &bitcoin::KeyPair,
-    path: &Option<(TapLeafHash, u32)>,
-) -> SchnorrSig {
-    let annex = None;
-    let sighash: TapSighashHash = sighash
-        .taproot_signature_hash(0, prevouts, annex, *path, hash_ty)
-        .expect("Signature hash cannot fail...");
-    let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..]).expect("Size must be correct.");
-    let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-    SchnorrSig { sig, hash_ty }
+    report: &'a mut InputReport,
+}
+
+impl<'a, C: Signing> InputSigner<'a, C> {
+    /// signs the key path (`path` is None) or a script path, without an annex
+    fn sign(
+        &mut self,
+        kp: &bitcoin::KeyPair,
+        path: Option<(TapLeafHash, u32)>,
+    ) -> Option<SchnorrSig> {
+        let annex = None;
+        let sighash: TapSighashHash = match self.sighash.taproot_signature_hash(
+            self.idx,
+            self.prevouts,
+            annex,
+            path,
+            self.hash_ty,
+        ) {
+            Ok(h) => h,
+            Err(e) => {
+                self.report.skipped.push(SkipReason::Sighash(e.to_string()));
+                return None;
+            }
+        };
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..])
+            .expect("Size must be correct.");
+        let sig = self.secp.sign_schnorr_no_aux_rand(&msg, kp);
+        Some(SchnorrSig {
+            sig,
+            hash_ty: self.hash_ty,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::bip32::DerivationPath;
+    use bitcoin::{OutPoint, Transaction, TxIn};
+    use std::str::FromStr;
+
+    /// a psbt spending a key path output of each key in `paths`
+    fn psbt_for(
+        secp: &Secp256k1<bitcoin::secp256k1::All>,
+        root: &ExtendedPrivKey,
+        paths: &[&str],
+    ) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..paths.len())
+                .map(|i| TxIn {
+                    previous_output: OutPoint::new(Default::default(), i as u32),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        for (input, path) in psbt.inputs.iter_mut().zip(paths) {
+            let path = DerivationPath::from_str(path).unwrap();
+            let key = root
+                .derive_priv(secp, &path)
+                .unwrap()
+                .to_keypair(secp)
+                .x_only_public_key()
+                .0;
+            input.tap_internal_key = Some(key);
+            input
+                .tap_key_origins
+                .insert(key, (vec![], (root.fingerprint(secp), path)));
+            input.witness_utxo = Some(TxOut {
+                value: 2000,
+                script_pubkey: Script::new_v1_p2tr(secp, key, None),
+            });
+        }
+        psbt
+    }
+
+    #[test]
+    fn signs_every_input_at_its_index() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let key = SigningKey(vec![root]);
+        let mut psbt = psbt_for(&secp, &root, &["m/0", "m/1"]);
+        let report = key
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::All)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0, 1]);
+        let utxos: Vec<TxOut> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        for (idx, input) in psbt.inputs.iter().enumerate() {
+            let h = cache
+                .taproot_key_spend_signature_hash(
+                    idx,
+                    &Prevouts::All(&utxos),
+                    SchnorrSighashType::All,
+                )
+                .unwrap();
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&h[..]).unwrap();
+            let pk = XOnlyPublicKey::from_slice(
+                &input.witness_utxo.as_ref().unwrap().script_pubkey[2..],
+            )
+            .unwrap();
+            secp.verify_schnorr(&input.tap_key_sig.unwrap().sig, &msg, &pk)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn per_input_sighash_and_skips() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let key = SigningKey(vec![root]);
+        let mut psbt = psbt_for(&secp, &root, &["m/0", "m/1", "m/2"]);
+        // the first input only commits to itself, so it can be signed without
+        // the other utxo
+        psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(
+            SchnorrSighashType::AllPlusAnyoneCanPay,
+        ));
+        psbt.inputs[1].witness_utxo = None;
+        // the last input is not ours
+        psbt.inputs[2].tap_key_origins.clear();
+        let report = key
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0]);
+        assert_eq!(
+            psbt.inputs[0].tap_key_sig.unwrap().hash_ty,
+            SchnorrSighashType::AllPlusAnyoneCanPay
+        );
+        assert_eq!(report.inputs[1].skipped, vec![SkipReason::MissingUtxo(1)]);
+        assert_eq!(report.inputs[2].skipped, vec![SkipReason::MissingUtxo(1)]);
+        psbt.inputs[1].witness_utxo = psbt_for(&secp, &root, &["m/0", "m/1"]).inputs[1]
+            .witness_utxo
+            .clone();
+        let report = key
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0, 1]);
+        assert_eq!(
+            report.inputs[2].skipped,
+            vec![SkipReason::NoInternalKey, SkipReason::NoMatchingKeys]
+        );
+    }
+
+    #[test]
+    fn signs_segwit_v0_inputs() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let key = SigningKey(vec![root]);
+        // a taproot input next to a P2WPKH and a 2-of-2 P2WSH input
+        let mut psbt = psbt_for(&secp, &root, &["m/0", "m/1", "m/2"]);
+        let origin = |path: &str| {
+            let path = DerivationPath::from_str(path).unwrap();
+            let pk = root
+                .derive_priv(&secp, &path)
+                .unwrap()
+                .private_key
+                .public_key(&secp);
+            (pk, (root.fingerprint(&secp), path))
+        };
+        let (wpkh, wpkh_source) = origin("m/1");
+        let (a, a_source) = origin("m/2");
+        let (b, b_source) = origin("m/3");
+        let other = ExtendedPrivKey::new_master(Network::Regtest, &[8; 32])
+            .unwrap()
+            .private_key
+            .public_key(&secp);
+        let ws = bitcoin::blockdata::script::Builder::new()
+            .push_int(2)
+            .push_key(&bitcoin::PublicKey::new(a))
+            .push_key(&bitcoin::PublicKey::new(b))
+            .push_int(2)
+            .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        for i in &mut psbt.inputs[1..] {
+            *i = Default::default();
+        }
+        psbt.inputs[1].bip32_derivation.insert(wpkh, wpkh_source);
+        psbt.inputs[1].witness_utxo = Some(TxOut {
+            value: 3000,
+            script_pubkey: Script::new_v0_p2wpkh(
+                &bitcoin::PublicKey::new(wpkh).wpubkey_hash().unwrap(),
+            ),
+        });
+        psbt.inputs[2].bip32_derivation.insert(a, a_source);
+        psbt.inputs[2].bip32_derivation.insert(b, b_source.clone());
+        // a key of ours which the script does not use
+        psbt.inputs[2]
+            .bip32_derivation
+            .insert(wpkh, origin("m/1").1);
+        // a key which is not ours
+        psbt.inputs[2].bip32_derivation.insert(other, b_source);
+        psbt.inputs[2].witness_utxo = Some(TxOut {
+            value: 4000,
+            script_pubkey: Script::new_v0_p2wsh(&ws.wscript_hash()),
+        });
+        psbt.inputs[2].witness_script = Some(ws.clone());
+
+        let report = key
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0, 1, 2]);
+        assert_eq!(
+            report.inputs[2].skipped,
+            vec![SkipReason::KeyNotInScript(bitcoin::PublicKey::new(wpkh))]
+        );
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+        let checks = [
+            (
+                1,
+                wpkh,
+                Script::new_p2pkh(&bitcoin::PublicKey::new(wpkh).pubkey_hash()),
+                3000,
+            ),
+            (2, a, ws.clone(), 4000),
+            (2, b, ws, 4000),
+        ];
+        for (idx, pk, script_code, value) in checks {
+            let h = cache
+                .segwit_signature_hash(idx, &script_code, value, EcdsaSighashType::All)
+                .unwrap();
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(&h[..]).unwrap();
+            let sig = psbt.inputs[idx].partial_sigs[&bitcoin::PublicKey::new(pk)];
+            assert_eq!(sig.hash_ty, EcdsaSighashType::All);
+            secp.verify_ecdsa(&msg, &sig.sig, &pk).unwrap();
+        }
+        assert_eq!(psbt.inputs[2].partial_sigs.len(), 2);
+
+        // a witness script which does not match the output is refused
+        psbt.inputs[2].witness_script = Some(Script::new());
+        let report = key
+            .sign_psbt_input_mut(&mut psbt, &secp, 2, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.skipped, vec![SkipReason::WitnessScriptMismatch]);
+    }
+
+    #[test]
+    fn signing_v2_locks_modifiable_flags() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let key = SigningKey(vec![root]);
+        let v0 = psbt_for(&secp, &root, &["m/0", "m/1"]);
+        let mut psbt = PsbtV2::new(2, None);
+        for (txin, input) in v0.unsigned_tx.input.iter().zip(&v0.inputs) {
+            psbt.add_input(txin.clone(), input.clone(), Default::default())
+                .unwrap();
+            psbt.add_output(v0.unsigned_tx.output[0].clone(), Default::default())
+                .unwrap();
+        }
+        psbt.psbt.inputs[1].tap_key_origins.clear();
+        psbt.psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(
+            SchnorrSighashType::SinglePlusAnyoneCanPay,
+        ));
+        let report = key
+            .sign_psbt_v2_mut(&mut psbt, &secp, SchnorrSighashType::Default)
+            .unwrap();
+        assert_eq!(report.signed_inputs(), vec![0]);
+        assert_eq!(psbt.tx_modifiable, INPUTS_MODIFIABLE | HAS_SIGHASH_SINGLE);
+        let decoded = PsbtV2::decode_any(&psbt.encode(PsbtVersion::V2)).unwrap();
+        assert_eq!(decoded, (psbt, PsbtVersion::V2));
+    }
 }
@@ -0,0 +1,80 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs `sapio-mock-signer` as an external signer
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey};
+use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut};
+use sapio_psbt::external_signer::{ExternalSigner, ExternalSignerError};
+use sapio_psbt::{PsbtV2, PsbtVersion};
+use std::str::FromStr;
+use tempfile::TempDir;
+
+/// a signer reading its key from a file in a directory which is removed when
+/// the returned `TempDir` is dropped
+fn mock_signer(key: Option<&ExtendedPrivKey>) -> (TempDir, ExternalSigner) {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("key");
+    match key {
+        Some(k) => std::fs::write(&file, k.encode()).unwrap(),
+        None => std::fs::write(&file, b"not a key").unwrap(),
+    }
+    let signer = ExternalSigner::new(
+        env!("CARGO_BIN_EXE_sapio-mock-signer"),
+        vec![file.to_string_lossy().into()],
+    );
+    (dir, signer)
+}
+
+#[tokio::test]
+async fn signs_through_the_mock_signer() {
+    let secp = Secp256k1::new();
+    let root = ExtendedPrivKey::new_master(Network::Regtest, &[3; 32]).unwrap();
+    let (_dir, signer) = mock_signer(Some(&root));
+    assert_eq!(signer.fingerprint().await.unwrap(), root.fingerprint(&secp));
+    let path = DerivationPath::from_str("m/86'/1'/0'").unwrap();
+    let xpub = signer.get_xpub(&path).await.unwrap();
+    let key = xpub.to_x_only_pub();
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::default(),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: 1000,
+            script_pubkey: Script::new(),
+        }],
+    };
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+    psbt.inputs[0].tap_internal_key = Some(key);
+    psbt.inputs[0]
+        .tap_key_origins
+        .insert(key, (vec![], (root.fingerprint(&secp), path)));
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value: 2000,
+        script_pubkey: Script::new_v1_p2tr(&secp, key, None),
+    });
+    let psbt = PsbtV2::from(psbt);
+    for version in [PsbtVersion::V0, PsbtVersion::V2] {
+        let signed = signer.sign_psbt(&psbt, version).await.unwrap();
+        assert!(signed.psbt.inputs[0].tap_key_sig.is_some());
+    }
+
+    let (_broken_dir, broken) = mock_signer(None);
+    assert!(matches!(
+        broken.fingerprint().await,
+        Err(ExternalSignerError::Signer(_))
+    ));
+    let missing = ExternalSigner::new("/nonexistent/signer", vec![]);
+    assert!(matches!(
+        missing.fingerprint().await,
+        Err(ExternalSignerError::Io(_))
+    ));
+}
@@ -0,0 +1,483 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! the simulated chain, mempool and clock
+use super::*;
+use bitcoin::{Script, Transaction, TxIn, TxOut};
+use sapio_base::timelocks::START_OF_TIME;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// the time of the genesis block, a fixed date so simulations are repeatable
+pub const GENESIS_TIME: u32 = 1_600_000_000;
+/// the default spacing between blocks, in seconds
+pub const BLOCK_INTERVAL: u32 = 600;
+
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0xffff;
+const SEQUENCE_GRANULARITY: u32 = 9;
+
+/// An unspent output and the height it was confirmed at
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coin {
+    /// the output
+    pub output: TxOut,
+    /// the height of the block which created it
+    pub height: u32,
+}
+
+#[derive(Clone)]
+struct State {
+    /// block times, indexed by height
+    blocks: Vec<u32>,
+    utxos: BTreeMap<OutPoint, Coin>,
+    /// every transaction seen, whether confirmed, in the mempool, or only
+    /// tracked through `TxIndex::add_tx`
+    txs: BTreeMap<Txid, Arc<Transaction>>,
+    confirmed: BTreeMap<Txid, u32>,
+    /// mempool transactions in the order they were accepted, which is also a
+    /// valid order to mine them in
+    mempool: Vec<Txid>,
+    /// outputs spent by mempool transactions
+    mempool_spends: BTreeMap<OutPoint, Txid>,
+    clock: u32,
+    faucet_nonce: u64,
+}
+
+/// A simulated Bitcoin network.
+///
+/// All methods take `&self` so that a `Simulator` can be shared (e.g., as the
+/// `TxIndex` a contract is bound with) while transactions are broadcast.
+pub struct Simulator {
+    policy: MempoolPolicy,
+    state: Mutex<State>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new(MempoolPolicy::default())
+    }
+}
+
+impl State {
+    fn height(&self) -> u32 {
+        self.blocks.len() as u32 - 1
+    }
+    /// median time of the 11 blocks ending at `height`
+    fn mtp_at(&self, height: u32) -> u32 {
+        let end = height as usize + 1;
+        let mut times: Vec<u32> = self.blocks[end.saturating_sub(11)..end].to_vec();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+    fn mtp(&self) -> u32 {
+        self.mtp_at(self.height())
+    }
+
+    /// finds the coin spent by `out`, which may be created by a mempool
+    /// transaction (in which case its height is that of the next block).
+    fn coin(&self, out: &OutPoint) -> Result<Coin, SimError> {
+        // every spender in the mempool is in `txs`, so any spend conflicts
+        if self.mempool_spends.contains_key(out) {
+            return Err(SimError::Conflict(*out));
+        }
+        if let Some(c) = self.utxos.get(out) {
+            return Ok(c.clone());
+        }
+        if self.mempool.contains(&out.txid) {
+            if let Some(output) = self.txs[&out.txid].output.get(out.vout as usize) {
+                return Ok(Coin {
+                    output: output.clone(),
+                    height: self.height() + 1,
+                });
+            }
+        }
+        Err(SimError::MissingInput(*out))
+    }
+
+    /// the nLockTime rule, evaluated for inclusion in the next block
+    fn is_final(&self, tx: &Transaction) -> bool {
+        if tx.lock_time == 0 {
+            return true;
+        }
+        let cutoff = if tx.lock_time < START_OF_TIME.get() {
+            self.height() + 1
+        } else {
+            self.mtp()
+        };
+        tx.lock_time < cutoff || tx.input.iter().all(|i| i.sequence == 0xffff_ffff)
+    }
+
+    /// the BIP-68 relative lock time rules, evaluated for inclusion in the
+    /// next block
+    fn check_sequence_locks(&self, tx: &Transaction, coins: &[Coin]) -> Result<(), SimError> {
+        if tx.version < 2 {
+            return Ok(());
+        }
+        let next = self.height() as i64 + 1;
+        let mtp = self.mtp() as i64;
+        for (idx, (input, coin)) in tx.input.iter().zip(coins).enumerate() {
+            if input.sequence & SEQUENCE_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let value = (input.sequence & SEQUENCE_MASK) as i64;
+            let locked = if input.sequence & SEQUENCE_TYPE_FLAG != 0 {
+                let coin_time = self.mtp_at(coin.height.saturating_sub(1)) as i64;
+                coin_time + (value << SEQUENCE_GRANULARITY) > mtp
+            } else {
+                coin.height as i64 + value > next
+            };
+            if locked {
+                return Err(SimError::SequenceLocked(idx));
+            }
+        }
+        Ok(())
+    }
+
+    /// checks `tx` and adds it to the mempool, returning its fee.
+    fn accept(&mut self, policy: &MempoolPolicy, tx: Transaction) -> Result<Amount, SimError> {
+        let txid = tx.txid();
+        if self.confirmed.contains_key(&txid) || self.mempool.contains(&txid) {
+            return Err(SimError::AlreadyKnown(txid));
+        }
+        policy.check_standard(&tx)?;
+        let coins = tx
+            .input
+            .iter()
+            .map(|i| self.coin(&i.previous_output))
+            .collect::<Result<Vec<Coin>, SimError>>()?;
+        let value_in: u64 = coins.iter().map(|c| c.output.value).sum();
+        let value_out: u64 = tx.output.iter().map(|o| o.value).sum();
+        let fee = value_in
+            .checked_sub(value_out)
+            .ok_or(SimError::InsufficientInputs)?;
+        if !self.is_final(&tx) {
+            return Err(SimError::NonFinal);
+        }
+        self.check_sequence_locks(&tx, &coins[..])?;
+        if policy.verify_scripts {
+            let prevouts: Vec<TxOut> = coins.into_iter().map(|c| c.output).collect();
+            for idx in 0..tx.input.len() {
+                sapio::util::interpreter::verify_input(&tx, idx, &prevouts[..])
+                    .map_err(|e| SimError::Script(idx, e))?;
+            }
+        }
+        for i in &tx.input {
+            self.mempool_spends.insert(i.previous_output, txid);
+        }
+        self.mempool.push(txid);
+        self.txs.insert(txid, Arc::new(tx));
+        Ok(Amount::from_sat(fee))
+    }
+
+    /// mines a block at `time` with every mempool transaction
+    fn mine(&mut self, time: u32) {
+        self.blocks.push(time);
+        let height = self.height();
+        for txid in std::mem::take(&mut self.mempool) {
+            let tx = self.txs[&txid].clone();
+            for i in &tx.input {
+                self.utxos.remove(&i.previous_output);
+            }
+            self.add_outputs(&tx, height);
+            self.confirmed.insert(txid, height);
+        }
+        self.mempool_spends.clear();
+    }
+
+    fn add_outputs(&mut self, tx: &Transaction, height: u32) {
+        let txid = tx.txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            if !output.script_pubkey.is_provably_unspendable() {
+                self.utxos.insert(
+                    OutPoint::new(txid, vout as u32),
+                    Coin {
+                        output: output.clone(),
+                        height,
+                    },
+                );
+            }
+        }
+    }
+}
+
+impl Simulator {
+    /// creates a chain with just a genesis block, enforcing `policy` on the
+    /// mempool.
+    pub fn new(policy: MempoolPolicy) -> Self {
+        Simulator {
+            policy,
+            state: Mutex::new(State {
+                blocks: vec![GENESIS_TIME],
+                utxos: Default::default(),
+                txs: Default::default(),
+                confirmed: Default::default(),
+                mempool: vec![],
+                mempool_spends: Default::default(),
+                clock: GENESIS_TIME + BLOCK_INTERVAL,
+                faucet_nonce: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// the mempool policy in use
+    pub fn policy(&self) -> &MempoolPolicy {
+        &self.policy
+    }
+
+    /// the height of the tip
+    pub fn height(&self) -> u32 {
+        self.lock().height()
+    }
+
+    /// the median time past of the tip, which is what time based locks are
+    /// compared against
+    pub fn median_time_past(&self) -> u32 {
+        self.lock().mtp()
+    }
+
+    /// the current time of the clock
+    pub fn time(&self) -> u32 {
+        self.lock().clock
+    }
+
+    /// moves the clock forward without mining
+    pub fn advance_time(&self, seconds: u32) {
+        self.lock().clock += seconds;
+    }
+
+    /// sets the clock. Block times never go backwards past the median time
+    /// past, so setting an earlier time only affects future blocks.
+    pub fn set_time(&self, time: u32) {
+        self.lock().clock = time;
+    }
+
+    /// mines `n` blocks, including every mempool transaction in the first.
+    ///
+    /// Each block is stamped with the clock (or just after the median time
+    /// past, if later), after which the clock advances by `BLOCK_INTERVAL`.
+    pub fn mine(&self, n: u32) {
+        let mut state = self.lock();
+        for _ in 0..n {
+            let time = std::cmp::max(state.clock, state.mtp() + 1);
+            state.mine(time);
+            state.clock = time + BLOCK_INTERVAL;
+        }
+    }
+
+    /// creates a confirmed coin paying `amount` to `script_pubkey`, mining a
+    /// block (with any mempool transactions) to do so.
+    pub fn fund(&self, script_pubkey: Script, amount: Amount) -> OutPoint {
+        let tx = {
+            let mut state = self.lock();
+            state.faucet_nonce += 1;
+            let mut script_sig = b"sapio-sim faucet".to_vec();
+            script_sig.extend(state.faucet_nonce.to_le_bytes());
+            Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::from(script_sig),
+                    sequence: 0xffff_ffff,
+                    witness: Default::default(),
+                }],
+                output: vec![TxOut {
+                    value: amount.as_sat(),
+                    script_pubkey,
+                }],
+            }
+        };
+        self.mine(1);
+        let mut state = self.lock();
+        let height = state.height();
+        let txid = tx.txid();
+        state.add_outputs(&tx, height);
+        state.confirmed.insert(txid, height);
+        state.txs.insert(txid, Arc::new(tx));
+        OutPoint::new(txid, 0)
+    }
+
+    /// submits a transaction to the mempool
+    pub fn broadcast(&self, tx: Transaction) -> Result<Txid, SimError> {
+        self.broadcast_package(vec![tx]).map(|mut v| v.remove(0))
+    }
+
+    /// submits transactions to the mempool together, in order. The minimum fee
+    /// is checked for the package as a whole, so children can pay for parents
+    /// which pay no fee. If any transaction is rejected, none are accepted.
+    pub fn broadcast_package(&self, txs: Vec<Transaction>) -> Result<Vec<Txid>, SimError> {
+        let mut state = self.lock();
+        let mut attempt = state.clone();
+        let vsize: usize = txs.iter().map(|t| t.vsize()).sum();
+        let mut fee = Amount::ZERO;
+        let mut txids = vec![];
+        for tx in txs {
+            txids.push(tx.txid());
+            fee += attempt.accept(&self.policy, tx)?;
+        }
+        let required = self.policy.min_fee(vsize);
+        if fee < required {
+            return Err(SimError::FeeTooLow { fee, required });
+        }
+        *state = attempt;
+        Ok(txids)
+    }
+
+    /// Broadcasts `txs` as soon as each is valid, mining a block at a time,
+    /// until all of them are confirmed.
+    ///
+    /// Transactions that are not yet valid (because they spend unconfirmed
+    /// outputs or are timelocked) are retried after every block; any other
+    /// rejection is returned. Fails with `SimError::Stuck` if some are still
+    /// unconfirmed after `max_blocks`.
+    pub fn unroll(&self, txs: Vec<Transaction>, max_blocks: u32) -> Result<(), SimError> {
+        let mut pending = txs;
+        for _ in 0..max_blocks {
+            let mut waiting = vec![];
+            for tx in pending {
+                match self.broadcast(tx.clone()) {
+                    Ok(_) => {}
+                    Err(e) if e.is_premature() => waiting.push(tx),
+                    Err(e) => return Err(e),
+                }
+            }
+            pending = waiting;
+            if pending.is_empty() && self.mempool().is_empty() {
+                return Ok(());
+            }
+            self.mine(1);
+        }
+        if pending.is_empty() && self.mempool().is_empty() {
+            return Ok(());
+        }
+        Err(SimError::Stuck(pending.iter().map(|t| t.txid()).collect()))
+    }
+
+    /// the transactions in the mempool, in the order they were accepted
+    pub fn mempool(&self) -> Vec<Txid> {
+        self.lock().mempool.clone()
+    }
+
+    /// the number of confirmations of a transaction, or None if unconfirmed
+    pub fn confirmations(&self, txid: &Txid) -> Option<u32> {
+        let state = self.lock();
+        state.confirmed.get(txid).map(|h| state.height() - h + 1)
+    }
+
+    /// the unspent confirmed outputs
+    pub fn utxos(&self) -> BTreeMap<OutPoint, Coin> {
+        self.lock().utxos.clone()
+    }
+
+    /// the total value of confirmed unspent outputs paying to `script_pubkey`
+    pub fn balance(&self, script_pubkey: &Script) -> Amount {
+        Amount::from_sat(
+            self.lock()
+                .utxos
+                .values()
+                .filter(|c| c.output.script_pubkey == *script_pubkey)
+                .map(|c| c.output.value)
+                .sum(),
+        )
+    }
+}
+
+/// Transactions added through `add_tx` are only tracked (e.g., so that
+/// `bind_psbt` can look up the outputs of templates it has created), not
+/// broadcast.
+impl TxIndex for Simulator {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<Transaction>, TxIndexError> {
+        self.lock()
+            .txs
+            .get(b)
+            .cloned()
+            .ok_or(TxIndexError::UnknownTxid(*b))
+    }
+    fn add_tx(&self, tx: Arc<Transaction>) -> Result<Txid, TxIndexError> {
+        let txid = tx.txid();
+        self.lock().txs.entry(txid).or_insert(tx);
+        Ok(txid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::Witness;
+
+    /// a P2WSH output anyone can spend, and a witness spending it
+    fn anyone_can_spend() -> (Script, Witness) {
+        let script = Builder::new().push_opcode(OP_PUSHNUM_1).into_script();
+        (
+            script.to_v0_p2wsh(),
+            Witness::from_vec(vec![script.to_bytes()]),
+        )
+    }
+
+    fn spend(out: OutPoint, value: u64, sequence: u32) -> Transaction {
+        let (spk, witness) = anyone_can_spend();
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: out,
+                script_sig: Script::new(),
+                sequence,
+                witness,
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: spk,
+            }],
+        }
+    }
+
+    #[test]
+    fn relative_locks_and_packages() {
+        let sim = Simulator::default();
+        let (spk, _) = anyone_can_spend();
+        let coin = sim.fund(spk.clone(), Amount::from_sat(100_000));
+
+        // a 3 block relative lock can be mined at the coin's height + 3
+        let locked = spend(coin, 99_000, 3);
+        assert!(matches!(
+            sim.broadcast(locked.clone()),
+            Err(SimError::SequenceLocked(0))
+        ));
+        sim.mine(1);
+        assert!(sim.broadcast(locked.clone()).is_err());
+        sim.mine(1);
+        let parent = sim.broadcast(locked).unwrap();
+        sim.mine(1);
+        assert_eq!(sim.confirmations(&parent), Some(1));
+
+        // a parent without fees needs a child to pay for it
+        let parent = spend(OutPoint::new(parent, 0), 99_000, 0xffff_ffff);
+        let child = spend(OutPoint::new(parent.txid(), 0), 98_000, 0xffff_ffff);
+        assert!(matches!(
+            sim.broadcast(parent.clone()),
+            Err(SimError::FeeTooLow { .. })
+        ));
+        assert!(sim.mempool().is_empty());
+        sim.broadcast_package(vec![parent, child.clone()]).unwrap();
+        // conflicts with the mempool are rejected
+        assert!(matches!(
+            sim.broadcast(spend(child.input[0].previous_output, 1000, 0xffff_ffff)),
+            Err(SimError::Conflict(_))
+        ));
+        sim.mine(1);
+        assert_eq!(sim.balance(&spk), Amount::from_sat(98_000));
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A deterministic, in-memory blockchain for executing Sapio contracts.
+//!
+//! `Simulator` keeps a UTXO set, a chain of blocks with heights and median
+//! time past, a mempool with (simplified) Bitcoin Core acceptance rules, and a
+//! clock that only moves when told to. Contracts can be bound to a coin from
+//! the simulator's faucet, their transactions finalized and broadcast, and the
+//! chain advanced until they have fully unrolled.
+#![deny(missing_docs)]
+use bitcoin::{Amount, OutPoint, Txid};
+use sapio::util::interpreter::InterpreterError;
+use std::fmt;
+
+pub mod chain;
+pub mod policy;
+pub mod program;
+pub use chain::{Coin, Simulator};
+pub use policy::MempoolPolicy;
+pub use program::finalize_program;
+
+/// Reasons a transaction was rejected
+#[derive(Debug)]
+pub enum SimError {
+    /// The transaction is already in the mempool or the chain
+    AlreadyKnown(Txid),
+    /// An input spends an output that does not exist or is already spent
+    MissingInput(OutPoint),
+    /// An input spends an output already spent by a mempool transaction
+    Conflict(OutPoint),
+    /// The transaction is not standard
+    NonStandard(&'static str),
+    /// The outputs are worth more than the inputs
+    InsufficientInputs,
+    /// The transaction (or package) does not pay the minimum relay fee
+    FeeTooLow {
+        /// the fee paid
+        fee: Amount,
+        /// the fee required
+        required: Amount,
+    },
+    /// The nLockTime of the transaction is not yet final
+    NonFinal,
+    /// The relative timelock of this input is not yet satisfied
+    SequenceLocked(usize),
+    /// The witness of this input does not satisfy the output it spends
+    Script(usize, InterpreterError),
+    /// A PSBT could not be decoded or finalized
+    Psbt(String),
+    /// These transactions could not be confirmed within the block limit
+    Stuck(Vec<Txid>),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl SimError {
+    /// whether the transaction may become valid as the chain advances, i.e.,
+    /// it spends outputs that are not yet confirmed or is timelocked.
+    pub fn is_premature(&self) -> bool {
+        matches!(
+            self,
+            SimError::MissingInput(_) | SimError::NonFinal | SimError::SequenceLocked(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::Script;
+    use sapio::contract::{Compilable, Context};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::txindex::TxIndex;
+    use sapio_contrib::contracts::treepay::{Payment, TreePay};
+    use sapio_contrib::contracts::vault::{Vault, VaultAddress};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    const HOT: &str = "bcrt1qumrrqgt7e3a7damzm8x97m6sjs20u8hjw2hcjj";
+
+    fn cold() -> bitcoin::Address {
+        let script = Script::from(vec![0x52]); // OP_2
+        bitcoin::Address::p2wsh(&script, bitcoin::Network::Regtest)
+    }
+
+    fn spk(a: &str) -> Script {
+        a.parse::<bitcoin::Address>().unwrap().script_pubkey()
+    }
+
+    fn context(amount: Amount) -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("sim").unwrap(),
+            Arc::new(MapEffectDB::default()),
+            None,
+        )
+    }
+
+    #[test]
+    fn vault_unrolls_to_hot_storage() -> Result<(), Box<dyn std::error::Error>> {
+        let args = format!(
+            "{{\"amount_step\":{{\"Sats\":1000000}},\"cold_storage\":\"{}\",\"hot_storage\":\"{}\",\
+             \"mature\":{{\"RH\":10}},\"n_steps\":2,\"timeout\":{{\"RH\":5}}}}",
+            cold(),
+            HOT
+        );
+        let vault: Vault = serde_json::from_str::<VaultAddress>(&args)?.into();
+        let amount = Amount::from_sat(2_000_000);
+        let compiled = vault.compile(context(amount))?;
+
+        // templates pay no fees, so accept them without a fee bumping child
+        let sim = Rc::new(Simulator::new(MempoolPolicy {
+            min_fee_rate: 0,
+            ..Default::default()
+        }));
+        let coin = sim.fund(compiled.address.clone().into(), amount);
+        let index: Rc<dyn TxIndex> = sim.clone();
+        let program = compiled.bind_psbt(coin, BTreeMap::new(), index, &CTVAvailable)?;
+        let txs: Vec<_> = finalize_program(&program)?
+            .into_iter()
+            .filter(|tx| {
+                tx.output
+                    .iter()
+                    .all(|o| o.script_pubkey != cold().script_pubkey())
+            })
+            .collect();
+        // two steps and two completions
+        assert_eq!(txs.len(), 4);
+
+        let start = sim.height();
+        sim.unroll(txs, 100)?;
+        assert_eq!(sim.balance(&spk(HOT)), amount);
+        assert_eq!(sim.balance(&cold().script_pubkey()), Amount::ZERO);
+        // the second step waits 5 blocks on the first, and the last completion
+        // another 10 blocks
+        assert!(sim.height() - start >= 15);
+        Ok(())
+    }
+
+    #[test]
+    fn treepay_pays_every_participant() -> Result<(), Box<dyn std::error::Error>> {
+        // OP_1..OP_10, each paid a different amount
+        let participants: Vec<_> = (0..10u8)
+            .map(|i| {
+                let script = Script::from(vec![0x51 + i]);
+                Payment {
+                    amount: Amount::from_sat(10_000 * (i as u64 + 1)).into(),
+                    address: bitcoin::Address::p2wsh(&script, bitcoin::Network::Regtest),
+                }
+            })
+            .collect();
+        let amount = Amount::from_sat((1..=10).map(|i| 10_000 * i).sum());
+        let compiled = TreePay {
+            participants: participants.clone(),
+            radix: 3,
+        }
+        .compile(context(amount))?;
+
+        let sim = Rc::new(Simulator::new(MempoolPolicy {
+            min_fee_rate: 0,
+            ..Default::default()
+        }));
+        let coin = sim.fund(compiled.address.clone().into(), amount);
+        let index: Rc<dyn TxIndex> = sim.clone();
+        let program = compiled.bind_psbt(coin, BTreeMap::new(), index, &CTVAvailable)?;
+        let txs = finalize_program(&program)?;
+        // the root splits into chunks of 3, 3, 3 and 1, and each chunk
+        // pays out
+        assert_eq!(txs.len(), 5);
+        sim.unroll(txs, 10)?;
+        let mut paid = Amount::ZERO;
+        for p in &participants {
+            let expected: Amount = p.amount.try_into()?;
+            assert_eq!(sim.balance(&p.address.script_pubkey()), expected);
+            paid += expected;
+        }
+        // so everything funded was paid out, without fees
+        assert_eq!(paid, amount);
+        assert_eq!(sim.balance(&compiled.address.clone().into()), Amount::ZERO);
+        Ok(())
+    }
+}
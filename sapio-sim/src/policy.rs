@@ -0,0 +1,88 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! mempool acceptance rules which do not depend on chain state
+use super::SimError;
+use bitcoin::{Amount, Script, Transaction};
+
+/// Rules applied to transactions entering the mempool, modeled on Bitcoin
+/// Core's defaults.
+#[derive(Clone, Debug)]
+pub struct MempoolPolicy {
+    /// the minimum fee rate, in sats per vbyte. Sapio templates often pay no
+    /// fee themselves, in which case either this must be 0 or they must be
+    /// broadcast in a package with a child paying for them.
+    pub min_fee_rate: u64,
+    /// the maximum weight of a standard transaction
+    pub max_weight: usize,
+    /// the maximum size of an OP_RETURN output's script
+    pub max_op_return: usize,
+    /// whether outputs below the dust threshold are rejected
+    pub reject_dust: bool,
+    /// whether witnesses are executed against the outputs they spend
+    pub verify_scripts: bool,
+}
+
+impl Default for MempoolPolicy {
+    fn default() -> Self {
+        MempoolPolicy {
+            min_fee_rate: 1,
+            max_weight: 400_000,
+            max_op_return: 83,
+            reject_dust: true,
+            verify_scripts: true,
+        }
+    }
+}
+
+impl MempoolPolicy {
+    /// the minimum fee for a transaction (or package) of `vsize` vbytes
+    pub fn min_fee(&self, vsize: usize) -> Amount {
+        Amount::from_sat(self.min_fee_rate * vsize as u64)
+    }
+
+    fn is_standard_script(&self, s: &Script) -> bool {
+        if s.is_op_return() {
+            s.len() <= self.max_op_return
+        } else {
+            s.is_p2pkh() || s.is_p2sh() || s.is_witness_program()
+        }
+    }
+
+    /// checks the rules which only depend on the transaction itself
+    pub fn check_standard(&self, tx: &Transaction) -> Result<(), SimError> {
+        if tx.is_coin_base() {
+            return Err(SimError::NonStandard("coinbase"));
+        }
+        if !(1..=2).contains(&tx.version) {
+            return Err(SimError::NonStandard("version"));
+        }
+        if tx.input.is_empty() || tx.output.is_empty() {
+            return Err(SimError::NonStandard("empty"));
+        }
+        if tx.weight() > self.max_weight {
+            return Err(SimError::NonStandard("tx-size"));
+        }
+        if tx
+            .output
+            .iter()
+            .filter(|o| o.script_pubkey.is_op_return())
+            .count()
+            > 1
+        {
+            return Err(SimError::NonStandard("multi-op-return"));
+        }
+        for o in &tx.output {
+            if !self.is_standard_script(&o.script_pubkey) {
+                return Err(SimError::NonStandard("scriptpubkey"));
+            }
+            if self.reject_dust && o.value < o.script_pubkey.dust_value().as_sat() {
+                return Err(SimError::NonStandard("dust"));
+            }
+        }
+        Ok(())
+    }
+}
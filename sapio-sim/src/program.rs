@@ -0,0 +1,35 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! turning a bound contract into broadcastable transactions
+use super::*;
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::Transaction;
+use sapio::contract::abi::studio::{Program, SapioStudioFormat};
+use sapio::miniscript::psbt::PsbtExt;
+use sapio_base::psbt_v2::PsbtV2;
+
+/// Finalizes every PSBT (of either version) of a `Program` (as returned by
+/// `Object::bind_psbt`), returning the transactions for every path of the contract.
+///
+/// Note that alternative paths conflict with each other, so callers should
+/// pick which to broadcast.
+pub fn finalize_program(program: &Program) -> Result<Vec<Transaction>, SimError> {
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    let mut txs = vec![];
+    for obj in program.program.values() {
+        for SapioStudioFormat::LinkedPSBT { psbt, .. } in &obj.txs {
+            let bytes = base64::decode(psbt).map_err(|e| SimError::Psbt(e.to_string()))?;
+            let (psbt, _) =
+                PsbtV2::decode_any(&bytes[..]).map_err(|e| SimError::Psbt(e.to_string()))?;
+            let mut psbt: PartiallySignedTransaction = psbt.into();
+            psbt.finalize_mut(&secp)
+                .map_err(|e| SimError::Psbt(format!("{:?}", e)))?;
+            txs.push(psbt.extract_tx());
+        }
+    }
+    Ok(txs)
+}
@@ -1,3 +1,5 @@
+use bitcoin::{Amount, Network};
+use sapio_base::plugin_args::{ContextualArguments, CreateArgs};
 use schemars::*;
 use serde::*;
 use serde_json::Value;
@@ -10,34 +12,120 @@ impl SapioAPIHandle for serde_json::Value {
         self.clone()
     }
 }
+/// Validate `instance` against the JSON Schema `schema`, listing every
+/// validation error on failure.
+pub fn validate_json_schema(schema: &Value, instance: &Value) -> Result<(), String> {
+    validate_with(&compile(schema)?, instance)
+}
+
+/// A JSON Schema checked once, to validate many instances against
+pub struct CompiledSchema {
+    schema: Value,
+}
+
+impl CompiledSchema {
+    /// Check that `schema` compiles, so that validating against it can only
+    /// fail because of the instance.
+    pub fn new(schema: Value) -> Result<Self, String> {
+        compile(&schema)?;
+        Ok(CompiledSchema { schema })
+    }
+    /// the schema compiled
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+    /// Validate `instance`, listing every validation error on failure. The
+    /// compiled form borrows the schema, so it is rebuilt for each instance.
+    pub fn validate(&self, instance: &Value) -> Result<(), String> {
+        validate_json_schema(&self.schema, instance)
+    }
+}
+
+/// compile a schema, as draft 7 which schemars generates
+fn compile(schema: &Value) -> Result<jsonschema_valid::Config<'_>, String> {
+    jsonschema_valid::Config::from_schema(schema, Some(jsonschema_valid::schemas::Draft::Draft7))
+        .map_err(|e| format!("Error Compiling Schema: {}", e))
+}
+
+fn validate_with(config: &jsonschema_valid::Config<'_>, instance: &Value) -> Result<(), String> {
+    config.validate(instance).map_err(|e| {
+        let mut s = String::from("Validation Errors:");
+        for error in e {
+            s += &format!("\n    - {}", error);
+        }
+        s
+    })
+}
+
 pub trait SapioJSONTrait: JsonSchema + Serialize + for<'a> Deserialize<'a> {
     fn get_example_for_api_checking() -> Value;
+    /// Check that a module's input schema accepts
+    /// [`Self::get_example_for_api_checking`] as its arguments.
     fn check_trait_implemented_inner(api: &dyn SapioAPIHandle) -> Result<(), String> {
-        let _tag = Self::get_example_for_api_checking();
-        let _japi = api.get_api();
-        // let compiled = JSONSchema::compile(&japi).map_err(|_| "Error Compiling Schema")?;
-        // compiled
-        //     .validate(
-        //         &serde_json::to_value(CreateArgs {
-        //             arguments: tag,
-        //             context: ContextualArguments {
-        //                 amount: Amount::from_sat(0),
-        //                 network: Network::Bitcoin,
-        //                 effects: Default::default(),
-        //             },
-        //         })
-        //         .map_err(|e| format!("{:?}", e))?,
-        //     )
-        //     .map_err(|e| {
-        //         let mut s = String::from("Validation Errors:");
-        //         for error in e {
-        //             s += &format!("\n    - {}", error);
-        //         }
-        //         s
-        //     })?;
-        Ok(())
+        let tag = Self::get_example_for_api_checking();
+        let japi = api.get_api();
+        let args = serde_json::to_value(CreateArgs {
+            arguments: tag,
+            context: ContextualArguments {
+                amount: Amount::from_sat(0),
+                network: Network::Bitcoin,
+                effects: Default::default(),
+                ordinals_info: None,
+            },
+        })
+        .map_err(|e| format!("{:?}", e))?;
+        validate_json_schema(&japi, &args)
     }
     fn check_trait_implemented(api: &dyn SapioAPIHandle) -> bool {
         Self::check_trait_implemented_inner(api).is_ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(JsonSchema, Serialize, Deserialize)]
+    struct Payment {
+        amount: u64,
+        to: String,
+    }
+
+    impl SapioJSONTrait for Payment {
+        fn get_example_for_api_checking() -> Value {
+            json!({"amount": 1, "to": "alice"})
+        }
+    }
+
+    #[test]
+    fn accepts_and_rejects_instances() {
+        let schema =
+            CompiledSchema::new(serde_json::to_value(schema_for!(Payment)).unwrap()).unwrap();
+        assert!(schema.validate(&json!({"amount": 5, "to": "bob"})).is_ok());
+        assert!(schema
+            .validate(&json!({"amount": "5", "to": "bob"}))
+            .is_err());
+        assert!(schema.validate(&json!({"amount": 5})).is_err());
+    }
+
+    #[test]
+    fn validates_as_draft7() {
+        // if/then was added in draft 7, earlier drafts ignore it
+        let schema = json!({
+            "if": {"properties": {"kind": {"const": "payment"}}},
+            "then": {"required": ["amount"]}
+        });
+        assert!(validate_json_schema(&schema, &json!({"kind": "payment", "amount": 1})).is_ok());
+        assert!(validate_json_schema(&schema, &json!({"kind": "payment"})).is_err());
+        assert!(validate_json_schema(&schema, &json!({"kind": "other"})).is_ok());
+    }
+
+    #[test]
+    fn checks_module_input_schema() {
+        let accepts = serde_json::to_value(schema_for!(CreateArgs<Payment>)).unwrap();
+        assert!(Payment::check_trait_implemented(&accepts));
+        let rejects = serde_json::to_value(schema_for!(CreateArgs<u64>)).unwrap();
+        assert!(!Payment::check_trait_implemented(&rejects));
+    }
+}
@@ -19,13 +19,33 @@ use miniscript::*;
 use sapio_base::effects::EffectPath;
 use sapio_base::miniscript;
 use sapio_base::serialization_helpers::SArc;
-use sapio_base::txindex::TxIndex;
+use sapio_base::txindex::{AsyncTxIndex, PrefetchedTxIndex, TxIndex};
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::sync::Arc;
 
 impl Object {
+    /// Like `bind_psbt`, but first fetches every output the PSBTs will spend
+    /// which is not created by the contract itself (`out_in` and anything in
+    /// `output_map`) concurrently from `blockdata`.
+    ///
+    /// Transactions created by the contract are tracked locally and are not
+    /// added to `blockdata`.
+    pub async fn bind_psbt_prefetched(
+        &self,
+        out_in: bitcoin::OutPoint,
+        output_map: BTreeMap<Sha256, Vec<Option<bitcoin::OutPoint>>>,
+        blockdata: &dyn AsyncTxIndex,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Program, ObjectError> {
+        let external: Vec<OutPoint> = std::iter::once(out_in)
+            .chain(output_map.values().flatten().flatten().cloned())
+            .collect();
+        let prefetched = PrefetchedTxIndex::fetch(blockdata, &external).await?;
+        self.bind_psbt(out_in, output_map, Rc::new(prefetched), emulator)
+    }
+
     /// bind_psbt attaches and `Object` to a specific UTXO, returning a
     /// Vector of PSBTs and transaction metadata.
     ///
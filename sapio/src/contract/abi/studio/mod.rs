@@ -13,6 +13,7 @@ use bitcoin::consensus::serialize;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::OutPoint;
 use miniscript::*;
+use sapio_base::psbt_v2::{PsbtV2, PsbtV2Error, PsbtVersion};
 use sapio_base::serialization_helpers::SArc;
 use sapio_base::{effects::EffectPath, miniscript};
 use schemars::JsonSchema;
@@ -54,17 +55,27 @@ pub enum SapioStudioFormat {
 
 impl From<LinkedPSBT> for SapioStudioFormat {
     fn from(l: LinkedPSBT) -> SapioStudioFormat {
+        l.into_studio_format(PsbtVersion::V0)
+    }
+}
+
+impl LinkedPSBT {
+    /// Converts to the Sapio Studio format, encoding the PSBT as `version`
+    pub fn into_studio_format(self, version: PsbtVersion) -> SapioStudioFormat {
         let psbt = {
-            let bytes = serialize(&l.psbt);
+            let bytes = match version {
+                PsbtVersion::V0 => serialize(&self.psbt),
+                PsbtVersion::V2 => PsbtV2::from(self.psbt.clone()).serialize(),
+            };
             base64::encode(bytes)
         };
-        let hex = bitcoin::consensus::encode::serialize_hex(&l.psbt.extract_tx());
+        let hex = bitcoin::consensus::encode::serialize_hex(&self.psbt.extract_tx());
         SapioStudioFormat::LinkedPSBT {
             psbt,
             hex,
-            metadata: l.metadata,
-            output_metadata: l.output_metadata,
-            added_output_metadata: l.added_output_metadata,
+            metadata: self.metadata,
+            output_metadata: self.output_metadata,
+            added_output_metadata: self.added_output_metadata,
         }
     }
 }
@@ -88,6 +99,21 @@ pub struct Program {
     pub program: BTreeMap<SArc<EffectPath>, SapioStudioObject>,
 }
 
+impl Program {
+    /// Re-encodes every PSBT in the program as `version`
+    pub fn set_psbt_version(&mut self, version: PsbtVersion) -> Result<(), PsbtV2Error> {
+        for obj in self.program.values_mut() {
+            for SapioStudioFormat::LinkedPSBT { psbt, .. } in obj.txs.iter_mut() {
+                let bytes = base64::decode(&psbt)
+                    .map_err(|_| PsbtV2Error::Invalid("psbt is not base64"))?;
+                let (p, _) = PsbtV2::decode_any(&bytes)?;
+                *psbt = base64::encode(p.encode(version));
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A `SapioStudioObject` is a json-friendly format for a `Object` for use in Sapio Studio
 #[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct SapioStudioObject {
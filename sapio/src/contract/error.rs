@@ -19,6 +19,16 @@ use std::collections::LinkedList;
 use std::error::Error;
 use std::fmt;
 type ErrT = Box<dyn std::error::Error>;
+
+/// A resource limit placed on a running module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// The module ran out of fuel (the budget is given)
+    Fuel(u64),
+    /// The module tried to grow memory past the given number of pages
+    MemoryPages(u32),
+}
+
 /// Sapio's core error type.
 #[derive(Debug)]
 pub enum CompilationError {
@@ -94,11 +104,18 @@ pub enum CompilationError {
     ModuleCouldNotGetLogo(ErrT),
     /// Module failed to get_name
     ModuleCouldNotGetName(ErrT),
+    /// Module failed to get_manifest
+    ModuleCouldNotGetManifest(ErrT),
     /// Module hit an error at runtime
     ModuleRuntimeError(ErrT),
+    /// Module (identified by its key) exhausted a resource limit while running
+    ModuleExceededLimit(String, ResourceLimit),
     /// API Check Failed, module didn't satisfy examples.
     /// Used in Plugin interface (TODO: Wrap these types)
     ModuleFailedAPICheck(String),
+    /// Module's output did not match the schema of the type expected from it.
+    /// Used in Plugin interface
+    ModuleFailedOutputCheck(String),
     /// CompError
     ModuleCompilationErrorUnsendable(String),
     /// Issue in the Ordinals System
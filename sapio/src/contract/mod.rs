@@ -15,7 +15,7 @@ pub use abi::object;
 pub mod actions;
 pub mod compiler;
 pub mod error;
-pub use error::CompilationError;
+pub use error::{CompilationError, ResourceLimit};
 pub mod context;
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
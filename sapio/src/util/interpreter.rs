@@ -0,0 +1,108 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking that finalized transactions really spend their inputs.
+//!
+//! The interpreter executes the witness of an input against the output it
+//! spends, verifying the taproot commitment of the leaf, every signature, and
+//! `OP_CHECKTEMPLATEVERIFY` (the template hash is computed from the spending
+//! transaction, exactly as the opcode does). Leaves are run with miniscript's
+//! interpreter, which covers every script Sapio compiles.
+//!
+//! Timelocks are checked against the spending transaction's own `nSequence`
+//! and `nLockTime`; whether those are final at a given chain height is not
+//! checked here.
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::sighash::Prevouts;
+use bitcoin::{Transaction, TxOut};
+use sapio_base::miniscript::interpreter::{Error, SatisfiedConstraint};
+use sapio_base::miniscript::Interpreter;
+use sapio_base::CTVHash;
+use std::fmt;
+
+/// Reasons a spend could not be verified
+#[derive(Debug)]
+pub enum InterpreterError {
+    /// The transaction has no input at this index, or the wrong number of
+    /// prevouts was given
+    MissingPrevout(usize),
+    /// The witness does not satisfy the spent output
+    Script(Error),
+    /// The transaction spending a template's output has a different template
+    /// hash than the template commits to
+    TemplateMismatch {
+        /// the template hash of the template
+        expected: sha256::Hash,
+        /// the template hash of the transaction
+        found: sha256::Hash,
+    },
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for InterpreterError {}
+
+impl From<Error> for InterpreterError {
+    fn from(e: Error) -> Self {
+        InterpreterError::Script(e)
+    }
+}
+
+/// Runs the witness of input `idx` of `tx` against the output it spends.
+///
+/// `prevouts` must contain the output spent by every input of `tx`, in order.
+///
+/// Returns every constraint the witness satisfied.
+pub fn verify_input(
+    tx: &Transaction,
+    idx: usize,
+    prevouts: &[TxOut],
+) -> Result<Vec<SatisfiedConstraint>, InterpreterError> {
+    if prevouts.len() != tx.input.len() {
+        return Err(InterpreterError::MissingPrevout(idx));
+    }
+    let input = tx
+        .input
+        .get(idx)
+        .ok_or(InterpreterError::MissingPrevout(idx))?;
+    let interpreter = Interpreter::from_txdata(
+        &prevouts[idx].script_pubkey,
+        &input.script_sig,
+        &input.witness,
+        input.sequence,
+        tx.lock_time,
+        tx.get_ctv_hash(idx as u32),
+    )?;
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    let prevouts = Prevouts::All(prevouts);
+    let satisfied = interpreter
+        .iter(&secp, tx, idx, &prevouts)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(satisfied)
+}
+
+/// Checks that `tx` is an instance of the template `t` (up to the outpoints
+/// spent, which CTV does not commit to) and that the witness of its input at
+/// `t.ctv_index` spends the template's output.
+pub fn verify_template_spend(
+    t: &Template,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+) -> Result<Vec<SatisfiedConstraint>, InterpreterError> {
+    let found = tx.get_ctv_hash(t.ctv_index);
+    if found != t.ctv {
+        return Err(InterpreterError::TemplateMismatch {
+            expected: t.ctv,
+            found,
+        });
+    }
+    verify_input(tx, t.ctv_index as usize, prevouts)
+}
@@ -0,0 +1,92 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! calling async indexes from synchronous code
+use super::*;
+use sapio_base::txindex::AsyncTxIndex;
+use std::future::Future;
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+
+/// Runs `f` to completion on `runtime` from synchronous code, which may itself
+/// be running inside of a tokio runtime.
+///
+/// On a multi-threaded runtime the current worker is handed off with
+/// `block_in_place`. A current-thread runtime can't do that (and can't make
+/// progress while we wait), so `f` is driven from a separate thread instead.
+/// `runtime` must not be the current-thread runtime calling this.
+pub fn block_on<F>(runtime: &Runtime, f: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match Handle::try_current().map(|h| h.runtime_flavor()) {
+        Err(_) => runtime.block_on(f),
+        Ok(RuntimeFlavor::CurrentThread) => std::thread::scope(|s| {
+            s.spawn(|| runtime.block_on(f))
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        }),
+        Ok(_) => tokio::task::block_in_place(|| runtime.block_on(f)),
+    }
+}
+
+/// Adapts an AsyncTxIndex into a synchronous TxIndex, e.g. for `bind_psbt`.
+pub struct BlockingTxIndex<T> {
+    /// the async index
+    pub inner: T,
+    /// tokio runtime the lookups are run on
+    pub runtime: Arc<Runtime>,
+}
+
+impl<T: AsyncTxIndex> TxIndex for BlockingTxIndex<T> {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        block_on(&self.runtime, self.inner.lookup_tx(b))
+    }
+    fn lookup_output(&self, b: &bitcoin::OutPoint) -> Result<bitcoin::TxOut> {
+        block_on(&self.runtime, self.inner.lookup_output(b))
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        block_on(&self.runtime, self.inner.add_tx(tx))
+    }
+}
+
+impl<T: AsyncTxIndex> BatchTxIndex for BlockingTxIndex<T> {
+    fn lookup_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>> {
+        block_on(
+            &self.runtime,
+            sapio_base::txindex::join_all(txids.iter().map(|t| self.inner.lookup_tx(t))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::txindex::{SyncTxIndex, TxIndexLogger};
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn blocking_inside_current_thread_runtime() {
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let index = BlockingTxIndex {
+            inner: SyncTxIndex(TxIndexLogger::new()),
+            runtime: Arc::new(Runtime::new().unwrap()),
+        };
+        let txid = index.add_tx(Arc::new(tx.clone())).unwrap();
+        assert_eq!(*index.lookup_tx(&txid).unwrap(), tx);
+        assert!(matches!(
+            index.lookup_output(&bitcoin::OutPoint::new(txid, 0)),
+            Err(TxIndexError::IndexTooHigh(0))
+        ));
+        // the runtime can't be dropped from async code
+        let BlockingTxIndex { runtime, .. } = index;
+        std::thread::spawn(move || drop(runtime)).join().unwrap();
+    }
+}
@@ -0,0 +1,544 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A TxIndex persisted to a directory, for use as a long lived cache
+use super::*;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::BlockHash;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MANIFEST: &str = "index.json";
+const TX_EXTENSION: &str = "tx";
+/// how many changes to the manifest may be unsaved before it is written.
+/// Transaction files are always written immediately, and `open` adopts any
+/// the manifest doesn't know about, so losing unsaved changes only loses
+/// recency and confirmation information.
+const SAVE_EVERY: u64 = 64;
+
+/// The block a transaction was confirmed in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    /// height of the block
+    pub height: u32,
+    /// hash of the block
+    pub block: BlockHash,
+}
+
+/// Bounds on the size of a `DiskTxIndex`. When exceeded, the least recently
+/// used transactions are evicted.
+#[derive(Clone, Copy, Debug)]
+pub struct DiskLimits {
+    /// the most bytes of transaction data to keep
+    pub max_bytes: u64,
+    /// the most transactions to keep
+    pub max_entries: usize,
+}
+
+impl Default for DiskLimits {
+    fn default() -> Self {
+        DiskLimits {
+            max_bytes: 256 * 1024 * 1024,
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    confirmation: Option<Confirmation>,
+    last_used: u64,
+    /// added with `add_tx` rather than looked up from a chain source, so a
+    /// node may never have seen it (e.g., an unbroadcast template)
+    #[serde(default)]
+    added: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    entries: BTreeMap<Txid, Entry>,
+    clock: u64,
+    /// entries ordered from least to most recently used
+    #[serde(skip)]
+    lru: BTreeSet<(u64, Txid)>,
+    /// changes since the manifest was last saved
+    #[serde(skip)]
+    unsaved: u64,
+    #[serde(skip)]
+    bytes: u64,
+}
+
+impl Manifest {
+    /// rebuilds the indexes which aren't saved
+    fn reindex(&mut self) {
+        self.lru = self
+            .entries
+            .iter()
+            .map(|(t, e)| (e.last_used, *t))
+            .collect();
+        self.bytes = self.entries.values().map(|e| e.size).sum();
+    }
+    fn insert(&mut self, txid: Txid, entry: Entry) {
+        self.lru.insert((entry.last_used, txid));
+        self.bytes += entry.size;
+        if let Some(old) = self.entries.insert(txid, entry) {
+            self.lru.remove(&(old.last_used, txid));
+            self.bytes -= old.size;
+        }
+        self.unsaved += 1;
+    }
+    fn remove(&mut self, txid: &Txid) -> Option<Entry> {
+        let e = self.entries.remove(txid)?;
+        self.lru.remove(&(e.last_used, *txid));
+        self.bytes -= e.size;
+        self.unsaved += 1;
+        Some(e)
+    }
+    fn touch(&mut self, txid: &Txid) {
+        self.clock += 1;
+        if let Some(e) = self.entries.get_mut(txid) {
+            self.lru.remove(&(e.last_used, *txid));
+            e.last_used = self.clock;
+            self.lru.insert((e.last_used, *txid));
+            self.unsaved += 1;
+        }
+    }
+}
+
+/// A TxIndex storing each transaction in its own file in a directory, with a
+/// manifest (`index.json`) recording sizes, recency and the block each
+/// transaction was confirmed in, if known. The manifest is saved every so
+/// many changes, by `flush`, and on drop.
+///
+/// Transactions never change, so entries stay valid forever; only their
+/// confirmation can be undone by a reorg. `sync_with_node` drops entries
+/// confirmed in blocks a node no longer has in its best chain.
+///
+/// Usually used as the `cache` of a `CachedTxIndex`.
+pub struct DiskTxIndex {
+    dir: PathBuf,
+    limits: DiskLimits,
+    manifest: Mutex<Manifest>,
+}
+
+fn storage(e: std::io::Error) -> TxIndexError {
+    TxIndexError::StorageError(e)
+}
+
+impl DiskTxIndex {
+    /// Opens (creating if needed) the index in `dir`. Transaction files
+    /// missing from the manifest (e.g., written by a process which did not
+    /// save it) are adopted.
+    pub fn open(dir: impl AsRef<Path>, limits: DiskLimits) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir).map_err(storage)?;
+        let mut manifest: Manifest = match fs::read(dir.join(MANIFEST)) {
+            Ok(b) => serde_json::from_slice(&b).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(storage(e)),
+        };
+        let mut on_disk = BTreeMap::new();
+        for f in fs::read_dir(&dir).map_err(storage)? {
+            let path = f.map_err(storage)?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(TX_EXTENSION) => {}
+                // left behind by an interrupted write
+                Some("tmp") => {
+                    fs::remove_file(&path).map_err(storage)?;
+                    continue;
+                }
+                _ => continue,
+            }
+            let txid = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<Txid>().ok());
+            match txid {
+                Some(txid) => {
+                    let size = fs::metadata(&path).map_err(storage)?.len();
+                    on_disk.insert(txid, size);
+                }
+                None => fs::remove_file(&path).map_err(storage)?,
+            }
+        }
+        manifest.entries.retain(|t, _| on_disk.contains_key(t));
+        for (txid, size) in on_disk {
+            // we can't know where an adopted transaction came from
+            manifest.entries.entry(txid).or_insert(Entry {
+                size,
+                confirmation: None,
+                last_used: 0,
+                added: true,
+            });
+        }
+        manifest.reindex();
+        let index = DiskTxIndex {
+            dir,
+            limits,
+            manifest: Mutex::new(manifest),
+        };
+        {
+            let mut m = index.lock();
+            index.evict(&mut m)?;
+            index.save(&mut m)?;
+        }
+        Ok(index)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Manifest> {
+        self.manifest.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn path(&self, txid: &Txid) -> PathBuf {
+        self.dir.join(format!("{}.{}", txid, TX_EXTENSION))
+    }
+
+    /// writes `data` to `path` via a rename so readers never see partial files
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).map_err(storage)?;
+        fs::rename(&tmp, path).map_err(storage)
+    }
+
+    fn save(&self, m: &mut Manifest) -> Result<()> {
+        let data = serde_json::to_vec(&*m).map_err(|e| storage(e.into()))?;
+        self.write_atomic(&self.dir.join(MANIFEST), &data)?;
+        m.unsaved = 0;
+        Ok(())
+    }
+
+    /// saves the manifest if enough changes have built up
+    fn save_batched(&self, m: &mut Manifest) -> Result<()> {
+        if m.unsaved >= SAVE_EVERY {
+            self.save(m)?;
+        }
+        Ok(())
+    }
+
+    /// Saves any changes to the manifest now
+    pub fn flush(&self) -> Result<()> {
+        let mut m = self.lock();
+        if m.unsaved > 0 {
+            self.save(&mut m)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, m: &mut Manifest, txid: &Txid) -> Result<()> {
+        if m.remove(txid).is_some() {
+            match fs::remove_file(self.path(txid)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(storage(e)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn evict(&self, m: &mut Manifest) -> Result<()> {
+        while m.bytes > self.limits.max_bytes || m.entries.len() > self.limits.max_entries {
+            let oldest = match m.lru.first() {
+                Some((_, t)) => *t,
+                None => break,
+            };
+            self.remove(m, &oldest)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `tx`, which was looked up from a chain source, recording where
+    /// it was confirmed. An existing confirmation is kept if `confirmation` is
+    /// None.
+    pub fn insert(
+        &self,
+        tx: &bitcoin::Transaction,
+        confirmation: Option<Confirmation>,
+    ) -> Result<Txid> {
+        self.store(tx, confirmation, false)
+    }
+
+    fn store(
+        &self,
+        tx: &bitcoin::Transaction,
+        confirmation: Option<Confirmation>,
+        added: bool,
+    ) -> Result<Txid> {
+        let txid = tx.txid();
+        let mut m = self.lock();
+        match m.entries.get_mut(&txid) {
+            Some(e) => {
+                // once a chain source has it, it stays known
+                if e.added && !added {
+                    e.added = false;
+                    m.unsaved += 1;
+                }
+            }
+            None => {
+                let data = serialize(tx);
+                self.write_atomic(&self.path(&txid), &data)?;
+                m.insert(
+                    txid,
+                    Entry {
+                        size: data.len() as u64,
+                        confirmation: None,
+                        last_used: 0,
+                        added,
+                    },
+                );
+            }
+        }
+        m.touch(&txid);
+        if let (Some(e), Some(c)) = (m.entries.get_mut(&txid), confirmation) {
+            e.confirmation = Some(c);
+        }
+        self.evict(&mut m)?;
+        self.save_batched(&mut m)?;
+        Ok(txid)
+    }
+
+    /// where `txid` was confirmed, if it is stored and that is known
+    pub fn confirmation(&self, txid: &Txid) -> Option<Confirmation> {
+        self.lock().entries.get(txid).and_then(|e| e.confirmation)
+    }
+
+    /// the number of transactions stored
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// whether no transactions are stored
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the total size of the transactions stored
+    pub fn bytes(&self) -> u64 {
+        self.lock().bytes
+    }
+
+    /// Removes every transaction confirmed in a block for which `in_chain`
+    /// returns false, returning how many were removed.
+    pub fn retain_blocks(&self, mut in_chain: impl FnMut(&Confirmation) -> bool) -> Result<usize> {
+        let mut m = self.lock();
+        let stale: Vec<Txid> = m
+            .entries
+            .iter()
+            .filter(|(_, e)| e.confirmation.as_ref().is_some_and(|c| !in_chain(c)))
+            .map(|(t, _)| *t)
+            .collect();
+        for t in &stale {
+            self.remove(&mut m, t)?;
+        }
+        self.save(&mut m)?;
+        Ok(stale.len())
+    }
+
+    /// Removes every transaction confirmed at or above `height`, e.g. after a
+    /// reorg to that height.
+    pub fn invalidate_from(&self, height: u32) -> Result<usize> {
+        self.retain_blocks(|c| c.height < height)
+    }
+
+    /// Checks the stored confirmations against a node's best chain: entries
+    /// from blocks which were reorged out are removed, and unconfirmed entries
+    /// which the node knows to be confirmed are updated. Entries which were
+    /// added rather than looked up (e.g., templates) aren't asked about.
+    /// Returns how many entries were removed.
+    pub async fn sync_with_node(&self, client: &rpc::Client) -> Result<usize> {
+        let (blocks, unconfirmed) = {
+            let m = self.lock();
+            let blocks: BTreeMap<u32, BlockHash> = m
+                .entries
+                .values()
+                .filter_map(|e| e.confirmation.map(|c| (c.height, c.block)))
+                .collect();
+            let unconfirmed: Vec<Txid> = m
+                .entries
+                .iter()
+                .filter(|(_, e)| e.confirmation.is_none() && !e.added)
+                .map(|(t, _)| *t)
+                .collect();
+            (blocks, unconfirmed)
+        };
+        let tip = client.get_block_count().await.map_err(rpc_error)?;
+        let mut best = BTreeMap::new();
+        for height in blocks.keys().filter(|h| **h as u64 <= tip) {
+            best.insert(
+                *height,
+                client
+                    .get_block_hash(*height as u64)
+                    .await
+                    .map_err(rpc_error)?,
+            );
+        }
+        let removed = self.retain_blocks(|c| best.get(&c.height) == Some(&c.block))?;
+        let mut found = vec![];
+        for txid in unconfirmed {
+            // without -txindex the node may not know the transaction at all
+            let block = match client.get_raw_transaction_info(&txid, None).await {
+                Ok(info) => info.blockhash,
+                Err(_) => None,
+            };
+            if let Some(block) = block {
+                let header = client
+                    .get_block_header_info(&block)
+                    .await
+                    .map_err(rpc_error)?;
+                found.push((
+                    txid,
+                    Confirmation {
+                        height: header.height as u32,
+                        block,
+                    },
+                ));
+            }
+        }
+        let mut m = self.lock();
+        for (txid, c) in found {
+            if let Some(e) = m.entries.get_mut(&txid) {
+                e.confirmation = Some(c);
+            }
+        }
+        self.save(&mut m)?;
+        Ok(removed)
+    }
+}
+
+impl Drop for DiskTxIndex {
+    /// saves any changes not yet saved
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl TxIndex for DiskTxIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        let mut m = self.lock();
+        if !m.entries.contains_key(b) {
+            return Err(TxIndexError::UnknownTxid(*b));
+        }
+        let tx = fs::read(self.path(b))
+            .ok()
+            .and_then(|data| deserialize::<bitcoin::Transaction>(&data).ok())
+            .filter(|tx| tx.txid() == *b);
+        match tx {
+            Some(tx) => {
+                m.touch(b);
+                Ok(Arc::new(tx))
+            }
+            None => {
+                // deleted or corrupted out from under us
+                self.remove(&mut m, b)?;
+                Err(TxIndexError::UnknownTxid(*b))
+            }
+        }
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.store(&tx, None, true)
+    }
+    fn cache_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.store(&tx, None, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use sapio_base::txindex::{CachedTxIndex, TxIndexLogger};
+
+    fn example_tx(n: u32) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: n,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut {
+                value: n as u64,
+                script_pubkey: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn persists_evicts_and_invalidates() {
+        let dir = std::env::temp_dir().join(format!("sapio-disk-txindex-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let limits = DiskLimits {
+            max_bytes: 1_000_000,
+            max_entries: 3,
+        };
+        let txs: Vec<_> = (0..4).map(example_tx).collect();
+        let block = |h: u32| Confirmation {
+            height: h,
+            block: BlockHash::from_inner([h as u8; 32]),
+        };
+        {
+            let primary = TxIndexLogger::new();
+            for tx in &txs {
+                primary.add_tx(Arc::new(tx.clone())).unwrap();
+            }
+            let index = CachedTxIndex {
+                cache: DiskTxIndex::open(&dir, limits).unwrap(),
+                primary,
+            };
+            for tx in &txs[..3] {
+                index.lookup_tx(&tx.txid()).unwrap();
+            }
+            // make txs[0] the most recently used, so txs[1] is evicted next
+            index.cache.lookup_tx(&txs[0].txid()).unwrap();
+            index.cache.insert(&txs[3], Some(block(10))).unwrap();
+            assert_eq!(index.cache.len(), 3);
+            assert!(index.cache.lookup_tx(&txs[1].txid()).is_err());
+            index.cache.insert(&txs[0], Some(block(5))).unwrap();
+        }
+        let index = DiskTxIndex::open(&dir, limits).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(*index.lookup_tx(&txs[2].txid()).unwrap(), txs[2]);
+        assert_eq!(index.confirmation(&txs[3].txid()), Some(block(10)));
+        assert_eq!(index.confirmation(&txs[2].txid()), None);
+        // a reorg replacing block 10
+        assert_eq!(index.invalidate_from(10).unwrap(), 1);
+        assert!(index.lookup_tx(&txs[3].txid()).is_err());
+        // a block replaced at the same height
+        assert_eq!(
+            index.retain_blocks(|c| c.block != block(5).block).unwrap(),
+            1
+        );
+        assert_eq!(index.len(), 1);
+        drop(index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batches_manifest_writes_and_tracks_origin() {
+        let dir = std::env::temp_dir().join(format!("sapio-disk-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let index = DiskTxIndex::open(&dir, Default::default()).unwrap();
+        let saved = || -> Manifest {
+            serde_json::from_slice(&fs::read(dir.join(MANIFEST)).unwrap()).unwrap()
+        };
+        let (local, looked_up) = (example_tx(1), example_tx(2));
+        index.add_tx(Arc::new(local.clone())).unwrap();
+        index.cache_tx(Arc::new(looked_up.clone())).unwrap();
+        // not saved until enough changes build up, or a flush
+        assert!(saved().entries.is_empty());
+        index.flush().unwrap();
+        let m = saved();
+        assert!(m.entries[&local.txid()].added);
+        assert!(!m.entries[&looked_up.txid()].added);
+        // a local transaction a chain source later returns becomes known
+        index.cache_tx(Arc::new(local.clone())).unwrap();
+        for n in 3..3 + SAVE_EVERY as u32 {
+            index.add_tx(Arc::new(example_tx(n))).unwrap();
+        }
+        assert!(!saved().entries[&local.txid()].added);
+        drop(index);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
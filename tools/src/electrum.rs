@@ -0,0 +1,479 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A TxIndex backed by an Electrum server
+use super::*;
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::hashes::hex::FromHex;
+use sapio_base::txindex::select_outputs;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{self, pki_types::ServerName};
+
+/// the client name and protocol version sent in `server.version`
+const CLIENT_VERSION: (&str, &str) = ("sapio", "1.4");
+
+/// a plain or TLS connection to a server
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+struct Connection {
+    reader: BufReader<ReadHalf<Box<dyn Stream>>>,
+    writer: WriteHalf<Box<dyn Stream>>,
+}
+
+/// A TxIndex based on an Electrum server, reached over TLS with
+/// `ssl://host:port` (e.g., `ssl://electrum.blockstream.info:50002`) or over
+/// plain TCP with `tcp://host:port` or just `host:port` (e.g.,
+/// `localhost:50001`). TLS servers must have a certificate from a public
+/// certificate authority.
+///
+/// A single connection is kept open and re-established on failure.
+pub struct ElectrumIndex {
+    address: String,
+    connection: Mutex<Option<Connection>>,
+    next_id: AtomicU64,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    /// if can_add is true, then allow the Index to broadcast transactions
+    pub can_add: bool,
+    /// the most requests sent in one JSON-RPC batch
+    pub batch_size: usize,
+    /// how long connecting, or one request and its replies, may take
+    pub timeout: Duration,
+    /// the longest line read from the server, in bytes
+    pub max_line: usize,
+}
+
+type Reply = std::result::Result<Value, Value>;
+
+impl ElectrumIndex {
+    /// Create an index for the server at `address` (`ssl://host:port`,
+    /// `tcp://host:port` or `host:port`). No connection is made until the
+    /// first lookup.
+    pub fn new(address: String, runtime: Arc<tokio::runtime::Runtime>) -> Self {
+        ElectrumIndex {
+            address,
+            connection: Mutex::new(None),
+            next_id: AtomicU64::new(0),
+            runtime,
+            can_add: false,
+            batch_size: 100,
+            timeout: Duration::from_secs(30),
+            max_line: 32 * 1024 * 1024,
+        }
+    }
+
+    async fn open(&self) -> std::io::Result<Box<dyn Stream>> {
+        let invalid = |m: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, m.to_owned());
+        let (tls, address) = match self.address.split_once("://") {
+            Some(("ssl", a)) => (true, a),
+            Some(("tcp", a)) => (false, a),
+            Some(_) => return Err(invalid("expected an ssl:// or tcp:// address")),
+            None => (false, &self.address[..]),
+        };
+        let tcp = TcpStream::connect(address).await?;
+        if !tls {
+            return Ok(Box::new(tcp));
+        }
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(h, _)| h)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = ServerName::try_from(host.to_owned()).map_err(|_| invalid("invalid host"))?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await?;
+        Ok(Box::new(tls))
+    }
+
+    async fn connect(&self) -> std::io::Result<Connection> {
+        let stream = timeout(self.timeout, self.open()).await?;
+        let (r, w) = tokio::io::split(stream);
+        let mut conn = Connection {
+            reader: BufReader::new(r),
+            writer: w,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let req = json!({"jsonrpc": "2.0", "id": id, "method": "server.version",
+            "params": [CLIENT_VERSION.0, CLIENT_VERSION.1]});
+        timeout(
+            self.timeout,
+            exchange(&mut conn, &req, &[id], self.max_line),
+        )
+        .await?;
+        Ok(conn)
+    }
+
+    /// Makes the `calls` (method and params) as one JSON-RPC batch, returning
+    /// each call's result or error in order.
+    async fn call(&self, calls: &[(&str, Value)]) -> Result<Vec<Reply>> {
+        let mut guard = self.connection.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.connect().await.map_err(TxIndexError::NetworkError)?);
+        }
+        let ids: Vec<u64> = calls
+            .iter()
+            .map(|_| self.next_id.fetch_add(1, Ordering::Relaxed))
+            .collect();
+        let reqs: Vec<Value> = calls
+            .iter()
+            .zip(&ids)
+            .map(|((method, params), id)| {
+                json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params})
+            })
+            .collect();
+        let req = if reqs.len() == 1 {
+            reqs[0].clone()
+        } else {
+            Value::Array(reqs)
+        };
+        let conn = guard.as_mut().expect("connected above");
+        match timeout(self.timeout, exchange(conn, &req, &ids, self.max_line)).await {
+            Ok(mut replies) => Ok(ids
+                .iter()
+                .map(|id| replies.remove(id).expect("exchange returns every id"))
+                .collect()),
+            Err(e) => {
+                // the stream may be out of sync, so start over next time
+                *guard = None;
+                Err(TxIndexError::NetworkError(e))
+            }
+        }
+    }
+
+    async fn get_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>> {
+        let mut results = Vec::with_capacity(txids.len());
+        for chunk in txids.chunks(self.batch_size.max(1)) {
+            let calls: Vec<(&str, Value)> = chunk
+                .iter()
+                .map(|t| ("blockchain.transaction.get", json!([t.to_string()])))
+                .collect();
+            match self.call(&calls).await {
+                Ok(replies) => {
+                    results.extend(replies.into_iter().zip(chunk).map(|(r, t)| parse_tx(r, t)))
+                }
+                Err(e) => {
+                    let msg = e.to_string();
+                    results.push(Err(e));
+                    results.extend(chunk[1..].iter().map(|_| Err(protocol_error(&msg))));
+                }
+            }
+        }
+        results
+    }
+}
+
+/// runs `f`, failing if it takes longer than `limit`
+async fn timeout<T>(
+    limit: Duration,
+    f: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(limit, f)
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+/// Sends `req` and reads lines of at most `max_line` bytes until a response
+/// for every id in `ids` has arrived, skipping notifications.
+async fn exchange(
+    conn: &mut Connection,
+    req: &Value,
+    ids: &[u64],
+    max_line: usize,
+) -> std::io::Result<BTreeMap<u64, Reply>> {
+    let invalid = |m: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, m.to_owned());
+    let mut line = serde_json::to_vec(req)?;
+    line.push(b'\n');
+    conn.writer.write_all(&line).await?;
+    let mut replies = BTreeMap::new();
+    while replies.len() < ids.len() {
+        let mut line = String::new();
+        let read = (&mut conn.reader)
+            .take(max_line as u64 + 1)
+            .read_line(&mut line)
+            .await?;
+        if read > max_line {
+            return Err(invalid("line from server too long"));
+        }
+        if !line.ends_with('\n') {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let msgs = match serde_json::from_str(&line)? {
+            Value::Array(v) => v,
+            v => vec![v],
+        };
+        for mut msg in msgs {
+            let id = match msg.get("id").and_then(Value::as_u64) {
+                Some(id) if ids.contains(&id) => id,
+                // notifications (or stale replies)
+                _ => continue,
+            };
+            let reply = match msg.get_mut("error").map(Value::take) {
+                Some(e) if !e.is_null() => Err(e),
+                _ => Ok(msg
+                    .get_mut("result")
+                    .map(Value::take)
+                    .ok_or_else(|| invalid("reply without result"))?),
+            };
+            replies.insert(id, reply);
+        }
+    }
+    Ok(replies)
+}
+
+fn server_error(e: Value) -> TxIndexError {
+    let msg = e
+        .get("message")
+        .and_then(Value::as_str)
+        .map(String::from)
+        .unwrap_or_else(|| e.to_string());
+    protocol_error(msg)
+}
+
+fn parse_tx(r: Reply, txid: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+    let hex = r.map_err(server_error)?;
+    let bytes = hex
+        .as_str()
+        .and_then(|h| Vec::<u8>::from_hex(h).ok())
+        .ok_or_else(|| protocol_error("expected a hex transaction"))?;
+    let tx: bitcoin::Transaction = deserialize(&bytes).map_err(rpc_error)?;
+    if tx.txid() != *txid {
+        return Err(protocol_error("server returned the wrong transaction"));
+    }
+    Ok(Arc::new(tx))
+}
+
+impl ElectrumIndex {
+    async fn broadcast(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        let txid = tx.txid();
+        if !self.can_add {
+            return Ok(txid);
+        }
+        let call = [(
+            "blockchain.transaction.broadcast",
+            json!([serialize_hex(&*tx)]),
+        )];
+        let reply = self.call(&call).await?.remove(0).map_err(server_error)?;
+        let returned = reply
+            .as_str()
+            .and_then(|s| Txid::from_str(s).ok())
+            .ok_or_else(|| protocol_error("server returned an invalid txid"))?;
+        if returned != txid {
+            return Err(protocol_error("server returned the wrong txid"));
+        }
+        Ok(txid)
+    }
+}
+
+/// Servers report unknown transactions with an error message, which is
+/// returned as a `TxIndexError::RpcError`.
+impl TxIndex for ElectrumIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        self.lookup_txs(std::slice::from_ref(b)).remove(0)
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        block_on(&self.runtime, self.broadcast(tx))
+    }
+}
+
+impl BatchTxIndex for ElectrumIndex {
+    /// Sends JSON-RPC batches of up to `batch_size` requests.
+    fn lookup_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>> {
+        block_on(&self.runtime, self.get_txs(txids))
+    }
+}
+
+/// Lookups run on the caller's runtime, not `runtime`.
+#[async_trait]
+impl AsyncTxIndex for ElectrumIndex {
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        self.get_txs(std::slice::from_ref(b)).await.remove(0)
+    }
+    async fn lookup_outputs(&self, outs: &[bitcoin::OutPoint]) -> Vec<Result<bitcoin::TxOut>> {
+        let txids: Vec<Txid> = outs
+            .iter()
+            .map(|o| o.txid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let txs = self.get_txs(&txids).await;
+        select_outputs(outs, txids.into_iter().zip(txs).collect())
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.broadcast(tx).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::txindex::{CachedTxIndex, TxIndexLogger};
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::AtomicUsize;
+
+    fn example_tx(n: u32) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: n,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut {
+                value: n as u64,
+                script_pubkey: Default::default(),
+            }],
+        }
+    }
+
+    /// serves `txs` over the Electrum protocol, counting the lines received
+    fn mock_electrum(txs: Vec<bitcoin::Transaction>) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let txs: BTreeMap<String, String> = txs
+            .iter()
+            .map(|t| (t.txid().to_string(), serialize_hex(t)))
+            .collect();
+        std::thread::spawn(move || {
+            let stream = listener.incoming().next().unwrap().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let req: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let respond = |r: &Value| {
+                    let (id, method, params) = (&r["id"], &r["method"], &r["params"]);
+                    match method.as_str().unwrap() {
+                        "server.version" => json!({"id": id, "result": ["mock", "1.4"]}),
+                        "blockchain.transaction.get" => {
+                            match txs.get(params[0].as_str().unwrap()) {
+                                Some(hex) => json!({"id": id, "result": hex}),
+                                None => json!({"id": id, "error":
+                                    {"code": 2, "message": "missing transaction"}}),
+                            }
+                        }
+                        _ => json!({"id": id, "error": {"code": -32601, "message": "unknown"}}),
+                    }
+                };
+                let resp = match &req {
+                    Value::Array(v) => Value::Array(v.iter().rev().map(respond).collect()),
+                    r => respond(r),
+                };
+                // interleave a notification to check it is skipped
+                writeln!(
+                    writer,
+                    "{}",
+                    json!({"method": "blockchain.headers.subscribe", "params": []})
+                )
+                .unwrap();
+                writeln!(writer, "{}", resp).unwrap();
+            }
+        });
+        (address, count)
+    }
+
+    /// accepts one connection, sends `reply` and holds the connection open,
+    /// returning the first byte the client sent
+    fn mock_server(reply: Vec<u8>) -> (String, std::sync::mpsc::Receiver<u8>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut first = [0];
+            if std::io::Read::read_exact(&mut stream, &mut first).is_ok() {
+                let _ = tx.send(first[0]);
+            }
+            let _ = stream.write_all(&reply);
+            std::thread::sleep(std::time::Duration::from_secs(5));
+        });
+        (address, rx)
+    }
+
+    fn lookup_error(electrum: &ElectrumIndex) -> std::io::ErrorKind {
+        match electrum
+            .runtime
+            .block_on(electrum.get_txs(&[example_tx(1).txid()]))
+            .remove(0)
+        {
+            Err(TxIndexError::NetworkError(e)) => e.kind(),
+            r => panic!("expected a network error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn electrum_bounds_replies() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let (address, _) = mock_server(vec![b'x'; 100]);
+        let mut electrum = ElectrumIndex::new(address, runtime.clone());
+        electrum.max_line = 10;
+        assert_eq!(lookup_error(&electrum), std::io::ErrorKind::InvalidData);
+
+        let (address, _) = mock_server(vec![]);
+        let mut electrum = ElectrumIndex::new(address, runtime);
+        electrum.timeout = Duration::from_millis(200);
+        assert_eq!(lookup_error(&electrum), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn electrum_ssl_uses_tls() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let (address, first) = mock_server(vec![]);
+        let mut electrum = ElectrumIndex::new(format!("ssl://{}", address), runtime.clone());
+        electrum.timeout = Duration::from_millis(200);
+        lookup_error(&electrum);
+        // a TLS handshake record, rather than JSON
+        assert_eq!(first.recv().unwrap(), 0x16);
+
+        let electrum = ElectrumIndex::new("http://localhost:50001".into(), runtime);
+        assert_eq!(lookup_error(&electrum), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn electrum_batches_lookups() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let txs: Vec<_> = (1..=5).map(example_tx).collect();
+        let (address, count) = mock_electrum(txs.clone());
+        let mut electrum = ElectrumIndex::new(address, runtime);
+        electrum.batch_size = 3;
+        let index = CachedTxIndex {
+            cache: TxIndexLogger::new(),
+            primary: electrum,
+        };
+        let mut txids: Vec<Txid> = txs.iter().map(|t| t.txid()).collect();
+        // server.version, then one line per batch
+        prefetch(&index, &txids).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        for tx in &txs {
+            assert_eq!(*TxIndex::lookup_tx(&index, &tx.txid()).unwrap(), *tx);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        txids.push(example_tx(6).txid());
+        assert!(matches!(
+            prefetch(&index, &txids),
+            Err(TxIndexError::RpcError(_))
+        ));
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+}
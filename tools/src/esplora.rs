@@ -0,0 +1,313 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A TxIndex backed by the Esplora REST API
+use super::http::HttpClient;
+use super::*;
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use reqwest::Method;
+use sapio_base::txindex::select_outputs;
+use std::str::FromStr;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// A TxIndex based on an Esplora server (e.g., `http://localhost:3000` or
+/// `https://blockstream.info/api`).
+///
+/// Every request times out after 30 seconds, and responses over 32 MiB are
+/// refused.
+pub struct EsploraIndex {
+    http: HttpClient,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    /// if can_add is true, then allow the Index to broadcast transactions
+    pub can_add: bool,
+    /// the most requests made at once by a batch lookup
+    pub max_concurrency: usize,
+}
+
+impl EsploraIndex {
+    /// Create an index for the server at `url`, which must be `http://` or
+    /// `https://`
+    pub fn new(url: &str, runtime: Arc<tokio::runtime::Runtime>) -> Result<Self> {
+        Ok(EsploraIndex {
+            http: HttpClient::new(url).map_err(TxIndexError::NetworkError)?,
+            runtime,
+            can_add: false,
+            max_concurrency: 8,
+        })
+    }
+}
+
+/// why a lookup failed, which (unlike TxIndexError) can be sent between tasks
+enum Fetch {
+    Network(std::io::Error),
+    NotFound,
+    Invalid(String),
+}
+
+impl Fetch {
+    fn into_error(self, txid: Txid) -> TxIndexError {
+        match self {
+            Fetch::Network(e) => TxIndexError::NetworkError(e),
+            Fetch::NotFound => TxIndexError::UnknownTxid(txid),
+            Fetch::Invalid(m) => protocol_error(m),
+        }
+    }
+}
+
+async fn get_tx(
+    http: &HttpClient,
+    txid: Txid,
+) -> std::result::Result<Arc<bitcoin::Transaction>, Fetch> {
+    let r = http
+        .request(Method::GET, &format!("/tx/{}/raw", txid), None)
+        .await
+        .map_err(Fetch::Network)?;
+    match r.status {
+        200 => {
+            let tx: bitcoin::Transaction =
+                deserialize(&r.body).map_err(|e| Fetch::Invalid(e.to_string()))?;
+            if tx.txid() != txid {
+                return Err(Fetch::Invalid(
+                    "server returned the wrong transaction".into(),
+                ));
+            }
+            Ok(Arc::new(tx))
+        }
+        404 => Err(Fetch::NotFound),
+        s => Err(Fetch::Invalid(http_error(s, &r.body))),
+    }
+}
+
+fn http_error(status: u16, body: &[u8]) -> String {
+    format!(
+        "http status {}: {}",
+        status,
+        String::from_utf8_lossy(body).trim()
+    )
+}
+
+impl EsploraIndex {
+    async fn broadcast(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        let txid = tx.txid();
+        if !self.can_add {
+            return Ok(txid);
+        }
+        let body = serialize_hex(&*tx);
+        let r = self
+            .http
+            .request(Method::POST, "/tx", Some(body.into_bytes()))
+            .await
+            .map_err(TxIndexError::NetworkError)?;
+        if r.status != 200 {
+            return Err(protocol_error(http_error(r.status, &r.body)));
+        }
+        let returned = Txid::from_str(String::from_utf8_lossy(&r.body).trim())
+            .map_err(|_| protocol_error("server returned an invalid txid"))?;
+        if returned != txid {
+            return Err(protocol_error("server returned the wrong txid"));
+        }
+        Ok(txid)
+    }
+
+    /// Esplora has no batch endpoint, so this runs up to `max_concurrency`
+    /// requests at a time.
+    async fn get_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>> {
+        let limit = Arc::new(Semaphore::new(self.max_concurrency.max(1)));
+        let mut set = JoinSet::new();
+        for (i, txid) in txids.iter().cloned().enumerate() {
+            let http = self.http.clone();
+            let limit = limit.clone();
+            set.spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                (i, get_tx(&http, txid).await.map_err(|e| (e, txid)))
+            });
+        }
+        let mut results: Vec<Option<_>> = txids.iter().map(|_| None).collect();
+        while let Some(joined) = set.join_next().await {
+            match joined {
+                Ok((i, r)) => results[i] = Some(r),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+        results
+            .into_iter()
+            .flatten()
+            .map(|r| r.map_err(|(e, txid)| e.into_error(txid)))
+            .collect()
+    }
+}
+
+impl TxIndex for EsploraIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        block_on(&self.runtime, get_tx(&self.http, *b)).map_err(|e| e.into_error(*b))
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        block_on(&self.runtime, self.broadcast(tx))
+    }
+}
+
+impl BatchTxIndex for EsploraIndex {
+    fn lookup_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>> {
+        block_on(&self.runtime, self.get_txs(txids))
+    }
+}
+
+/// Lookups run on the caller's runtime, not `runtime`.
+#[async_trait]
+impl AsyncTxIndex for EsploraIndex {
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        get_tx(&self.http, *b).await.map_err(|e| e.into_error(*b))
+    }
+    async fn lookup_outputs(&self, outs: &[bitcoin::OutPoint]) -> Vec<Result<bitcoin::TxOut>> {
+        let txids: Vec<Txid> = outs
+            .iter()
+            .map(|o| o.txid)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let txs = self.get_txs(&txids).await;
+        select_outputs(outs, txids.into_iter().zip(txs).collect())
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.broadcast(tx).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use sapio_base::txindex::{CachedTxIndex, TxIndexLogger};
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn example_tx(n: u32) -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 2,
+            lock_time: n,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut {
+                value: n as u64,
+                script_pubkey: Default::default(),
+            }],
+        }
+    }
+
+    /// serves `txs` over HTTP, counting the requests made
+    fn mock_esplora(txs: Vec<bitcoin::Transaction>) -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let txs: BTreeMap<String, bitcoin::Transaction> =
+            txs.into_iter().map(|t| (t.txid().to_string(), t)).collect();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut h = String::new();
+                    stream.read_line(&mut h).unwrap();
+                    if h == "\r\n" {
+                        break;
+                    }
+                    if let Some(v) = h.to_ascii_lowercase().strip_prefix("content-length: ") {
+                        length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                let path: Vec<&str> = line.split(' ').nth(1).unwrap().split('/').collect();
+                let (status, reply) = match (&path[..], line.starts_with("POST")) {
+                    (["", "api", "tx", txid, "raw"], false) => match txs.get(*txid) {
+                        Some(tx) => ("200 OK", bitcoin::consensus::serialize(tx)),
+                        None => ("404 Not Found", b"Transaction not found".to_vec()),
+                    },
+                    (["", "api", "tx"], true) => {
+                        let hex = std::str::from_utf8(&body).unwrap();
+                        let tx: bitcoin::Transaction =
+                            deserialize(&Vec::<u8>::from_hex(hex).unwrap()).unwrap();
+                        ("200 OK", tx.txid().to_string().into_bytes())
+                    }
+                    _ => ("400 Bad Request", vec![]),
+                };
+                let mut stream = stream.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+                    status,
+                    reply.len()
+                )
+                .unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        (url, count)
+    }
+
+    #[test]
+    fn esplora_lookup_and_cache() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let txs: Vec<_> = (1..=4).map(example_tx).collect();
+        let new = example_tx(6);
+        let (url, count) = mock_esplora(txs.iter().chain([&new]).cloned().collect());
+        let mut esplora = EsploraIndex::new(&url, runtime).unwrap();
+        esplora.can_add = true;
+        assert_eq!(
+            *TxIndex::lookup_tx(&esplora, &txs[0].txid()).unwrap(),
+            txs[0]
+        );
+        assert!(matches!(
+            TxIndex::lookup_tx(&esplora, &example_tx(5).txid()),
+            Err(TxIndexError::UnknownTxid(_))
+        ));
+        assert_eq!(
+            TxIndex::add_tx(&esplora, Arc::new(example_tx(5))).unwrap(),
+            example_tx(5).txid()
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let index = CachedTxIndex {
+            cache: TxIndexLogger::new(),
+            primary: esplora,
+        };
+        let txids: Vec<Txid> = txs.iter().map(|t| t.txid()).collect();
+        prefetch(&index, &txids).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 7);
+        for tx in &txs {
+            assert_eq!(*TxIndex::lookup_tx(&index, &tx.txid()).unwrap(), *tx);
+        }
+        // everything was served from the cache
+        prefetch(&index, &txids).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 7);
+
+        // async batches only fetch each missing transaction once
+        let outs = [
+            bitcoin::OutPoint::new(txs[0].txid(), 0),
+            bitcoin::OutPoint::new(new.txid(), 0),
+            bitcoin::OutPoint::new(new.txid(), 1),
+            bitcoin::OutPoint::new(example_tx(7).txid(), 0),
+        ];
+        let runtime = index.primary.runtime.clone();
+        let found = runtime.block_on(AsyncTxIndex::lookup_outputs(&index, &outs));
+        assert_eq!(count.load(Ordering::SeqCst), 9);
+        assert_eq!(found[0].as_ref().unwrap(), &txs[0].output[0]);
+        assert_eq!(found[1].as_ref().unwrap(), &new.output[0]);
+        assert!(matches!(found[2], Err(TxIndexError::IndexTooHigh(1))));
+        assert!(matches!(found[3], Err(TxIndexError::UnknownTxid(_))));
+
+        // and cache what they fetched, so asking again doesn't reach esplora
+        let again = runtime.block_on(AsyncTxIndex::lookup_outputs(&index, &outs[..3]));
+        assert_eq!(count.load(Ordering::SeqCst), 9);
+        assert_eq!(again[1].as_ref().unwrap(), &new.output[0]);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small wrapper around reqwest for REST APIs like Esplora, over `http://`
+//! or `https://`, with every request bounded in time and response size.
+use reqwest::{Client, Method, Url};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// how long a request may take, from connecting to reading the whole body
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(30);
+/// the largest response body that will be read
+pub(crate) const MAX_BODY: usize = 32 * 1024 * 1024;
+
+/// A client for the API at a base URL, e.g. `https://blockstream.info/api`
+#[derive(Clone, Debug)]
+pub(crate) struct HttpClient {
+    client: Client,
+    /// the base URL, without a trailing slash
+    base: String,
+    max_body: usize,
+}
+
+/// A response status and body
+pub(crate) struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+fn network_error(e: reqwest::Error) -> Error {
+    if e.is_timeout() {
+        Error::new(ErrorKind::TimedOut, e)
+    } else {
+        Error::other(e)
+    }
+}
+
+impl HttpClient {
+    /// a client for `url` with the default limits
+    pub(crate) fn new(url: &str) -> Result<HttpClient, Error> {
+        Self::with_limits(url, TIMEOUT, MAX_BODY)
+    }
+
+    pub(crate) fn with_limits(
+        url: &str,
+        timeout: Duration,
+        max_body: usize,
+    ) -> Result<HttpClient, Error> {
+        let parsed = Url::parse(url).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        if !matches!(parsed.scheme(), "http" | "https") || parsed.host().is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "expected an http:// or https:// url",
+            ));
+        }
+        let client = Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .map_err(network_error)?;
+        Ok(HttpClient {
+            client,
+            base: url.trim_end_matches('/').to_owned(),
+            max_body,
+        })
+    }
+
+    /// Makes a request for `path` under the base URL. Bodies larger than the
+    /// limit are an error rather than being read.
+    pub(crate) async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response, Error> {
+        let mut req = self
+            .client
+            .request(method, format!("{}{}", self.base, path));
+        if let Some(b) = body {
+            req = req.header("Content-Type", "text/plain").body(b);
+        }
+        let mut resp = req.send().await.map_err(network_error)?;
+        let too_large = || Error::new(ErrorKind::InvalidData, "http body too large");
+        if resp
+            .content_length()
+            .is_some_and(|l| l > self.max_body as u64)
+        {
+            return Err(too_large());
+        }
+        let status = resp.status().as_u16();
+        let mut body = vec![];
+        while let Some(chunk) = resp.chunk().await.map_err(network_error)? {
+            if body.len() + chunk.len() > self.max_body {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Response { status, body })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// serves one connection by reading the request and writing `reply`, then
+    /// holding the connection open
+    fn serve_once(reply: Vec<u8>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(&reply);
+            std::thread::sleep(Duration::from_secs(5));
+        });
+        url
+    }
+
+    #[test]
+    fn accepts_http_and_https_urls() {
+        assert!(HttpClient::new("http://localhost:3000/api/").is_ok());
+        assert!(HttpClient::new("https://blockstream.info/api").is_ok());
+        assert!(HttpClient::new("ftp://blockstream.info/api").is_err());
+        assert!(HttpClient::new("localhost:3000").is_err());
+    }
+
+    #[tokio::test]
+    async fn bounds_responses() {
+        let ok = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc".to_vec());
+        let client = HttpClient::with_limits(&ok, Duration::from_secs(2), 8).unwrap();
+        let r = client.request(Method::GET, "/x", None).await.unwrap();
+        assert_eq!((r.status, &r.body[..]), (200, &b"abc"[..]));
+
+        let large = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n123456789".to_vec());
+        let client = HttpClient::with_limits(&large, Duration::from_secs(2), 8).unwrap();
+        let e = client.request(Method::GET, "/x", None).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // a server which never finishes its response
+        let hung = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\na".to_vec());
+        let client = HttpClient::with_limits(&hung, Duration::from_millis(200), 8).unwrap();
+        let e = client.request(Method::GET, "/x", None).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -7,11 +7,24 @@
 //! tools is a workspace-global set of util functions that require heavy dependencies
 
 #![deny(missing_docs)]
+use async_trait::async_trait;
 use bitcoin::hash_types::*;
 use bitcoincore_rpc_async as rpc;
 use rpc::RpcApi;
-use sapio_base::txindex::{TxIndex, TxIndexError};
+use sapio_base::txindex::{AsyncTxIndex, CachedTxIndex, TxIndex, TxIndexError};
+use std::collections::BTreeSet;
 use std::sync::Arc;
+
+pub mod blocking;
+pub mod disk;
+pub mod electrum;
+pub mod esplora;
+mod http;
+pub use blocking::{block_on, BlockingTxIndex};
+pub use disk::DiskTxIndex;
+pub use electrum::ElectrumIndex;
+pub use esplora::EsploraIndex;
+
 /// A TxIndex based on a Bitcoin RPC Client
 pub struct BitcoinNodeIndex {
     /// RPC Client
@@ -23,31 +36,120 @@ pub struct BitcoinNodeIndex {
 }
 
 type Result<T> = std::result::Result<T, TxIndexError>;
+
+/// A TxIndex which can look up many transactions at once more efficiently
+/// than one at a time.
+pub trait BatchTxIndex: TxIndex {
+    /// lookup many txs, returning a result for each txid in order
+    fn lookup_txs(&self, txids: &[Txid]) -> Vec<Result<Arc<bitcoin::Transaction>>>;
+}
+
+/// Fetches every transaction in `txids` missing from the cache of `index` in
+/// one batch, adding them to the cache.
+///
+/// Transactions which were found are cached even if others fail, in which case
+/// the first failure is returned.
+pub fn prefetch<Cache, Primary>(index: &CachedTxIndex<Cache, Primary>, txids: &[Txid]) -> Result<()>
+where
+    Cache: TxIndex,
+    Primary: BatchTxIndex,
+{
+    let missing: Vec<Txid> = txids
+        .iter()
+        .filter(|t| index.cache.lookup_tx(t).is_err())
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut error = None;
+    for r in index.primary.lookup_txs(&missing) {
+        match r {
+            Ok(tx) => {
+                index.cache.cache_tx(tx)?;
+            }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    error.map_or(Ok(()), Err)
+}
+
+/// An error in the response of a remote TxIndex server
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ProtocolError {}
+
+fn protocol_error(msg: impl Into<String>) -> TxIndexError {
+    rpc_error(ProtocolError(msg.into()))
+}
+
+fn rpc_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> TxIndexError {
+    TxIndexError::RpcError(Box::new(e))
+}
+
+/// An AsyncTxIndex based on a Bitcoin RPC Client, for use from async code
+/// (which has no need for a separate runtime)
+pub struct AsyncBitcoinNodeIndex {
+    /// RPC Client
+    pub client: rpc::Client,
+    /// if can_add is true, then allow the Index to call send_raw_transaction
+    pub can_add: bool,
+}
+
+async fn node_lookup_tx(client: &rpc::Client, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+    client
+        .get_raw_transaction(b, None)
+        .await
+        .map(Arc::new)
+        .map_err(rpc_error)
+}
+
+async fn node_add_tx(
+    client: &rpc::Client,
+    can_add: bool,
+    tx: Arc<bitcoin::Transaction>,
+) -> Result<Txid> {
+    if can_add {
+        client.send_raw_transaction(&*tx).await.map_err(rpc_error)
+    } else {
+        Ok(tx.txid())
+    }
+}
+
+#[async_trait]
+impl AsyncTxIndex for AsyncBitcoinNodeIndex {
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        node_lookup_tx(&self.client, b).await
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        node_add_tx(&self.client, self.can_add, tx).await
+    }
+}
+
+#[async_trait]
+impl AsyncTxIndex for BitcoinNodeIndex {
+    async fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        node_lookup_tx(&self.client, b).await
+    }
+    async fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        node_add_tx(&self.client, self.can_add, tx).await
+    }
+}
+
 impl TxIndex for BitcoinNodeIndex {
     fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
-        tokio::task::block_in_place(|| {
-            self.runtime
-                .block_on(self.client.get_raw_transaction(b, None))
-                .map(Arc::new)
-                .map_err(|e| {
-                    let b: Box<dyn std::error::Error> = Box::new(e);
-                    TxIndexError::RpcError(b)
-                })
-        })
+        block_on(&self.runtime, node_lookup_tx(&self.client, b))
     }
     fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
-        let txid = tx.txid();
-        if self.can_add {
-            tokio::task::block_in_place(|| {
-                self.runtime
-                    .block_on(self.client.send_raw_transaction(&*tx))
-            })
-            .map_err(|e| {
-                let b: Box<dyn std::error::Error> = Box::new(e);
-                TxIndexError::RpcError(b)
-            })
-        } else {
-            Ok(txid)
-        }
+        block_on(&self.runtime, node_add_tx(&self.client, self.can_add, tx))
     }
 }